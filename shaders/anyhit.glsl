@@ -0,0 +1,84 @@
+#version 460
+#pragma shader_stage(anyhit)
+
+#extension GL_EXT_ray_tracing : require
+#extension GL_EXT_nonuniform_qualifier : enable
+#extension GL_EXT_scalar_block_layout : enable
+#extension GL_EXT_buffer_reference2 : enable
+#extension GL_EXT_shader_explicit_arithmetic_types_int64 : require
+
+layout(location = 0) rayPayloadInEXT vec4 hitValue;
+
+hitAttributeEXT vec3 attribs;
+
+// Base color textures, index 0 is white
+layout(binding = 5, set = 0) uniform sampler2D textures[];
+
+layout(buffer_reference, buffer_reference_align = 8, scalar)
+buffer IndexBuffer {
+  uvec3 i[];
+};
+// 16 bit indices, two per element
+layout(buffer_reference, buffer_reference_align = 4, scalar)
+buffer IndexBuffer16 {
+  uint i[];
+};
+layout(buffer_reference, buffer_reference_align = 16, scalar)
+buffer ColorBuffer {
+  vec4 c[];
+};
+layout(buffer_reference, buffer_reference_align = 8, scalar)
+buffer UvBuffer {
+  vec2 uv[];
+};
+
+// Same layout as in closest_hit.glsl
+layout(shaderRecordEXT, std430) buffer SBT {
+  IndexBuffer indices;
+  uint64_t normals;
+  uint64_t positions;
+  uint64_t tangents;
+  vec4 baseColor;
+  uint indexSize;
+  ColorBuffer colors;
+  UvBuffer uvs; // null if the mesh has no texture coordinates
+  uint textureIndex;
+};
+
+const float ALPHA_CUTOFF = 0.5;
+
+uint index16(uint i)
+{
+  uint packed = IndexBuffer16(indices).i[i / 2];
+  return (i % 2 == 0) ? packed & 0xffff : packed >> 16;
+}
+
+uvec3 triangleIndices(uint primitive)
+{
+  if (indexSize == 0) {
+    return uvec3(3 * primitive, 3 * primitive + 1, 3 * primitive + 2);
+  }
+  if (indexSize == 2) {
+    return uvec3(index16(3 * primitive), index16(3 * primitive + 1), index16(3 * primitive + 2));
+  }
+  return indices.i[primitive];
+}
+
+void main()
+{
+  // Only invoked for meshes that are not flagged opaque (--alpha-test)
+  const uvec3 index = triangleIndices(gl_PrimitiveID);
+  const vec3 barycentrics = vec3(1.0 - attribs.x - attribs.y, attribs.x, attribs.y);
+  float alpha = baseColor.a;
+  if (uint64_t(colors) != 0) {
+    alpha *= dot(barycentrics, vec3(colors.c[index.x].a, colors.c[index.y].a, colors.c[index.z].a));
+  }
+  if (uint64_t(uvs) != 0) {
+    const vec2 uv = barycentrics.x * uvs.uv[index.x] + barycentrics.y * uvs.uv[index.y] + barycentrics.z * uvs.uv[index.z];
+    // There are no derivatives to select a mip level from in ray tracing shaders
+    alpha *= textureLod(textures[nonuniformEXT(textureIndex)], uv, 0.0).a;
+  }
+  if (alpha < ALPHA_CUTOFF) {
+    ignoreIntersectionEXT;
+  }
+}
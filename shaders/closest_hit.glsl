@@ -5,11 +5,14 @@
 #extension GL_EXT_nonuniform_qualifier : enable
 #extension GL_EXT_scalar_block_layout : enable
 #extension GL_EXT_buffer_reference2 : enable
+#extension GL_EXT_shader_explicit_arithmetic_types_int64 : require
 
-layout(location = 0) rayPayloadInEXT vec3 hitValue;
-layout(location = 1) rayPayloadEXT vec3 next;
+layout(location = 0) rayPayloadInEXT vec4 hitValue;
+layout(location = 1) rayPayloadEXT vec4 next;
+layout(location = 2) rayPayloadEXT bool shadowed;
 layout(binding = 0, set = 0) uniform accelerationStructureEXT topLevelAS;
 
+// Barycentrics for triangles, object space normal for procedural geometry
 hitAttributeEXT vec3 attribs;
 //layout(binding = 0, set = 0) uniform accelerationStructureEXT topLevelAS;
 
@@ -18,50 +21,176 @@ buffer NormalBuffer {
   vec3 n[];
 };
 layout(buffer_reference, buffer_reference_align = 8, scalar)
+buffer PositionBuffer {
+  vec3 p[];
+};
+layout(buffer_reference, buffer_reference_align = 8, scalar)
 buffer IndexBuffer {
   uvec3 i[];
 };
+// 16 bit indices, two per element
+layout(buffer_reference, buffer_reference_align = 4, scalar)
+buffer IndexBuffer16 {
+  uint i[];
+};
+
+layout(buffer_reference, buffer_reference_align = 16, scalar)
+buffer TangentBuffer {
+  vec4 t[];
+};
+
+layout(buffer_reference, buffer_reference_align = 16, scalar)
+buffer ColorBuffer {
+  vec4 c[];
+};
 
 layout(shaderRecordEXT, std430) buffer SBT {
   IndexBuffer indices;
   NormalBuffer normals;
+  PositionBuffer positions;
+  TangentBuffer tangents; // null if the mesh has no texture coordinates
+  vec4 baseColor; // linear RGBA, white for procedural geometry
+  uint indexSize; // 2 or 4 bytes, 0 for triangle soups without index buffer
+  ColorBuffer colors; // null if the mesh has no vertex colors
 };
 
-layout( push_constant ) uniform constants
+uint index16(uint i)
 {
-    vec4 light;
+  uint packed = IndexBuffer16(indices).i[i / 2];
+  return (i % 2 == 0) ? packed & 0xffff : packed >> 16;
+}
+
+uvec3 triangleIndices(uint primitive)
+{
+  if (indexSize == 0) {
+    return uvec3(3 * primitive, 3 * primitive + 1, 3 * primitive + 2);
+  }
+  if (indexSize == 2) {
+    return uvec3(index16(3 * primitive), index16(3 * primitive + 1), index16(3 * primitive + 2));
+  }
+  return indices.i[primitive];
+}
+
+layout(constant_id = 0) const bool visualizeNormals = false;
+layout(constant_id = 1) const bool visualizeDepth = false;
+layout(constant_id = 2) const bool visualizeWireframe = false;
+layout(constant_id = 3) const bool flatShading = false;
+// Set if the pipeline's recursion depth allows tracing shadow rays
+layout(constant_id = 9) const bool castShadows = false;
+
+// Index of the shadow miss shader among the miss records
+const uint SHADOW_MISS_INDEX = 1;
+const float SHADOW_RAY_EPSILON = 1e-3;
+// Ray length towards directional lights
+const float SHADOW_RAY_TMAX = 1e6;
+
+// Barycentric coordinate below which a hit counts as lying on a triangle edge
+const float WIREFRAME_EDGE_WIDTH = 0.02;
+
+struct Light {
+    vec4 position; // w = 0: direction towards the light, w = 1: point light position
+    vec4 color; // intensity in a
+};
+
+layout(set = 0, binding = 3) uniform LightBuffer {
+    uint count;
+    Light lights[8];
+} lights;
+
+const float AMBIENT = 0.05;
+
+// Whether anything lies between `position` and the light at `toLight` (unnormalized for point
+// lights). Hit shaders are skipped, only the shadow miss shader clears the payload.
+bool isShadowed(vec3 position, vec3 normal, vec3 toLight, bool pointLight)
+{
+    shadowed = true;
+    float tmax = pointLight ? length(toLight) : SHADOW_RAY_TMAX;
+    vec3 origin = position + SHADOW_RAY_EPSILON * max(1.0, length(position)) * normal;
+    traceRayEXT(topLevelAS, gl_RayFlagsTerminateOnFirstHitEXT | gl_RayFlagsSkipClosestHitShaderEXT,
+                0xff, 0 /*sbtRecordOffset*/, 1 /*sbtRecordStride*/, SHADOW_MISS_INDEX, origin,
+                0.0, normalize(toLight), tmax, 2 /*payload*/);
+    return shadowed;
+}
+
+vec3 shade(vec3 normal, vec3 position)
+{
+    vec3 color = vec3(AMBIENT);
+    for (uint i = 0; i < lights.count; ++i) {
+        Light light = lights.lights[i];
+        vec3 toLight = light.position.xyz - light.position.w * position;
+        float cosine = dot(normal, normalize(toLight));
+        if (cosine <= 0.0 || (castShadows && isShadowed(position, normal, toLight, light.position.w != 0.0))) {
+            continue;
+        }
+        float attenuation = light.position.w == 0.0 ? 1.0 : 1.0 / dot(toLight, toLight);
+        color += light.color.rgb * light.color.a * attenuation * cosine;
+    }
+    return color;
+}
+
+layout(set = 0, binding = 1) uniform CameraBuffer {
     mat4 view;
-    mat4 model;
     mat4 proj;
-} PushConstants;
+} camera;
 
 void main()
 {
   const vec3 barycentrics = vec3(1.0 - attribs.x - attribs.y, attribs.x, attribs.y);
+  // Hit kinds of procedural geometry are chosen by the intersection shader
+  const bool procedural = gl_HitKindEXT != gl_HitKindFrontFacingTriangleEXT
+      && gl_HitKindEXT != gl_HitKindBackFacingTriangleEXT;
 
-
-  uvec3 index = indices.i[gl_PrimitiveID];
-  vec3 n0 = normals.n[index.x];
-  vec3 n1 = normals.n[index.y];
-  vec3 n2 = normals.n[index.z];
-  vec3 normal = normalize(n0 * barycentrics.x + n1 * barycentrics.y + n2 * barycentrics.z);
+  vec3 normal;
+  vec4 color = baseColor;
+  if (procedural) {
+    normal = attribs;
+  } else {
+    uvec3 index = triangleIndices(gl_PrimitiveID);
+    if (uint64_t(colors) != 0) {
+      color *= colors.c[index.x] * barycentrics.x + colors.c[index.y] * barycentrics.y
+          + colors.c[index.z] * barycentrics.z;
+    }
+    vec3 n0 = normals.n[index.x];
+    vec3 n1 = normals.n[index.y];
+    vec3 n2 = normals.n[index.z];
+    normal = normalize(n0 * barycentrics.x + n1 * barycentrics.y + n2 * barycentrics.z);
+    if (flatShading) {
+      vec3 p0 = positions.p[index.x];
+      vec3 faceNormal = normalize(cross(positions.p[index.y] - p0, positions.p[index.z] - p0));
+      normal = dot(faceNormal, normal) < 0.0 ? -faceNormal : faceNormal;
+    }
+  }
 
   vec3 hitPos = gl_WorldRayOriginEXT + gl_HitTEXT * gl_WorldRayDirectionEXT;
 
-  //mat4 mvp = PushConstants.proj * PushConstants.model;
   //normal = mat3(transpose(inverse(mvp))) * normal;
   normal = normalize(vec3(normal * gl_WorldToObjectEXT));
+  if (visualizeNormals) {
+    hitValue = vec4(normal * 0.5 + 0.5, 1.0);
+    return;
+  }
+  if (visualizeDepth) {
+    // Clip planes from the projection (z_clip = a * z_view + b, w_clip = -z_view for perspective
+    // and 1 for orthographic projections)
+    float a = camera.proj[2][2];
+    float b = camera.proj[3][2];
+    bool orthographic = camera.proj[3][3] == 1.0;
+    float near = b / a;
+    float far = orthographic ? (b - 1.0) / a : b / (a + 1.0);
+    // Distance along the view direction like the depth buffer of the rasterizer
+    float depth = -(camera.view * vec4(hitPos, 1.0)).z;
+    hitValue = vec4(vec3(clamp((depth - near) / (far - near), 0.0, 1.0)), 1.0);
+    return;
+  }
 
-  uint rayFlags = gl_RayFlagsOpaqueEXT;
-  uint cullMask = 0xff;
-  float tmin = 1;
-  float tmax = 100.0;
-
-  vec3 direction = gl_WorldRayDirectionEXT - 2 * dot(gl_WorldRayDirectionEXT, normal);
-  traceRayEXT(topLevelAS, rayFlags, cullMask, 0 /*sbtRecordOffset*/, 0 /*sbtRecordStride*/, 0 /*missIndex*/, hitPos, tmin, direction, tmax, 1 /*payload*/);
   //hitValue = 0.1 * normal + next;
   //if (barycentrics.x < 0.06 || barycentrics.y < 0.06 || barycentrics.z < 0.06) {
-  hitValue = normal + 0.5;
+  // Shade both sides of the surface
+  hitValue = color * vec4(shade(dot(normal, gl_WorldRayDirectionEXT) > 0.0 ? -normal : normal, hitPos), 1.0);
+  if (visualizeWireframe && !procedural) {
+    float edgeDistance = min(barycentrics.x, min(barycentrics.y, barycentrics.z));
+    hitValue = edgeDistance < WIREFRAME_EDGE_WIDTH ? vec4(1.0) : vec4(0.2 * hitValue.rgb, 1.0);
+  }
   //} else  {
   //hitValue = vec3(0,0,0);
   //}
@@ -0,0 +1,17 @@
+#version 450
+
+layout (location = 0) in vec3 vPosition;
+layout (location = 1) in vec3 vColor;
+layout (location = 0) out vec3 outColor;
+
+layout (set = 0, binding = 0) uniform CameraBuffer {
+    mat4 view;
+    mat4 proj;
+} camera;
+
+void main()
+{
+    // Lines are in world space
+    gl_Position = camera.proj * camera.view * vec4(vPosition, 1.0);
+    outColor = vColor;
+}
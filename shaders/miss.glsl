@@ -3,9 +3,30 @@
 
 #extension GL_EXT_ray_tracing : require
 
-layout(location = 0) rayPayloadInEXT ivec3 hitValue;
+layout(location = 0) rayPayloadInEXT vec4 hitValue;
+
+// Equirectangular environment map
+layout(binding = 4, set = 0) uniform sampler2D environmentMap;
+
+layout( push_constant ) uniform constants
+{
+    vec4 background;
+    vec4 backgroundTop; // same as background without gradient
+    uint sampleIndex;
+    uint maxSamples;
+    uint useEnvironmentMap;
+} PushConstants;
+
+const float PI = 3.14159265358979323846;
 
 void main()
 {
-  hitValue = ivec3(0, 0, 0);
+  vec3 direction = normalize(gl_WorldRayDirectionEXT);
+  if (PushConstants.useEnvironmentMap != 0) {
+    vec2 uv = vec2(atan(direction.x, -direction.z) / (2.0 * PI) + 0.5, acos(clamp(direction.y, -1.0, 1.0)) / PI);
+    hitValue = vec4(texture(environmentMap, uv).rgb, 1.0);
+    return;
+  }
+  float t = 0.5 * normalize(gl_WorldRayDirectionEXT).y + 0.5;
+  hitValue = mix(PushConstants.background, PushConstants.backgroundTop, t);
 }
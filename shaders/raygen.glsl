@@ -5,35 +5,124 @@
 //#extension GL_EXT_debug_printf : enable
 
 layout(binding = 0, set = 0) uniform accelerationStructureEXT topLevelAS;
-layout(binding = 1, set = 0, rgba8) uniform image2D image;
+// Swapchain image, in its own set so that it can be pushed each frame
+layout(binding = 0, set = 1, rgba8) uniform image2D image;
+// Sum of all samples since the last reset
+layout(binding = 2, set = 0, rgba32f) uniform image2D accumulationImage;
 
-layout( push_constant ) uniform constants
-{
-    vec4 light;
+layout(binding = 1, set = 0) uniform CameraBuffer {
     mat4 view;
-    mat4 model;
     mat4 proj;
+} camera;
+
+layout( push_constant ) uniform constants
+{
+    vec4 background;
+    vec4 backgroundTop;
+    uint sampleIndex;
+    uint maxSamples; // 0 to accumulate indefinitely
+    uint useEnvironmentMap; // Only read by the miss shader
+    // Pixel of the swapchain image traced by the first ray
+    uint offsetX;
+    uint offsetY;
 } PushConstants;
 
-layout(location = 0) rayPayloadEXT vec3 hitValue;
+layout(location = 0) rayPayloadEXT vec4 hitValue;
+
+layout(constant_id = 4) const bool doubleSided = false;
+// 0: clamp, 1: Reinhard, 2: ACES
+layout(constant_id = 5) const uint tonemap = 0;
+// Set if the swapchain format doesn't sRGB encode the values written to it
+layout(constant_id = 6) const bool encodeSrgb = false;
+
+// PCG hash, see "Hash Functions for GPU Rendering" (Jarzynski, Olano)
+uint pcg(uint v)
+{
+  uint state = v * 747796405u + 2891336453u;
+  uint word = ((state >> ((state >> 28u) + 4u)) ^ state) * 277803737u;
+  return (word >> 22u) ^ word;
+}
+
+// Fit of the ACES filmic curve, see "ACES Filmic Tone Mapping Curve" (Narkowicz)
+vec3 aces(vec3 x)
+{
+  const float a = 2.51;
+  const float b = 0.03;
+  const float c = 2.43;
+  const float d = 0.59;
+  const float e = 0.14;
+  return clamp((x * (a * x + b)) / (x * (c * x + d) + e), 0.0, 1.0);
+}
+
+vec3 linearToSrgb(vec3 linear)
+{
+  vec3 higher = 1.055 * pow(linear, vec3(1.0 / 2.4)) - 0.055;
+  vec3 lower = linear * 12.92;
+  return mix(higher, lower, lessThan(linear, vec3(0.0031308)));
+}
+
+// Maps the mean of the accumulated samples to the swapchain image
+vec4 displayColor(vec4 radiance)
+{
+  vec3 color = max(radiance.rgb, vec3(0.0));
+  if (tonemap == 1) {
+    color = color / (1.0 + color);
+  } else if (tonemap == 2) {
+    color = aces(color);
+  }
+  color = clamp(color, 0.0, 1.0);
+  if (encodeSrgb) {
+    color = linearToSrgb(color);
+  }
+  return vec4(color, radiance.a);
+}
+
+vec2 subpixelJitter()
+{
+  if (PushConstants.sampleIndex == 0) {
+    return vec2(0.5);
+  }
+  uint seed = pcg(gl_LaunchIDEXT.x + gl_LaunchSizeEXT.x * (gl_LaunchIDEXT.y + gl_LaunchSizeEXT.y * PushConstants.sampleIndex));
+  uint seed2 = pcg(seed);
+  return vec2(seed, seed2) / 4294967295.0;
+}
 
 void main() 
 {
-  const vec2 pixelCenter = vec2(gl_LaunchIDEXT.xy) + vec2(0.5);
+  const ivec2 pixel = ivec2(gl_LaunchIDEXT.xy);
+  const ivec2 imagePixel = pixel + ivec2(PushConstants.offsetX, PushConstants.offsetY);
+  vec4 accumulated = PushConstants.sampleIndex == 0 ? vec4(0.0) : imageLoad(accumulationImage, pixel);
+  if (PushConstants.maxSamples != 0 && PushConstants.sampleIndex >= PushConstants.maxSamples) {
+    // Converged, only present what was accumulated
+    imageStore(image, imagePixel, displayColor(accumulated / float(PushConstants.sampleIndex)));
+    return;
+  }
+
+  const vec2 pixelCenter = vec2(gl_LaunchIDEXT.xy) + subpixelJitter();
   const vec2 inUV = pixelCenter/vec2(gl_LaunchSizeEXT.xy);
   vec2 d = inUV * 2.0 - 1.0;
 
-  //mat4 mvp = PushConstants.proj * PushConstants.view * PushConstants.model;
-  mat4 viewInverse =inverse(PushConstants.view * PushConstants.model);
-  vec4 origin = viewInverse * vec4(0,0,0,1);
-  vec4 target = inverse(PushConstants.proj * PushConstants.model) * vec4(d.x, d.y, 1, 1);
-  vec4 direction = inverse(PushConstants.view * PushConstants.model) * vec4(normalize(target.xyz), 0);
+  mat4 viewInverse = inverse(camera.view);
+  vec4 origin;
+  vec4 direction;
+  if (camera.proj[3][3] == 1.0) {
+    // Orthographic: parallel rays starting on the near plane
+    origin = viewInverse * (inverse(camera.proj) * vec4(d.x, d.y, 0, 1));
+    direction = viewInverse * vec4(0, 0, -1, 0);
+  } else {
+    origin = viewInverse * vec4(0,0,0,1);
+    vec4 target = inverse(camera.proj) * vec4(d.x, d.y, 1, 1);
+    direction = viewInverse * vec4(normalize(target.xyz), 0);
+  }
 
-  uint rayFlags = gl_RayFlagsOpaqueEXT;
+  // Camera rays cull back faces like the rasterizer unless geometry is double-sided
+  uint rayFlags = doubleSided ? gl_RayFlagsNoneEXT : gl_RayFlagsCullBackFacingTrianglesEXT;
   uint cullMask = 0xff;
   float tmin = 0.001;
   float tmax = 10000.0;
 
-  traceRayEXT(topLevelAS, rayFlags, cullMask, 0 /*sbtRecordOffset*/, 0 /*sbtRecordStride*/, 0 /*missIndex*/, origin.xyz, tmin, direction.xyz, tmax, 0 /*payload*/);
-  imageStore(image, ivec2(gl_LaunchIDEXT.xy), vec4(hitValue, 1.0));
+  traceRayEXT(topLevelAS, rayFlags, cullMask, 0 /*sbtRecordOffset*/, 1 /*sbtRecordStride*/, 0 /*missIndex*/, origin.xyz, tmin, direction.xyz, tmax, 0 /*payload*/);
+  accumulated += hitValue;
+  imageStore(accumulationImage, pixel, accumulated);
+  imageStore(image, imagePixel, displayColor(accumulated / float(PushConstants.sampleIndex + 1)));
 }
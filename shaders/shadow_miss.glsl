@@ -0,0 +1,12 @@
+#version 460
+#pragma shader_stage(miss)
+
+#extension GL_EXT_ray_tracing : require
+
+// Shadow rays that reach the light without hitting anything
+layout(location = 2) rayPayloadInEXT bool shadowed;
+
+void main()
+{
+  shadowed = false;
+}
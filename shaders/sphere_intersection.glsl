@@ -0,0 +1,46 @@
+#version 460
+#pragma shader_stage(intersection)
+
+#extension GL_EXT_ray_tracing : require
+#extension GL_EXT_scalar_block_layout : enable
+#extension GL_EXT_buffer_reference2 : enable
+
+// Object space normal at the hit point, read by the closest-hit shader
+hitAttributeEXT vec3 attribs;
+
+layout(buffer_reference, buffer_reference_align = 16, scalar)
+buffer SphereBuffer {
+  vec4 s[]; // center in xyz, radius in w
+};
+
+layout(shaderRecordEXT, std430) buffer SBT {
+  SphereBuffer spheres;
+};
+
+void main()
+{
+  vec4 sphere = spheres.s[gl_PrimitiveID];
+  vec3 center = sphere.xyz;
+  float radius = sphere.w;
+
+  vec3 origin = gl_ObjectRayOriginEXT - center;
+  vec3 direction = gl_ObjectRayDirectionEXT;
+  float a = dot(direction, direction);
+  float halfB = dot(origin, direction);
+  float c = dot(origin, origin) - radius * radius;
+  float discriminant = halfB * halfB - a * c;
+  if (discriminant < 0.0) {
+    return;
+  }
+
+  // Prefer the front intersection, fall back to the back one for rays starting inside
+  float root = sqrt(discriminant);
+  float t = (-halfB - root) / a;
+  if (t < gl_RayTminEXT) {
+    t = (-halfB + root) / a;
+  }
+  if (t >= gl_RayTminEXT && t <= gl_RayTmaxEXT) {
+    attribs = (origin + t * direction) / radius;
+    reportIntersectionEXT(t, 0);
+  }
+}
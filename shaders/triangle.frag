@@ -2,10 +2,93 @@
 #extension GL_ARB_separate_shader_objects : enable
 #extension GL_ARB_shading_language_420pack : enable
 
-layout (location = 0) in vec3 normal;
+layout (location = 1) in vec2 uv;
+layout (location = 2) in vec3 worldNormal;
+layout (location = 3) in vec3 worldPosition;
+layout (location = 5) in vec3 viewNormal;
+layout (location = 6) in vec4 vertexColor;
+
+layout (constant_id = 0) const bool visualizeNormals = false;
+layout (constant_id = 1) const bool visualizeDepth = false;
+layout (constant_id = 3) const bool flatShading = false;
+layout (constant_id = 4) const bool doubleSided = false;
+// Set if the swapchain format doesn't sRGB encode the values written to it
+layout (constant_id = 6) const bool encodeSrgb = false;
+// Set if the pipeline has a normal attachment for ambient occlusion
+layout (constant_id = 7) const bool writeNormals = false;
+// Set if the pipeline draws point clouds, which are rarely stored with normals to shade them
+layout (constant_id = 8) const bool points = false;
+
+layout (set = 0, binding = 0) uniform sampler2D baseColor;
+
+// Follows the model matrix and point size of the vertex shader
+layout (push_constant) uniform Material {
+    layout (offset = 80) vec4 color;
+} material;
+
+struct Light {
+    vec4 position; // w = 0: direction towards the light, w = 1: point light position
+    vec4 color; // intensity in a
+};
+
+layout (set = 0, binding = 1) uniform LightBuffer {
+    uint count;
+    Light lights[8];
+} lights;
+
+const float AMBIENT = 0.05;
+
+vec3 shade(vec3 normal, vec3 position)
+{
+    vec3 color = vec3(AMBIENT);
+    for (uint i = 0; i < lights.count; ++i) {
+        Light light = lights.lights[i];
+        vec3 toLight = light.position.xyz - light.position.w * position;
+        float attenuation = light.position.w == 0.0 ? 1.0 : 1.0 / dot(toLight, toLight);
+        color += light.color.rgb * light.color.a * attenuation * max(dot(normal, normalize(toLight)), 0.0);
+    }
+    return color;
+}
+
+vec3 linearToSrgb(vec3 linear)
+{
+    vec3 higher = 1.055 * pow(linear, vec3(1.0 / 2.4)) - 0.055;
+    vec3 lower = linear * 12.92;
+    return mix(higher, lower, lessThan(linear, vec3(0.0031308)));
+}
 
 layout (location = 0) out vec4 uFragColor;
+layout (location = 1) out vec4 outViewNormal;
 
 void main() {
-    uFragColor = vec4(1.0, normal.xy, 1.0);
+    vec3 normal = normalize(worldNormal);
+    if (flatShading) {
+        // Face normal from the screen space derivatives, oriented like the vertex normals
+        vec3 faceNormal = normalize(cross(dFdx(worldPosition), dFdy(worldPosition)));
+        normal = dot(faceNormal, normal) < 0.0 ? -faceNormal : faceNormal;
+    }
+    if (doubleSided && !gl_FrontFacing) {
+        normal = -normal;
+    }
+    if (writeNormals) {
+        vec3 n = normalize(viewNormal);
+        outViewNormal = vec4(doubleSided && !gl_FrontFacing ? -n : n, 0.0);
+    }
+    if (visualizeNormals) {
+        uFragColor = vec4(normal * 0.5 + 0.5, 1.0);
+        return;
+    }
+    if (visualizeDepth) {
+        // Overwritten with the depth buffer by the compute pass of the rasterizer
+        uFragColor = vec4(0.0, 0.0, 0.0, 1.0);
+        return;
+    }
+    if (points) {
+        uFragColor = material.color * vertexColor;
+    } else {
+        uFragColor = material.color * vertexColor * texture(baseColor, uv) * vec4(shade(normal, worldPosition), 1.0);
+    }
+    if (encodeSrgb) {
+        uFragColor.rgb = linearToSrgb(clamp(uFragColor.rgb, 0.0, 1.0));
+    }
 }
@@ -2,19 +2,37 @@
 
 layout (location = 0) in vec3 vPosition;
 layout (location = 1) in vec3 vNormal;
-layout (location = 0) out vec3 outNormal;
+layout (location = 2) in vec2 vUv;
+// White for meshes without vertex colors
+layout (location = 3) in vec4 vColor;
+layout (location = 1) out vec2 outUv;
+layout (location = 2) out vec3 outWorldNormal;
+layout (location = 3) out vec3 outWorldPosition;
+layout (location = 5) out vec3 outViewNormal;
+layout (location = 6) out vec4 outColor;
+// The depth prepass runs this shader without fragment stage, its depth must match exactly
+invariant gl_Position;
+
+layout (set = 0, binding = 2) uniform CameraBuffer {
+    mat4 view;
+    mat4 proj;
+} camera;
 
 layout( push_constant ) uniform constants
 {
-    vec4 light;
-    mat4 view;
     mat4 model;
-    mat4 proj;
+    // Only used when drawing points
+    float pointSize;
 } PushConstants;
 
 void main()
 {
-    mat4 mvp = PushConstants.proj * PushConstants.view * PushConstants.model;
+    mat4 mvp = camera.proj * camera.view * PushConstants.model;
     gl_Position = mvp * vec4(vPosition, 1.0);
-    outNormal = mat3(transpose(inverse(mvp))) * vNormal;
+    gl_PointSize = PushConstants.pointSize;
+    outUv = vUv;
+    outColor = vColor;
+    outWorldNormal = mat3(transpose(inverse(PushConstants.model))) * vNormal;
+    outWorldPosition = (PushConstants.model * vec4(vPosition, 1.0)).xyz;
+    outViewNormal = mat3(camera.view) * outWorldNormal;
 }
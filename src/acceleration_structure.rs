@@ -2,185 +2,632 @@ use std::{mem::size_of, rc::Rc};
 
 use anyhow::Context;
 use ash::vk;
-use log::debug;
+use log::{debug, info};
 
 use crate::{
     device_mesh::{Buffer, DeviceMesh},
-    mesh::Position,
+    mesh::{Mesh, Position},
+    primitive::Primitive,
 };
 
-// TODO: destroy
+// Acceleration structures must be destroyed before `_buffer` frees their backing memory, which
+// the field drop order after `Drop::drop` guarantees. Callers must make sure the device is idle.
 pub struct BottomLevelAccelerationStructure<'device> {
     structure: vk::AccelerationStructureKHR,
     _buffer: Buffer<'device>,
     handle: vk::DeviceAddress,
-    mesh: Option<Rc<DeviceMesh<'device>>>,
+    meshes: Vec<Rc<DeviceMesh<'device>>>,
+    as_extension: ash::extensions::khr::AccelerationStructure,
+    /// Size of the acceleration structure in `_buffer`
+    size: vk::DeviceSize,
+    /// Additional AABB geometry after the triangle geometries of `meshes`
+    procedural: Option<ProceduralGeometry<'device>>,
+}
+
+/// Bounding boxes of implicit primitives and the primitive parameters the intersection shader
+/// reads
+pub struct ProceduralGeometry<'device> {
+    aabbs: Buffer<'device>,
+    primitives: Buffer<'device>,
+    count: u32,
+}
+
+impl<'device> ProceduralGeometry<'device> {
+    pub fn new(
+        device: &'device ash::Device,
+        device_memory_properties: &vk::PhysicalDeviceMemoryProperties,
+        primitives: &[Primitive],
+    ) -> anyhow::Result<Self> {
+        let aabbs: Vec<_> = primitives.iter().map(Primitive::aabb).collect();
+        let data: Vec<_> = primitives.iter().map(Primitive::shader_data).collect();
+        Ok(Self {
+            aabbs: Buffer::new(
+                device,
+                device_memory_properties,
+                &vk::BufferCreateInfo::default()
+                    .size(std::mem::size_of_val(aabbs.as_slice()) as vk::DeviceSize)
+                    .usage(
+                        vk::BufferUsageFlags::SHADER_DEVICE_ADDRESS
+                            | vk::BufferUsageFlags::ACCELERATION_STRUCTURE_BUILD_INPUT_READ_ONLY_KHR,
+                    ),
+                Some(&aabbs),
+            )?,
+            primitives: Buffer::new(
+                device,
+                device_memory_properties,
+                &vk::BufferCreateInfo::default()
+                    .size(std::mem::size_of_val(data.as_slice()) as vk::DeviceSize)
+                    .usage(
+                        vk::BufferUsageFlags::SHADER_DEVICE_ADDRESS
+                            | vk::BufferUsageFlags::STORAGE_BUFFER,
+                    ),
+                Some(&data),
+            )?,
+            count: primitives.len() as u32,
+        })
+    }
+
+    /// Address of the `vec4` parameters of each primitive, indexed by `gl_PrimitiveID`
+    pub fn primitives_device_address(&self) -> vk::DeviceAddress {
+        self.primitives.device_address()
+    }
+}
+
+/// What a hit record of the shader binding table is created for
+pub enum HitGeometry<'a, 'device> {
+    Triangles(&'a Rc<DeviceMesh<'device>>),
+    Procedural(&'a ProceduralGeometry<'device>),
+}
+
+/// Custom index and visibility mask of a top level instance. Shaders read the index as
+/// `gl_InstanceCustomIndexEXT`, rays only hit instances whose mask shares a bit with their cull
+/// mask.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct InstanceAttributes {
+    /// Only the lower 24 bits are available
+    pub custom_index: u32,
+    pub mask: u8,
+}
+
+impl InstanceAttributes {
+    /// Attributes of the `idx`-th instance when none are given: its index, visible to all rays
+    pub fn default_for(idx: usize) -> Self {
+        Self {
+            custom_index: idx as u32,
+            mask: 0xff,
+        }
+    }
 }
 
 pub struct TopLevelAccelerationStructure<'device> {
+    device: &'device ash::Device,
     structure: vk::AccelerationStructureKHR,
     _buffer: Buffer<'device>,
     _handle: vk::DeviceAddress,
-    bottomlevel_as: Vec<(BottomLevelAccelerationStructure<'device>, [f32; 12])>,
+    /// Shared with later top level structures that keep the same bottom level structures
+    bottomlevel_as: Vec<(Rc<BottomLevelAccelerationStructure<'device>>, [f32; 12])>,
     as_extension: ash::extensions::khr::AccelerationStructure,
+    instances: Vec<vk::AccelerationStructureInstanceKHR>,
+    instance_buffer: Buffer<'device>,
+    /// Only present when built with `ALLOW_UPDATE`
+    update_scratch_buffer: Option<Buffer<'device>>,
+}
+
+/// Scratch memory shared by consecutive acceleration structure builds. It only grows, so after
+/// loading a scene it fits the largest build and later rebuilds don't allocate.
+#[derive(Default)]
+pub struct ScratchBuffer<'device> {
+    buffer: Option<(Buffer<'device>, vk::DeviceSize)>,
+}
+
+impl<'device> ScratchBuffer<'device> {
+    /// Device address of at least `size` bytes of scratch memory. Reallocating frees the previous
+    /// buffer, so builds using it must have completed.
+    pub fn device_address(
+        &mut self,
+        device: &'device ash::Device,
+        device_memory_properties: &vk::PhysicalDeviceMemoryProperties,
+        size: vk::DeviceSize,
+    ) -> anyhow::Result<vk::DeviceAddress> {
+        match &self.buffer {
+            Some((buffer, capacity)) if *capacity >= size => Ok(buffer.device_address()),
+            _ => {
+                debug!("Allocating {size} bytes of acceleration structure scratch memory");
+                let buffer = Buffer::new::<u8>(
+                    device,
+                    device_memory_properties,
+                    &vk::BufferCreateInfo::default().size(size).usage(
+                        vk::BufferUsageFlags::SHADER_DEVICE_ADDRESS
+                            | vk::BufferUsageFlags::STORAGE_BUFFER,
+                    ),
+                    None,
+                )?;
+                let address = buffer.device_address();
+                self.buffer = Some((buffer, size));
+                Ok(address)
+            }
+        }
+    }
+}
+
+/// Acceleration structure of `size` bytes with its own buffer
+fn create_acceleration_structure<'device>(
+    device: &'device ash::Device,
+    device_memory_properties: &vk::PhysicalDeviceMemoryProperties,
+    as_extension: &ash::extensions::khr::AccelerationStructure,
+    ty: vk::AccelerationStructureTypeKHR,
+    size: vk::DeviceSize,
+) -> anyhow::Result<(vk::AccelerationStructureKHR, Buffer<'device>)> {
+    let buffer = Buffer::new::<u8>(
+        device,
+        device_memory_properties,
+        &vk::BufferCreateInfo::default().size(size).usage(
+            vk::BufferUsageFlags::ACCELERATION_STRUCTURE_STORAGE_KHR
+                | vk::BufferUsageFlags::SHADER_DEVICE_ADDRESS
+                | vk::BufferUsageFlags::STORAGE_BUFFER,
+        ),
+        None,
+    )?;
+    let structure = unsafe {
+        as_extension.create_acceleration_structure(
+            &vk::AccelerationStructureCreateInfoKHR::default()
+                .ty(ty)
+                .size(size)
+                .buffer(buffer.buffer())
+                .offset(0),
+            None,
+        )
+    }?;
+    Ok((structure, buffer))
+}
+
+/// Submit `cmd` and block until it has executed
+unsafe fn submit_and_wait(
+    device: &ash::Device,
+    cmd: vk::CommandBuffer,
+    graphics_queue: vk::Queue,
+) -> anyhow::Result<()> {
+    let fence = device.create_fence(&vk::FenceCreateInfo::default(), None)?;
+    let result = device
+        .queue_submit(
+            graphics_queue,
+            &[vk::SubmitInfo::default().command_buffers(&[cmd])],
+            fence,
+        )
+        .context("queue submit failed.")
+        .and_then(|()| {
+            device
+                .wait_for_fences(&[fence], true, u64::MAX)
+                .context("Failed to wait for acceleration structure builds")
+        });
+    device.destroy_fence(fence, None);
+    result
+}
+
+impl Drop for BottomLevelAccelerationStructure<'_> {
+    fn drop(&mut self) {
+        unsafe {
+            self.as_extension
+                .destroy_acceleration_structure(self.structure, None);
+        }
+    }
 }
 
 impl Drop for TopLevelAccelerationStructure<'_> {
     fn drop(&mut self) {
         unsafe {
-            for bl_as in self.bottomlevel_as.drain(..) {
-                self.as_extension
-                    .destroy_acceleration_structure(bl_as.0.structure, None);
-            }
             self.as_extension
                 .destroy_acceleration_structure(self.structure, None);
         }
     }
 }
 
+/// Number of triangles `mesh` adds to a BLAS. Without index buffer, every three consecutive
+/// vertices form a triangle.
+fn primitive_count(mesh: &Mesh, indexed: bool) -> u32 {
+    if indexed {
+        mesh.num_triangles() as u32
+    } else {
+        (mesh.num_vertices() / 3) as u32
+    }
+}
+
+/// Build ranges of geometries with `primitive_counts` triangles, the same counts that size the
+/// acceleration structure
+fn build_range_infos(primitive_counts: &[u32]) -> Vec<vk::AccelerationStructureBuildRangeInfoKHR> {
+    primitive_counts
+        .iter()
+        .map(|&primitive_count| {
+            vk::AccelerationStructureBuildRangeInfoKHR::default()
+                .first_vertex(0)
+                .primitive_count(primitive_count)
+                .primitive_offset(0)
+                .transform_offset(0)
+        })
+        .collect()
+}
+
+/// Triangle geometry of every mesh, hit shaders can tell them apart by `gl_GeometryIndexEXT`
+fn triangle_geometries<'a>(
+    meshes: &[Rc<DeviceMesh<'_>>],
+) -> anyhow::Result<Vec<vk::AccelerationStructureGeometryKHR<'a>>> {
+    meshes
+        .iter()
+        .map(|mesh| {
+            Ok(vk::AccelerationStructureGeometryKHR::default()
+                .geometry_type(vk::GeometryTypeKHR::TRIANGLES)
+                .geometry(vk::AccelerationStructureGeometryDataKHR {
+                    triangles: vk::AccelerationStructureGeometryTrianglesDataKHR::default()
+                        .vertex_data(vk::DeviceOrHostAddressConstKHR {
+                            device_address: mesh
+                                .positions_device_address()
+                                .ok_or_else(|| anyhow::anyhow!("No vertex buffer on mesh"))?,
+                        })
+                        .max_vertex(mesh.num_vertices() as u32 - 1)
+                        .vertex_stride(size_of::<Position>() as u64)
+                        .vertex_format(vk::Format::R32G32B32_SFLOAT)
+                        // Null for meshes without indices together with `NONE_KHR`
+                        .index_data(vk::DeviceOrHostAddressConstKHR {
+                            device_address: mesh.indices_device_address().unwrap_or(0),
+                        })
+                        .index_type(mesh.index_type()),
+                })
+                .flags(if mesh.is_opaque() {
+                    vk::GeometryFlagsKHR::OPAQUE
+                } else {
+                    vk::GeometryFlagsKHR::empty()
+                }))
+        })
+        .collect()
+}
+
 impl<'device> BottomLevelAccelerationStructure<'device> {
-    pub fn build_bottomlevel(
+    /// Build one bottom level acceleration structure with a geometry per mesh for each group of
+    /// meshes. All builds are recorded into `cmd` and submitted at once. With `compact`, the
+    /// structures are copied into buffers of their compacted size afterwards.
+    pub fn build_bottomlevels(
+        cmd: vk::CommandBuffer,
+        device: &'device ash::Device,
+        groups: &[&[Rc<DeviceMesh<'device>>]],
+        device_memory_properties: &vk::PhysicalDeviceMemoryProperties,
+        as_extension: &ash::extensions::khr::AccelerationStructure,
+        graphics_queue: vk::Queue,
+        scratch_buffer: &mut ScratchBuffer<'device>,
+        compact: bool,
+    ) -> anyhow::Result<Vec<Self>> {
+        if groups.is_empty() {
+            return Ok(Vec::new());
+        }
+        debug!(
+            "Building {} bottom level acceleration structures",
+            groups.len()
+        );
+        let geometries = groups
+            .iter()
+            .map(|meshes| {
+                anyhow::ensure!(!meshes.is_empty(), "No meshes for acceleration structure");
+                triangle_geometries(meshes)
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        let primitive_counts: Vec<Vec<_>> = groups
+            .iter()
+            .map(|meshes| {
+                meshes
+                    .iter()
+                    .map(|m| primitive_count(m.mesh(), m.indices().is_some()))
+                    .collect()
+            })
+            .collect();
+
+        let mut build_infos = Vec::with_capacity(groups.len());
+        let mut structures = Vec::with_capacity(groups.len());
+        let mut scratch_size = 0;
+        let build_flags = if compact {
+            vk::BuildAccelerationStructureFlagsKHR::PREFER_FAST_TRACE
+                | vk::BuildAccelerationStructureFlagsKHR::ALLOW_COMPACTION
+        } else {
+            vk::BuildAccelerationStructureFlagsKHR::PREFER_FAST_TRACE
+        };
+        for ((meshes, geometries), primitive_counts) in groups
+            .iter()
+            .zip(geometries.iter())
+            .zip(primitive_counts.iter())
+        {
+            let build_info = vk::AccelerationStructureBuildGeometryInfoKHR::default()
+                .flags(build_flags)
+                .geometries(geometries)
+                .mode(vk::BuildAccelerationStructureModeKHR::BUILD)
+                .ty(vk::AccelerationStructureTypeKHR::BOTTOM_LEVEL);
+
+            let size_info = unsafe {
+                as_extension.get_acceleration_structure_build_sizes(
+                    vk::AccelerationStructureBuildTypeKHR::DEVICE,
+                    &build_info,
+                    primitive_counts,
+                )
+            };
+            scratch_size = scratch_size.max(size_info.build_scratch_size);
+
+            let (structure, bottom_as_buffer) = create_acceleration_structure(
+                device,
+                device_memory_properties,
+                as_extension,
+                build_info.ty,
+                size_info.acceleration_structure_size,
+            )?;
+            build_infos.push(build_info.dst_acceleration_structure(structure));
+            structures.push(Self::new(
+                structure,
+                bottom_as_buffer,
+                meshes,
+                as_extension,
+                size_info.acceleration_structure_size,
+            ));
+        }
+        if structures.is_empty() {
+            return Ok(structures);
+        }
+
+        let scratch_address =
+            scratch_buffer.device_address(device, device_memory_properties, scratch_size)?;
+        let query_pool = if compact {
+            Some(unsafe {
+                device.create_query_pool(
+                    &vk::QueryPoolCreateInfo::default()
+                        .query_type(vk::QueryType::ACCELERATION_STRUCTURE_COMPACTED_SIZE_KHR)
+                        .query_count(structures.len() as u32),
+                    None,
+                )?
+            })
+        } else {
+            None
+        };
+        unsafe {
+            device.begin_command_buffer(
+                cmd,
+                &vk::CommandBufferBeginInfo::default()
+                    .flags(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT),
+            )?;
+            if let Some(query_pool) = query_pool {
+                device.cmd_reset_query_pool(cmd, query_pool, 0, structures.len() as u32);
+            }
+            for ((build_info, geometries), primitive_counts) in build_infos
+                .into_iter()
+                .zip(geometries.iter())
+                .zip(primitive_counts.iter())
+            {
+                let build_info = build_info.scratch_data(vk::DeviceOrHostAddressKHR {
+                    device_address: scratch_address,
+                });
+                let build_range_infos = build_range_infos(primitive_counts);
+                debug_assert_eq!(geometries.len(), build_range_infos.len());
+                as_extension.cmd_build_acceleration_structures(
+                    cmd,
+                    &[build_info],
+                    &[&build_range_infos],
+                );
+                // The next build reuses the scratch memory
+                device.cmd_pipeline_barrier(
+                    cmd,
+                    vk::PipelineStageFlags::ACCELERATION_STRUCTURE_BUILD_KHR,
+                    vk::PipelineStageFlags::ACCELERATION_STRUCTURE_BUILD_KHR,
+                    vk::DependencyFlags::empty(),
+                    &[vk::MemoryBarrier::default()
+                        .src_access_mask(vk::AccessFlags::ACCELERATION_STRUCTURE_WRITE_KHR)
+                        .dst_access_mask(
+                            vk::AccessFlags::ACCELERATION_STRUCTURE_READ_KHR
+                                | vk::AccessFlags::ACCELERATION_STRUCTURE_WRITE_KHR,
+                        )],
+                    &[],
+                    &[],
+                );
+            }
+            if let Some(query_pool) = query_pool {
+                let handles: Vec<_> = structures.iter().map(|s| s.structure).collect();
+                as_extension.cmd_write_acceleration_structures_properties(
+                    cmd,
+                    &handles,
+                    vk::QueryType::ACCELERATION_STRUCTURE_COMPACTED_SIZE_KHR,
+                    query_pool,
+                    0,
+                );
+            }
+            device.end_command_buffer(cmd)?;
+            let result = submit_and_wait(device, cmd, graphics_queue);
+            if let (Err(_), Some(query_pool)) = (&result, query_pool) {
+                device.destroy_query_pool(query_pool, None);
+            }
+            result?;
+        }
+        debug!("Built bottom level acceleration structures");
+        match query_pool {
+            Some(query_pool) => {
+                let compacted = Self::compact(
+                    cmd,
+                    device,
+                    &structures,
+                    query_pool,
+                    device_memory_properties,
+                    as_extension,
+                    graphics_queue,
+                );
+                unsafe { device.destroy_query_pool(query_pool, None) };
+                compacted
+            }
+            None => Ok(structures),
+        }
+    }
+
+    fn new(
+        structure: vk::AccelerationStructureKHR,
+        buffer: Buffer<'device>,
+        meshes: &[Rc<DeviceMesh<'device>>],
+        as_extension: &ash::extensions::khr::AccelerationStructure,
+        size: vk::DeviceSize,
+    ) -> Self {
+        let handle = unsafe {
+            as_extension.get_acceleration_structure_device_address(
+                &vk::AccelerationStructureDeviceAddressInfoKHR::default()
+                    .acceleration_structure(structure),
+            )
+        };
+        BottomLevelAccelerationStructure {
+            _buffer: buffer,
+            structure,
+            handle,
+            meshes: meshes.to_vec(),
+            as_extension: as_extension.clone(),
+            size,
+            procedural: None,
+        }
+    }
+
+    /// Build a bottom level acceleration structure with a single AABB geometry
+    pub fn build_procedural(
         cmd: vk::CommandBuffer,
         device: &'device ash::Device,
-        mesh: Rc<DeviceMesh<'device>>,
+        geometry: ProceduralGeometry<'device>,
         device_memory_properties: &vk::PhysicalDeviceMemoryProperties,
         as_extension: &ash::extensions::khr::AccelerationStructure,
         graphics_queue: vk::Queue,
+        scratch_buffer: &mut ScratchBuffer<'device>,
     ) -> anyhow::Result<Self> {
-        debug!("Building bottom level acceleration structure");
-        let geometry = vk::AccelerationStructureGeometryKHR::default()
-            .geometry_type(vk::GeometryTypeKHR::TRIANGLES)
+        debug!(
+            "Building procedural acceleration structure with {} primitives",
+            geometry.count
+        );
+        let geometries = [vk::AccelerationStructureGeometryKHR::default()
+            .geometry_type(vk::GeometryTypeKHR::AABBS)
             .geometry(vk::AccelerationStructureGeometryDataKHR {
-                triangles: vk::AccelerationStructureGeometryTrianglesDataKHR::default()
-                    .vertex_data(vk::DeviceOrHostAddressConstKHR {
-                        device_address: unsafe {
-                            device.get_buffer_device_address(
-                                &vk::BufferDeviceAddressInfo::default().buffer(
-                                    *mesh.position().ok_or_else(|| {
-                                        anyhow::anyhow!("No vertex buffer on mesh")
-                                    })?,
-                                ),
-                            )
-                        },
+                aabbs: vk::AccelerationStructureGeometryAabbsDataKHR::default()
+                    .data(vk::DeviceOrHostAddressConstKHR {
+                        device_address: geometry.aabbs.device_address(),
                     })
-                    .max_vertex(mesh.num_vertices() as u32 - 1)
-                    .vertex_stride(size_of::<Position>() as u64)
-                    .vertex_format(vk::Format::R32G32B32_SFLOAT)
-                    .index_data(vk::DeviceOrHostAddressConstKHR {
-                        device_address: unsafe {
-                            device.get_buffer_device_address(
-                                &vk::BufferDeviceAddressInfo::default().buffer(
-                                    *mesh.indices().ok_or_else(|| {
-                                        anyhow::anyhow!("No index buffer on mesh")
-                                    })?,
-                                ),
-                            )
-                        },
-                    })
-                    .index_type(vk::IndexType::UINT32),
+                    .stride(size_of::<vk::AabbPositionsKHR>() as vk::DeviceSize),
             })
-            .flags(vk::GeometryFlagsKHR::OPAQUE);
-        let build_range_info = vk::AccelerationStructureBuildRangeInfoKHR::default()
-            .first_vertex(0)
-            .primitive_count(mesh.num_triangles() as u32)
-            .primitive_offset(0)
-            .transform_offset(0);
-
-        let geometries = &[geometry];
-        let mut build_info = vk::AccelerationStructureBuildGeometryInfoKHR::default()
+            .flags(vk::GeometryFlagsKHR::OPAQUE)];
+        let build_info = vk::AccelerationStructureBuildGeometryInfoKHR::default()
             .flags(vk::BuildAccelerationStructureFlagsKHR::PREFER_FAST_TRACE)
-            .geometries(geometries)
+            .geometries(&geometries)
             .mode(vk::BuildAccelerationStructureModeKHR::BUILD)
             .ty(vk::AccelerationStructureTypeKHR::BOTTOM_LEVEL);
-
         let size_info = unsafe {
             as_extension.get_acceleration_structure_build_sizes(
                 vk::AccelerationStructureBuildTypeKHR::DEVICE,
                 &build_info,
-                &[mesh.num_triangles() as u32],
+                &[geometry.count],
             )
         };
-
-        let bottom_as_buffer = Buffer::new::<u8>(
+        let (structure, buffer) = create_acceleration_structure(
             device,
             device_memory_properties,
-            &vk::BufferCreateInfo::default()
-                .size(size_info.acceleration_structure_size)
-                .usage(
-                    vk::BufferUsageFlags::ACCELERATION_STRUCTURE_STORAGE_KHR
-                        | vk::BufferUsageFlags::SHADER_DEVICE_ADDRESS
-                        | vk::BufferUsageFlags::STORAGE_BUFFER,
-                ),
-            None,
+            as_extension,
+            build_info.ty,
+            size_info.acceleration_structure_size,
         )?;
-
-        let as_create_info = vk::AccelerationStructureCreateInfoKHR::default()
-            .ty(build_info.ty)
-            .size(size_info.acceleration_structure_size)
-            .buffer(bottom_as_buffer.buffer())
-            .offset(0);
-
-        let bottom_as =
-            unsafe { as_extension.create_acceleration_structure(&as_create_info, None) }?;
-
-        build_info.dst_acceleration_structure = bottom_as;
-
-        let scratch_buffer = Buffer::new::<u8>(
+        // Destroys the structure should building fail
+        let mut bottomlevel_as = Self::new(
+            structure,
+            buffer,
+            &[],
+            as_extension,
+            size_info.acceleration_structure_size,
+        );
+        let scratch_address = scratch_buffer.device_address(
             device,
             device_memory_properties,
-            &vk::BufferCreateInfo::default()
-                .size(size_info.build_scratch_size)
-                .usage(
-                    vk::BufferUsageFlags::SHADER_DEVICE_ADDRESS
-                        | vk::BufferUsageFlags::STORAGE_BUFFER,
-                ),
-            None,
+            size_info.build_scratch_size,
         )?;
-
-        build_info.scratch_data = vk::DeviceOrHostAddressKHR {
-            device_address: unsafe {
-                device.get_buffer_device_address(
-                    &vk::BufferDeviceAddressInfo::default().buffer(scratch_buffer.buffer()),
-                )
-            },
-        };
+        let build_info = build_info
+            .dst_acceleration_structure(structure)
+            .scratch_data(vk::DeviceOrHostAddressKHR {
+                device_address: scratch_address,
+            });
+        let build_range_info =
+            vk::AccelerationStructureBuildRangeInfoKHR::default().primitive_count(geometry.count);
         unsafe {
             device.begin_command_buffer(
                 cmd,
                 &vk::CommandBufferBeginInfo::default()
                     .flags(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT),
             )?;
-
             as_extension.cmd_build_acceleration_structures(
                 cmd,
                 &[build_info],
                 &[&[build_range_info]],
             );
             device.end_command_buffer(cmd)?;
-            device
-                .queue_submit(
-                    graphics_queue,
-                    &[vk::SubmitInfo::default().command_buffers(&[cmd])],
-                    vk::Fence::null(),
-                )
-                .context("queue submit failed.")?;
-
-            device.queue_wait_idle(graphics_queue)?;
+            submit_and_wait(device, cmd, graphics_queue)?;
         }
+        bottomlevel_as.procedural = Some(geometry);
+        Ok(bottomlevel_as)
+    }
 
-        let handle = unsafe {
-            as_extension.get_acceleration_structure_device_address(
-                &vk::AccelerationStructureDeviceAddressInfoKHR::default()
-                    .acceleration_structure(bottom_as),
-            )
-        };
-        debug!("Built bottom level acceleration structure");
-        Ok(BottomLevelAccelerationStructure {
-            _buffer: bottom_as_buffer,
-            structure: bottom_as,
-            handle,
-            mesh: Some(Rc::clone(&mesh)),
-        })
+    /// Copy `structures` into buffers of the sizes written to `query_pool`
+    fn compact(
+        cmd: vk::CommandBuffer,
+        device: &'device ash::Device,
+        structures: &[Self],
+        query_pool: vk::QueryPool,
+        device_memory_properties: &vk::PhysicalDeviceMemoryProperties,
+        as_extension: &ash::extensions::khr::AccelerationStructure,
+        graphics_queue: vk::Queue,
+    ) -> anyhow::Result<Vec<Self>> {
+        let mut compacted_sizes = vec![0u64; structures.len()];
+        unsafe {
+            device.get_query_pool_results(
+                query_pool,
+                0,
+                &mut compacted_sizes,
+                vk::QueryResultFlags::WAIT | vk::QueryResultFlags::TYPE_64,
+            )?;
+        }
+        let compacted = structures
+            .iter()
+            .zip(compacted_sizes.iter())
+            .map(|(original, &size)| {
+                let (structure, buffer) = create_acceleration_structure(
+                    device,
+                    device_memory_properties,
+                    as_extension,
+                    vk::AccelerationStructureTypeKHR::BOTTOM_LEVEL,
+                    size,
+                )?;
+                Ok(Self::new(
+                    structure,
+                    buffer,
+                    &original.meshes,
+                    as_extension,
+                    size,
+                ))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        unsafe {
+            device.begin_command_buffer(
+                cmd,
+                &vk::CommandBufferBeginInfo::default()
+                    .flags(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT),
+            )?;
+            for (original, compacted) in structures.iter().zip(compacted.iter()) {
+                as_extension.cmd_copy_acceleration_structure(
+                    cmd,
+                    &vk::CopyAccelerationStructureInfoKHR::default()
+                        .src(original.structure)
+                        .dst(compacted.structure)
+                        .mode(vk::CopyAccelerationStructureModeKHR::COMPACT),
+                );
+            }
+            device.end_command_buffer(cmd)?;
+            submit_and_wait(device, cmd, graphics_queue)?;
+        }
+        let original_size: vk::DeviceSize = structures.iter().map(|s| s.size).sum();
+        let compacted_size: vk::DeviceSize = compacted_sizes.iter().sum();
+        info!(
+            "Compacted bottom level acceleration structures from {original_size} to \
+             {compacted_size} bytes (saved {} bytes)",
+            original_size.saturating_sub(compacted_size)
+        );
+        Ok(compacted)
     }
 
     pub fn reference(&self) -> vk::AccelerationStructureReferenceKHR {
@@ -189,37 +636,96 @@ impl<'device> BottomLevelAccelerationStructure<'device> {
         }
     }
 
-    pub fn mesh(&self) -> Option<&Rc<DeviceMesh>> {
-        self.mesh.as_ref()
+    pub fn meshes(&self) -> &[Rc<DeviceMesh<'device>>] {
+        &self.meshes
+    }
+
+    /// Number of hit records this acceleration structure needs
+    pub fn num_geometries(&self) -> usize {
+        self.meshes.len() + usize::from(self.procedural.is_some())
+    }
+
+    /// Whether this structure holds the AABBs of `build_procedural`
+    pub fn is_procedural(&self) -> bool {
+        self.procedural.is_some()
+    }
+
+    /// Whether this structure was built from exactly the triangle geometries of `meshes`, so that
+    /// it can be kept instead of building them again
+    pub fn is_built_from(&self, meshes: &[Rc<DeviceMesh<'device>>]) -> bool {
+        self.meshes.len() == meshes.len()
+            && self
+                .meshes
+                .iter()
+                .zip(meshes)
+                .all(|(a, b)| Rc::ptr_eq(a, b))
     }
 }
 
 impl<'device> TopLevelAccelerationStructure<'device> {
+    /// Build from one instance per bottom level structure with its transform and attributes,
+    /// which default to [`InstanceAttributes::default_for`] the instance index
+    #[allow(clippy::type_complexity)]
     pub fn build_toplevel(
         cmd: vk::CommandBuffer,
         device: &'device ash::Device,
-        bottomlevel_as: Vec<(BottomLevelAccelerationStructure<'device>, [f32; 12])>,
+        instances: Vec<(
+            Rc<BottomLevelAccelerationStructure<'device>>,
+            [f32; 12],
+            Option<InstanceAttributes>,
+        )>,
         device_memory_properties: &vk::PhysicalDeviceMemoryProperties,
         as_extension: ash::extensions::khr::AccelerationStructure,
         graphics_queue: vk::Queue,
-        attributes_per_instance: u32,
+        allow_update: bool,
+        instance_flags: vk::GeometryInstanceFlagsKHR,
+        scratch_buffer: &mut ScratchBuffer<'device>,
     ) -> anyhow::Result<Self> {
         debug!("Building top level acceleration structure");
-        let instances: Vec<_> = bottomlevel_as
+        let attributes: Vec<_> = instances
             .iter()
             .enumerate()
-            .map(
-                |(i, (bottomlevel_as, transform))| vk::AccelerationStructureInstanceKHR {
-                    transform: vk::TransformMatrixKHR { matrix: *transform },
-                    instance_shader_binding_table_record_offset_and_flags: ash::vk::Packed24_8::new(
-                        attributes_per_instance * i as u32, //TODO: make attribute
-                        vk::GeometryInstanceFlagsKHR::TRIANGLE_FACING_CULL_DISABLE.as_raw() as u8,
-                    ),
-                    instance_custom_index_and_mask: ash::vk::Packed24_8::new(0, 0xff),
-                    acceleration_structure_reference: bottomlevel_as.reference(),
-                },
-            )
+            .map(|(i, (_, _, attributes))| {
+                attributes.unwrap_or_else(|| InstanceAttributes::default_for(i))
+            })
             .collect();
+        if let Some(attributes) = attributes.iter().find(|a| a.custom_index >= 1 << 24) {
+            anyhow::bail!(
+                "Instance custom index {} exceeds 24 bits",
+                attributes.custom_index
+            );
+        }
+        let bottomlevel_as: Vec<_> = instances
+            .into_iter()
+            .map(|(bottomlevel_as, transform, _)| (bottomlevel_as, transform))
+            .collect();
+        // Hit records are laid out per geometry, so each instance starts after the geometries of
+        // all previous instances
+        let sbt_offsets = bottomlevel_as
+            .iter()
+            .scan(0, |offset, (bottomlevel_as, _)| {
+                let current = *offset;
+                *offset += bottomlevel_as.num_geometries() as u32;
+                Some(current)
+            });
+        let instances: Vec<_> =
+            bottomlevel_as
+                .iter()
+                .zip(sbt_offsets)
+                .zip(&attributes)
+                .map(|(((bottomlevel_as, transform), sbt_offset), attributes)| {
+                    vk::AccelerationStructureInstanceKHR {
+                        transform: vk::TransformMatrixKHR { matrix: *transform },
+                        instance_shader_binding_table_record_offset_and_flags:
+                            ash::vk::Packed24_8::new(sbt_offset, instance_flags.as_raw() as u8),
+                        instance_custom_index_and_mask: ash::vk::Packed24_8::new(
+                            attributes.custom_index,
+                            attributes.mask,
+                        ),
+                        acceleration_structure_reference: bottomlevel_as.reference(),
+                    }
+                })
+                .collect();
         let instance_buffer_size =
             std::mem::size_of::<vk::AccelerationStructureInstanceKHR>() * instances.len();
 
@@ -234,7 +740,13 @@ impl<'device> TopLevelAccelerationStructure<'device> {
                 ),
             Some(&instances),
         )?;
-        let (top_as, top_as_buffer) = {
+        let build_flags = if allow_update {
+            vk::BuildAccelerationStructureFlagsKHR::PREFER_FAST_TRACE
+                | vk::BuildAccelerationStructureFlagsKHR::ALLOW_UPDATE
+        } else {
+            vk::BuildAccelerationStructureFlagsKHR::PREFER_FAST_TRACE
+        };
+        let (top_as, top_as_buffer, update_scratch_buffer) = {
             let build_range_info = vk::AccelerationStructureBuildRangeInfoKHR::default()
                 .first_vertex(0)
                 .primitive_count(instances.len() as u32)
@@ -279,7 +791,7 @@ impl<'device> TopLevelAccelerationStructure<'device> {
             let geometries = [geometry];
 
             let mut build_info = vk::AccelerationStructureBuildGeometryInfoKHR::default()
-                .flags(vk::BuildAccelerationStructureFlagsKHR::PREFER_FAST_TRACE)
+                .flags(build_flags)
                 .geometries(&geometries)
                 .mode(vk::BuildAccelerationStructureModeKHR::BUILD)
                 .ty(vk::AccelerationStructureTypeKHR::TOP_LEVEL);
@@ -316,24 +828,12 @@ impl<'device> TopLevelAccelerationStructure<'device> {
 
             build_info.dst_acceleration_structure = top_as;
 
-            let scratch_buffer = Buffer::new::<u8>(
-                device,
-                device_memory_properties,
-                &vk::BufferCreateInfo::default()
-                    .size(size_info.build_scratch_size)
-                    .usage(
-                        vk::BufferUsageFlags::SHADER_DEVICE_ADDRESS
-                            | vk::BufferUsageFlags::STORAGE_BUFFER,
-                    ),
-                None,
-            )?;
-
             build_info.scratch_data = vk::DeviceOrHostAddressKHR {
-                device_address: unsafe {
-                    device.get_buffer_device_address(
-                        &vk::BufferDeviceAddressInfo::default().buffer(scratch_buffer.buffer()),
-                    )
-                },
+                device_address: scratch_buffer.device_address(
+                    device,
+                    device_memory_properties,
+                    size_info.build_scratch_size,
+                )?,
             };
 
             unsafe {
@@ -354,11 +854,28 @@ impl<'device> TopLevelAccelerationStructure<'device> {
                 device.queue_wait_idle(graphics_queue)?;
             }
 
-            (top_as, top_as_buffer)
+            let update_scratch_buffer = allow_update
+                .then(|| {
+                    Buffer::new::<u8>(
+                        device,
+                        device_memory_properties,
+                        &vk::BufferCreateInfo::default()
+                            .size(size_info.update_scratch_size)
+                            .usage(
+                                vk::BufferUsageFlags::SHADER_DEVICE_ADDRESS
+                                    | vk::BufferUsageFlags::STORAGE_BUFFER,
+                            ),
+                        None,
+                    )
+                })
+                .transpose()?;
+
+            (top_as, top_as_buffer, update_scratch_buffer)
         };
 
         debug!("Built top level acceleration structure");
         Ok(Self {
+            device,
             structure: top_as,
             _buffer: top_as_buffer,
             _handle: unsafe {
@@ -369,21 +886,146 @@ impl<'device> TopLevelAccelerationStructure<'device> {
             },
             bottomlevel_as,
             as_extension,
+            instances,
+            instance_buffer,
+            update_scratch_buffer,
         })
     }
 
+    /// Refit the acceleration structure to new instance transforms (one per bottom-level
+    /// structure) without a full rebuild. Requires building with `allow_update`. The device must
+    /// not use the acceleration structure while this runs.
+    pub fn update_instances(
+        &mut self,
+        cmd: vk::CommandBuffer,
+        graphics_queue: vk::Queue,
+        transforms: &[[f32; 12]],
+    ) -> anyhow::Result<()> {
+        let scratch_buffer = self.update_scratch_buffer.as_ref().ok_or_else(|| {
+            anyhow::anyhow!("Top level acceleration structure was not built with ALLOW_UPDATE")
+        })?;
+        anyhow::ensure!(
+            transforms.len() == self.instances.len(),
+            "Expected {} instance transforms, got {}",
+            self.instances.len(),
+            transforms.len()
+        );
+        debug!("Updating top level acceleration structure");
+        for ((instance, bottomlevel_as), transform) in self
+            .instances
+            .iter_mut()
+            .zip(self.bottomlevel_as.iter_mut())
+            .zip(transforms)
+        {
+            instance.transform = vk::TransformMatrixKHR { matrix: *transform };
+            bottomlevel_as.1 = *transform;
+        }
+        self.instance_buffer.write(&self.instances)?;
+
+        let device = self.device;
+        let geometries = [vk::AccelerationStructureGeometryKHR::default()
+            .geometry_type(vk::GeometryTypeKHR::INSTANCES)
+            .geometry(vk::AccelerationStructureGeometryDataKHR {
+                instances: vk::AccelerationStructureGeometryInstancesDataKHR::default()
+                    .array_of_pointers(false)
+                    .data(vk::DeviceOrHostAddressConstKHR {
+                        device_address: self.instance_buffer.device_address(),
+                    }),
+            })];
+        let build_info = vk::AccelerationStructureBuildGeometryInfoKHR::default()
+            .flags(
+                vk::BuildAccelerationStructureFlagsKHR::PREFER_FAST_TRACE
+                    | vk::BuildAccelerationStructureFlagsKHR::ALLOW_UPDATE,
+            )
+            .geometries(&geometries)
+            .mode(vk::BuildAccelerationStructureModeKHR::UPDATE)
+            .ty(vk::AccelerationStructureTypeKHR::TOP_LEVEL)
+            .src_acceleration_structure(self.structure)
+            .dst_acceleration_structure(self.structure)
+            .scratch_data(vk::DeviceOrHostAddressKHR {
+                device_address: scratch_buffer.device_address(),
+            });
+        let build_range_info = vk::AccelerationStructureBuildRangeInfoKHR::default()
+            .primitive_count(self.instances.len() as u32);
+
+        unsafe {
+            device.begin_command_buffer(
+                cmd,
+                &vk::CommandBufferBeginInfo::default()
+                    .flags(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT),
+            )?;
+            self.as_extension.cmd_build_acceleration_structures(
+                cmd,
+                &[build_info],
+                &[&[build_range_info]],
+            );
+            device.end_command_buffer(cmd)?;
+            device
+                .queue_submit(
+                    graphics_queue,
+                    &[vk::SubmitInfo::default().command_buffers(&[cmd])],
+                    vk::Fence::null(),
+                )
+                .context("queue submit failed.")?;
+            device.queue_wait_idle(graphics_queue)?;
+        }
+        Ok(())
+    }
+
     pub fn structure(&self) -> vk::AccelerationStructureKHR {
         self.structure
     }
 
-    pub fn bottomlevel_as(&self) -> &[(BottomLevelAccelerationStructure, [f32; 12])] {
+    pub fn bottomlevel_as(&self) -> &[(Rc<BottomLevelAccelerationStructure<'device>>, [f32; 12])] {
         self.bottomlevel_as.as_ref()
     }
 
-    pub fn meshes(&self) -> Vec<&Rc<DeviceMesh>> {
-        self.bottomlevel_as()
+    /// All geometries in hit record order
+    pub fn hit_geometries(&self) -> Vec<HitGeometry<'_, 'device>> {
+        self.bottomlevel_as
             .iter()
-            .flat_map(|a| a.0.mesh())
+            .flat_map(|(a, _)| {
+                a.meshes
+                    .iter()
+                    .map(HitGeometry::Triangles)
+                    .chain(a.procedural.iter().map(HitGeometry::Procedural))
+            })
             .collect()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mesh::ReadOptions;
+    use std::path::Path;
+
+    #[test]
+    fn indexed_meshes_count_every_triangle() {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/cube.obj");
+        let cube = Mesh::from_file(&path, ReadOptions::OnlyTriangles, false)
+            .expect("Failed to read cube.obj");
+        assert_eq!(cube.num_vertices(), 8);
+        assert_eq!(cube.indices_flat().len(), 36);
+        // Neither the number of indices nor of vertices
+        let primitive_counts = [primitive_count(&cube, true)];
+        assert_eq!(primitive_counts, [12]);
+        // The size query gets `primitive_counts` itself, the build ranges are derived from it
+        let build_range_infos = build_range_infos(&primitive_counts);
+        assert_eq!(build_range_infos.len(), 1);
+        assert_eq!(build_range_infos[0].primitive_count, 12);
+        assert_eq!(build_range_infos[0].first_vertex, 0);
+    }
+
+    #[test]
+    fn index_less_meshes_count_every_three_vertices() {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/soup.obj");
+        let mesh = Mesh::from_file(&path, ReadOptions::OnlyTriangles, false)
+            .expect("Failed to read soup.obj");
+        // Uploaded without index buffer by `DeviceMesh`
+        assert!(mesh.is_triangle_soup());
+        assert_eq!(mesh.num_vertices(), 6);
+        assert_eq!(primitive_count(&mesh, false), 2);
+        assert_eq!(primitive_count(&mesh, true), 2);
+    }
+}
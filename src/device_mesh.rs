@@ -5,18 +5,20 @@
 //
 
 use std::{
+    cell::Cell,
     collections::HashMap,
     mem::{align_of, size_of},
     rc::Rc,
 };
 
 use ash::{util::Align, vk};
+use cgmath::{Matrix4, SquareMatrix};
 use log::debug;
 
-use crate::mesh::Mesh;
+use crate::{mesh::Mesh, texture::Texture};
 
 // From ash examples
-fn find_memorytype_index(
+pub(crate) fn find_memorytype_index(
     memory_req: &vk::MemoryRequirements,
     memory_prop: &vk::PhysicalDeviceMemoryProperties,
     flags: vk::MemoryPropertyFlags,
@@ -35,6 +37,8 @@ pub struct Buffer<'device> {
     device: &'device ash::Device,
     memory: vk::DeviceMemory,
     buffer: vk::Buffer,
+    /// Queried once on creation, 0 without `SHADER_DEVICE_ADDRESS` usage
+    device_address: vk::DeviceAddress,
     //buffer_view: vk::BufferView,
 }
 
@@ -86,25 +90,63 @@ impl<'device> Buffer<'device> {
                 device.unmap_memory(memory);
             }
             device.bind_buffer_memory(buffer, memory, 0)?;
+            let device_address = if buffer_create_info
+                .usage
+                .contains(vk::BufferUsageFlags::SHADER_DEVICE_ADDRESS)
+            {
+                device.get_buffer_device_address(
+                    &vk::BufferDeviceAddressInfo::default().buffer(buffer),
+                )
+            } else {
+                0
+            };
             Ok(Self {
                 device,
                 memory,
                 buffer,
+                device_address,
             })
         }
     }
 
     pub fn device_address(&self) -> vk::DeviceAddress {
-        unsafe {
-            self.device.get_buffer_device_address(
-                &vk::BufferDeviceAddressInfo::default().buffer(self.buffer),
-            )
-        }
+        debug_assert!(
+            self.device_address != 0,
+            "Buffer was created without SHADER_DEVICE_ADDRESS usage"
+        );
+        self.device_address
     }
 
     pub fn buffer(&self) -> vk::Buffer {
         self.buffer
     }
+
+    /// Copy `len` elements from the start of a buffer that was created with host memory
+    pub fn read<T: Copy>(&self, len: usize) -> anyhow::Result<Vec<T>> {
+        let size = (len * size_of::<T>()) as vk::DeviceSize;
+        unsafe {
+            let ptr = self
+                .device
+                .map_memory(self.memory, 0, size, vk::MemoryMapFlags::empty())?;
+            let data = std::slice::from_raw_parts(ptr as *const T, len).to_vec();
+            self.device.unmap_memory(self.memory);
+            Ok(data)
+        }
+    }
+
+    /// Overwrite the start of a buffer that was created with host memory
+    pub fn write<T: Copy>(&self, host_memory: &[T]) -> anyhow::Result<()> {
+        let size = std::mem::size_of_val(host_memory) as vk::DeviceSize;
+        unsafe {
+            let ptr = self
+                .device
+                .map_memory(self.memory, 0, size, vk::MemoryMapFlags::empty())?;
+            let mut map_slice = Align::new(ptr, align_of::<T>() as u64, size);
+            map_slice.copy_from_slice(host_memory);
+            self.device.unmap_memory(self.memory);
+        }
+        Ok(())
+    }
 }
 
 #[derive(Debug, Copy, Clone, Hash, Eq, PartialEq)]
@@ -112,11 +154,20 @@ pub enum AttributeType {
     Normals,
     Position,
     Index,
+    Tangent,
+    Uv,
+    Color,
 }
 
 pub struct DeviceMesh<'device> {
     mesh: Rc<Mesh>,
     buffers: HashMap<AttributeType, Buffer<'device>>,
+    opaque: bool,
+    transform: Cell<Matrix4<f32>>,
+    texture: Option<Rc<Texture<'device>>>,
+    base_color: [f32; 4],
+    index_type: vk::IndexType,
+    group: Option<usize>,
 }
 
 impl<'device> DeviceMesh<'device> {
@@ -167,35 +218,173 @@ impl<'device> DeviceMesh<'device> {
                 )?,
             );
         }
-        buffers.insert(
-            AttributeType::Index,
-            Buffer::new(
-                device,
-                mem_properties,
-                &vk::BufferCreateInfo::default()
-                    .size((3 * size_of::<u32>() * mesh.num_triangles()) as vk::DeviceSize)
-                    .usage(index_buffer_usage)
-                    .sharing_mode(vk::SharingMode::EXCLUSIVE),
-                Some(mesh.triangles()),
-            )?,
-        );
+        if let Some(uvs) = mesh.uvs() {
+            buffers.insert(
+                AttributeType::Uv,
+                Buffer::new(
+                    device,
+                    mem_properties,
+                    &vk::BufferCreateInfo::default()
+                        .size((2 * size_of::<f32>() * mesh.num_vertices()) as vk::DeviceSize)
+                        .usage(vertex_buffer_usage)
+                        .sharing_mode(vk::SharingMode::EXCLUSIVE),
+                    Some(uvs),
+                )?,
+            );
+        }
+        if let Some(tangents) = mesh.tangents() {
+            buffers.insert(
+                AttributeType::Tangent,
+                Buffer::new(
+                    device,
+                    mem_properties,
+                    &vk::BufferCreateInfo::default()
+                        .size((4 * size_of::<f32>() * mesh.num_vertices()) as vk::DeviceSize)
+                        .usage(vertex_buffer_usage)
+                        .sharing_mode(vk::SharingMode::EXCLUSIVE),
+                    Some(tangents),
+                )?,
+            );
+        }
+        if let Some(colors) = mesh.vertex_colors() {
+            buffers.insert(
+                AttributeType::Color,
+                Buffer::new(
+                    device,
+                    mem_properties,
+                    &vk::BufferCreateInfo::default()
+                        .size((4 * size_of::<f32>() * mesh.num_vertices()) as vk::DeviceSize)
+                        .usage(vertex_buffer_usage)
+                        .sharing_mode(vk::SharingMode::EXCLUSIVE),
+                    Some(colors),
+                )?,
+            );
+        }
+        // Triangle soups are drawn and traced without index buffer, point clouds have no indices
+        let index_type = if mesh.is_triangle_soup() || mesh.is_point_cloud() {
+            vk::IndexType::NONE_KHR
+        } else {
+            let (index_type, index_buffer) = if index_type(mesh.num_vertices())
+                == vk::IndexType::UINT16
+            {
+                let indices = indices_u16(&mesh.indices_flat());
+                (
+                    vk::IndexType::UINT16,
+                    Buffer::new(
+                        device,
+                        mem_properties,
+                        &vk::BufferCreateInfo::default()
+                            .size((size_of::<u16>() * indices.len()) as vk::DeviceSize)
+                            .usage(index_buffer_usage)
+                            .sharing_mode(vk::SharingMode::EXCLUSIVE),
+                        Some(&indices),
+                    )?,
+                )
+            } else {
+                (
+                    vk::IndexType::UINT32,
+                    Buffer::new(
+                        device,
+                        mem_properties,
+                        &vk::BufferCreateInfo::default()
+                            .size((3 * size_of::<u32>() * mesh.num_triangles()) as vk::DeviceSize)
+                            .usage(index_buffer_usage)
+                            .sharing_mode(vk::SharingMode::EXCLUSIVE),
+                        Some(mesh.triangles()),
+                    )?,
+                )
+            };
+            buffers.insert(AttributeType::Index, index_buffer);
+            index_type
+        };
 
         Ok(Self {
             mesh: Rc::clone(mesh),
             buffers,
+            opaque: true,
+            transform: Cell::new(Matrix4::identity()),
+            texture: None,
+            base_color: [1.0; 4],
+            index_type,
+            group: None,
         })
     }
 
+    /// Set whether the mesh is opaque. Ray tracing invokes the any-hit shader for non-opaque meshes.
+    #[must_use]
+    pub fn with_opaque(mut self, opaque: bool) -> Self {
+        self.opaque = opaque;
+        self
+    }
+
+    /// Mark meshes that always share their transform, e.g. because they were read from the same
+    /// file. Consecutive meshes of a group are traced as one bottom level acceleration structure.
+    #[must_use]
+    pub fn with_group(mut self, group: usize) -> Self {
+        self.group = Some(group);
+        self
+    }
+
+    pub fn group(&self) -> Option<usize> {
+        self.group
+    }
+
+    /// Set the model matrix placing the mesh in the scene
+    #[must_use]
+    pub fn with_transform(self, transform: Matrix4<f32>) -> Self {
+        self.set_transform(transform);
+        self
+    }
+
+    /// Move the mesh. Ray tracing only picks this up on [`crate::renderers::Renderer::update_transforms`].
+    pub fn set_transform(&self, transform: Matrix4<f32>) {
+        self.transform.set(transform);
+    }
+
+    /// Set the base color texture. The rasterizer uses a white texture for meshes without one.
+    #[must_use]
+    pub fn with_texture(mut self, texture: Option<Rc<Texture<'device>>>) -> Self {
+        self.texture = texture;
+        self
+    }
+
+    pub fn texture(&self) -> Option<&Rc<Texture<'device>>> {
+        self.texture.as_ref()
+    }
+
+    /// Set the linear RGBA color the texture is multiplied with, white by default
+    #[must_use]
+    pub fn with_base_color(mut self, base_color: [f32; 4]) -> Self {
+        self.base_color = base_color;
+        self
+    }
+
+    pub fn base_color(&self) -> [f32; 4] {
+        self.base_color
+    }
+
     pub fn position(&self) -> Option<&vk::Buffer> {
         self.buffers
             .get(&AttributeType::Position)
             .map(|b| &b.buffer)
     }
 
+    pub fn positions_device_address(&self) -> Option<vk::DeviceAddress> {
+        self.buffers
+            .get(&AttributeType::Position)
+            .map(|b| b.device_address())
+    }
+
     pub fn indices(&self) -> Option<&vk::Buffer> {
         self.buffers.get(&AttributeType::Index).map(|b| &b.buffer)
     }
 
+    /// `UINT16` for meshes with less than 65536 vertices, `UINT32` otherwise and `NONE_KHR` if
+    /// the mesh has no index buffer
+    pub fn index_type(&self) -> vk::IndexType {
+        self.index_type
+    }
+
     pub fn indices_device_address(&self) -> Option<vk::DeviceAddress> {
         self.buffers
             .get(&AttributeType::Index)
@@ -212,6 +401,45 @@ impl<'device> DeviceMesh<'device> {
             .map(|b| b.device_address())
     }
 
+    pub fn uvs(&self) -> Option<&vk::Buffer> {
+        self.buffers.get(&AttributeType::Uv).map(|b| &b.buffer)
+    }
+
+    pub fn uvs_device_address(&self) -> Option<vk::DeviceAddress> {
+        self.buffers
+            .get(&AttributeType::Uv)
+            .map(|b| b.device_address())
+    }
+
+    pub fn tangents_device_address(&self) -> Option<vk::DeviceAddress> {
+        self.buffers
+            .get(&AttributeType::Tangent)
+            .map(|b| b.device_address())
+    }
+
+    pub fn colors(&self) -> Option<&vk::Buffer> {
+        self.buffers.get(&AttributeType::Color).map(|b| &b.buffer)
+    }
+
+    pub fn colors_device_address(&self) -> Option<vk::DeviceAddress> {
+        self.buffers
+            .get(&AttributeType::Color)
+            .map(|b| b.device_address())
+    }
+
+    pub fn is_opaque(&self) -> bool {
+        self.opaque
+    }
+
+    pub fn transform(&self) -> Matrix4<f32> {
+        self.transform.get()
+    }
+
+    /// Model matrix as row-major 3x4 matrix as expected by `vk::TransformMatrixKHR`
+    pub fn instance_transform(&self) -> [f32; 12] {
+        instance_transform(self.transform())
+    }
+
     pub fn num_triangles(&self) -> usize {
         self.mesh.num_triangles()
     }
@@ -226,3 +454,72 @@ impl<'device> DeviceMesh<'device> {
         self.mesh.as_ref()
     }
 }
+
+/// `m` as row-major 3x4 matrix as expected by `vk::TransformMatrixKHR`
+fn instance_transform(m: Matrix4<f32>) -> [f32; 12] {
+    [
+        m.x.x, m.y.x, m.z.x, m.w.x, //
+        m.x.y, m.y.y, m.z.y, m.w.y, //
+        m.x.z, m.y.z, m.z.z, m.w.z,
+    ]
+}
+
+/// Type of the indices uploaded for a mesh with `num_vertices` vertices. 16 bit indices halve the
+/// index bandwidth of small meshes.
+fn index_type(num_vertices: usize) -> vk::IndexType {
+    if num_vertices <= u16::MAX as usize {
+        vk::IndexType::UINT16
+    } else {
+        vk::IndexType::UINT32
+    }
+}
+
+/// `indices` as 16 bit, padded with a zero to a multiple of 4 bytes since the closest-hit shader
+/// reads them in pairs packed into 32 bit words
+fn indices_u16(indices: &[u32]) -> Vec<u16> {
+    let mut indices: Vec<_> = indices.iter().map(|&i| i as u16).collect();
+    if indices.len() % 2 == 1 {
+        indices.push(0);
+    }
+    indices
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scene::Scene;
+    use cgmath::Vector3;
+    use std::path::Path;
+
+    #[test]
+    fn meshes_without_scene_transform_get_identity_instances() {
+        let transform = Scene::default().transform(Path::new("bunny.ply"));
+        assert_eq!(
+            instance_transform(transform),
+            [1.0, 0.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 0.0, 1.0, 0.0]
+        );
+    }
+
+    #[test]
+    fn instance_transforms_are_row_major() {
+        let transform = Matrix4::from_translation(Vector3::new(1.0, 2.0, 3.0));
+        assert_eq!(
+            instance_transform(transform),
+            [1.0, 0.0, 0.0, 1.0, 0.0, 1.0, 0.0, 2.0, 0.0, 0.0, 1.0, 3.0]
+        );
+    }
+
+    #[test]
+    fn index_type_fits_the_vertex_count() {
+        assert_eq!(index_type(3), vk::IndexType::UINT16);
+        assert_eq!(index_type(u16::MAX as usize), vk::IndexType::UINT16);
+        assert_eq!(index_type(u16::MAX as usize + 1), vk::IndexType::UINT32);
+    }
+
+    #[test]
+    fn indices_u16_are_padded_to_whole_words() {
+        assert_eq!(indices_u16(&[0, 1, 2]), [0, 1, 2, 0]);
+        assert_eq!(indices_u16(&[0, 1, 2, 2, 1, 3]), [0, 1, 2, 2, 1, 3]);
+        assert_eq!(indices_u16(&[65535, 1, 2]), [65535, 1, 2, 0]);
+    }
+}
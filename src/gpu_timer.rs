@@ -0,0 +1,68 @@
+use ash::vk;
+
+/// Number of renderers per frame that get their own pair of timestamp queries
+pub const MAX_TIMED_RENDERERS: usize = 4;
+
+/// Begin and end timestamp of a renderer's main work. Does nothing without a query pool, i.e.
+/// when GPU timestamps weren't requested or aren't supported.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct GpuTimer {
+    pool: Option<vk::QueryPool>,
+    /// Query of the begin timestamp, the end timestamp follows it
+    query: u32,
+}
+
+impl GpuTimer {
+    /// Write the begin timestamp once all previous commands reached `stage`
+    pub fn cmd_begin(
+        &self,
+        device: &ash::Device,
+        cmd: vk::CommandBuffer,
+        stage: vk::PipelineStageFlags,
+    ) {
+        if let Some(pool) = self.pool {
+            unsafe { device.cmd_write_timestamp(cmd, stage, pool, self.query) };
+        }
+    }
+
+    /// Write the end timestamp once all previous commands completed `stage`
+    pub fn cmd_end(
+        &self,
+        device: &ash::Device,
+        cmd: vk::CommandBuffer,
+        stage: vk::PipelineStageFlags,
+    ) {
+        if let Some(pool) = self.pool {
+            unsafe { device.cmd_write_timestamp(cmd, stage, pool, self.query + 1) };
+        }
+    }
+}
+
+/// Timers of the renderers drawing into one frame
+#[derive(Copy, Clone, Debug, Default)]
+pub struct FrameTimers {
+    pool: Option<vk::QueryPool>,
+    first_query: u32,
+}
+
+impl FrameTimers {
+    /// `MAX_TIMED_RENDERERS` begin/end pairs starting at `first_query` of `pool`
+    pub(crate) fn new(pool: vk::QueryPool, first_query: u32) -> Self {
+        Self {
+            pool: Some(pool),
+            first_query,
+        }
+    }
+
+    /// Timer of the `idx`-th renderer drawing into the frame, a no-op from `MAX_TIMED_RENDERERS`
+    /// on
+    pub fn renderer(&self, idx: usize) -> GpuTimer {
+        match self.pool {
+            Some(pool) if idx < MAX_TIMED_RENDERERS => GpuTimer {
+                pool: Some(pool),
+                query: self.first_query + 2 * idx as u32,
+            },
+            _ => GpuTimer::default(),
+        }
+    }
+}
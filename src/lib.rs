@@ -0,0 +1,33 @@
+//
+// lib.rs
+// Copyright (C) 2022 Stephan Seitz <stephan.seitz@fau.de>
+// Distributed under terms of the GPLv3 license.
+//
+
+//! Vulkan renderers for triangle meshes, usable without the `ash-rtx-renderer` binary.
+//!
+//! Create a [`VulkanApp`] for a `winit` window (or headless), upload [`Mesh`]es as
+//! [`DeviceMesh`]es, hand them to one or more [`RendererImpl`]s and call [`Renderer::draw`] from
+//! the closure passed to [`VulkanApp::draw`] each frame.
+
+pub mod acceleration_structure;
+pub mod device_mesh;
+pub mod gpu_timer;
+pub mod mesh;
+pub mod primitive;
+pub mod renderers;
+pub mod sampler;
+pub mod scene;
+pub mod shader;
+pub mod texture;
+pub mod uniforms;
+pub mod vulkan_app;
+
+pub use acceleration_structure::{
+    BottomLevelAccelerationStructure, HitGeometry, InstanceAttributes, ProceduralGeometry,
+    ScratchBuffer, TopLevelAccelerationStructure,
+};
+pub use device_mesh::DeviceMesh;
+pub use mesh::{Mesh, ReadOptions};
+pub use renderers::{Region, RenderStyle, Renderer, RendererImpl};
+pub use vulkan_app::{AppOptions, VulkanApp};
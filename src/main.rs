@@ -1,39 +1,44 @@
-use anyhow::Error;
+use anyhow::{Context, Error};
 use ash::vk;
-use device_mesh::DeviceMesh;
+use ash_rtx_renderer::{
+    device_mesh::DeviceMesh,
+    gpu_timer::GpuTimer,
+    mesh::{bounding_box_corners, Mesh, ReadOptions},
+    renderers::{
+        color_sine::ColorSine,
+        compute::Compute,
+        cuda::Cuda,
+        helpers::Helpers,
+        raster::Raster,
+        ray_tracing::{RayTrace, Tonemap},
+        Region, RenderStyle, Renderer, RendererImpl,
+    },
+    sampler::{SamplerCache, SamplerOptions},
+    scene::Scene,
+    texture::Texture,
+    uniforms::{Background, Camera, Projection, PushConstants},
+    vulkan_app::{AppOptions, DeviceSelector, TracingMode, VulkanApp},
+};
+use cgmath::{Deg, EuclideanSpace, InnerSpace, Matrix4, Point3, Transform, Vector4};
 use hotwatch::Hotwatch;
 use log::{debug, error, info, warn};
-use renderers::{ray_tracing::RayTrace, RenderStyle};
 use std::{
-    path::PathBuf,
+    collections::HashMap,
+    path::{Path, PathBuf},
     rc::Rc,
-    sync::{atomic::AtomicBool, Arc},
-    time::{Duration, Instant},
+    sync::{atomic::AtomicBool, mpsc, Arc},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 use tracing_log::LogTracer;
 use tracing_subscriber::layer::SubscriberExt;
 
 use clap::Parser;
-use mesh::Mesh;
 use winit::{
     event::{Event, WindowEvent},
     event_loop::{ControlFlow, EventLoop},
     window::{Fullscreen, WindowBuilder},
 };
 
-use crate::{
-    renderers::{color_sine::ColorSine, cuda::Cuda, raster::Raster, Renderer, RendererImpl},
-    vulkan_app::{TracingMode, VulkanApp},
-};
-
-mod acceleration_structure;
-mod device_mesh;
-mod mesh;
-mod renderers;
-mod shader;
-mod uniforms;
-mod vulkan_app;
-
 fn setup_tracing() -> anyhow::Result<()> {
     LogTracer::init()?;
     tracing::subscriber::set_global_default(
@@ -42,28 +47,848 @@ fn setup_tracing() -> anyhow::Result<()> {
     .map_err(|err| anyhow::anyhow!("Failed to set up tracing: {err}"))
 }
 
+#[derive(clap::ArgEnum, Copy, Clone, Debug)]
+enum PresentMode {
+    Fifo,
+    Mailbox,
+    Immediate,
+}
+
+impl From<PresentMode> for vk::PresentModeKHR {
+    fn from(mode: PresentMode) -> Self {
+        match mode {
+            PresentMode::Fifo => vk::PresentModeKHR::FIFO,
+            PresentMode::Mailbox => vk::PresentModeKHR::MAILBOX,
+            PresentMode::Immediate => vk::PresentModeKHR::IMMEDIATE,
+        }
+    }
+}
+
+#[derive(clap::ArgEnum, Copy, Clone, Debug)]
+enum SurfaceFormat {
+    /// 8 bit sRGB, the hardware encodes the linear colors written by shaders
+    Srgb,
+    /// 8 bit UNORM, shaders encode their output themselves
+    Unorm,
+    /// 10 bit UNORM for displays with a higher bit depth
+    Hdr,
+}
+
+impl SurfaceFormat {
+    /// Swapchain formats in order of preference
+    fn preference(self) -> Vec<vk::Format> {
+        let srgb = [vk::Format::B8G8R8A8_SRGB, vk::Format::R8G8B8A8_SRGB];
+        let unorm = [vk::Format::B8G8R8A8_UNORM, vk::Format::R8G8B8A8_UNORM];
+        let hdr = [
+            vk::Format::A2B10G10R10_UNORM_PACK32,
+            vk::Format::A2R10G10B10_UNORM_PACK32,
+        ];
+        match self {
+            SurfaceFormat::Srgb => [&srgb[..], &unorm].concat(),
+            SurfaceFormat::Unorm => [&unorm[..], &srgb].concat(),
+            SurfaceFormat::Hdr => [&hdr[..], &srgb, &unorm].concat(),
+        }
+    }
+}
+
+#[derive(clap::ArgEnum, Copy, Clone, Debug)]
+enum Winding {
+    Cw,
+    Ccw,
+}
+
+impl From<Winding> for vk::FrontFace {
+    fn from(winding: Winding) -> Self {
+        match winding {
+            Winding::Cw => vk::FrontFace::CLOCKWISE,
+            Winding::Ccw => vk::FrontFace::COUNTER_CLOCKWISE,
+        }
+    }
+}
+
+#[derive(clap::ArgEnum, Copy, Clone, Debug)]
+enum TonemapOperator {
+    None,
+    Reinhard,
+    Aces,
+}
+
+impl From<TonemapOperator> for Tonemap {
+    fn from(operator: TonemapOperator) -> Self {
+        match operator {
+            TonemapOperator::None => Tonemap::Clamp,
+            TonemapOperator::Reinhard => Tonemap::Reinhard,
+            TonemapOperator::Aces => Tonemap::Aces,
+        }
+    }
+}
+
 #[derive(clap::Parser)]
 #[clap(author, version, about)]
 struct Args {
-    /// Mesh file to render (can be specified more than once for multiple meshes)
+    /// Mesh file to render (can be specified more than once for multiple meshes). glTF files add
+    /// one mesh per primitive
     #[clap(short, long)]
     mesh_file: Vec<PathBuf>,
 
+    /// JSON scene file assigning a translation/rotation/scale to each mesh file and defining
+    /// lights
+    #[clap(long)]
+    scene: Option<PathBuf>,
+
     /// Whether to not read any Triangle attributes such as normals
     #[clap(long)]
     only_triangles: bool,
 
+    /// Whether to drop zero-area triangles and triangles with repeated indices (and vertices no
+    /// longer referenced) when loading meshes
+    #[clap(long)]
+    clean: bool,
+
+    /// Shade each triangle with its geometric normal instead of the interpolated vertex normals
+    #[clap(long)]
+    flat: bool,
+
+    /// Whether to render back faces instead of culling them, for meshes with inconsistent
+    /// winding (toggle with C)
+    #[clap(long)]
+    double_sided: bool,
+
+    /// Width of wireframe edges in pixels, clamped to the range the device supports
+    #[clap(long, default_value_t = 1.0)]
+    line_width: f32,
+
+    /// Rasterize the vertices of all meshes as points. Done anyway if all meshes are point clouds
+    /// (PLY files without faces), which are not drawn otherwise.
+    #[clap(long)]
+    points: bool,
+
+    /// Diameter of points in pixels, clamped to the range the device supports (change with + and
+    /// -)
+    #[clap(long, default_value_t = 2.0)]
+    point_size: f32,
+
+    /// Vertex order of front-facing triangles
+    #[clap(long, arg_enum, default_value = "ccw")]
+    winding: Winding,
+
     /// Whether to disable raytracing renderer
     #[clap(short, long)]
     no_raytracing: bool,
 
+    /// Whether to add the CUDA renderer (needs VK_NVX_binary_import and VK_NVX_image_view_handle)
+    #[clap(long)]
+    cuda: bool,
+
+    /// Whether the ray tracer ignores hits where the alpha of the base color, vertex color and
+    /// texture is below 0.5 instead of treating meshes as opaque (invokes the any-hit shader)
+    #[clap(long)]
+    alpha_test: bool,
+
+    /// Maximum recursion depth of rays traced by the raytracing renderer, 2 or more enables
+    /// shadows
+    #[clap(long, default_value_t = 1)]
+    ray_recursion_depth: u32,
+
+    /// Background color as `R,G,B` or `R,G,B,A` in [0, 1]. Alpha 0 gives transparent captures
+    #[clap(long, parse(try_from_str = parse_color), default_value = "0,0,0,0")]
+    background: Vector4<f32>,
+
+    /// Color the ray traced background blends to for upward rays
+    #[clap(long, parse(try_from_str = parse_color))]
+    background_top: Option<Vector4<f32>>,
+
+    /// Equirectangular HDR image (e.g. `.hdr`) lighting ray traced reflections and background
+    #[clap(long)]
+    env_map: Option<PathBuf>,
+
+    /// Anisotropic filtering of textures with up to this many samples, clamped to the device
+    /// limit (1 disables it)
+    #[clap(long, default_value_t = 1)]
+    anisotropy: u32,
+
+    /// Whether to compact the ray tracing acceleration structures of the meshes to save GPU
+    /// memory
+    #[clap(long)]
+    compact_as: bool,
+
+    /// Samples per pixel of the rasterizer (1, 2, 4, 8, ...)
+    #[clap(long, default_value_t = 1)]
+    msaa: u32,
+
+    /// Darken creases and contacts of the rasterized meshes with screen-space ambient occlusion
+    /// (not supported with --msaa)
+    #[clap(long)]
+    ssao: bool,
+
+    /// Draw the depth of the rasterized meshes before shading them so that each pixel is shaded
+    /// only once, which helps meshes with a lot of overdraw
+    #[clap(long)]
+    depth_prepass: bool,
+
+    /// Number of frames the raytracing renderer accumulates for a still camera before it stops
+    /// tracing new samples (accumulates indefinitely if omitted)
+    #[clap(long)]
+    max_samples: Option<u32>,
+
+    /// Tonemapping operator of the raytracing renderer, applied to the accumulated linear
+    /// radiance before it is written to the swapchain (none only clamps)
+    #[clap(long, arg_enum, default_value = "none")]
+    tonemap: TonemapOperator,
+
+    /// Vertical field of view in degrees
+    #[clap(long, default_value_t = 60.0)]
+    fov: f32,
+
+    /// Whether the mouse wheel zooms by changing the field of view instead of moving the camera
+    /// (toggle with Z)
+    #[clap(long)]
+    scroll_fov: bool,
+
+    /// Use a parallel projection showing as much of the scene as --fov does at the camera target.
+    /// The clip planes are fitted to the loaded meshes.
+    #[clap(long)]
+    orthographic: bool,
+
+    /// Distance of the near clip plane
+    #[clap(long, default_value_t = 0.01)]
+    near: f32,
+
+    /// Distance of the far clip plane
+    #[clap(long, default_value_t = 100.0)]
+    far: f32,
+
+    /// Whether to fit the near and far clip planes to the extent of the loaded meshes instead of
+    /// using --near/--far
+    #[clap(long)]
+    auto_clip_planes: bool,
+
+    /// Index of the renderer that is active on startup
+    #[clap(long, default_value_t = 0)]
+    renderer: usize,
+
+    /// Indices of renderers that draw into each frame in this order instead of only the active
+    /// one, e.g. `--compose 0,4`. Only the overlay, the last renderer, can be drawn on top of
+    /// another one. Switching the renderer with the number keys ends composition.
+    #[clap(long, use_value_delimiter = true)]
+    compose: Vec<usize>,
+
+    /// Indices of two renderers shown side by side, e.g. `--compare 0,1` for the rasterizer on
+    /// the left and the ray tracer on the right. Switching the renderer ends the comparison.
+    #[clap(long, use_value_delimiter = true, conflicts_with = "compose")]
+    compare: Vec<usize>,
+
+    /// Whether to render a single frame to --output without opening a window
+    #[clap(long, requires = "output")]
+    headless: bool,
+
+    /// Image file the frame is written to in headless mode
+    #[clap(short, long)]
+    output: Option<PathBuf>,
+
+    /// Render this many frames as fast as possible (without vsync), print their GPU times and
+    /// exit
+    #[clap(long)]
+    benchmark: Option<u32>,
+
+    /// Width of the rendered image in headless mode
+    #[clap(long, default_value_t = 1920)]
+    width: u32,
+
+    /// Height of the rendered image in headless mode
+    #[clap(long, default_value_t = 1080)]
+    height: u32,
+
+    /// GPU to use as index among the devices with graphics and surface support or as
+    /// case-insensitive substring of its name (defaults to the first NVIDIA device)
+    #[clap(long)]
+    device: Option<DeviceSelector>,
+
+    /// Whether to enable the Khronos validation layer (including synchronization validation) and
+    /// log its messages
+    #[clap(long)]
+    validation: bool,
+
+    /// Number of frames recorded ahead of the GPU. Fewer frames lower the input latency, more
+    /// frames keep the GPU busy when the CPU time per frame varies.
+    #[clap(long, default_value_t = 3)]
+    frames_in_flight: usize,
+
+    /// Cap the frame rate, e.g. with the mailbox or immediate present modes. 0 doesn't limit it.
+    #[clap(long, default_value_t = 0)]
+    max_fps: u32,
+
+    /// Render at this multiple of the window resolution and downsample with a linear filter,
+    /// e.g. 2 for 4 samples per pixel
+    #[clap(long, default_value_t = 1)]
+    supersample: u32,
+
+    /// Swapchain present mode, FIFO is vsync
+    #[clap(long, arg_enum, default_value = "fifo")]
+    present_mode: PresentMode,
+
+    /// Preferred swapchain format, others are tried if the surface doesn't support it (or the
+    /// storage image usage of the renderers)
+    #[clap(long, arg_enum, default_value = "srgb")]
+    format: SurfaceFormat,
+
     /// Whether to enable tracing for Tracy (https://github.com/wolfpld/tracy)
     #[clap(short, long)]
     tracing: bool,
 }
 
+/// World space axis-aligned bounding box over all meshes, `None` if there are no vertices
+fn scene_bounding_box(
+    meshes: &[(PathBuf, Rc<Mesh>, Matrix4<f32>)],
+) -> Option<(Point3<f32>, Point3<f32>)> {
+    meshes
+        .iter()
+        .filter(|(_, mesh, _)| mesh.num_vertices() > 0)
+        .flat_map(|(_, mesh, transform)| {
+            let (min, max) = mesh.bounding_box();
+            bounding_box_corners(min, max).map(|p| transform.transform_point(p))
+        })
+        .fold(None, |bounds, p| {
+            let (min, max) = bounds.unwrap_or((p, p));
+            Some((
+                Point3::new(min.x.min(p.x), min.y.min(p.y), min.z.min(p.z)),
+                Point3::new(max.x.max(p.x), max.y.max(p.y), max.z.max(p.z)),
+            ))
+        })
+}
+
+/// Parse `R,G,B` or `R,G,B,A` (alpha defaults to 1)
+fn parse_color(color: &str) -> anyhow::Result<Vector4<f32>> {
+    let components = color
+        .split(',')
+        .map(|c| c.trim().parse::<f32>())
+        .collect::<Result<Vec<_>, _>>()
+        .with_context(|| format!("Invalid color {color:?}"))?;
+    match components[..] {
+        [r, g, b] => Ok(Vector4::new(r, g, b, 1.0)),
+        [r, g, b, a] => Ok(Vector4::new(r, g, b, a)),
+        _ => anyhow::bail!("Expected 3 or 4 comma separated components, got {color:?}"),
+    }
+}
+
+/// Highest supported sample count not exceeding `requested`
+fn msaa_samples(requested: u32, supported: vk::SampleCountFlags) -> vk::SampleCountFlags {
+    let samples = (0..u32::BITS)
+        .rev()
+        .map(|bit| vk::SampleCountFlags::from_raw(1 << bit))
+        .find(|&s| s.as_raw() <= requested.max(1) && supported.contains(s))
+        .unwrap_or(vk::SampleCountFlags::TYPE_1);
+    if samples.as_raw() != requested {
+        warn!(
+            "MSAA with {requested} samples is not supported, using {} samples",
+            samples.as_raw()
+        );
+    }
+    samples
+}
+
+fn window_title(vulkan_app: &VulkanApp, renderer: Option<&RendererImpl>) -> String {
+    let mut title = String::from("ash-rtx-renderer");
+    if let Some(renderer) = renderer {
+        title += &format!(" | {}", renderer.name());
+    }
+    if let Some(present_mode) = vulkan_app.present_mode() {
+        title += &format!(" ({present_mode:?})");
+    }
+    if let Some(timings) = vulkan_app.frame_timings() {
+        title += &format!(
+            " | {:.0} fps {:.2} ms | 1% low {:.0} fps",
+            1.0 / timings.average.as_secs_f64(),
+            1e3 * timings.average.as_secs_f64(),
+            1.0 / timings.one_percent_low.as_secs_f64(),
+        );
+    }
+    title
+}
+
+/// Meshes read from one file on a worker thread
+type LoadedFile = (PathBuf, anyhow::Result<Vec<Mesh>>);
+
+/// Read every mesh file on its own thread. Results arrive in the order the files finish loading.
+fn load_meshes_in_background(
+    paths: &[PathBuf],
+    options: ReadOptions,
+    clean: bool,
+    winding: Winding,
+) -> mpsc::Receiver<LoadedFile> {
+    let (sender, receiver) = mpsc::channel();
+    for path in paths {
+        let sender = sender.clone();
+        let path = path.clone();
+        std::thread::spawn(move || {
+            let meshes = Mesh::from_file_multi(&path, options, clean);
+            for mesh in meshes.iter().flatten() {
+                warn_if_inside_out(&path, mesh, winding);
+            }
+            // The receiver is gone if the app exited in the meantime
+            let _ = sender.send((path, meshes));
+        });
+    }
+    receiver
+}
+
+/// Warn about meshes that are likely invisible with back-face culling because their front faces
+/// point inwards. Closed meshes enclose a positive volume if their counterclockwise triangles
+/// face outwards, open ones like planes or terrain have no inside and are skipped.
+fn warn_if_inside_out(path: &Path, mesh: &Mesh, winding: Winding) {
+    if !mesh.is_closed() {
+        return;
+    }
+    let volume = mesh.signed_volume();
+    let inside_out = match winding {
+        Winding::Ccw => volume < 0.0,
+        Winding::Cw => volume > 0.0,
+    };
+    if inside_out {
+        warn!(
+            "Front faces of mesh in {path:?} point inwards with --winding {winding:?}, so it may \
+             be culled. Try the other winding or --double-sided"
+        );
+    }
+}
+
+/// Base colors of untextured meshes without color in the scene file or material, cycled through
+/// in load order
+const MESH_PALETTE: [[f32; 4]; 6] = [
+    [0.8, 0.8, 0.8, 1.0],
+    [0.8, 0.3, 0.25, 1.0],
+    [0.3, 0.6, 0.3, 1.0],
+    [0.3, 0.4, 0.8, 1.0],
+    [0.85, 0.7, 0.3, 1.0],
+    [0.6, 0.35, 0.7, 1.0],
+];
+
+/// Meshes uploaded so far with the file they were read from and their model matrix
+struct LoadedMeshes {
+    host: Vec<(PathBuf, Rc<Mesh>, Matrix4<f32>)>,
+    device: Vec<Rc<DeviceMesh<'static>>>,
+    textures: HashMap<PathBuf, Rc<Texture<'static>>>,
+    samplers: Rc<SamplerCache<'static>>,
+    /// Filtering of mesh textures
+    texture_sampler: SamplerOptions,
+    /// Number of files added, meshes of each file form a [`DeviceMesh::group`]
+    num_files: usize,
+}
+
+impl LoadedMeshes {
+    fn new(samplers: Rc<SamplerCache<'static>>, texture_sampler: SamplerOptions) -> Self {
+        Self {
+            host: Vec::new(),
+            device: Vec::new(),
+            textures: HashMap::new(),
+            samplers,
+            texture_sampler,
+            num_files: 0,
+        }
+    }
+
+    /// Texture read from `texture_path`, shared between meshes using the same file
+    fn texture(
+        &mut self,
+        vulkan_app: &VulkanApp,
+        device: &'static ash::Device,
+        texture_path: &Path,
+    ) -> anyhow::Result<Rc<Texture<'static>>> {
+        if let Some(texture) = self.textures.get(texture_path) {
+            return Ok(Rc::clone(texture));
+        }
+        let cmd = vulkan_app.allocate_command_buffers(1)?[0];
+        let texture = Texture::from_file(
+            device,
+            vulkan_app.device_memory_properties(),
+            cmd,
+            vulkan_app.graphics_queue(),
+            &self.samplers,
+            self.texture_sampler,
+            texture_path,
+            true,
+        );
+        vulkan_app.free_command_buffers(&[cmd]);
+        let texture = Rc::new(texture?);
+        self.textures
+            .insert(texture_path.to_owned(), Rc::clone(&texture));
+        Ok(texture)
+    }
+
+    /// Upload the meshes read from `path` with transform, texture and color from `scene`.
+    /// Otherwise the texture and color of the mesh's material are used.
+    fn add(
+        &mut self,
+        vulkan_app: &VulkanApp,
+        device: &'static ash::Device,
+        scene: &Scene,
+        alpha_test: bool,
+        path: PathBuf,
+        meshes: Vec<Mesh>,
+    ) -> anyhow::Result<()> {
+        let transform = scene.transform(&path);
+        // The scene file transforms all meshes of a file alike
+        let group = self.num_files;
+        self.num_files += 1;
+        for mesh in meshes {
+            // Meshes with several materials are split on load, see `Mesh::from_file_multi`
+            let material = match mesh.materials() {
+                [material] => Some(material.clone()),
+                _ => None,
+            };
+            let material_texture = material
+                .as_ref()
+                .and_then(|material| material.texture.as_deref());
+            let texture = match (scene.texture(&path), material_texture) {
+                (Some(texture_path), _) => Some(self.texture(vulkan_app, device, texture_path)?),
+                // Textures referenced by material libraries are often missing
+                (None, Some(texture_path)) => self
+                    .texture(vulkan_app, device, texture_path)
+                    .map_err(|err| warn!("Failed to load texture {texture_path:?}: {err:#}"))
+                    .ok(),
+                (None, None) => None,
+            };
+            info!(
+                "Loaded mesh with {} triangles and {} vertices. vertex_normals: {}.",
+                mesh.num_triangles(),
+                mesh.num_vertices(),
+                mesh.has_vertex_normals()
+            );
+            // Vertex colors replace the color of the material or palette, but are still tinted
+            // by a color from the scene file
+            let base_color = scene
+                .color(&path)
+                .or_else(|| mesh.vertex_colors().map(|_| [1.0; 4]))
+                .or_else(|| material.map(|material| material.base_color))
+                .unwrap_or(if texture.is_some() {
+                    [1.0; 4]
+                } else {
+                    MESH_PALETTE[self.device.len() % MESH_PALETTE.len()]
+                });
+            let mesh = Rc::new(mesh);
+            self.device.push(Rc::new(
+                DeviceMesh::new(
+                    device,
+                    vulkan_app.device_memory_properties(),
+                    &mesh,
+                    vulkan_app.raytracing_support(),
+                )?
+                .with_opaque(!alpha_test)
+                .with_group(group)
+                .with_transform(transform)
+                .with_texture(texture)
+                .with_base_color(base_color),
+            ));
+            self.host.push((path.clone(), mesh, transform));
+        }
+        Ok(())
+    }
+
+    /// Replace the meshes of all renderers with the loaded ones
+    fn upload_to(
+        &self,
+        renderers: &mut [RendererImpl],
+        vulkan_app: &VulkanApp,
+    ) -> anyhow::Result<()> {
+        // Frames in flight might still use descriptors of the previous meshes
+        unsafe { vulkan_app.device().device_wait_idle()? };
+        for r in renderers.iter_mut() {
+            let cmd = vulkan_app.allocate_command_buffers(1)?[0];
+            r.set_meshes(
+                &self.device,
+                cmd,
+                vulkan_app.graphics_queue(),
+                vulkan_app.device_memory_properties(),
+            )?;
+            vulkan_app.free_command_buffers(&[cmd]);
+        }
+        Ok(())
+    }
+
+    /// Vertex-weighted centroid and the radius of the sphere around it enclosing all meshes
+    fn extent(&self) -> (Point3<f32>, Option<f32>) {
+        let num_vertices = self
+            .host
+            .iter()
+            .map(|(_, m, _)| m.num_vertices())
+            .sum::<usize>();
+        let centroid =
+            self.host
+                .iter()
+                .fold(Point3::new(0.0f32, 0.0, 0.0), |i, (_, m, transform)| {
+                    i + transform.transform_point(m.centroid()).to_vec() * m.num_vertices() as f32
+                        / num_vertices.max(1) as f32
+                });
+        let radius = scene_bounding_box(&self.host).map(|(min, max)| {
+            bounding_box_corners(min, max)
+                .map(|p| (p - centroid).magnitude())
+                .fold(0.0f32, f32::max)
+                .max(1e-3)
+        });
+        (centroid, radius)
+    }
+}
+
+/// Point `camera` at a sphere around `centroid` and, with --auto-clip-planes or an orthographic
+/// projection, fit the clip planes to it
+fn fit_camera(
+    camera: Camera,
+    centroid: Point3<f32>,
+    scene_radius: Option<f32>,
+    args: &Args,
+) -> Camera {
+    let mut camera = camera.with_projection(Deg(args.fov), args.near, args.far);
+    if let Some(radius) = scene_radius {
+        camera.frame(centroid, radius);
+    }
+    let orthographic = camera.projection_type() == Projection::Orthographic;
+    let (near, far) = match (args.auto_clip_planes || orthographic, scene_radius) {
+        // Parallel projections can clip behind the eye, which keeps the scene when dollying in
+        (true, Some(radius)) if orthographic => (-radius, 10.0 * (camera.distance() + radius)),
+        // Leave headroom for dollying out
+        (true, Some(radius)) => (1e-3 * radius, 10.0 * (camera.distance() + radius)),
+        _ => (args.near, args.far),
+    };
+    info!("Clip planes: near {near} far {far}");
+    camera.with_projection(Deg(args.fov), near, far)
+}
+
+fn render_to_file(
+    vulkan_app: &mut VulkanApp,
+    renderers: &mut [RendererImpl],
+    drawer_indices: &[usize],
+    render_style: RenderStyle,
+    output: &Path,
+) -> anyhow::Result<()> {
+    info!(
+        "Rendering one frame with {:?} to {output:?}",
+        drawer_indices
+            .iter()
+            .map(|&idx| renderers[idx].name())
+            .collect::<Vec<_>>()
+    );
+    for r in renderers.iter_mut() {
+        r.set_resolution(
+            vulkan_app.surface_format(),
+            vulkan_app.extent(),
+            vulkan_app.images(),
+            vulkan_app.device_memory_properties(),
+            render_style,
+        )?;
+    }
+    vulkan_app.request_capture()?;
+    vulkan_app.draw(
+        |device, cmd, image, instant, swapchain_idx, timers| -> Result<(), anyhow::Error> {
+            for (i, &idx) in drawer_indices.iter().enumerate() {
+                renderers[idx].draw(
+                    device,
+                    cmd,
+                    image,
+                    instant,
+                    swapchain_idx,
+                    timers.renderer(i),
+                )?;
+            }
+            Ok(())
+        },
+    )?;
+    vulkan_app.save_captured_frame(output)
+}
+
+/// Split the swapchain images between the two `compared` renderers and let the helpers at
+/// `overlay_idx` draw a line between them. Without renderers to compare, all draw to the whole
+/// image again. Takes effect on the next `set_resolution`.
+fn set_comparison(renderers: &mut [RendererImpl], compared: &[usize], overlay_idx: usize) {
+    for r in renderers.iter_mut() {
+        r.set_region(Region::default());
+    }
+    if let [left, right] = *compared {
+        renderers[left].set_region(Region::left_half());
+        renderers[right].set_region(Region::right_half());
+    }
+    if let Some(RendererImpl::Helpers(helpers)) = renderers.get_mut(overlay_idx) {
+        helpers.set_divider((compared.len() == 2).then(|| Region::left_half().right));
+    }
+}
+
+/// Wait until `min_frame_time` passed since `last_frame`. Sleeps most of the time and spins for
+/// the last bit, as sleeping alone tends to overshoot by up to a scheduler tick.
+fn limit_frame_rate(last_frame: Option<Instant>, min_frame_time: Duration) {
+    const SPIN_TIME: Duration = Duration::from_millis(2);
+    let deadline = match last_frame {
+        Some(last_frame) => last_frame + min_frame_time,
+        None => return,
+    };
+    let remaining = deadline.saturating_duration_since(Instant::now());
+    if remaining > SPIN_TIME {
+        std::thread::sleep(remaining - SPIN_TIME);
+    }
+    while Instant::now() < deadline {
+        std::hint::spin_loop();
+    }
+}
+
+/// Do one empty draw to recreate the swapchain and let the renderers use its new images
+fn rebuild_swapchain(
+    vulkan_app: &mut VulkanApp,
+    renderers: &mut [RendererImpl],
+    render_style: RenderStyle,
+) -> anyhow::Result<()> {
+    vulkan_app.draw(
+        |_device, _cmd, _image, _instant, _swapchain_idx, _timers| -> Result<(), anyhow::Error> {
+            Ok(())
+        },
+    )?;
+    for r in renderers.iter_mut() {
+        r.set_resolution(
+            vulkan_app.surface_format(),
+            vulkan_app.extent(),
+            vulkan_app.images(),
+            vulkan_app.device_memory_properties(),
+            render_style,
+        )?;
+    }
+    Ok(())
+}
+
+/// Save the depth buffer of the rasterizer to `path`
+fn capture_depth(
+    vulkan_app: &VulkanApp,
+    renderers: &[RendererImpl],
+    path: &Path,
+) -> anyhow::Result<()> {
+    let raster = renderers
+        .iter()
+        .find_map(|r| match r {
+            RendererImpl::Raster(raster) => Some(raster),
+            _ => None,
+        })
+        .ok_or_else(|| anyhow::anyhow!("No rasterizer to capture the depth of"))?;
+    let cmd = vulkan_app.allocate_command_buffers(1)?[0];
+    let result = raster.capture_depth(
+        cmd,
+        vulkan_app.graphics_queue(),
+        vulkan_app.device_memory_properties(),
+        path,
+    );
+    vulkan_app.free_command_buffers(&[cmd]);
+    result
+}
+
+/// Draw `num_frames` frames and print statistics of their GPU times
+fn benchmark(
+    vulkan_app: &mut VulkanApp,
+    renderers: &mut [RendererImpl],
+    drawer_indices: &[usize],
+    render_style: RenderStyle,
+    num_frames: u32,
+) -> anyhow::Result<()> {
+    info!(
+        "Benchmarking {num_frames} frames with {:?}",
+        drawer_indices
+            .iter()
+            .map(|&idx| renderers[idx].name())
+            .collect::<Vec<_>>()
+    );
+    for r in renderers.iter_mut() {
+        r.set_resolution(
+            vulkan_app.surface_format(),
+            vulkan_app.extent(),
+            vulkan_app.images(),
+            vulkan_app.device_memory_properties(),
+            render_style,
+        )?;
+    }
+    let start = Instant::now();
+    for _ in 0..num_frames {
+        vulkan_app.draw(
+            |device, cmd, image, instant, swapchain_idx, timers| -> Result<(), anyhow::Error> {
+                for (i, &idx) in drawer_indices.iter().enumerate() {
+                    renderers[idx].draw(
+                        device,
+                        cmd,
+                        image,
+                        instant,
+                        swapchain_idx,
+                        timers.renderer(i),
+                    )?;
+                }
+                Ok(())
+            },
+        )?;
+    }
+    let gpu_times = vulkan_app.take_gpu_times()?;
+    let elapsed = start.elapsed();
+    println!(
+        "{num_frames} frames in {elapsed:.2?} ({:.1} fps)",
+        num_frames as f64 / elapsed.as_secs_f64()
+    );
+    match gpu_times {
+        Some(mut gpu_times) if !gpu_times.frames.is_empty() => {
+            print_gpu_times("GPU time", &mut gpu_times.frames);
+            for (&idx, times) in drawer_indices.iter().zip(gpu_times.renderers.iter_mut()) {
+                if !times.is_empty() {
+                    print_gpu_times(&format!("  {}", renderers[idx].name()), times);
+                }
+            }
+        }
+        _ => println!("GPU time: unavailable, the graphics queue doesn't support timestamps"),
+    }
+    Ok(())
+}
+
+/// Print min/avg/p99 of the non-empty `times`
+fn print_gpu_times(label: &str, times: &mut [Duration]) {
+    times.sort_unstable();
+    let p99 = times[(times.len() - 1) * 99 / 100];
+    println!(
+        "{label}: min {:.3?} avg {:.3?} p99 {p99:.3?}",
+        times[0],
+        times.iter().sum::<Duration>() / times.len() as u32
+    );
+}
+
 fn main() -> anyhow::Result<()> {
     let args = Args::parse();
+    anyhow::ensure!(
+        args.near > 0.0,
+        "Near clip plane must be positive (got --near {})",
+        args.near
+    );
+    anyhow::ensure!(
+        args.far > args.near,
+        "Far clip plane must be behind the near clip plane (got --near {} --far {})",
+        args.near,
+        args.far
+    );
+    anyhow::ensure!(
+        args.fov > 0.0 && args.fov < 180.0,
+        "Field of view must be between 0 and 180 degrees (got --fov {})",
+        args.fov
+    );
+
+    anyhow::ensure!(
+        args.line_width > 0.0,
+        "Line width must be positive (got --line-width {})",
+        args.line_width
+    );
+    anyhow::ensure!(
+        args.frames_in_flight > 0,
+        "At least one frame has to be in flight (got --frames-in-flight 0)"
+    );
+    anyhow::ensure!(
+        !(args.ssao && args.msaa > 1),
+        "--ssao is not supported together with --msaa {}",
+        args.msaa
+    );
+    anyhow::ensure!(
+        args.supersample > 0,
+        "Supersampling factor must be at least 1 (got --supersample 0)"
+    );
+    anyhow::ensure!(
+        args.benchmark != Some(0),
+        "Benchmark needs at least one frame"
+    );
 
     let tracing_mode = if args.tracing {
         setup_tracing()?;
@@ -73,93 +898,324 @@ fn main() -> anyhow::Result<()> {
         TracingMode::NoTracing
     };
 
-    let mut meshes = Vec::new();
-    for mesh in args.mesh_file.iter().map(|mesh| {
-        Mesh::from_file(
-            &mesh,
-            if args.only_triangles {
-                crate::mesh::ReadOptions::OnlyTriangles
-            } else {
-                crate::mesh::ReadOptions::WithAttributes
-            },
-        )
-    }) {
-        let mesh = mesh?;
-        info!(
-            "Loaded mesh with {} triangles and {} vertices. vertex_normals: {}.",
-            mesh.num_triangles(),
-            mesh.num_vertices(),
-            mesh.has_vertex_normals()
-        );
-        meshes.push(Rc::new(mesh));
-    }
-    if meshes.is_empty() {
+    let scene = args
+        .scene
+        .as_deref()
+        .map(Scene::from_file)
+        .transpose()?
+        .unwrap_or_default();
+    let read_options = if args.only_triangles {
+        ReadOptions::OnlyTriangles
+    } else {
+        ReadOptions::WithAttributes
+    };
+    let num_mesh_files = args.mesh_file.len();
+    let mesh_files =
+        load_meshes_in_background(&args.mesh_file, read_options, args.clean, args.winding);
+    if num_mesh_files == 0 {
         warn!("No meshes specified!");
     }
 
-    let event_loop = EventLoop::new();
-    let window = WindowBuilder::new()
-        .with_position(winit::dpi::PhysicalPosition::new(1300i32, 800))
-        .build(&event_loop)?;
-    let with_raytracing = !args.no_raytracing;
-    let mut vulkan_app = VulkanApp::new(&window, with_raytracing, tracing_mode)?;
+    let app_options = AppOptions {
+        with_raytracing: !args.no_raytracing,
+        with_cuda: args.cuda,
+        tracing_mode,
+        validation: args.validation,
+        gpu_timestamps: args.benchmark.is_some(),
+        frames_in_flight: args.frames_in_flight,
+        supersample: args.supersample,
+        present_mode: if args.benchmark.is_some() {
+            vk::PresentModeKHR::IMMEDIATE
+        } else {
+            args.present_mode.into()
+        },
+        device: args.device.clone(),
+        surface_formats: args.format.preference(),
+        sampler_anisotropy: args.anisotropy > 1,
+    };
+    let (windowed, mut vulkan_app) = if args.headless {
+        let extent = vk::Extent2D {
+            width: args.width,
+            height: args.height,
+        };
+        let vulkan_app = VulkanApp::new_headless(extent, app_options)?;
+        (None, vulkan_app)
+    } else {
+        let event_loop = EventLoop::new();
+        let window = WindowBuilder::new()
+            .with_position(winit::dpi::PhysicalPosition::new(1300i32, 800))
+            .build(&event_loop)?;
+        let vulkan_app = VulkanApp::new(&window, app_options)?;
+        (Some((event_loop, window)), vulkan_app)
+    };
+    // Pipeline layouts exceeding the limit fail to be created or misbehave on some drivers
+    anyhow::ensure!(
+        std::mem::size_of::<PushConstants>() <= vulkan_app.max_push_constants_size() as usize,
+        "The renderers need {} bytes of push constants but the device only supports {}",
+        std::mem::size_of::<PushConstants>(),
+        vulkan_app.max_push_constants_size()
+    );
+
+    let background = Background {
+        color: args.background,
+        gradient_top: args.background_top,
+    };
+    let capabilities = vulkan_app.capabilities();
+    if capabilities.raytracing || args.no_raytracing {
+        info!("Device {capabilities}");
+    } else {
+        warn!("Raytracing renderer disabled. Device {capabilities}");
+    }
 
     // Device must be 'static as it must outlive structs moved into eventloop referencing it
-    let device = Box::leak(Box::new(vulkan_app.device().clone()));
+    let device: &'static ash::Device = Box::leak(Box::new(vulkan_app.device().clone()));
+    let samplers = Rc::new(SamplerCache::new(
+        device,
+        vulkan_app.max_sampler_anisotropy(),
+    ));
+    let texture_sampler = SamplerOptions::linear().with_max_anisotropy(args.anisotropy);
+    if args.anisotropy > 1 && !vulkan_app.capabilities().sampler_anisotropy {
+        warn!(
+            "Anisotropic filtering is not supported by the device, ignoring --anisotropy {}",
+            args.anisotropy
+        );
+    }
 
-    let raster = RendererImpl::Raster(Raster::new(device)?);
+    let depth_format = vulkan_app.depth_format();
+    info!("Depth format: {depth_format:?}");
+    let raster = RendererImpl::Raster(
+        Raster::new(device, vulkan_app.pipeline_cache())?
+            .with_background(background)
+            .with_msaa(msaa_samples(
+                args.msaa,
+                vulkan_app.supported_sample_counts(),
+            ))
+            .with_ssao(args.ssao)
+            .with_depth_prepass(args.depth_prepass)
+            .with_points(args.points)
+            .with_depth_format(depth_format)
+            .with_samplers(Rc::clone(&samplers)),
+    );
     let mut renderers = vec![raster];
 
     if vulkan_app.raytracing_support() {
-        let raytrace = RendererImpl::RayTrace(RayTrace::new(
-            device,
-            vulkan_app.instance(),
-            VulkanApp::rt_pipeline_properties(
-                vulkan_app.physical_device(),
-                vulkan_app.instance().clone(),
-            ), // hack due two weird lifetime requirements of vk::PhysicalDeviceRayTracingPipelinePropertiesKHR
-        )?);
+        let environment_map = match &args.env_map {
+            Some(path) => {
+                let cmd = vulkan_app.allocate_command_buffers(1)?[0];
+                let texture = Texture::from_hdr_file(
+                    device,
+                    vulkan_app.device_memory_properties(),
+                    cmd,
+                    vulkan_app.graphics_queue(),
+                    &samplers,
+                    texture_sampler,
+                    path,
+                );
+                vulkan_app.free_command_buffers(&[cmd]);
+                Some(texture?)
+            }
+            None => None,
+        };
+        let raytrace = RendererImpl::RayTrace(
+            RayTrace::new(
+                device,
+                vulkan_app.instance(),
+                VulkanApp::rt_pipeline_properties(
+                    vulkan_app.physical_device(),
+                    vulkan_app.instance().clone(),
+                ), // hack due two weird lifetime requirements of vk::PhysicalDeviceRayTracingPipelinePropertiesKHR
+                vulkan_app.pipeline_cache(),
+            )?
+            .with_environment_map(environment_map)
+            .with_samplers(Rc::clone(&samplers))
+            .with_recursion_depth(args.ray_recursion_depth)
+            .with_background(background)
+            .with_max_samples(args.max_samples)
+            .with_tonemap(args.tonemap.into())
+            .with_compaction(args.compact_as)
+            .with_front_face(args.winding.into())
+            .with_push_descriptors(vulkan_app.instance(), vulkan_app.push_descriptor_support())
+            .with_primitives(scene.primitives().to_vec()),
+        );
         renderers.push(raytrace);
     }
     let color_sine = RendererImpl::ColorSine(ColorSine::default());
     renderers.push(color_sine);
-    debug!("Renderers: {renderers:?}");
+    let [min_line_width, max_line_width] = vulkan_app.line_width_range();
+    let line_width = args.line_width.clamp(min_line_width, max_line_width);
+    if line_width != args.line_width {
+        if vulkan_app.capabilities().wide_lines {
+            warn!(
+                "Line width {} clamped to the supported range [{min_line_width}, {max_line_width}]",
+                args.line_width
+            );
+        } else {
+            warn!(
+                "Wide lines are not supported by the device, ignoring --line-width {}",
+                args.line_width
+            );
+        }
+    }
+    let [min_point_size, max_point_size] = vulkan_app.point_size_range();
+    let mut point_size = args.point_size.clamp(min_point_size, max_point_size);
+    if point_size != args.point_size {
+        warn!(
+            "Point size {} clamped to the supported range [{min_point_size}, {max_point_size}]",
+            args.point_size
+        );
+    }
+    for r in renderers.iter_mut() {
+        r.set_point_size(point_size);
+    }
 
     if vulkan_app.cuda_support() {
-        match Cuda::new(vulkan_app.instance(), device) {
+        match Cuda::new(vulkan_app.instance(), device, &samplers) {
             Ok(cuda) => renderers.push(RendererImpl::Cuda(cuda)),
             Err(err) => error!("Failed to create CUDA renderer: {err}"),
         }
+    } else if args.cuda {
+        warn!("Ignoring --cuda, the device lacks VK_NVX_binary_import or VK_NVX_image_view_handle");
     }
+    match Compute::new(device, vulkan_app.pipeline_cache()) {
+        Ok(compute) => renderers.push(RendererImpl::Compute(compute)),
+        Err(err) => error!("Failed to create compute renderer: {err}"),
+    }
+    // Not selectable, drawn on top of the active renderer
+    let overlay_idx = renderers.len();
+    renderers.push(RendererImpl::Helpers(Helpers::new(
+        device,
+        vulkan_app.pipeline_cache(),
+    )?));
+    // After all renderers exist, the overlay draws the only line pipeline
+    for pipeline in renderers
+        .iter_mut()
+        .filter_map(|r| r.graphics_pipeline_mut())
+    {
+        pipeline.set_flat_shading(args.flat);
+        pipeline.set_double_sided(args.double_sided);
+        pipeline.set_front_face(args.winding.into());
+        pipeline.set_line_width(line_width);
+    }
+    debug!("Renderers: {renderers:?}");
 
-    let meshes = meshes
-        .iter()
-        .map(|mesh| {
-            Ok(Rc::new(DeviceMesh::new(
-                device,
-                vulkan_app.device_memory_properties(),
-                mesh,
-                vulkan_app.raytracing_support(),
-            )?))
-        })
-        .collect::<anyhow::Result<Vec<_>>>()?;
+    let mut loaded_meshes = LoadedMeshes::new(Rc::clone(&samplers), texture_sampler);
+    let mut num_loaded_files = 0;
+    if args.headless || args.benchmark.is_some() {
+        // Nothing to show while waiting
+        for (path, meshes) in mesh_files.iter().take(num_mesh_files) {
+            num_loaded_files += 1;
+            info!("Loaded {num_loaded_files}/{num_mesh_files} mesh files");
+            loaded_meshes.add(&vulkan_app, device, &scene, args.alpha_test, path, meshes?)?;
+        }
+    }
+    loaded_meshes.upload_to(&mut renderers, &vulkan_app)?;
+
+    let (mut centroid, mut scene_radius) = loaded_meshes.extent();
+    let mut camera = fit_camera(
+        Camera::new(centroid)
+            .with_scroll_zooms_fov(args.scroll_fov)
+            .with_projection_type(if args.orthographic {
+                Projection::Orthographic
+            } else {
+                Projection::Perspective
+            }),
+        centroid,
+        scene_radius,
+        &args,
+    );
+    let mut initial_camera = camera;
     for r in renderers.iter_mut() {
-        let cmd = vulkan_app.allocate_command_buffers(1)?[0];
-        r.set_meshes(
-            &meshes,
-            cmd,
-            vulkan_app.graphics_queue(),
-            vulkan_app.device_memory_properties(),
-        )?;
-        vulkan_app.free_command_buffers(&[cmd]);
+        r.set_camera(&camera);
+        r.set_lights(&scene.lights())?;
     }
-    // Everything not moved into the event loop will not be dropped. So let renderers keep
-    // references and drop manually here
-    drop(meshes);
 
-    let mut active_drawer_idx = 0;
+    let mut active_drawer_idx = args.renderer.min(overlay_idx.saturating_sub(1));
+    anyhow::ensure!(
+        args.compose.iter().all(|&idx| idx < renderers.len()),
+        "--compose {:?} selects a renderer that doesn't exist, there are {}",
+        args.compose,
+        renderers.len()
+    );
+    // All renderers but the overlay clear or overwrite the whole image
+    anyhow::ensure!(
+        args.compose.iter().skip(1).all(|&idx| idx == overlay_idx),
+        "--compose only draws the overlay {overlay_idx} on top of another renderer, got {:?}",
+        args.compose
+    );
+    let mut comparing = !args.compare.is_empty();
+    if comparing {
+        anyhow::ensure!(
+            args.compare.len() == 2 && args.compare[0] != args.compare[1],
+            "--compare takes two different renderers, got {:?}",
+            args.compare
+        );
+        for &idx in &args.compare {
+            anyhow::ensure!(
+                matches!(
+                    renderers.get(idx),
+                    Some(RendererImpl::Raster(_) | RendererImpl::RayTrace(_))
+                ),
+                "--compare only supports the Raster and RayTrace renderers, {idx} is {}",
+                renderers.get(idx).map_or("no renderer", RendererImpl::name)
+            );
+        }
+        anyhow::ensure!(
+            !args.ssao,
+            "--ssao is not supported together with --compare"
+        );
+        set_comparison(&mut renderers, &args.compare, overlay_idx);
+    }
+    let mut composed = if comparing {
+        args.compare.clone()
+    } else {
+        args.compose.clone()
+    };
     let mut last_switch = Instant::now();
     let mut render_style = RenderStyle::Normal;
+    let mut double_sided = args.double_sided;
+    let mut show_helpers = false;
+    // Where to save the frame requested with P
+    let mut capture_path: Option<PathBuf> = None;
+    // The overlay is also drawn for the divider of a comparison
+    if let RendererImpl::Helpers(helpers) = &mut renderers[overlay_idx] {
+        helpers.set_show_lines(show_helpers);
+    }
+
+    if let Some(num_frames) = args.benchmark {
+        return benchmark(
+            &mut vulkan_app,
+            &mut renderers,
+            if composed.is_empty() {
+                std::slice::from_ref(&active_drawer_idx)
+            } else {
+                &composed
+            },
+            render_style,
+            num_frames,
+        );
+    }
+
+    let (event_loop, window) = match windowed {
+        Some(windowed) => windowed,
+        None => {
+            let output = args.output.as_ref().expect("--headless requires --output");
+            return render_to_file(
+                &mut vulkan_app,
+                &mut renderers,
+                if composed.is_empty() {
+                    std::slice::from_ref(&active_drawer_idx)
+                } else {
+                    &composed
+                },
+                render_style,
+                output,
+            );
+        }
+    };
+    window.set_title(&window_title(&vulkan_app, renderers.get(active_drawer_idx)));
+    let mut last_title_update = Instant::now();
+    let min_frame_time =
+        (args.max_fps > 0).then(|| Duration::from_secs_f64(1.0 / f64::from(args.max_fps)));
     let needs_reload = Arc::new(AtomicBool::new(false));
 
     let mut hotwatch = Hotwatch::new();
@@ -168,10 +1224,10 @@ fn main() -> anyhow::Result<()> {
             for f in r
                 .graphics_pipeline()
                 .iter()
-                .flat_map(|p| p.shaders_source_files().iter())
+                .flat_map(|p| p.shaders_source_files())
             {
                 let needs_reload = Arc::clone(&needs_reload);
-                if let Some(parent) = PathBuf::from(f).parent() {
+                if let Some(parent) = f.parent() {
                     let _ = hotwatch.watch(parent, move |event| match event {
                         hotwatch::Event::Create(changed) | hotwatch::Event::Write(changed) => {
                             info!("Shader file {changed:?} changed. Trying to reload");
@@ -184,6 +1240,18 @@ fn main() -> anyhow::Result<()> {
         }
     }
 
+    let scene_changed = Arc::new(AtomicBool::new(false));
+    if let (Ok(hotwatch), Some(scene_file)) = (&mut hotwatch, &args.scene) {
+        let scene_changed = Arc::clone(&scene_changed);
+        if let Err(err) = hotwatch.watch(scene_file, move |event| {
+            if let hotwatch::Event::Create(_) | hotwatch::Event::Write(_) = event {
+                scene_changed.store(true, std::sync::atomic::Ordering::Relaxed);
+            }
+        }) {
+            warn!("Failed to watch scene file: {err}");
+        }
+    }
+
     event_loop.run(move |event, _, control_flow| {
         *control_flow = ControlFlow::Poll;
         let mut exit = || *control_flow = ControlFlow::Exit;
@@ -204,10 +1272,7 @@ fn main() -> anyhow::Result<()> {
 
                 if let Err(err) = r.set_resolution(
                     vulkan_app.surface_format(),
-                    vk::Extent2D {
-                        width: window.inner_size().width,
-                        height: window.inner_size().height,
-                    },
+                    vulkan_app.extent(),
                     vulkan_app.images(),
                     vulkan_app.device_memory_properties(),
                     render_style,
@@ -218,47 +1283,107 @@ fn main() -> anyhow::Result<()> {
             needs_reload.store(false, std::sync::atomic::Ordering::Relaxed);
         }
 
+        if scene_changed.swap(false, std::sync::atomic::Ordering::Relaxed) {
+            if let Some(scene_file) = &args.scene {
+                info!("Scene file {scene_file:?} changed. Updating transforms");
+                match Scene::from_file(scene_file) {
+                    Ok(scene) => {
+                        for ((path, _, _), mesh) in
+                            loaded_meshes.host.iter().zip(loaded_meshes.device.iter())
+                        {
+                            mesh.set_transform(scene.transform(path));
+                        }
+                        for r in renderers.iter_mut() {
+                            if let Err(err) = r.set_lights(&scene.lights()) {
+                                fail(err);
+                            }
+                            match vulkan_app.allocate_command_buffers(1) {
+                                Ok(cmds) => {
+                                    if let Err(err) =
+                                        r.update_transforms(cmds[0], vulkan_app.graphics_queue())
+                                    {
+                                        fail(err);
+                                    }
+                                    vulkan_app.free_command_buffers(&cmds);
+                                }
+                                Err(err) => fail(err.into()),
+                            }
+                        }
+                    }
+                    Err(err) => warn!("Failed to reload scene: {err:?}"),
+                }
+            }
+        }
+
+        let mut received_meshes = false;
+        while let Ok((path, meshes)) = mesh_files.try_recv() {
+            num_loaded_files += 1;
+            info!("Loaded {num_loaded_files}/{num_mesh_files} mesh files");
+            match meshes.and_then(|meshes| {
+                loaded_meshes.add(&vulkan_app, device, &scene, args.alpha_test, path, meshes)
+            }) {
+                Ok(()) => received_meshes = true,
+                Err(err) => fail(err),
+            }
+        }
+        if received_meshes {
+            if let Err(err) = loaded_meshes.upload_to(&mut renderers, &vulkan_app) {
+                fail(err);
+            }
+            (centroid, scene_radius) = loaded_meshes.extent();
+            // Frame the whole scene once, instead of jumping each time another file arrives
+            if num_loaded_files == num_mesh_files {
+                camera = fit_camera(camera, centroid, scene_radius, &args);
+                initial_camera = camera;
+            }
+            for r in renderers.iter_mut() {
+                r.set_camera(&camera);
+                // The ray tracer binds the rebuilt acceleration structure here
+                if let Err(err) = r.set_resolution(
+                    vulkan_app.surface_format(),
+                    vulkan_app.extent(),
+                    vulkan_app.images(),
+                    vulkan_app.device_memory_properties(),
+                    render_style,
+                ) {
+                    fail(err)
+                };
+            }
+        }
+
         match event {
             Event::DeviceEvent { event, .. } => {
+                if camera.process_device_event(&event, window.inner_size()) {
+                    for r in renderers.iter_mut() {
+                        r.set_camera(&camera);
+                    }
+                }
                 for r in renderers.iter_mut() {
                     r.process_device_event(&event);
                 }
             }
 
             Event::WindowEvent { event, window_id } if window_id == window.id() => {
+                if camera.process_window_event(&event, window.inner_size()) {
+                    for r in renderers.iter_mut() {
+                        r.set_camera(&camera);
+                    }
+                }
                 for r in renderers.iter_mut() {
                     r.process_window_event(&event);
                 }
                 match event {
                     WindowEvent::CloseRequested => exit(),
+                    WindowEvent::Resized(size) if size.width == 0 || size.height == 0 => {
+                        debug!("Minimized, not drawing until resized");
+                    }
                     WindowEvent::Resized(size) => {
                         debug!("Resized: {size:?}");
                         vulkan_app.resize(size);
-                        // Do one draw call to rebuild swapchain
-                        if let Err(err) = vulkan_app.draw(
-                            |_device,
-                             _cmd,
-                             _image,
-                             _instant,
-                             _swapchain_idx|
-                             -> Result<(), anyhow::Error> { Ok(()) },
-                        ) {
+                        if let Err(err) =
+                            rebuild_swapchain(&mut vulkan_app, &mut renderers, render_style)
+                        {
                             fail(err);
-                        };
-                        // Set resolution for renderers with new swapchain images
-                        for r in renderers.iter_mut() {
-                            if let Err(err) = r.set_resolution(
-                                vulkan_app.surface_format(),
-                                vk::Extent2D {
-                                    width: size.width,
-                                    height: size.height,
-                                },
-                                vulkan_app.images(),
-                                vulkan_app.device_memory_properties(),
-                                render_style,
-                            ) {
-                                fail(err)
-                            };
                         }
                     }
                     WindowEvent::KeyboardInput { input, .. } => match input.virtual_keycode {
@@ -267,6 +1392,25 @@ fn main() -> anyhow::Result<()> {
                         ) => {
                             exit();
                         }
+                        Some(winit::event::VirtualKeyCode::R)
+                            if input.state == winit::event::ElementState::Pressed =>
+                        {
+                            // Also reset inactive renderers so switching keeps the same view
+                            camera.reset_view(&initial_camera);
+                            for r in renderers.iter_mut() {
+                                r.set_camera(&camera);
+                            }
+                        }
+                        Some(winit::event::VirtualKeyCode::Home)
+                            if input.state == winit::event::ElementState::Pressed =>
+                        {
+                            if let Some(radius) = scene_radius {
+                                camera.frame(centroid, radius);
+                                for r in renderers.iter_mut() {
+                                    r.set_camera(&camera);
+                                }
+                            }
+                        }
                         Some(
                             winit::event::VirtualKeyCode::F | winit::event::VirtualKeyCode::F11,
                         ) => {
@@ -284,6 +1428,7 @@ fn main() -> anyhow::Result<()> {
                             | winit::event::VirtualKeyCode::Key1,
                         ) => {
                             active_drawer_idx = 0;
+                            composed.clear();
                             info!(
                                 "Switched Drawer to {active_drawer_idx}: {:?}",
                                 renderers[active_drawer_idx]
@@ -294,6 +1439,7 @@ fn main() -> anyhow::Result<()> {
                             | winit::event::VirtualKeyCode::Key2,
                         ) => {
                             active_drawer_idx = 1;
+                            composed.clear();
                             info!(
                                 "Switched Drawer to {active_drawer_idx}: {:?}",
                                 renderers[active_drawer_idx]
@@ -303,8 +1449,9 @@ fn main() -> anyhow::Result<()> {
                             winit::event::VirtualKeyCode::Numpad3
                             | winit::event::VirtualKeyCode::Key3,
                         ) => {
-                            if renderers.len() > 2 {
+                            if overlay_idx > 2 {
                                 active_drawer_idx = 2;
+                                composed.clear();
                                 info!(
                                     "Switched Drawer to {active_drawer_idx}: {:?}",
                                     renderers[active_drawer_idx]
@@ -315,31 +1462,108 @@ fn main() -> anyhow::Result<()> {
                             winit::event::VirtualKeyCode::Numpad4
                             | winit::event::VirtualKeyCode::Key4,
                         ) => {
-                            if renderers.len() > 3 {
+                            if overlay_idx > 3 {
                                 active_drawer_idx = 3;
+                                composed.clear();
                                 info!(
                                     "Switched Drawer to {active_drawer_idx}: {:?}",
                                     renderers[active_drawer_idx]
                                 );
                             }
                         }
+                        Some(winit::event::VirtualKeyCode::P) => {
+                            if (Instant::now() - last_switch) > Duration::from_millis(500) {
+                                last_switch = Instant::now();
+                                let timestamp = SystemTime::now()
+                                    .duration_since(UNIX_EPOCH)
+                                    .unwrap_or_default()
+                                    .as_millis();
+                                let path = PathBuf::from(format!("frame_{timestamp}.png"));
+                                // Saved once the next frame has been drawn
+                                match vulkan_app.request_capture() {
+                                    Ok(()) => capture_path = Some(path),
+                                    Err(err) => error!("Failed to capture frame: {err:?}"),
+                                }
+                            }
+                        }
+                        Some(
+                            code @ (winit::event::VirtualKeyCode::Plus
+                            | winit::event::VirtualKeyCode::Equals
+                            | winit::event::VirtualKeyCode::NumpadAdd
+                            | winit::event::VirtualKeyCode::Minus
+                            | winit::event::VirtualKeyCode::NumpadSubtract),
+                        ) if input.state == winit::event::ElementState::Pressed => {
+                            point_size = match code {
+                                winit::event::VirtualKeyCode::Minus
+                                | winit::event::VirtualKeyCode::NumpadSubtract => point_size - 1.0,
+                                _ => point_size + 1.0,
+                            }
+                            .clamp(min_point_size, max_point_size);
+                            info!("Point size: {point_size}");
+                            for r in renderers.iter_mut() {
+                                r.set_point_size(point_size);
+                            }
+                        }
+                        Some(winit::event::VirtualKeyCode::B)
+                            if input.state == winit::event::ElementState::Pressed =>
+                        {
+                            let timestamp = SystemTime::now()
+                                .duration_since(UNIX_EPOCH)
+                                .unwrap_or_default()
+                                .as_millis();
+                            let path = PathBuf::from(format!("depth_{timestamp}.png"));
+                            if let Err(err) = capture_depth(&vulkan_app, &renderers, &path) {
+                                error!("Failed to capture depth: {err:?}");
+                            }
+                        }
+                        Some(winit::event::VirtualKeyCode::G)
+                            if input.state == winit::event::ElementState::Pressed =>
+                        {
+                            show_helpers = !show_helpers;
+                            info!("Bounding box and grid: {show_helpers}");
+                            if let RendererImpl::Helpers(helpers) = &mut renderers[overlay_idx] {
+                                helpers.set_show_lines(show_helpers);
+                            }
+                        }
+                        Some(winit::event::VirtualKeyCode::C)
+                            if input.state == winit::event::ElementState::Pressed =>
+                        {
+                            double_sided = !double_sided;
+                            info!("Double-sided: {double_sided}");
+                            // Culling is baked into the pipelines
+                            for r in renderers.iter_mut() {
+                                if let Some(p) = r.graphics_pipeline_mut() {
+                                    p.set_double_sided(double_sided);
+                                }
+                                if let Err(err) = r.set_resolution(
+                                    vulkan_app.surface_format(),
+                                    vulkan_app.extent(),
+                                    vulkan_app.images(),
+                                    vulkan_app.device_memory_properties(),
+                                    render_style,
+                                ) {
+                                    fail(err)
+                                };
+                            }
+                        }
                         Some(
                             code @ (winit::event::VirtualKeyCode::W
-                            | winit::event::VirtualKeyCode::N),
+                            | winit::event::VirtualKeyCode::N
+                            | winit::event::VirtualKeyCode::D
+                            | winit::event::VirtualKeyCode::M),
                         ) => {
-                            info!("Wireframe mode",);
                             render_style = match code {
                                 winit::event::VirtualKeyCode::W => RenderStyle::Wireframe,
                                 winit::event::VirtualKeyCode::N => RenderStyle::Normal,
+                                winit::event::VirtualKeyCode::D => RenderStyle::Depth,
+                                winit::event::VirtualKeyCode::M => render_style.next(),
                                 _ => unreachable!(),
                             };
+                            info!("Render style: {render_style:?}");
                             for r in renderers.iter_mut() {
                                 if let Err(err) = r.set_resolution(
                                     vulkan_app.surface_format(),
-                                    vk::Extent2D {
-                                        width: window.inner_size().width,
-                                        height: window.inner_size().height,
-                                    },
+                                    vulkan_app.extent(),
                                     vulkan_app.images(),
                                     vulkan_app.device_memory_properties(),
                                     render_style,
@@ -354,23 +1578,89 @@ fn main() -> anyhow::Result<()> {
                 }
             }
             Event::MainEventsCleared => {
+                let size = window.inner_size();
+                if size.width == 0 || size.height == 0 {
+                    return;
+                }
+                if comparing && composed.is_empty() {
+                    // Switching the renderer with the number keys ended the comparison
+                    comparing = false;
+                    set_comparison(&mut renderers, &[], overlay_idx);
+                    for r in renderers.iter_mut() {
+                        if let Err(err) = r.set_resolution(
+                            vulkan_app.surface_format(),
+                            vulkan_app.extent(),
+                            vulkan_app.images(),
+                            vulkan_app.device_memory_properties(),
+                            render_style,
+                        ) {
+                            fail(err)
+                        };
+                    }
+                }
+                if let Some(min_frame_time) = min_frame_time {
+                    limit_frame_rate(vulkan_app.last_frame_instant(), min_frame_time);
+                }
                 if let Err(err) = vulkan_app.draw(
-                    |device, cmd, image, instant, swapchain_idx| -> Result<(), anyhow::Error> {
+                    |device,
+                     cmd,
+                     image,
+                     instant,
+                     swapchain_idx,
+                     timers|
+                     -> Result<(), anyhow::Error> {
                         if !renderers.is_empty() {
-                            renderers[active_drawer_idx].draw(
-                                device,
-                                cmd,
-                                image,
-                                instant,
-                                swapchain_idx,
-                            )
-                        } else {
-                            Ok(())
+                            let drawer_indices = if composed.is_empty() {
+                                std::slice::from_ref(&active_drawer_idx)
+                            } else {
+                                &composed
+                            };
+                            for (i, &idx) in drawer_indices.iter().enumerate() {
+                                renderers[idx].draw(
+                                    device,
+                                    cmd,
+                                    image,
+                                    instant,
+                                    swapchain_idx,
+                                    timers.renderer(i),
+                                )?;
+                            }
+                            if show_helpers || comparing {
+                                renderers[overlay_idx].draw(
+                                    device,
+                                    cmd,
+                                    image,
+                                    instant,
+                                    swapchain_idx,
+                                    GpuTimer::default(),
+                                )?;
+                            }
                         }
+                        Ok(())
                     },
                 ) {
                     fail(err)
                 }
+                if vulkan_app.has_captured_frame() {
+                    if let Some(path) = capture_path.take() {
+                        if let Err(err) = vulkan_app.save_captured_frame(&path) {
+                            error!("Failed to capture frame: {err:?}");
+                        }
+                    }
+                }
+                if vulkan_app.take_swapchain_out_of_date() {
+                    // The surface changed, most likely its size
+                    vulkan_app.resize(window.inner_size());
+                    if let Err(err) =
+                        rebuild_swapchain(&mut vulkan_app, &mut renderers, render_style)
+                    {
+                        fail(err);
+                    }
+                }
+                if last_title_update.elapsed() > Duration::from_millis(500) {
+                    last_title_update = Instant::now();
+                    window.set_title(&window_title(&vulkan_app, renderers.get(active_drawer_idx)));
+                }
             }
             _ => (),
         }
@@ -1,7 +1,10 @@
+use cgmath::{InnerSpace, Matrix, Matrix3, Matrix4, Point3, SquareMatrix, Transform, Vector3};
 use log::info;
 use ply_rs::ply;
-use std::mem::transmute;
-use std::path::Path;
+use std::cell::OnceCell;
+use std::collections::HashMap;
+use std::io::{BufRead, Read};
+use std::path::{Path, PathBuf};
 
 #[derive(Debug, Default, Clone, Copy)]
 pub struct Position {
@@ -17,10 +20,15 @@ pub struct Normal {
     pub z: f32,
 }
 
+/// Tangent `xyz` with the bitangent handedness (±1) in `w`
+pub type Tangent = [f32; 4];
+
 #[derive(Debug, Default, Clone, Copy)]
 pub struct Vertex {
     pos: Position,
     normal: Option<Normal>,
+    uv: Option<[f32; 2]>,
+    color: Option<[f32; 4]>,
 }
 
 #[derive(Debug, Default, Copy, Clone)]
@@ -28,6 +36,25 @@ pub struct Triangle {
     indices: [i32; 3],
 }
 
+/// Surface appearance of the triangles of a mesh, read from OBJ material libraries
+#[derive(Debug, Clone, PartialEq)]
+pub struct Material {
+    /// Linear RGBA diffuse color (`Kd`)
+    pub base_color: [f32; 4],
+    /// Diffuse texture (`map_Kd`) relative to the working directory
+    pub texture: Option<PathBuf>,
+}
+
+impl Default for Material {
+    /// Gray used for OBJ faces without (known) material
+    fn default() -> Self {
+        Self {
+            base_color: [0.8, 0.8, 0.8, 1.0],
+            texture: None,
+        }
+    }
+}
+
 #[derive(thiserror::Error, Debug)]
 pub enum MeshIOError {
     #[error("Unsupported mesh file type: {0:?}")]
@@ -36,6 +63,28 @@ pub enum MeshIOError {
     NoFileExtension,
     #[error("Vertex attribute number does not agree with number of vertices: {0} attributes vs {1} vertices")]
     InvalidNumberOfVertexAttributes(usize, usize),
+    #[error("Failed to parse STL file: {0}")]
+    InvalidStl(String),
+    #[error("glTF file contains no scene")]
+    NoGltfScene,
+    #[error("glTF primitive has no POSITION attribute")]
+    NoGltfPositions,
+    #[error("Failed to parse OBJ file: {0}")]
+    InvalidObj(String),
+}
+
+/// The eight corners of an axis-aligned box, bit `i` of the index selects `max` on axis `i`
+pub fn bounding_box_corners(
+    min: Point3<f32>,
+    max: Point3<f32>,
+) -> impl Iterator<Item = Point3<f32>> {
+    (0..8).map(move |i| {
+        Point3::new(
+            if i & 1 == 0 { min.x } else { max.x },
+            if i & 2 == 0 { min.y } else { max.y },
+            if i & 4 == 0 { min.z } else { max.z },
+        )
+    })
 }
 
 fn get_normals(mesh: &tri_mesh::mesh::Mesh) -> anyhow::Result<Vec<Normal>> {
@@ -51,41 +100,440 @@ fn get_normals(mesh: &tri_mesh::mesh::Mesh) -> anyhow::Result<Vec<Normal>> {
     Ok(normals)
 }
 
-fn get_positions(mesh: &tri_mesh::mesh::Mesh) -> Vec<Position> {
-    mesh.vertex_iter()
-        .map(|v| {
-            let pos = mesh.vertex_position(v);
-            Position {
-                x: pos.x as f32,
-                y: pos.y as f32,
-                z: pos.z as f32,
-            }
+fn compute_normals(positions: &[Position], triangles: &[Triangle]) -> anyhow::Result<Vec<Normal>> {
+    let mesh = tri_mesh::mesh_builder::MeshBuilder::new()
+        .with_positions(
+            positions
+                .iter()
+                .flat_map(|p| [p.x as f64, p.y as f64, p.z as f64])
+                .collect(),
+        )
+        .with_indices(
+            triangles
+                .iter()
+                .flat_map(|t| {
+                    [
+                        t.indices[0] as u32,
+                        t.indices[1] as u32,
+                        t.indices[2] as u32,
+                    ]
+                })
+                .collect(),
+        )
+        .build()
+        .map_err(|err| anyhow::anyhow!("Failed to calc normals: {err:?}"))?;
+
+    get_normals(&mesh)
+}
+
+/// Twice the area of a triangle relative to its longest edge squared below which it is dropped
+/// by [`remove_degenerate_triangles`]
+const DEGENERATE_TRIANGLE_RATIO: f32 = 1e-6;
+
+/// Drops triangles with repeated indices or (nearly) zero area and compacts the vertices so that
+/// only referenced ones remain, numbered in order of first use. Returns the previous index of
+/// every remaining vertex, per-vertex attributes must be updated with [`select_vertices`].
+/// Point clouds without any triangles keep all their vertices.
+fn remove_degenerate_triangles(
+    positions: &[Position],
+    triangles: &mut Vec<Triangle>,
+) -> Vec<usize> {
+    if triangles.is_empty() {
+        return (0..positions.len()).collect();
+    }
+    let num_triangles = triangles.len();
+    triangles.retain(|t| !is_degenerate(positions, t));
+    let kept_vertices = compact_vertices(positions.len(), triangles);
+    info!(
+        "Removed {} degenerate triangles and {} unreferenced vertices",
+        num_triangles - triangles.len(),
+        positions.len() - kept_vertices.len()
+    );
+    kept_vertices
+}
+
+/// Whether a triangle has repeated indices or (nearly) zero area
+fn is_degenerate(positions: &[Position], t: &Triangle) -> bool {
+    let [a, b, c] = t.indices;
+    if a == b || b == c || a == c {
+        return true;
+    }
+    let [a, b, c] = t
+        .indices
+        .map(|i| positions[i as usize])
+        .map(|p| Vector3::new(p.x, p.y, p.z));
+    let longest_edge = (b - a)
+        .magnitude2()
+        .max((c - b).magnitude2())
+        .max((a - c).magnitude2());
+    (b - a).cross(c - a).magnitude() <= DEGENERATE_TRIANGLE_RATIO * longest_edge
+}
+
+/// Renumbers the vertices of `triangles` in order of first use and returns the previous index of
+/// every referenced vertex
+fn compact_vertices(num_vertices: usize, triangles: &mut [Triangle]) -> Vec<usize> {
+    let mut new_indices = vec![None; num_vertices];
+    let mut kept_vertices = Vec::new();
+    for t in triangles.iter_mut() {
+        for i in t.indices.iter_mut() {
+            *i = *new_indices[*i as usize].get_or_insert_with(|| {
+                kept_vertices.push(*i as usize);
+                (kept_vertices.len() - 1) as i32
+            });
+        }
+    }
+    kept_vertices
+}
+
+/// Per-vertex `attribute` of the `kept_vertices` returned by [`remove_degenerate_triangles`]
+fn select_vertices<T: Copy>(attribute: &[T], kept_vertices: &[usize]) -> Vec<T> {
+    kept_vertices.iter().map(|&i| attribute[i]).collect()
+}
+
+/// Per-vertex tangents following Lengyel, "Computing Tangent Space Basis Vectors for an
+/// Arbitrary Mesh"
+fn compute_tangents(
+    positions: &[Position],
+    normals: &[Normal],
+    uvs: &[[f32; 2]],
+    triangles: &[Triangle],
+) -> Vec<Tangent> {
+    let to_vec = |p: &Position| Vector3::new(p.x, p.y, p.z);
+    let mut tan1 = vec![Vector3::new(0.0f32, 0.0, 0.0); positions.len()];
+    let mut tan2 = tan1.clone();
+    for t in triangles {
+        let [i0, i1, i2] = t.indices.map(|i| i as usize);
+        let e1 = to_vec(&positions[i1]) - to_vec(&positions[i0]);
+        let e2 = to_vec(&positions[i2]) - to_vec(&positions[i0]);
+        let (s1, t1) = (uvs[i1][0] - uvs[i0][0], uvs[i1][1] - uvs[i0][1]);
+        let (s2, t2) = (uvs[i2][0] - uvs[i0][0], uvs[i2][1] - uvs[i0][1]);
+        let det = s1 * t2 - s2 * t1;
+        if det.abs() < f32::EPSILON {
+            continue;
+        }
+        let r = 1.0 / det;
+        let sdir = (e1 * t2 - e2 * t1) * r;
+        let tdir = (e2 * s1 - e1 * s2) * r;
+        for i in [i0, i1, i2] {
+            tan1[i] += sdir;
+            tan2[i] += tdir;
+        }
+    }
+
+    normals
+        .iter()
+        .zip(tan1.iter().zip(tan2.iter()))
+        .map(|(n, (t, b))| {
+            let n = Vector3::new(n.x, n.y, n.z);
+            // Gram-Schmidt orthogonalize
+            let tangent = t - n * n.dot(*t);
+            let tangent = if tangent.magnitude2() > 0.0 {
+                tangent.normalize()
+            } else {
+                // No UV gradient: any direction perpendicular to the normal will do
+                let axis = if n.x.abs() < 0.9 {
+                    Vector3::unit_x()
+                } else {
+                    Vector3::unit_y()
+                };
+                n.cross(axis).normalize()
+            };
+            let handedness = if n.cross(*t).dot(*b) < 0.0 { -1.0 } else { 1.0 };
+            [tangent.x, tangent.y, tangent.z, handedness]
         })
         .collect()
 }
 
-fn get_indices(mesh: &tri_mesh::mesh::Mesh) -> Vec<Triangle> {
-    mesh.face_iter()
-        .map(|f| {
-            let (a, b, c) = mesh.face_vertices(f);
-            unsafe {
-                Triangle {
-                    indices: [transmute(a), transmute(b), transmute(c)],
+/// Tangents for meshes with normals and texture coordinates
+fn generate_tangents(
+    positions: &[Position],
+    normals: Option<&[Normal]>,
+    uvs: Option<&[[f32; 2]]>,
+    triangles: &[Triangle],
+) -> Option<Vec<Tangent>> {
+    let normals = normals?;
+    match uvs {
+        Some(uvs) => Some(compute_tangents(positions, normals, uvs, triangles)),
+        None => {
+            info!("Mesh has no texture coordinates, skipping tangent generation");
+            None
+        }
+    }
+}
+
+type ObjCorner = (usize, Option<usize>, Option<usize>);
+
+struct ObjData {
+    positions: Vec<Position>,
+    normals: Option<Vec<Normal>>,
+    uvs: Option<Vec<[f32; 2]>>,
+    triangles: Vec<Triangle>,
+    /// Material libraries referenced with `mtllib`
+    material_libraries: Vec<String>,
+    /// Distinct `usemtl` names in order of first use
+    material_names: Vec<String>,
+    /// Per triangle, 0 for faces without `usemtl` and `i + 1` for `material_names[i]`
+    material_indices: Vec<u32>,
+}
+
+impl ObjData {
+    fn remove_degenerate_triangles(&mut self) {
+        self.material_indices = self
+            .triangles
+            .iter()
+            .zip(self.material_indices.iter())
+            .filter(|(t, _)| !is_degenerate(&self.positions, t))
+            .map(|(_, &material)| material)
+            .collect();
+        let kept = remove_degenerate_triangles(&self.positions, &mut self.triangles);
+        self.positions = select_vertices(&self.positions, &kept);
+        self.normals = self.normals.as_deref().map(|n| select_vertices(n, &kept));
+        self.uvs = self.uvs.as_deref().map(|uvs| select_vertices(uvs, &kept));
+    }
+}
+
+fn parse_floats<'a, const N: usize>(
+    mut words: impl Iterator<Item = &'a str>,
+    line: &str,
+) -> anyhow::Result<[f32; N]> {
+    let mut values = [0.0f32; N];
+    for v in values.iter_mut() {
+        *v = words
+            .next()
+            .ok_or_else(|| MeshIOError::InvalidObj(format!("Expected {N} numbers: {line:?}")))?
+            .parse()?;
+    }
+    Ok(values)
+}
+
+/// Resolve a 1-based (or negative, relative to the end) OBJ index
+fn obj_index(index: &str, len: usize, line: &str) -> anyhow::Result<usize> {
+    let index: i64 = index.parse()?;
+    let resolved = if index < 0 {
+        len as i64 + index
+    } else {
+        index - 1
+    };
+    if resolved < 0 || resolved >= len as i64 {
+        return Err(
+            MeshIOError::InvalidObj(format!("Index {index} out of range: {line:?}")).into(),
+        );
+    }
+    Ok(resolved as usize)
+}
+
+/// Minimal OBJ reader for `v`, `vt`, `vn`, `f`, `mtllib` and `usemtl` statements. Polygons are
+/// triangulated as fans. Vertices are split per distinct position/uv/normal combination when
+/// reading attributes.
+fn read_obj(source: &str, with_attributes: bool) -> anyhow::Result<ObjData> {
+    let mut file_positions = Vec::new();
+    let mut file_uvs = Vec::new();
+    let mut file_normals = Vec::new();
+    let mut corners: Vec<ObjCorner> = Vec::new();
+    let mut material_libraries = Vec::new();
+    let mut material_names: Vec<String> = Vec::new();
+    let mut material_indices = Vec::new();
+    let mut material = 0;
+    for line in source.lines() {
+        let mut words = line.split_whitespace();
+        match words.next() {
+            // File names may contain spaces, but several libraries can be listed
+            Some("mtllib") => material_libraries.extend(words.map(str::to_owned)),
+            Some("usemtl") => {
+                let name = words.collect::<Vec<_>>().join(" ");
+                if !material_names.contains(&name) {
+                    material_names.push(name.clone());
+                }
+                // Index 0 is reserved for faces without material
+                material = 1 + material_names.iter().position(|n| *n == name).unwrap() as u32;
+            }
+            Some("v") => file_positions.push(parse_floats::<3>(words, line)?),
+            Some("vt") => file_uvs.push(parse_floats::<2>(words, line)?),
+            Some("vn") => file_normals.push(parse_floats::<3>(words, line)?),
+            Some("f") => {
+                let face = words
+                    .map(|corner| -> anyhow::Result<ObjCorner> {
+                        let mut parts = corner.split('/');
+                        let v = obj_index(parts.next().unwrap_or(""), file_positions.len(), line)?;
+                        let vt = match parts.next() {
+                            Some("") | None => None,
+                            Some(vt) => Some(obj_index(vt, file_uvs.len(), line)?),
+                        };
+                        let vn = match parts.next() {
+                            Some("") | None => None,
+                            Some(vn) => Some(obj_index(vn, file_normals.len(), line)?),
+                        };
+                        Ok((v, vt, vn))
+                    })
+                    .collect::<anyhow::Result<Vec<_>>>()?;
+                if face.len() < 3 {
+                    return Err(MeshIOError::InvalidObj(format!(
+                        "Face with less than 3 vertices: {line:?}"
+                    ))
+                    .into());
+                }
+                for i in 1..face.len() - 1 {
+                    corners.extend([face[0], face[i], face[i + 1]]);
+                    material_indices.push(material);
                 }
             }
+            _ => (),
+        }
+    }
+
+    let to_position = |p: [f32; 3]| Position {
+        x: p[0],
+        y: p[1],
+        z: p[2],
+    };
+    if !with_attributes {
+        return Ok(ObjData {
+            positions: file_positions.into_iter().map(to_position).collect(),
+            normals: None,
+            uvs: None,
+            triangles: corners
+                .chunks_exact(3)
+                .map(|t| Triangle {
+                    indices: [t[0].0 as i32, t[1].0 as i32, t[2].0 as i32],
+                })
+                .collect(),
+            material_libraries,
+            material_names,
+            material_indices,
+        });
+    }
+
+    let has_uvs = !corners.is_empty() && corners.iter().all(|c| c.1.is_some());
+    let has_normals = !corners.is_empty() && corners.iter().all(|c| c.2.is_some());
+    let mut positions = Vec::new();
+    let mut uvs = Vec::new();
+    let mut normals = Vec::new();
+    let mut vertex_indices = HashMap::new();
+    let indices: Vec<i32> = corners
+        .iter()
+        .map(|&(v, vt, vn)| {
+            let key = (v, vt.filter(|_| has_uvs), vn.filter(|_| has_normals));
+            *vertex_indices.entry(key).or_insert_with(|| {
+                positions.push(to_position(file_positions[v]));
+                if let Some(vt) = key.1 {
+                    uvs.push(file_uvs[vt]);
+                }
+                if let Some(vn) = key.2 {
+                    let [x, y, z] = file_normals[vn];
+                    normals.push(Normal { x, y, z });
+                }
+                (positions.len() - 1) as i32
+            })
         })
+        .collect();
+
+    Ok(ObjData {
+        positions,
+        normals: has_normals.then_some(normals),
+        uvs: has_uvs.then_some(uvs),
+        triangles: indices
+            .chunks_exact(3)
+            .map(|t| Triangle {
+                indices: [t[0], t[1], t[2]],
+            })
+            .collect(),
+        material_libraries,
+        material_names,
+        material_indices,
+    })
+}
+
+/// Reads `newmtl`, `Kd` and `map_Kd` statements of an OBJ material library. Texture paths are
+/// resolved against `dir`, the directory of the library.
+fn read_mtl(source: &str, dir: &Path) -> anyhow::Result<HashMap<String, Material>> {
+    let mut materials = HashMap::new();
+    let mut current: Option<(String, Material)> = None;
+    for line in source.lines() {
+        let mut words = line.split_whitespace();
+        match words.next() {
+            Some("newmtl") => {
+                materials.extend(current.take());
+                let name = words.collect::<Vec<_>>().join(" ");
+                current = Some((name, Material::default()));
+            }
+            Some("Kd") => {
+                if let Some((_, material)) = current.as_mut() {
+                    let [r, g, b] = parse_floats::<3>(words, line)?;
+                    material.base_color = [r, g, b, 1.0];
+                }
+            }
+            Some("map_Kd") => {
+                // Options like `-s 1 1 1` precede the file name
+                if let (Some((_, material)), Some(file)) = (current.as_mut(), words.last()) {
+                    material.texture = Some(dir.join(file));
+                }
+            }
+            _ => (),
+        }
+    }
+    materials.extend(current);
+    Ok(materials)
+}
+
+/// Materials of an OBJ file, indexed like [`ObjData::material_indices`]. Faces without material,
+/// with unknown material or without readable library are gray.
+fn obj_materials(obj: &ObjData, obj_path: &Path) -> Vec<Material> {
+    let dir = obj_path.parent().unwrap_or_else(|| Path::new(""));
+    let mut library = HashMap::new();
+    for file in obj.material_libraries.iter() {
+        let path = dir.join(file);
+        let materials = std::fs::read_to_string(&path)
+            .map_err(anyhow::Error::from)
+            .and_then(|source| read_mtl(&source, path.parent().unwrap_or(dir)));
+        match materials {
+            Ok(materials) => library.extend(materials),
+            Err(err) => log::warn!("Failed to read material library {path:?}: {err}"),
+        }
+    }
+    std::iter::once(Material::default())
+        .chain(obj.material_names.iter().map(|name| {
+            library.get(name).cloned().unwrap_or_else(|| {
+                log::warn!(
+                    "Material {name:?} not found in {:?}",
+                    obj.material_libraries
+                );
+                Material::default()
+            })
+        }))
         .collect()
 }
 
+/// Scalar PLY property as `f32`, e.g. `double` coordinates as written by CloudCompare
+fn property_as_f32(property: ply::Property) -> Option<f32> {
+    match property {
+        ply::Property::Float(v) => Some(v),
+        ply::Property::Double(v) => Some(v as f32),
+        ply::Property::Int(v) => Some(v as f32),
+        ply::Property::UInt(v) => Some(v as f32),
+        _ => None,
+    }
+}
+
+/// PLY color channel in `[0, 1]`, integer channels are normalized by their maximum
+fn color_channel(property: ply::Property) -> Option<f32> {
+    match property {
+        ply::Property::UChar(v) => Some(v as f32 / u8::MAX as f32),
+        ply::Property::UShort(v) => Some(v as f32 / u16::MAX as f32),
+        ply::Property::Float(v) => Some(v),
+        ply::Property::Double(v) => Some(v as f32),
+        _ => None,
+    }
+}
+
 impl ply::PropertyAccess for Position {
     fn new() -> Self {
         Self::default()
     }
     fn set_property(&mut self, key: String, property: ply::Property) {
-        match (key.as_ref(), property) {
-            ("x", ply::Property::Float(v)) => self.x = v,
-            ("y", ply::Property::Float(v)) => self.y = v,
-            ("z", ply::Property::Float(v)) => self.z = v,
+        match (key.as_ref(), property_as_f32(property)) {
+            ("x", Some(v)) => self.x = v,
+            ("y", Some(v)) => self.y = v,
+            ("z", Some(v)) => self.z = v,
             _ => (),
         }
     }
@@ -96,39 +544,147 @@ impl ply::PropertyAccess for Vertex {
         Vertex::default()
     }
     fn set_property(&mut self, key: String, property: ply::Property) {
-        match (key.as_ref(), property) {
-            ("x", ply::Property::Float(v)) => self.pos.x = v,
-            ("y", ply::Property::Float(v)) => self.pos.y = v,
-            ("z", ply::Property::Float(v)) => self.pos.z = v,
-            ("nx", ply::Property::Float(v)) => self.normal.get_or_insert(Default::default()).x = v,
-            ("ny", ply::Property::Float(v)) => self.normal.get_or_insert(Default::default()).y = v,
-            ("nz", ply::Property::Float(v)) => self.normal.get_or_insert(Default::default()).z = v,
+        let channel = match key.as_ref() {
+            "red" => Some(0),
+            "green" => Some(1),
+            "blue" => Some(2),
+            "alpha" => Some(3),
+            _ => None,
+        };
+        if let Some(channel) = channel {
+            if let Some(v) = color_channel(property) {
+                self.color.get_or_insert([0.0, 0.0, 0.0, 1.0])[channel] = v;
+            }
+            return;
+        }
+        match (key.as_ref(), property_as_f32(property)) {
+            ("x", Some(v)) => self.pos.x = v,
+            ("y", Some(v)) => self.pos.y = v,
+            ("z", Some(v)) => self.pos.z = v,
+            ("nx", Some(v)) => self.normal.get_or_insert(Default::default()).x = v,
+            ("ny", Some(v)) => self.normal.get_or_insert(Default::default()).y = v,
+            ("nz", Some(v)) => self.normal.get_or_insert(Default::default()).z = v,
+            ("s" | "u", Some(v)) => self.uv.get_or_insert(Default::default())[0] = v,
+            ("t" | "v", Some(v)) => self.uv.get_or_insert(Default::default())[1] = v,
             _ => (),
         }
     }
 }
 
-impl ply::PropertyAccess for Triangle {
+/// PLY face with any number of vertices
+#[derive(Debug, Default, Clone)]
+struct Face {
+    indices: Vec<i32>,
+}
+
+impl ply::PropertyAccess for Face {
     fn new() -> Self {
-        Triangle {
-            indices: Default::default(),
-        }
+        Face::default()
     }
     fn set_property(&mut self, key: String, property: ply::Property) {
         match (key.as_ref(), property) {
-            ("vertex_indices", ply::Property::ListInt(vec)) => {
-                let result = vec.try_into();
-                match result {
-                    Ok(triangle) => self.indices = triangle,
-                    Err(err) => log::error!("Found face that's not a triangle: {err:?}"),
+            ("vertex_indices", ply::Property::ListInt(vec)) => self.indices = vec,
+            (k, _) => panic!("Face: Unexpected key/value combination: key: {}", k),
+        }
+    }
+}
+
+/// Splits polygons into triangle fans around their first vertex, which keeps the winding and is
+/// correct for convex polygons
+fn triangulate_faces(faces: &[Face]) -> Vec<Triangle> {
+    let mut triangles = Vec::with_capacity(faces.len());
+    for face in faces {
+        match face.indices.as_slice() {
+            [first, rest @ ..] if rest.len() >= 2 => {
+                triangles.extend(rest.windows(2).map(|edge| Triangle {
+                    indices: [*first, edge[0], edge[1]],
+                }))
+            }
+            indices => log::error!("Skipping face with less than 3 vertices: {indices:?}"),
+        }
+    }
+    triangles
+}
+
+type StlFacet = ([f32; 3], [[f32; 3]; 3]);
+
+fn read_binary_stl(bytes: &[u8]) -> anyhow::Result<Vec<StlFacet>> {
+    fn read_vec3(reader: &mut &[u8]) -> anyhow::Result<[f32; 3]> {
+        let mut buf = [0u8; 12];
+        reader.read_exact(&mut buf)?;
+        Ok([
+            f32::from_le_bytes(buf[0..4].try_into()?),
+            f32::from_le_bytes(buf[4..8].try_into()?),
+            f32::from_le_bytes(buf[8..12].try_into()?),
+        ])
+    }
+
+    let mut reader = &bytes[84..];
+    let mut facets = Vec::with_capacity((bytes.len() - 84) / 50);
+    while !reader.is_empty() {
+        let normal = read_vec3(&mut reader)?;
+        let corners = [
+            read_vec3(&mut reader)?,
+            read_vec3(&mut reader)?,
+            read_vec3(&mut reader)?,
+        ];
+        // Skip the attribute byte count
+        let mut attribute = [0u8; 2];
+        reader.read_exact(&mut attribute)?;
+        facets.push((normal, corners));
+    }
+    Ok(facets)
+}
+
+fn read_ascii_stl(bytes: &[u8]) -> anyhow::Result<Vec<StlFacet>> {
+    fn parse_vec3<'a>(
+        mut words: impl Iterator<Item = &'a str>,
+        line: &str,
+    ) -> anyhow::Result<[f32; 3]> {
+        let mut vec = [0.0f32; 3];
+        for v in vec.iter_mut() {
+            *v = words
+                .next()
+                .ok_or_else(|| MeshIOError::InvalidStl(format!("Expected 3 numbers: {line:?}")))?
+                .parse()?;
+        }
+        Ok(vec)
+    }
+
+    let mut facets = Vec::new();
+    let mut normal = [0.0f32; 3];
+    let mut corners = Vec::with_capacity(3);
+    for line in bytes.lines() {
+        let line = line?;
+        let mut words = line.split_whitespace();
+        match words.next() {
+            Some("facet") => {
+                if words.next() != Some("normal") {
+                    return Err(MeshIOError::InvalidStl(format!(
+                        "Expected facet normal: {line:?}"
+                    ))
+                    .into());
                 }
+                normal = parse_vec3(words, &line)?;
+                corners.clear();
             }
-            (k, _) => panic!("Face: Unexpected key/value combination: key: {}", k),
+            Some("vertex") => corners.push(parse_vec3(words, &line)?),
+            Some("endfacet") => {
+                let corners: [[f32; 3]; 3] = corners.as_slice().try_into().map_err(|_| {
+                    MeshIOError::InvalidStl(format!(
+                        "Found facet that's not a triangle ({} vertices)",
+                        corners.len()
+                    ))
+                })?;
+                facets.push((normal, corners));
+            }
+            _ => (),
         }
     }
+    Ok(facets)
 }
 
-#[derive(Debug, Eq, PartialEq)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
 pub enum ReadOptions {
     OnlyTriangles,
     WithAttributes,
@@ -139,6 +695,22 @@ pub struct Mesh {
     positions: Vec<Position>,
     triangles: Vec<Triangle>,
     vertex_normals: Option<Vec<Normal>>,
+    uvs: Option<Vec<[f32; 2]>>,
+    tangents: Option<Vec<Tangent>>,
+    /// Linear RGBA per vertex
+    vertex_colors: Option<Vec<[f32; 4]>>,
+    /// Empty for formats without materials
+    materials: Vec<Material>,
+    /// Index into `materials` per triangle, empty if there are no materials
+    material_indices: Vec<u32>,
+    bounds: OnceCell<Bounds>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Bounds {
+    min: Point3<f32>,
+    max: Point3<f32>,
+    centroid: Point3<f32>,
 }
 
 impl Mesh {
@@ -150,16 +722,80 @@ impl Mesh {
         self.positions.len()
     }
 
+    /// Whether the mesh has vertices but no faces, like a scan stored as PLY without faces
+    pub fn is_point_cloud(&self) -> bool {
+        self.triangles.is_empty() && !self.positions.is_empty()
+    }
+
     pub fn has_vertex_normals(&self) -> bool {
         self.vertex_normals.is_some()
     }
 
-    fn from_ply(path: impl AsRef<Path>, options: ReadOptions) -> anyhow::Result<Self> {
+    fn bounds(&self) -> &Bounds {
+        self.bounds.get_or_init(|| {
+            let mut min = Point3::new(f32::INFINITY, f32::INFINITY, f32::INFINITY);
+            let mut max = Point3::new(f32::NEG_INFINITY, f32::NEG_INFINITY, f32::NEG_INFINITY);
+            let mut sum = Vector3::new(0.0f64, 0.0, 0.0);
+            for p in self.positions.iter() {
+                min = Point3::new(min.x.min(p.x), min.y.min(p.y), min.z.min(p.z));
+                max = Point3::new(max.x.max(p.x), max.y.max(p.y), max.z.max(p.z));
+                sum += Vector3::new(p.x as f64, p.y as f64, p.z as f64);
+            }
+            let mean = sum / self.positions.len().max(1) as f64;
+            Bounds {
+                min,
+                max,
+                centroid: Point3::new(mean.x as f32, mean.y as f32, mean.z as f32),
+            }
+        })
+    }
+
+    /// Axis-aligned bounding box `(min, max)` of the vertex positions in object space. Infinite
+    /// (with `min > max`) for meshes without vertices.
+    pub fn bounding_box(&self) -> (Point3<f32>, Point3<f32>) {
+        let bounds = self.bounds();
+        (bounds.min, bounds.max)
+    }
+
+    /// Mean of the vertex positions in object space
+    pub fn centroid(&self) -> Point3<f32> {
+        self.bounds().centroid
+    }
+
+    /// Whether every edge is shared by exactly two triangles, so that the mesh encloses a volume.
+    /// Triangle soups are never closed since their triangles don't share vertices.
+    pub fn is_closed(&self) -> bool {
+        let mut edge_counts: HashMap<(i32, i32), u32> = HashMap::new();
+        for t in self.triangles.iter() {
+            let [a, b, c] = t.indices;
+            for (i, j) in [(a, b), (b, c), (c, a)] {
+                *edge_counts.entry((i.min(j), i.max(j))).or_default() += 1;
+            }
+        }
+        !edge_counts.is_empty() && edge_counts.values().all(|&count| count == 2)
+    }
+
+    /// Enclosed volume, positive if counterclockwise triangles face outwards. Only meaningful
+    /// for closed meshes, see [`Mesh::is_closed`].
+    pub fn signed_volume(&self) -> f64 {
+        self.triangles
+            .iter()
+            .map(|t| {
+                let [a, b, c] = t
+                    .indices
+                    .map(|i| self.positions[i as usize])
+                    .map(|p| Vector3::new(p.x as f64, p.y as f64, p.z as f64));
+                a.dot(b.cross(c)) / 6.0
+            })
+            .sum()
+    }
+
+    fn from_ply(path: impl AsRef<Path>, options: ReadOptions, clean: bool) -> anyhow::Result<Self> {
         info!("Reading {:?}", path.as_ref().to_str());
         let f = std::fs::File::open(&path)?;
         let mut f = std::io::BufReader::new(f);
 
-        let face_parser = ply_rs::parser::Parser::<Triangle>::new();
+        let face_parser = ply_rs::parser::Parser::<Face>::new();
         match options {
             ReadOptions::OnlyTriangles => {
                 let vertex_parser = ply_rs::parser::Parser::<Position>::new();
@@ -175,16 +811,27 @@ impl Mesh {
                                 vertex_parser.read_payload_for_element(&mut f, element, &header)?;
                         }
                         "face" => {
-                            triangles =
-                                face_parser.read_payload_for_element(&mut f, element, &header)?;
+                            triangles = triangulate_faces(
+                                &face_parser.read_payload_for_element(&mut f, element, &header)?,
+                            );
                         }
                         _ => (),
                     }
                 }
+                if clean {
+                    let kept = remove_degenerate_triangles(&positions, &mut triangles);
+                    positions = select_vertices(&positions, &kept);
+                }
                 Ok(Mesh {
                     positions,
                     triangles,
                     vertex_normals: None,
+                    uvs: None,
+                    tangents: None,
+                    vertex_colors: None,
+                    materials: Vec::new(),
+                    material_indices: Vec::new(),
+                    bounds: Default::default(),
                 })
             }
             ReadOptions::WithAttributes => {
@@ -202,87 +849,435 @@ impl Mesh {
                                 vertex_parser.read_payload_for_element(&mut f, element, &header)?;
                         }
                         "face" => {
-                            triangles =
-                                face_parser.read_payload_for_element(&mut f, element, &header)?;
+                            triangles = triangulate_faces(
+                                &face_parser.read_payload_for_element(&mut f, element, &header)?,
+                            );
                         }
                         _ => (),
                     }
                 }
+                if clean {
+                    let positions: Vec<_> = vertices.iter().map(|v| v.pos).collect();
+                    let kept = remove_degenerate_triangles(&positions, &mut triangles);
+                    vertices = select_vertices(&vertices, &kept);
+                }
                 let positions: Vec<_> = vertices.iter().map(|v| v.pos).collect();
                 let vertex_normals: Vec<_> = vertices.iter().flat_map(|v| v.normal).collect();
 
                 let vertex_normals = match (vertex_normals.len(), positions.len()) {
-                    (0, _) => Ok({
-                        let mesh = tri_mesh::mesh_builder::MeshBuilder::new()
-                            .with_positions(
-                                positions
-                                    .iter()
-                                    .flat_map(|p| [p.x as f64, p.y as f64, p.z as f64])
-                                    .collect(),
-                            )
-                            .with_indices(
-                                triangles
-                                    .iter()
-                                    .flat_map(|t| {
-                                        [
-                                            t.indices[0] as u32,
-                                            t.indices[1] as u32,
-                                            t.indices[2] as u32,
-                                        ]
-                                    })
-                                    .collect(),
-                            )
-                            .build()
-                            .map_err(|err| anyhow::anyhow!("Failed to calc normals: {err:?}"))?;
-
-                        Some(get_normals(&mesh)?)
-                    }),
+                    // Point clouds have no faces to compute normals from
+                    (0, _) if triangles.is_empty() => Ok(None),
+                    (0, _) => Ok(Some(compute_normals(&positions, &triangles)?)),
                     (a, b) if a == b => Ok(Some(vertex_normals)),
                     (a, b) => {
                         anyhow::Result::Err(MeshIOError::InvalidNumberOfVertexAttributes(a, b))
                     }
                 }?;
+                let uvs: Vec<_> = vertices.iter().flat_map(|v| v.uv).collect();
+                let uvs = match (uvs.len(), positions.len()) {
+                    (0, _) => None,
+                    (a, b) if a == b => Some(uvs),
+                    (a, b) => return Err(MeshIOError::InvalidNumberOfVertexAttributes(a, b).into()),
+                };
+                let tangents = generate_tangents(
+                    &positions,
+                    vertex_normals.as_deref(),
+                    uvs.as_deref(),
+                    &triangles,
+                );
+                let vertex_colors: Vec<_> = vertices.iter().flat_map(|v| v.color).collect();
+                let vertex_colors = match (vertex_colors.len(), positions.len()) {
+                    (0, _) => None,
+                    (a, b) if a == b => Some(vertex_colors),
+                    (a, b) => return Err(MeshIOError::InvalidNumberOfVertexAttributes(a, b).into()),
+                };
 
                 Ok(Mesh {
                     positions,
                     triangles,
                     vertex_normals,
+                    uvs,
+                    tangents,
+                    vertex_colors,
+                    materials: Vec::new(),
+                    material_indices: Vec::new(),
+                    bounds: Default::default(),
                 })
             }
         }
     }
 
-    fn from_obj(path: impl AsRef<Path>, options: ReadOptions) -> anyhow::Result<Self> {
+    fn from_obj(path: impl AsRef<Path>, options: ReadOptions, clean: bool) -> anyhow::Result<Self> {
         info!("Reading {:?}", path.as_ref().to_str());
         let obj_source = std::fs::read_to_string(path.as_ref())?;
-        let mesh = tri_mesh::mesh_builder::MeshBuilder::new()
-            .with_obj(obj_source)
-            .build()
-            .map_err(|err| anyhow::anyhow!("Failed to read obj: {err:?}"))?;
+        let mut obj = read_obj(&obj_source, options == ReadOptions::WithAttributes)?;
+        if clean {
+            obj.remove_degenerate_triangles();
+        }
+        let materials = obj_materials(&obj, path.as_ref());
 
         match options {
             ReadOptions::OnlyTriangles => Ok(Mesh {
-                positions: get_positions(&mesh),
-                triangles: get_indices(&mesh),
+                positions: obj.positions,
+                triangles: obj.triangles,
                 vertex_normals: None,
+                uvs: None,
+                tangents: None,
+                vertex_colors: None,
+                materials,
+                material_indices: obj.material_indices,
+                bounds: Default::default(),
             }),
-            ReadOptions::WithAttributes => Ok(Mesh {
-                positions: get_positions(&mesh),
-                triangles: get_indices(&mesh),
-                vertex_normals: Some(get_normals(&mesh)?),
-            }),
+            ReadOptions::WithAttributes => {
+                let vertex_normals = match obj.normals {
+                    Some(normals) => normals,
+                    None => compute_normals(&obj.positions, &obj.triangles)?,
+                };
+                let tangents = generate_tangents(
+                    &obj.positions,
+                    Some(&vertex_normals),
+                    obj.uvs.as_deref(),
+                    &obj.triangles,
+                );
+                Ok(Mesh {
+                    positions: obj.positions,
+                    triangles: obj.triangles,
+                    vertex_normals: Some(vertex_normals),
+                    uvs: obj.uvs,
+                    tangents,
+                    vertex_colors: None,
+                    materials,
+                    material_indices: obj.material_indices,
+                    bounds: Default::default(),
+                })
+            }
+        }
+    }
+
+    fn from_stl(path: impl AsRef<Path>, options: ReadOptions, clean: bool) -> anyhow::Result<Self> {
+        info!("Reading {:?}", path.as_ref().to_str());
+        let bytes = std::fs::read(path.as_ref())?;
+
+        // Binary STL has an 80 byte header followed by the triangle count. ASCII files start
+        // with "solid", but so do some binary files, so trust the size check first.
+        let is_binary = bytes.len() >= 84 && {
+            let count = u32::from_le_bytes(bytes[80..84].try_into()?) as usize;
+            bytes.len() == 84 + 50 * count
+        };
+        let facets = if is_binary {
+            read_binary_stl(&bytes)?
+        } else {
+            read_ascii_stl(&bytes)?
+        };
+
+        let mut positions = Vec::new();
+        let mut triangles = Vec::with_capacity(facets.len());
+        let mut normal_sums: Vec<[f32; 3]> = Vec::new();
+        let mut vertex_indices = HashMap::new();
+        for (normal, corners) in facets.iter() {
+            let mut indices = [0i32; 3];
+            for (idx, corner) in indices.iter_mut().zip(corners.iter()) {
+                let key = corner.map(f32::to_bits);
+                *idx = *vertex_indices.entry(key).or_insert_with(|| {
+                    positions.push(Position {
+                        x: corner[0],
+                        y: corner[1],
+                        z: corner[2],
+                    });
+                    normal_sums.push([0.0; 3]);
+                    (positions.len() - 1) as i32
+                });
+                let sum = &mut normal_sums[*idx as usize];
+                sum[0] += normal[0];
+                sum[1] += normal[1];
+                sum[2] += normal[2];
+            }
+            triangles.push(Triangle { indices });
+        }
+        if clean {
+            let kept = remove_degenerate_triangles(&positions, &mut triangles);
+            positions = select_vertices(&positions, &kept);
+            normal_sums = select_vertices(&normal_sums, &kept);
+        }
+
+        let vertex_normals = match options {
+            ReadOptions::OnlyTriangles => None,
+            ReadOptions::WithAttributes => Some(
+                normal_sums
+                    .iter()
+                    .map(|n| {
+                        let len = (n[0] * n[0] + n[1] * n[1] + n[2] * n[2]).sqrt();
+                        let len = if len > 0.0 { len } else { 1.0 };
+                        Normal {
+                            x: n[0] / len,
+                            y: n[1] / len,
+                            z: n[2] / len,
+                        }
+                    })
+                    .collect(),
+            ),
+        };
+
+        let tangents = generate_tangents(&positions, vertex_normals.as_deref(), None, &triangles);
+
+        Ok(Mesh {
+            positions,
+            triangles,
+            vertex_normals,
+            uvs: None,
+            tangents,
+            vertex_colors: None,
+            materials: Vec::new(),
+            material_indices: Vec::new(),
+            bounds: Default::default(),
+        })
+    }
+
+    fn from_gltf(
+        path: impl AsRef<Path>,
+        options: ReadOptions,
+        clean: bool,
+    ) -> anyhow::Result<Vec<Self>> {
+        info!("Reading {:?}", path.as_ref().to_str());
+        let (document, buffers, _images) = gltf::import(path.as_ref())?;
+        let scene = document
+            .default_scene()
+            .or_else(|| document.scenes().next())
+            .ok_or(MeshIOError::NoGltfScene)?;
+
+        let mut meshes = Vec::new();
+        let mut stack: Vec<_> = scene
+            .nodes()
+            .map(|node| (node, Matrix4::<f32>::identity()))
+            .collect();
+        while let Some((node, parent_transform)) = stack.pop() {
+            let transform = parent_transform * Matrix4::from(node.transform().matrix());
+            stack.extend(node.children().map(|child| (child, transform)));
+
+            let mesh = match node.mesh() {
+                Some(mesh) => mesh,
+                None => continue,
+            };
+            let normal_transform = Matrix3::from_cols(
+                transform.x.truncate(),
+                transform.y.truncate(),
+                transform.z.truncate(),
+            )
+            .invert()
+            .map(|m| m.transpose())
+            .unwrap_or_else(Matrix3::identity);
+
+            for primitive in mesh.primitives() {
+                if primitive.mode() != gltf::mesh::Mode::Triangles {
+                    log::error!(
+                        "Skipping primitive of mesh {:?} with unsupported mode {:?}",
+                        mesh.name(),
+                        primitive.mode()
+                    );
+                    continue;
+                }
+                let reader = primitive.reader(|buffer| Some(&buffers[buffer.index()]));
+                let mut positions: Vec<_> = reader
+                    .read_positions()
+                    .ok_or(MeshIOError::NoGltfPositions)?
+                    .map(|p| {
+                        let p = transform.transform_point(Point3::from(p));
+                        Position {
+                            x: p.x,
+                            y: p.y,
+                            z: p.z,
+                        }
+                    })
+                    .collect();
+                let indices: Vec<u32> = match reader.read_indices() {
+                    Some(indices) => indices.into_u32().collect(),
+                    None => (0..positions.len() as u32).collect(),
+                };
+                let mut triangles: Vec<_> = indices
+                    .chunks_exact(3)
+                    .map(|t| Triangle {
+                        indices: [t[0] as i32, t[1] as i32, t[2] as i32],
+                    })
+                    .collect();
+                let num_read_vertices = positions.len();
+                let kept_vertices =
+                    clean.then(|| remove_degenerate_triangles(&positions, &mut triangles));
+                if let Some(kept) = &kept_vertices {
+                    positions = select_vertices(&positions, kept);
+                }
+
+                let vertex_normals = match options {
+                    ReadOptions::OnlyTriangles => None,
+                    ReadOptions::WithAttributes => match reader.read_normals() {
+                        Some(normals) => {
+                            let normals: Vec<_> = normals
+                                .map(|n| {
+                                    let n = (normal_transform * Vector3::from(n)).normalize();
+                                    Normal {
+                                        x: n.x,
+                                        y: n.y,
+                                        z: n.z,
+                                    }
+                                })
+                                .collect();
+                            if normals.len() != num_read_vertices {
+                                return Err(MeshIOError::InvalidNumberOfVertexAttributes(
+                                    normals.len(),
+                                    num_read_vertices,
+                                )
+                                .into());
+                            }
+                            Some(match &kept_vertices {
+                                Some(kept) => select_vertices(&normals, kept),
+                                None => normals,
+                            })
+                        }
+                        None => Some(compute_normals(&positions, &triangles)?),
+                    },
+                };
+                let mut uvs: Option<Vec<[f32; 2]>> = match options {
+                    ReadOptions::OnlyTriangles => None,
+                    ReadOptions::WithAttributes => reader
+                        .read_tex_coords(0)
+                        .map(|uvs| uvs.into_f32().collect()),
+                };
+                if let Some(read_uvs) = &uvs {
+                    if read_uvs.len() != num_read_vertices {
+                        return Err(MeshIOError::InvalidNumberOfVertexAttributes(
+                            read_uvs.len(),
+                            num_read_vertices,
+                        )
+                        .into());
+                    }
+                    if let Some(kept) = &kept_vertices {
+                        uvs = Some(select_vertices(read_uvs, kept));
+                    }
+                }
+                let tangents = match (&vertex_normals, reader.read_tangents()) {
+                    (None, _) => None,
+                    (Some(_), Some(tangents)) => {
+                        let tangents: Vec<_> = tangents
+                            .map(|[x, y, z, w]| {
+                                let t = transform
+                                    .transform_vector(Vector3::new(x, y, z))
+                                    .normalize();
+                                [t.x, t.y, t.z, w]
+                            })
+                            .collect();
+                        if tangents.len() != num_read_vertices {
+                            return Err(MeshIOError::InvalidNumberOfVertexAttributes(
+                                tangents.len(),
+                                num_read_vertices,
+                            )
+                            .into());
+                        }
+                        Some(match &kept_vertices {
+                            Some(kept) => select_vertices(&tangents, kept),
+                            None => tangents,
+                        })
+                    }
+                    (Some(_), None) => generate_tangents(
+                        &positions,
+                        vertex_normals.as_deref(),
+                        uvs.as_deref(),
+                        &triangles,
+                    ),
+                };
+
+                meshes.push(Mesh {
+                    positions,
+                    triangles,
+                    vertex_normals,
+                    uvs,
+                    tangents,
+                    vertex_colors: None,
+                    materials: Vec::new(),
+                    material_indices: Vec::new(),
+                    bounds: Default::default(),
+                });
+            }
+        }
+        Ok(meshes)
+    }
+
+    /// One mesh per material with only the vertices its triangles use. Meshes with at most one
+    /// material are returned as they are.
+    pub fn split_by_material(self) -> Vec<Self> {
+        if self.materials.len() <= 1 {
+            return vec![self];
+        }
+        let mut groups = vec![Vec::new(); self.materials.len()];
+        for (&t, &material) in self.triangles.iter().zip(self.material_indices.iter()) {
+            groups[material as usize].push(t);
+        }
+        groups
+            .into_iter()
+            .zip(self.materials.iter())
+            .filter(|(triangles, _)| !triangles.is_empty())
+            .map(|(mut triangles, material)| {
+                let kept = compact_vertices(self.positions.len(), &mut triangles);
+                let num_triangles = triangles.len();
+                Mesh {
+                    positions: select_vertices(&self.positions, &kept),
+                    triangles,
+                    vertex_normals: self
+                        .vertex_normals
+                        .as_deref()
+                        .map(|n| select_vertices(n, &kept)),
+                    uvs: self.uvs.as_deref().map(|uvs| select_vertices(uvs, &kept)),
+                    tangents: self.tangents.as_deref().map(|t| select_vertices(t, &kept)),
+                    vertex_colors: self
+                        .vertex_colors
+                        .as_deref()
+                        .map(|c| select_vertices(c, &kept)),
+                    materials: vec![material.clone()],
+                    material_indices: vec![0; num_triangles],
+                    bounds: Default::default(),
+                }
+            })
+            .collect()
+    }
+
+    /// Like [`Mesh::from_file`] but for formats that can contain more than one mesh (glTF).
+    /// Every mesh primitive becomes its own [`Mesh`] with node transforms baked into the
+    /// positions. OBJ files are split into one mesh per material.
+    pub fn from_file_multi(
+        path: &impl AsRef<Path>,
+        options: ReadOptions,
+        clean: bool,
+    ) -> anyhow::Result<Vec<Self>> {
+        let ext = path
+            .as_ref()
+            .extension()
+            .ok_or(MeshIOError::NoFileExtension)?;
+
+        match ext.to_str() {
+            Some("gltf") | Some("GLTF") | Some("glb") | Some("GLB") => {
+                Mesh::from_gltf(path, options, clean)
+            }
+            Some("obj") | Some("OBJ") => {
+                Ok(Mesh::from_obj(path, options, clean)?.split_by_material())
+            }
+            _ => Ok(vec![Mesh::from_file(path, options, clean)?]),
         }
     }
 
-    pub fn from_file(path: &impl AsRef<Path>, options: ReadOptions) -> anyhow::Result<Self> {
+    /// Read a mesh file. With `clean`, degenerate triangles and unreferenced vertices are removed
+    /// before normals are computed.
+    pub fn from_file(
+        path: &impl AsRef<Path>,
+        options: ReadOptions,
+        clean: bool,
+    ) -> anyhow::Result<Self> {
         let ext = path
             .as_ref()
             .extension()
             .ok_or(MeshIOError::NoFileExtension)?;
 
         match ext.to_str() {
-            Some("ply") | Some("PLY") => Mesh::from_ply(path, options),
-            Some("obj") | Some("OBJ") => Mesh::from_obj(path, options),
+            Some("ply") | Some("PLY") => Mesh::from_ply(path, options, clean),
+            Some("obj") | Some("OBJ") => Mesh::from_obj(path, options, clean),
+            Some("stl") | Some("STL") => Mesh::from_stl(path, options, clean),
             ext => Err(MeshIOError::UnsupportedMeshFileType(
                 ext.unwrap_or("<could not decode OsString>").to_string(),
             )
@@ -302,9 +1297,179 @@ impl Mesh {
         self.vertex_normals.as_ref()
     }
 
+    /// Get a reference to the mesh's texture coordinates.
+    #[must_use]
+    pub fn uvs(&self) -> Option<&Vec<[f32; 2]>> {
+        self.uvs.as_ref()
+    }
+
+    /// Get a reference to the mesh's vertex tangents.
+    #[must_use]
+    pub fn tangents(&self) -> Option<&Vec<Tangent>> {
+        self.tangents.as_ref()
+    }
+
+    /// Get a reference to the mesh's linear RGBA vertex colors.
+    #[must_use]
+    pub fn vertex_colors(&self) -> Option<&Vec<[f32; 4]>> {
+        self.vertex_colors.as_ref()
+    }
+
+    /// Materials referenced by [`Mesh::material_indices`], empty for formats without materials.
+    #[must_use]
+    pub fn materials(&self) -> &[Material] {
+        self.materials.as_ref()
+    }
+
+    /// Index into [`Mesh::materials`] per triangle
+    #[must_use]
+    pub fn material_indices(&self) -> &[u32] {
+        self.material_indices.as_ref()
+    }
+
     /// Get a reference to the mesh's triangles.
     #[must_use]
     pub fn triangles(&self) -> &[Triangle] {
         self.triangles.as_ref()
     }
+
+    /// Get the mesh's positions as flat `xyz` floats, as uploaded by `DeviceMesh`.
+    #[must_use]
+    pub fn positions_flat(&self) -> Vec<f32> {
+        self.positions
+            .iter()
+            .flat_map(|p| [p.x, p.y, p.z])
+            .collect()
+    }
+
+    /// Get the mesh's vertex normals as flat `xyz` floats, as uploaded by `DeviceMesh`.
+    #[must_use]
+    pub fn normals_flat(&self) -> Option<Vec<f32>> {
+        self.vertex_normals
+            .as_ref()
+            .map(|normals| normals.iter().flat_map(|n| [n.x, n.y, n.z]).collect())
+    }
+
+    /// Whether every three consecutive vertices form a triangle, so no indices are needed
+    #[must_use]
+    pub fn is_triangle_soup(&self) -> bool {
+        self.positions.len() == 3 * self.triangles.len()
+            && self
+                .triangles
+                .iter()
+                .enumerate()
+                .all(|(i, t)| t.indices == [3 * i as i32, 3 * i as i32 + 1, 3 * i as i32 + 2])
+    }
+
+    /// Get the mesh's triangle indices as flat `u32`s. `DeviceMesh` uploads them as `u16` if
+    /// the mesh has at most `u16::MAX` vertices.
+    #[must_use]
+    pub fn indices_flat(&self) -> Vec<u32> {
+        self.triangles
+            .iter()
+            .flat_map(|t| t.indices.map(|i| i as u32))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mesh(positions: &[[f32; 3]], triangles: &[[i32; 3]]) -> Mesh {
+        Mesh {
+            positions: positions
+                .iter()
+                .map(|&[x, y, z]| Position { x, y, z })
+                .collect(),
+            triangles: triangles
+                .iter()
+                .map(|&indices| Triangle { indices })
+                .collect(),
+            vertex_normals: None,
+            uvs: None,
+            tangents: None,
+            vertex_colors: None,
+            materials: Vec::new(),
+            material_indices: Vec::new(),
+            bounds: OnceCell::new(),
+        }
+    }
+
+    fn fixture(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/fixtures")
+            .join(name)
+    }
+
+    /// Tetrahedron with counterclockwise triangles facing outwards
+    fn tetrahedron() -> Mesh {
+        mesh(
+            &[
+                [0.0, 0.0, 0.0],
+                [1.0, 0.0, 0.0],
+                [0.0, 1.0, 0.0],
+                [0.0, 0.0, 1.0],
+            ],
+            &[[0, 2, 1], [0, 1, 3], [0, 3, 2], [1, 2, 3]],
+        )
+    }
+
+    #[test]
+    fn closed_meshes_share_every_edge_twice() {
+        let closed = tetrahedron();
+        assert!(closed.is_closed());
+        assert!((closed.signed_volume() - 1.0 / 6.0).abs() < 1e-9);
+
+        let open = mesh(
+            &[
+                [0.0, 0.0, 0.0],
+                [1.0, 0.0, 0.0],
+                [1.0, 1.0, 0.0],
+                [0.0, 1.0, 0.0],
+            ],
+            &[[0, 1, 2], [0, 2, 3]],
+        );
+        assert!(!open.is_closed());
+        assert!(!mesh(&[], &[]).is_closed());
+    }
+
+    #[test]
+    fn quads_are_split_into_two_triangles_with_their_winding() {
+        assert_eq!(
+            triangulate_faces(&[Face {
+                indices: vec![0, 1, 2, 3]
+            }])
+            .iter()
+            .map(|t| t.indices)
+            .collect::<Vec<_>>(),
+            [[0, 1, 2], [0, 2, 3]]
+        );
+
+        let quad = Mesh::from_file(&fixture("quad.ply"), ReadOptions::OnlyTriangles, false)
+            .expect("Failed to read quad.ply");
+        assert_eq!(quad.num_vertices(), 4);
+        assert_eq!(quad.indices_flat(), [0, 1, 2, 0, 2, 3]);
+        // Both triangles are counterclockwise seen from +z like the quad
+        for t in quad.triangles.iter() {
+            let [a, b, c] = t.indices.map(|i| quad.positions[i as usize]);
+            assert!((b.x - a.x) * (c.y - a.y) - (b.y - a.y) * (c.x - a.x) > 0.0);
+        }
+    }
+
+    #[test]
+    fn double_precision_ply_properties_are_read() {
+        assert_eq!(property_as_f32(ply::Property::Double(0.25)), Some(0.25));
+        assert_eq!(property_as_f32(ply::Property::UChar(1)), None);
+
+        for options in [ReadOptions::OnlyTriangles, ReadOptions::WithAttributes] {
+            let mesh = Mesh::from_file(&fixture("double_triangle.ply"), options, false)
+                .expect("Failed to read double_triangle.ply");
+            let positions: Vec<_> = mesh.positions().iter().map(|p| [p.x, p.y, p.z]).collect();
+            assert_eq!(
+                positions,
+                [[0.5, 0.25, 2.0], [1.5, 0.25, 2.0], [0.5, 1.25, 2.0]]
+            );
+        }
+    }
 }
@@ -0,0 +1,45 @@
+//
+// primitive.rs
+// Copyright (C) 2022 Stephan Seitz <stephan.seitz@fau.de>
+// Distributed under terms of the GPLv3 license.
+//
+
+use ash::vk;
+use serde::Deserialize;
+
+/// Implicit surface ray traced with an intersection shader, e.g.
+/// `{ "type": "sphere", "center": [0.0, 1.0, 0.0], "radius": 0.5 }`
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(tag = "type", rename_all = "lowercase", deny_unknown_fields)]
+pub enum Primitive {
+    Sphere { center: [f32; 3], radius: f32 },
+}
+
+impl Primitive {
+    /// Bounding box the acceleration structure is built from
+    pub fn aabb(&self) -> vk::AabbPositionsKHR {
+        match *self {
+            Primitive::Sphere {
+                center: [x, y, z],
+                radius,
+            } => vk::AabbPositionsKHR {
+                min_x: x - radius,
+                min_y: y - radius,
+                min_z: z - radius,
+                max_x: x + radius,
+                max_y: y + radius,
+                max_z: z + radius,
+            },
+        }
+    }
+
+    /// Parameters read by the intersection shader, center and radius for spheres
+    pub fn shader_data(&self) -> [f32; 4] {
+        match *self {
+            Primitive::Sphere {
+                center: [x, y, z],
+                radius,
+            } => [x, y, z, radius],
+        }
+    }
+}
@@ -1,5 +1,7 @@
 pub mod color_sine;
+pub mod compute;
 pub mod cuda;
+pub mod helpers;
 pub mod raster;
 pub mod ray_tracing;
 
@@ -11,10 +13,14 @@ use enum_dispatch::enum_dispatch;
 use winit::event::{DeviceEvent, WindowEvent};
 
 use crate::device_mesh::DeviceMesh;
+use crate::gpu_timer::GpuTimer;
 use crate::shader::ShaderPipeline;
+use crate::uniforms::{Camera, Lights};
 
 use self::color_sine::ColorSine;
+use self::compute::Compute;
 use self::cuda::Cuda;
+use self::helpers::Helpers;
 use self::raster::Raster;
 use self::ray_tracing::RayTrace;
 
@@ -30,6 +36,15 @@ pub trait Renderer<'device> {
         Ok(())
     }
 
+    /// Apply changed [`DeviceMesh::transform`]s of the meshes passed to `set_meshes`
+    fn update_transforms(
+        &mut self,
+        _cmd: vk::CommandBuffer,
+        _graphics_queue: vk::Queue,
+    ) -> anyhow::Result<()> {
+        Ok(())
+    }
+
     fn set_resolution(
         &mut self,
         _surface_format: SurfaceFormatKHR,
@@ -41,6 +56,9 @@ pub trait Renderer<'device> {
         Ok(())
     }
 
+    /// Record commands rendering to `image`. It arrives in `PRESENT_SRC_KHR` and has to be left in
+    /// that layout, so that several renderers can be composed in one frame. `timer` may be used to
+    /// write timestamps around the main work.
     fn draw(
         &self,
         device: &ash::Device,
@@ -48,6 +66,7 @@ pub trait Renderer<'device> {
         image: vk::Image,
         start_instant: Instant,
         swapchain_idx: usize,
+        timer: GpuTimer,
     ) -> anyhow::Result<()>;
 
     fn graphics_pipeline(&self) -> Option<&ShaderPipeline> {
@@ -57,6 +76,21 @@ pub trait Renderer<'device> {
         None
     }
 
+    fn set_camera(&mut self, _camera: &Camera) {}
+
+    /// Set the diameter in pixels of points drawn for point clouds
+    fn set_point_size(&mut self, _point_size: f32) {}
+
+    /// Restrict drawing to `region` of the swapchain images, e.g. to compare renderers side by
+    /// side. Takes effect on the next `set_resolution`. Only [`Raster`] and [`RayTrace`] support
+    /// this, others keep drawing to the whole image.
+    fn set_region(&mut self, _region: Region) {}
+
+    /// Replace the lights, which default to [`Lights::default`]
+    fn set_lights(&mut self, _lights: &Lights) -> anyhow::Result<()> {
+        Ok(())
+    }
+
     fn process_window_event(&mut self, _event: &WindowEvent) {}
     fn process_device_event(&mut self, _event: &DeviceEvent) {}
 }
@@ -69,10 +103,167 @@ pub enum RendererImpl<'device> {
     Raster(Raster<'device>),
     RayTrace(RayTrace<'device>),
     Cuda(Cuda<'device>),
+    Compute(Compute<'device>),
+    Helpers(Helpers<'device>),
+}
+
+impl RendererImpl<'_> {
+    pub fn name(&self) -> &'static str {
+        match self {
+            RendererImpl::ColorSine(_) => "ColorSine",
+            RendererImpl::Raster(_) => "Raster",
+            RendererImpl::RayTrace(_) => "RayTrace",
+            RendererImpl::Cuda(_) => "Cuda",
+            RendererImpl::Compute(_) => "Compute",
+            RendererImpl::Helpers(_) => "Helpers",
+        }
+    }
+}
+
+/// Whether `format` stores the values written to it as they are, so that shaders have to sRGB
+/// encode linear colors themselves
+fn needs_srgb_encoding(format: vk::Format) -> bool {
+    matches!(
+        format,
+        vk::Format::B8G8R8A8_UNORM
+            | vk::Format::R8G8B8A8_UNORM
+            | vk::Format::A8B8G8R8_UNORM_PACK32
+            | vk::Format::A2B10G10R10_UNORM_PACK32
+            | vk::Format::A2R10G10B10_UNORM_PACK32
+    )
+}
+
+/// Fail unless `swapchain_idx` selects one of the `num_images` per-image resources created by the
+/// last `set_resolution`
+fn check_swapchain_idx(swapchain_idx: usize, num_images: usize) -> anyhow::Result<()> {
+    anyhow::ensure!(
+        swapchain_idx < num_images,
+        "Swapchain image {swapchain_idx} out of bounds, resources exist for {num_images} images"
+    );
+    Ok(())
+}
+
+/// Whether `size` covers no pixels, like the surface of a minimized window. Renderers create no
+/// resources for such a size and skip drawing.
+fn is_empty_extent(size: vk::Extent2D) -> bool {
+    size.width == 0 || size.height == 0
+}
+
+/// Create one handle per item, destroying the handles created so far if creating one fails so that
+/// a failing `set_resolution` does not leak them
+fn create_per_image<T, H: Copy>(
+    items: &[T],
+    mut create: impl FnMut(&T) -> anyhow::Result<H>,
+    mut destroy: impl FnMut(H),
+) -> anyhow::Result<Vec<H>> {
+    let mut handles = Vec::with_capacity(items.len());
+    for item in items {
+        match create(item) {
+            Ok(handle) => handles.push(handle),
+            Err(err) => {
+                handles.into_iter().for_each(&mut destroy);
+                return Err(err);
+            }
+        }
+    }
+    Ok(handles)
+}
+
+/// Number of workgroups or blocks of size `y` to cover `x` items
+fn div_up(x: u32, y: u32) -> u32 {
+    (x + y - 1) / y
+}
+
+/// Columns of the swapchain images a renderer draws to, as fractions of their width
+#[derive(Debug, Copy, PartialEq, Clone)]
+pub struct Region {
+    pub left: f32,
+    pub right: f32,
+}
+
+impl Default for Region {
+    fn default() -> Self {
+        Self {
+            left: 0.0,
+            right: 1.0,
+        }
+    }
+}
+
+impl Region {
+    pub fn left_half() -> Self {
+        Self {
+            left: 0.0,
+            right: 0.5,
+        }
+    }
+
+    pub fn right_half() -> Self {
+        Self {
+            left: 0.5,
+            right: 1.0,
+        }
+    }
+
+    /// Pixels covered by the region in images of `size`
+    pub fn rect(&self, size: vk::Extent2D) -> vk::Rect2D {
+        let column = |fraction: f32| (fraction.clamp(0.0, 1.0) * size.width as f32).round() as u32;
+        let (left, right) = (column(self.left), column(self.right));
+        vk::Rect2D {
+            offset: vk::Offset2D {
+                x: left as i32,
+                y: 0,
+            },
+            extent: vk::Extent2D {
+                width: right.saturating_sub(left),
+                height: size.height,
+            },
+        }
+    }
 }
 
 #[derive(Debug, Copy, Eq, PartialEq, Clone)]
 pub enum RenderStyle {
     Normal,
+    /// Triangle edges only. The ray tracer highlights edges on top of the dimmed shading.
     Wireframe,
+    /// Shade with the surface normal mapped to `normal * 0.5 + 0.5` for debugging
+    Normals,
+    /// Shade with the distance to the camera, linearly mapped from the near to the far plane
+    Depth,
+}
+
+impl RenderStyle {
+    /// Cycles Normal -> Wireframe -> Normals -> Depth
+    pub fn next(self) -> Self {
+        match self {
+            RenderStyle::Normal => RenderStyle::Wireframe,
+            RenderStyle::Wireframe => RenderStyle::Normals,
+            RenderStyle::Normals => RenderStyle::Depth,
+            RenderStyle::Depth => RenderStyle::Normal,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn create_per_image_destroys_created_handles_on_error() {
+        let mut destroyed = Vec::new();
+        let result = create_per_image(
+            &[1, 2, 3, 4],
+            |&i| match i {
+                3 => Err(anyhow::anyhow!("out of memory")),
+                i => Ok(i * 10),
+            },
+            |handle| destroyed.push(handle),
+        );
+        assert!(result.is_err());
+        assert_eq!(destroyed, [10, 20]);
+
+        let handles = create_per_image(&[1, 2], |&i| Ok(i * 10), |_: i32| unreachable!()).unwrap();
+        assert_eq!(handles, [10, 20]);
+    }
 }
@@ -3,6 +3,8 @@ use std::time::Instant;
 use ash::vk;
 use log::trace;
 
+use crate::gpu_timer::GpuTimer;
+
 use super::Renderer;
 
 #[derive(Debug, Default)]
@@ -16,18 +18,21 @@ impl<'device> Renderer<'device> for ColorSine {
         image: vk::Image,
         start_instant: Instant,
         _swapchain_idx: usize,
+        _timer: GpuTimer,
     ) -> anyhow::Result<()> {
         trace!("draw for {self:?}");
 
         unsafe {
+            // Wait for renderers composed before this one
             device.cmd_pipeline_barrier(
                 cmd,
-                vk::PipelineStageFlags::TRANSFER,
+                vk::PipelineStageFlags::ALL_COMMANDS,
                 vk::PipelineStageFlags::TRANSFER,
                 vk::DependencyFlags::default(),
                 &[],
                 &[],
                 &[vk::ImageMemoryBarrier::default()
+                    .src_access_mask(vk::AccessFlags::MEMORY_WRITE)
                     .dst_access_mask(vk::AccessFlags::TRANSFER_WRITE)
                     .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
                     .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
@@ -0,0 +1,288 @@
+use ash::vk;
+use log::{debug, trace};
+use std::time::Instant;
+
+use crate::gpu_timer::GpuTimer;
+use crate::shader::{
+    specialization_constant, ShaderPipeline, WORKGROUP_SIZE_X_CONSTANT_ID,
+    WORKGROUP_SIZE_Y_CONSTANT_ID,
+};
+
+use super::{
+    check_swapchain_idx, create_per_image, div_up, is_empty_extent, RenderStyle, Renderer,
+};
+
+/// Workgroup size of `simple.comp` in x and y, passed as specialization constants
+const WORKGROUP_SIZE: u32 = 16;
+
+const COLOR_SUBRESOURCE_RANGE: vk::ImageSubresourceRange = vk::ImageSubresourceRange {
+    aspect_mask: vk::ImageAspectFlags::COLOR,
+    base_mip_level: 0,
+    level_count: 1,
+    base_array_layer: 0,
+    layer_count: 1,
+};
+
+/// Fills the swapchain image with a compute shader, a portable version of the CUDA renderer
+pub struct Compute<'device> {
+    device: &'device ash::Device,
+    pipeline_cache: vk::PipelineCache,
+    shader_pipeline: ShaderPipeline<'device>,
+    pipeline: Option<vk::Pipeline>,
+    pipeline_layout: Option<vk::PipelineLayout>,
+    descriptor_pool: Option<vk::DescriptorPool>,
+    /// One storage image descriptor per swapchain image
+    descriptor_sets: Vec<vk::DescriptorSet>,
+    image_views: Vec<vk::ImageView>,
+    size: vk::Extent2D,
+}
+
+impl std::fmt::Debug for Compute<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Compute")
+            .field("pipeline", &self.pipeline)
+            .field("image_views", &self.image_views)
+            .field("size", &self.size)
+            .finish()
+    }
+}
+
+impl<'device> Compute<'device> {
+    pub fn new(
+        device: &'device ash::Device,
+        pipeline_cache: vk::PipelineCache,
+    ) -> anyhow::Result<Self> {
+        let mut shader_pipeline = ShaderPipeline::new(
+            device,
+            &[&include_bytes!("../../shaders/simple.comp.spirv")[..]],
+        )?;
+        shader_pipeline.set_specialization_constants(&[
+            specialization_constant(WORKGROUP_SIZE_X_CONSTANT_ID, WORKGROUP_SIZE),
+            specialization_constant(WORKGROUP_SIZE_Y_CONSTANT_ID, WORKGROUP_SIZE),
+        ]);
+        Ok(Self {
+            device,
+            pipeline_cache,
+            shader_pipeline,
+            pipeline: None,
+            pipeline_layout: None,
+            descriptor_pool: None,
+            descriptor_sets: Vec::new(),
+            image_views: Vec::new(),
+            size: vk::Extent2D {
+                width: 0,
+                height: 0,
+            },
+        })
+    }
+
+    fn destroy_images(&mut self) -> anyhow::Result<()> {
+        let device = self.device;
+        unsafe {
+            device.device_wait_idle()?;
+            if let Some(p) = self.pipeline.take() {
+                device.destroy_pipeline(p, None);
+            }
+            if let Some(p) = self.pipeline_layout.take() {
+                device.destroy_pipeline_layout(p, None);
+            }
+            if let Some(pool) = self.descriptor_pool.take() {
+                device.destroy_descriptor_pool(pool, None);
+            }
+            self.descriptor_sets.clear();
+            for view in self.image_views.drain(..) {
+                device.destroy_image_view(view, None);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Compute<'_> {
+    fn drop(&mut self) {
+        let _ = self.destroy_images();
+    }
+}
+
+impl<'device> Renderer<'device> for Compute<'device> {
+    fn draw(
+        &self,
+        device: &ash::Device,
+        cmd: vk::CommandBuffer,
+        image: vk::Image,
+        start_instant: Instant,
+        swapchain_idx: usize,
+        _timer: GpuTimer,
+    ) -> anyhow::Result<()> {
+        trace!("draw for {self:?}");
+        let (pipeline, pipeline_layout) = match (self.pipeline, self.pipeline_layout) {
+            (Some(pipeline), Some(pipeline_layout)) => (pipeline, pipeline_layout),
+            _ => return Ok(()),
+        };
+
+        check_swapchain_idx(swapchain_idx, self.descriptor_sets.len())?;
+        unsafe {
+            // Wait for renderers composed before this one, every pixel is overwritten
+            device.cmd_pipeline_barrier(
+                cmd,
+                vk::PipelineStageFlags::ALL_COMMANDS,
+                vk::PipelineStageFlags::COMPUTE_SHADER,
+                vk::DependencyFlags::default(),
+                &[],
+                &[],
+                &[vk::ImageMemoryBarrier::default()
+                    .src_access_mask(vk::AccessFlags::MEMORY_WRITE)
+                    .dst_access_mask(vk::AccessFlags::SHADER_WRITE)
+                    .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                    .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                    .old_layout(vk::ImageLayout::UNDEFINED)
+                    .new_layout(vk::ImageLayout::GENERAL)
+                    .image(image)
+                    .subresource_range(COLOR_SUBRESOURCE_RANGE)],
+            );
+
+            let time = (start_instant.elapsed().as_secs_f32().sin() + 1.0) * 0.5;
+            device.cmd_bind_pipeline(cmd, vk::PipelineBindPoint::COMPUTE, pipeline);
+            device.cmd_bind_descriptor_sets(
+                cmd,
+                vk::PipelineBindPoint::COMPUTE,
+                pipeline_layout,
+                0,
+                &[self.descriptor_sets[swapchain_idx]],
+                &[],
+            );
+            device.cmd_push_constants(
+                cmd,
+                pipeline_layout,
+                vk::ShaderStageFlags::COMPUTE,
+                0,
+                &time.to_ne_bytes(),
+            );
+            device.cmd_dispatch(
+                cmd,
+                div_up(self.size.width, WORKGROUP_SIZE),
+                div_up(self.size.height, WORKGROUP_SIZE),
+                1,
+            );
+
+            device.cmd_pipeline_barrier(
+                cmd,
+                vk::PipelineStageFlags::COMPUTE_SHADER,
+                vk::PipelineStageFlags::BOTTOM_OF_PIPE,
+                vk::DependencyFlags::default(),
+                &[],
+                &[],
+                &[vk::ImageMemoryBarrier::default()
+                    .src_access_mask(vk::AccessFlags::SHADER_WRITE)
+                    .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                    .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                    .old_layout(vk::ImageLayout::GENERAL)
+                    .new_layout(vk::ImageLayout::PRESENT_SRC_KHR)
+                    .image(image)
+                    .subresource_range(COLOR_SUBRESOURCE_RANGE)],
+            );
+        }
+        Ok(())
+    }
+
+    fn set_resolution(
+        &mut self,
+        surface_format: vk::SurfaceFormatKHR,
+        size: vk::Extent2D,
+        images: &[vk::Image],
+        _device_memory_properties: &vk::PhysicalDeviceMemoryProperties,
+        render_style: RenderStyle,
+    ) -> anyhow::Result<()> {
+        debug!("Set resolution: {size:?} images: {images:?}");
+        self.destroy_images()?;
+        self.size = size;
+        if is_empty_extent(size) {
+            return Ok(());
+        }
+        let device = self.device;
+
+        let descriptor_set_layout = unsafe {
+            device.create_descriptor_set_layout(
+                &vk::DescriptorSetLayoutCreateInfo::default().bindings(&[
+                    vk::DescriptorSetLayoutBinding::default()
+                        .descriptor_count(1)
+                        .descriptor_type(vk::DescriptorType::STORAGE_IMAGE)
+                        .stage_flags(vk::ShaderStageFlags::COMPUTE)
+                        .binding(0),
+                ]),
+                None,
+            )
+        }?;
+        let (pipeline, pipeline_layout) = self.shader_pipeline.make_compute_pipeline(
+            device,
+            self.pipeline_cache,
+            &[descriptor_set_layout],
+            &[vk::PushConstantRange::default()
+                .offset(0)
+                .size(std::mem::size_of::<f32>() as u32)
+                .stage_flags(vk::ShaderStageFlags::COMPUTE)],
+            render_style,
+        )?;
+        self.pipeline = Some(pipeline);
+        self.pipeline_layout = Some(pipeline_layout);
+
+        self.image_views = create_per_image(
+            images,
+            |&image| {
+                let create_view_info = vk::ImageViewCreateInfo::default()
+                    .view_type(vk::ImageViewType::TYPE_2D)
+                    .format(surface_format.format)
+                    .subresource_range(COLOR_SUBRESOURCE_RANGE)
+                    .image(image);
+                unsafe { device.create_image_view(&create_view_info, None) }
+                    .map_err(|err| anyhow::anyhow!("Failed to create image view: {err}"))
+            },
+            |view| unsafe { device.destroy_image_view(view, None) },
+        )?;
+
+        let descriptor_pool = unsafe {
+            device.create_descriptor_pool(
+                &vk::DescriptorPoolCreateInfo::default()
+                    .pool_sizes(&[vk::DescriptorPoolSize {
+                        ty: vk::DescriptorType::STORAGE_IMAGE,
+                        descriptor_count: images.len() as u32,
+                    }])
+                    .max_sets(images.len() as u32),
+                None,
+            )
+        }?;
+        self.descriptor_pool = Some(descriptor_pool);
+        self.descriptor_sets = unsafe {
+            device.allocate_descriptor_sets(
+                &vk::DescriptorSetAllocateInfo::default()
+                    .descriptor_pool(descriptor_pool)
+                    .set_layouts(&vec![descriptor_set_layout; images.len()]),
+            )
+        }?;
+        for (&set, &view) in self.descriptor_sets.iter().zip(self.image_views.iter()) {
+            let image_info = [vk::DescriptorImageInfo::default()
+                .image_layout(vk::ImageLayout::GENERAL)
+                .image_view(view)];
+            unsafe {
+                device.update_descriptor_sets(
+                    &[vk::WriteDescriptorSet::default()
+                        .dst_set(set)
+                        .dst_binding(0)
+                        .descriptor_type(vk::DescriptorType::STORAGE_IMAGE)
+                        .image_info(&image_info)],
+                    &[],
+                )
+            };
+        }
+        unsafe { device.destroy_descriptor_set_layout(descriptor_set_layout, None) };
+        Ok(())
+    }
+
+    fn graphics_pipeline(&self) -> Option<&ShaderPipeline> {
+        Some(&self.shader_pipeline)
+    }
+
+    fn graphics_pipeline_mut(&mut self) -> Option<&mut ShaderPipeline<'device>> {
+        Some(&mut self.shader_pipeline)
+    }
+}
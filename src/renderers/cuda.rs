@@ -5,10 +5,14 @@ use std::{
     ffi::{c_void, CStr},
     mem::MaybeUninit,
     ptr::null,
+    rc::Rc,
     time::Instant,
 };
 
-use super::{RenderStyle, Renderer};
+use crate::gpu_timer::GpuTimer;
+use crate::sampler::{SamplerCache, SamplerOptions};
+
+use super::{div_up, is_empty_extent, RenderStyle, Renderer};
 
 pub struct Cuda<'device> {
     module: vk::CuModuleNVX,
@@ -18,6 +22,8 @@ pub struct Cuda<'device> {
     surface_format: vk::SurfaceFormatKHR,
     size: vk::Extent2D,
     sampler: vk::Sampler,
+    /// Owns `sampler`
+    _samplers: Rc<SamplerCache<'device>>,
     device: &'device ash::Device,
 }
 
@@ -35,19 +41,18 @@ impl Drop for Cuda<'_> {
     fn drop(&mut self) {
         unsafe {
             let _ = self.device.device_wait_idle();
-            self.device.destroy_sampler(self.sampler, None);
             (self.nvx_ext.destroy_cu_function_nvx)(self.device.handle(), self.function, null());
             (self.nvx_ext.destroy_cu_module_nvx)(self.device.handle(), self.module, null());
         }
     }
 }
 
-fn div_up(x: u32, y: u32) -> u32 {
-    (x + y - 1) / y
-}
-
 impl<'device> Cuda<'device> {
-    pub fn new(instance: &ash::Instance, device: &'device ash::Device) -> anyhow::Result<Self> {
+    pub fn new(
+        instance: &ash::Instance,
+        device: &'device ash::Device,
+        samplers: &Rc<SamplerCache<'device>>,
+    ) -> anyhow::Result<Self> {
         let nvx_ext = vk::NvxBinaryImportFn::load(|name| unsafe {
             std::mem::transmute(instance.get_device_proc_addr(device.handle(), name.as_ptr()))
         });
@@ -56,6 +61,7 @@ impl<'device> Cuda<'device> {
             std::mem::transmute(instance.get_device_proc_addr(device.handle(), name.as_ptr()))
         });
 
+        let sampler = samplers.get(SamplerOptions::nearest())?;
         let vec: Vec<u8> = include_bytes!("../../shaders/simple_cuda.cu.ptx").to_vec();
 
         let module = unsafe {
@@ -83,8 +89,6 @@ impl<'device> Cuda<'device> {
             .context("Failed to load CUDA function")?
         };
 
-        let sampler = unsafe { device.create_sampler(&vk::SamplerCreateInfo::default(), None)? };
-
         Ok(Self {
             module,
             function,
@@ -97,6 +101,7 @@ impl<'device> Cuda<'device> {
                 height: 0,
             },
             sampler,
+            _samplers: Rc::clone(samplers),
         })
     }
 }
@@ -109,18 +114,24 @@ impl<'device> Renderer<'device> for Cuda<'device> {
         image: vk::Image,
         start_instant: Instant,
         _swapchain_idx: usize,
+        _timer: GpuTimer,
     ) -> anyhow::Result<()> {
         trace!("Draw!");
+        if is_empty_extent(self.size) {
+            return Ok(());
+        }
 
         unsafe {
+            // Wait for renderers composed before this one
             device.cmd_pipeline_barrier(
                 cmd,
-                vk::PipelineStageFlags::TRANSFER,
+                vk::PipelineStageFlags::ALL_COMMANDS,
                 vk::PipelineStageFlags::TRANSFER,
                 vk::DependencyFlags::default(),
                 &[],
                 &[],
                 &[vk::ImageMemoryBarrier::default()
+                    .src_access_mask(vk::AccessFlags::MEMORY_WRITE)
                     .dst_access_mask(vk::AccessFlags::TRANSFER_WRITE)
                     .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
                     .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
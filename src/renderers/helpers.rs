@@ -0,0 +1,561 @@
+use std::{
+    mem::{size_of, size_of_val},
+    rc::Rc,
+    time::Instant,
+};
+
+use ash::vk;
+use cgmath::{Point3, Transform};
+use log::{debug, trace};
+
+use crate::{
+    device_mesh::{Buffer, DeviceMesh},
+    gpu_timer::GpuTimer,
+    mesh::bounding_box_corners,
+    shader::ShaderPipeline,
+    uniforms::{Camera, CameraBuffer, CameraUniforms},
+};
+
+use super::{
+    check_swapchain_idx, create_per_image, is_empty_extent, raster::create_attachment_image,
+    RenderStyle, Renderer,
+};
+
+/// Number of lines of the ground grid in each direction
+const GRID_LINES: usize = 11;
+const BOUNDING_BOX_COLOR: [f32; 3] = [1.0, 0.8, 0.2];
+const GRID_COLOR: [f32; 3] = [0.5, 0.5, 0.5];
+/// Width in pixels of the line between renderers compared side by side
+const DIVIDER_WIDTH: u32 = 2;
+const DIVIDER_COLOR: [f32; 4] = [1.0, 1.0, 1.0, 1.0];
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+struct LineVertex {
+    position: [f32; 3],
+    color: [f32; 3],
+}
+
+/// Draws the bounding box of all meshes and a ground grid below them on top of the image of
+/// another renderer, optionally with a divider between renderers compared side by side
+pub struct Helpers<'device> {
+    meshes: Vec<Rc<DeviceMesh<'device>>>,
+    /// Vertices of a line list, `None` without meshes
+    lines: Option<Buffer<'device>>,
+    num_line_vertices: usize,
+    viewports: Vec<vk::Viewport>,
+    scissors: Vec<vk::Rect2D>,
+    image_views: Vec<vk::ImageView>,
+    framebuffers: Vec<vk::Framebuffer>,
+    device: &'device ash::Device,
+    pipeline_cache: vk::PipelineCache,
+    renderpass: Option<vk::RenderPass>,
+    shader_pipeline: ShaderPipeline<'device>,
+    pipeline: Option<vk::Pipeline>,
+    pipeline_layout: Option<vk::PipelineLayout>,
+    resolution: vk::Rect2D,
+    depth_image: vk::Image,
+    depth_image_view: vk::ImageView,
+    depth_image_memory: vk::DeviceMemory,
+    camera_buffer: Option<CameraBuffer<'device>>,
+    descriptor_set_layout: vk::DescriptorSetLayout,
+    descriptor_pool: Option<vk::DescriptorPool>,
+    /// Binds the camera buffer
+    descriptor_set: Option<vk::DescriptorSet>,
+    size: vk::Extent2D,
+    camera: Camera,
+    /// Whether to draw the bounding box and the grid
+    show_lines: bool,
+    /// Position of a vertical divider line as a fraction of the image width
+    divider: Option<f32>,
+}
+
+impl<'device> Helpers<'device> {
+    pub fn new(
+        device: &'device ash::Device,
+        pipeline_cache: vk::PipelineCache,
+    ) -> anyhow::Result<Self> {
+        let mut shader_pipeline = ShaderPipeline::new(
+            device,
+            &[
+                &include_bytes!("../../shaders/lines.vert.spirv")[..],
+                &include_bytes!("../../shaders/lines.frag.spirv")[..],
+            ],
+        )?;
+        shader_pipeline.set_topology(vk::PrimitiveTopology::LINE_LIST);
+        shader_pipeline.set_overlay(true);
+        let descriptor_set_layout = unsafe {
+            device.create_descriptor_set_layout(
+                &vk::DescriptorSetLayoutCreateInfo::default().bindings(&[
+                    vk::DescriptorSetLayoutBinding::default()
+                        .binding(0)
+                        .descriptor_count(1)
+                        .descriptor_type(vk::DescriptorType::UNIFORM_BUFFER)
+                        .stage_flags(vk::ShaderStageFlags::VERTEX),
+                ]),
+                None,
+            )
+        }?;
+        Ok(Self {
+            meshes: Vec::new(),
+            lines: None,
+            num_line_vertices: 0,
+            viewports: Default::default(),
+            scissors: Default::default(),
+            image_views: Default::default(),
+            framebuffers: Default::default(),
+            device,
+            pipeline_cache,
+            renderpass: Default::default(),
+            shader_pipeline,
+            pipeline: Default::default(),
+            pipeline_layout: Default::default(),
+            resolution: Default::default(),
+            depth_image: Default::default(),
+            depth_image_view: Default::default(),
+            depth_image_memory: Default::default(),
+            camera_buffer: None,
+            descriptor_set_layout,
+            descriptor_pool: None,
+            descriptor_set: None,
+            size: vk::Extent2D {
+                width: 0,
+                height: 0,
+            },
+            camera: Camera::new(Point3::new(0.0, 0.0, 0.0)),
+            show_lines: true,
+            divider: None,
+        })
+    }
+
+    /// Whether to draw the bounding box and the grid, on by default
+    pub fn set_show_lines(&mut self, show_lines: bool) {
+        self.show_lines = show_lines;
+    }
+
+    /// Draw a vertical line at `divider` times the image width, e.g. at [`super::Region::right`]
+    /// of the left one of two renderers compared side by side
+    pub fn set_divider(&mut self, divider: Option<f32>) {
+        self.divider = divider;
+    }
+
+    fn destroy_images(&mut self) {
+        unsafe {
+            let device = self.device;
+            let _ = device.device_wait_idle();
+            device.destroy_image(self.depth_image, None);
+            device.destroy_image_view(self.depth_image_view, None);
+            device.free_memory(self.depth_image_memory, None);
+            for img in self.image_views.drain(..) {
+                device.destroy_image_view(img, None);
+            }
+            for framebuffer in self.framebuffers.drain(..) {
+                device.destroy_framebuffer(framebuffer, None);
+            }
+        }
+
+        if let Some(p) = self.pipeline.take() {
+            unsafe { self.device.destroy_pipeline(p, None) };
+        }
+        if let Some(p) = self.pipeline_layout.take() {
+            unsafe { self.device.destroy_pipeline_layout(p, None) };
+        }
+        if let Some(p) = self.renderpass.take() {
+            unsafe { self.device.destroy_render_pass(p, None) };
+        }
+    }
+
+    /// Create the camera buffer and the descriptor set binding it
+    fn create_camera_buffer(
+        &mut self,
+        device_memory_properties: &vk::PhysicalDeviceMemoryProperties,
+    ) -> anyhow::Result<()> {
+        let device = self.device;
+        let camera_buffer = CameraBuffer::new(
+            device,
+            device_memory_properties,
+            vk::PipelineStageFlags::VERTEX_SHADER,
+        )?;
+        let descriptor_pool = unsafe {
+            device.create_descriptor_pool(
+                &vk::DescriptorPoolCreateInfo::default()
+                    .pool_sizes(&[vk::DescriptorPoolSize {
+                        ty: vk::DescriptorType::UNIFORM_BUFFER,
+                        descriptor_count: 1,
+                    }])
+                    .max_sets(1),
+                None,
+            )
+        }?;
+        self.descriptor_pool = Some(descriptor_pool);
+        let descriptor_set = unsafe {
+            device.allocate_descriptor_sets(
+                &vk::DescriptorSetAllocateInfo::default()
+                    .descriptor_pool(descriptor_pool)
+                    .set_layouts(&[self.descriptor_set_layout]),
+            )
+        }?[0];
+        let camera_info = [camera_buffer.descriptor_buffer_info()];
+        unsafe {
+            device.update_descriptor_sets(
+                &[vk::WriteDescriptorSet::default()
+                    .dst_set(descriptor_set)
+                    .dst_binding(0)
+                    .descriptor_type(vk::DescriptorType::UNIFORM_BUFFER)
+                    .buffer_info(&camera_info)],
+                &[],
+            )
+        };
+        self.descriptor_set = Some(descriptor_set);
+        self.camera_buffer = Some(camera_buffer);
+        Ok(())
+    }
+}
+
+impl std::fmt::Debug for Helpers<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Helpers")
+            .field("image_views", &self.image_views)
+            .field("framebuffers", &self.framebuffers)
+            .finish()
+    }
+}
+
+/// World space bounding box edges of all meshes followed by a grid on the plane below them
+fn helper_lines(meshes: &[Rc<DeviceMesh>]) -> Option<Vec<LineVertex>> {
+    let (min, max) = meshes
+        .iter()
+        .filter(|mesh| mesh.num_vertices() > 0)
+        .flat_map(|mesh| {
+            let (min, max) = mesh.mesh().bounding_box();
+            let transform = mesh.transform();
+            bounding_box_corners(min, max).map(move |p| transform.transform_point(p))
+        })
+        .fold(None, |bounds, p| {
+            let (min, max) = bounds.unwrap_or((p, p));
+            Some((
+                Point3::new(min.x.min(p.x), min.y.min(p.y), min.z.min(p.z)),
+                Point3::new(max.x.max(p.x), max.y.max(p.y), max.z.max(p.z)),
+            ))
+        })?;
+    let corners: Vec<_> = bounding_box_corners(min, max).collect();
+    let mut vertices = Vec::new();
+    // Corners differing in exactly one bit of their index share an edge
+    for i in 0..8 {
+        for bit in [1, 2, 4] {
+            if i & bit == 0 {
+                for corner in [corners[i], corners[i | bit]] {
+                    vertices.push(LineVertex {
+                        position: corner.into(),
+                        color: BOUNDING_BOX_COLOR,
+                    });
+                }
+            }
+        }
+    }
+
+    // Square grid twice as large as the footprint of the bounding box
+    let half_size = (max.x - min.x).max(max.z - min.z).max(f32::EPSILON);
+    let (center_x, center_z) = (0.5 * (min.x + max.x), 0.5 * (min.z + max.z));
+    for i in 0..GRID_LINES {
+        let offset = -half_size + 2.0 * half_size * i as f32 / (GRID_LINES - 1) as f32;
+        for position in [
+            [center_x + offset, min.y, center_z - half_size],
+            [center_x + offset, min.y, center_z + half_size],
+            [center_x - half_size, min.y, center_z + offset],
+            [center_x + half_size, min.y, center_z + offset],
+        ] {
+            vertices.push(LineVertex {
+                position,
+                color: GRID_COLOR,
+            });
+        }
+    }
+    Some(vertices)
+}
+
+impl<'device> Renderer<'device> for Helpers<'device> {
+    fn draw(
+        &self,
+        device: &ash::Device,
+        cmd: vk::CommandBuffer,
+        _image: vk::Image,
+        _start_instant: Instant,
+        swapchain_idx: usize,
+        _timer: GpuTimer,
+    ) -> anyhow::Result<()> {
+        trace!("draw for {self:?}");
+        let lines = self.lines.as_ref().filter(|_| self.show_lines);
+        let (pipeline, camera_buffer, descriptor_set) = match (
+            self.pipeline,
+            self.camera_buffer.as_ref(),
+            self.descriptor_set,
+        ) {
+            (Some(pipeline), Some(camera_buffer), Some(descriptor_set))
+                if lines.is_some() || self.divider.is_some() =>
+            {
+                (pipeline, camera_buffer, descriptor_set)
+            }
+            _ => return Ok(()),
+        };
+        camera_buffer.cmd_update(device, cmd, CameraUniforms::new(self.size, &self.camera));
+        // The color attachment is loaded, only the depth attachment is cleared
+        let clear_values = [
+            vk::ClearValue::default(),
+            vk::ClearValue {
+                depth_stencil: vk::ClearDepthStencilValue {
+                    depth: 1.0,
+                    stencil: 0,
+                },
+            },
+        ];
+        check_swapchain_idx(swapchain_idx, self.framebuffers.len())?;
+        let render_pass_begin_info = vk::RenderPassBeginInfo::default()
+            .render_pass(
+                self.renderpass
+                    .ok_or_else(|| anyhow::anyhow!("No renderpass created"))?,
+            )
+            .framebuffer(self.framebuffers[swapchain_idx])
+            .render_area(self.resolution)
+            .clear_values(&clear_values);
+        unsafe {
+            device.cmd_begin_render_pass(cmd, &render_pass_begin_info, vk::SubpassContents::INLINE);
+            if let Some(lines) = lines {
+                device.cmd_bind_pipeline(cmd, vk::PipelineBindPoint::GRAPHICS, pipeline);
+                device.cmd_set_viewport(cmd, 0, &self.viewports);
+                device.cmd_set_scissor(cmd, 0, &self.scissors);
+                device.cmd_bind_descriptor_sets(
+                    cmd,
+                    vk::PipelineBindPoint::GRAPHICS,
+                    self.pipeline_layout.unwrap(),
+                    0,
+                    &[descriptor_set],
+                    &[],
+                );
+                device.cmd_bind_vertex_buffers(cmd, 0, &[lines.buffer()], &[0]);
+                device.cmd_draw(cmd, self.num_line_vertices as u32, 1, 0, 0);
+            }
+            if let Some(divider) = self.divider {
+                let width = DIVIDER_WIDTH.min(self.size.width);
+                let x = (divider.clamp(0.0, 1.0) * self.size.width as f32).round() as u32;
+                let left = x.saturating_sub(width / 2).min(self.size.width - width);
+                device.cmd_clear_attachments(
+                    cmd,
+                    &[vk::ClearAttachment {
+                        aspect_mask: vk::ImageAspectFlags::COLOR,
+                        color_attachment: 0,
+                        clear_value: vk::ClearValue {
+                            color: vk::ClearColorValue {
+                                float32: DIVIDER_COLOR,
+                            },
+                        },
+                    }],
+                    &[vk::ClearRect {
+                        rect: vk::Rect2D {
+                            offset: vk::Offset2D {
+                                x: left as i32,
+                                y: 0,
+                            },
+                            extent: vk::Extent2D {
+                                width,
+                                height: self.size.height,
+                            },
+                        },
+                        base_array_layer: 0,
+                        layer_count: 1,
+                    }],
+                );
+            }
+            device.cmd_end_render_pass(cmd);
+        }
+        Ok(())
+    }
+
+    fn set_meshes(
+        &mut self,
+        meshes: &[Rc<DeviceMesh<'device>>],
+        _cmd: vk::CommandBuffer,
+        _graphics_queue: vk::Queue,
+        device_memory_properties: &vk::PhysicalDeviceMemoryProperties,
+    ) -> anyhow::Result<()> {
+        self.meshes = meshes.to_vec();
+        let vertices = helper_lines(meshes).unwrap_or_default();
+        self.num_line_vertices = vertices.len();
+        self.lines = if vertices.is_empty() {
+            None
+        } else {
+            Some(Buffer::new(
+                self.device,
+                device_memory_properties,
+                &vk::BufferCreateInfo::default()
+                    .size(size_of_val(vertices.as_slice()) as vk::DeviceSize)
+                    .usage(vk::BufferUsageFlags::VERTEX_BUFFER)
+                    .sharing_mode(vk::SharingMode::EXCLUSIVE),
+                Some(&vertices),
+            )?)
+        };
+        Ok(())
+    }
+
+    fn update_transforms(
+        &mut self,
+        _cmd: vk::CommandBuffer,
+        _graphics_queue: vk::Queue,
+    ) -> anyhow::Result<()> {
+        if let (Some(lines), Some(vertices)) = (self.lines.as_ref(), helper_lines(&self.meshes)) {
+            // Previous frames might still draw the old lines
+            unsafe { self.device.device_wait_idle()? };
+            lines.write(&vertices)?;
+        }
+        Ok(())
+    }
+
+    fn set_resolution(
+        &mut self,
+        surface_format: ash::vk::SurfaceFormatKHR,
+        size: vk::Extent2D,
+        images: &[vk::Image],
+        device_memory_properties: &vk::PhysicalDeviceMemoryProperties,
+        render_style: RenderStyle,
+    ) -> anyhow::Result<()> {
+        let device = self.device;
+        debug!("Set resolution: {size:?} images: {images:?}");
+        self.destroy_images();
+        self.size = size;
+        if is_empty_extent(size) {
+            return Ok(());
+        }
+        if self.camera_buffer.is_none() {
+            self.create_camera_buffer(device_memory_properties)?;
+        }
+
+        self.viewports = vec![vk::Viewport {
+            x: 0.0,
+            y: 0.0,
+            width: size.width as f32,
+            height: size.height as f32,
+            min_depth: 0.0,
+            max_depth: 1.0,
+        }];
+        self.scissors = vec![size.into()];
+        let vertex_attribute_desc = [
+            vk::VertexInputAttributeDescription {
+                location: 0,
+                binding: 0,
+                format: vk::Format::R32G32B32_SFLOAT,
+                offset: 0,
+            },
+            vk::VertexInputAttributeDescription {
+                location: 1,
+                binding: 0,
+                format: vk::Format::R32G32B32_SFLOAT,
+                offset: size_of::<[f32; 3]>() as u32,
+            },
+        ];
+        let vertex_binding_desc = [vk::VertexInputBindingDescription {
+            binding: 0,
+            stride: size_of::<LineVertex>() as u32,
+            input_rate: vk::VertexInputRate::VERTEX,
+        }];
+        let (pipeline, renderpass, pipeline_layout) = self.shader_pipeline.make_graphics_pipeline(
+            device,
+            self.pipeline_cache,
+            &self.scissors,
+            &self.viewports,
+            surface_format.format,
+            &vertex_attribute_desc,
+            &vertex_binding_desc,
+            &[self.descriptor_set_layout],
+            &[],
+            render_style,
+            vk::SampleCountFlags::TYPE_1,
+        )?;
+        self.renderpass = Some(renderpass);
+        self.pipeline = Some(pipeline);
+        self.pipeline_layout = Some(pipeline_layout);
+        self.image_views = create_per_image(
+            images,
+            |&image| {
+                let create_view_info = vk::ImageViewCreateInfo::default()
+                    .view_type(vk::ImageViewType::TYPE_2D)
+                    .format(surface_format.format)
+                    .subresource_range(vk::ImageSubresourceRange {
+                        aspect_mask: vk::ImageAspectFlags::COLOR,
+                        base_mip_level: 0,
+                        level_count: 1,
+                        base_array_layer: 0,
+                        layer_count: 1,
+                    })
+                    .image(image);
+                unsafe {
+                    device
+                        .create_image_view(&create_view_info, None)
+                        .map_err(|err| anyhow::anyhow!("Failed to create image view: {err}"))
+                }
+            },
+            |view| unsafe { device.destroy_image_view(view, None) },
+        )?;
+
+        (
+            self.depth_image,
+            self.depth_image_memory,
+            self.depth_image_view,
+        ) = create_attachment_image(
+            device,
+            device_memory_properties,
+            size,
+            self.shader_pipeline.depth_format(),
+            vk::SampleCountFlags::TYPE_1,
+            vk::ImageUsageFlags::DEPTH_STENCIL_ATTACHMENT,
+            vk::ImageAspectFlags::DEPTH,
+        )?;
+
+        self.framebuffers = create_per_image(
+            &self.image_views,
+            |&view| {
+                let framebuffer_attachments = [view, self.depth_image_view];
+                let frame_buffer_create_info = vk::FramebufferCreateInfo::default()
+                    .render_pass(renderpass)
+                    .attachments(&framebuffer_attachments)
+                    .width(size.width)
+                    .height(size.height)
+                    .layers(1);
+
+                unsafe {
+                    device
+                        .create_framebuffer(&frame_buffer_create_info, None)
+                        .map_err(|err| anyhow::anyhow!("Failed to create framebuffer: {err}"))
+                }
+            },
+            |framebuffer| unsafe { device.destroy_framebuffer(framebuffer, None) },
+        )?;
+
+        self.resolution = size.into();
+        Ok(())
+    }
+
+    fn graphics_pipeline(&self) -> Option<&ShaderPipeline> {
+        Some(&self.shader_pipeline)
+    }
+
+    fn graphics_pipeline_mut(&mut self) -> Option<&mut ShaderPipeline<'device>> {
+        Some(&mut self.shader_pipeline)
+    }
+
+    fn set_camera(&mut self, camera: &Camera) {
+        self.camera = *camera;
+    }
+}
+
+impl Drop for Helpers<'_> {
+    fn drop(&mut self) {
+        self.destroy_images();
+        unsafe {
+            if let Some(pool) = self.descriptor_pool.take() {
+                self.device.destroy_descriptor_pool(pool, None);
+            }
+            self.device
+                .destroy_descriptor_set_layout(self.descriptor_set_layout, None);
+        }
+    }
+}
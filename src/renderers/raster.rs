@@ -1,16 +1,47 @@
 use crate::mesh::{Normal, Position};
-use std::{mem::size_of, mem::transmute, rc::Rc, time::Instant};
+use std::{mem::size_of, mem::transmute, path::Path, rc::Rc, time::Instant};
 
+use anyhow::Context;
 use ash::vk::{self, ShaderStageFlags};
-use cgmath::{Point3, Vector3, Vector4};
-use log::{debug, trace};
-use winit::event::WindowEvent;
+use cgmath::{Matrix4, Point3};
+use log::{debug, info, trace, warn};
 
-use crate::{device_mesh::DeviceMesh, shader::ShaderPipeline, uniforms::PushConstants};
+use crate::{
+    device_mesh::{Buffer, DeviceMesh},
+    gpu_timer::GpuTimer,
+    sampler::SamplerCache,
+    shader::{
+        specialization_constant, ShaderPipeline, ENCODE_SRGB_CONSTANT_ID, NORMAL_ATTACHMENT_FORMAT,
+        POINTS_CONSTANT_ID, WRITE_NORMALS_CONSTANT_ID,
+    },
+    texture::Texture,
+    uniforms::{Background, Camera, CameraBuffer, CameraUniforms, Lights},
+};
 
-use super::{RenderStyle, Renderer};
+use super::{
+    check_swapchain_idx, create_per_image, div_up, is_empty_extent, needs_srgb_encoding, Region,
+    RenderStyle, Renderer,
+};
 
-pub fn find_memorytype_index(
+/// Offset of the base color push constant of `triangle.frag`, behind the model matrix and point
+/// size of `triangle.vert` rounded up to the alignment of a `vec4`
+const BASE_COLOR_PUSH_CONSTANT_OFFSET: u32 = 80;
+/// Workgroup size of `ssao.comp` in x and y
+const SSAO_WORKGROUP_SIZE: u32 = 8;
+/// Radius of the sampled hemisphere relative to the camera distance
+const SSAO_RELATIVE_RADIUS: f32 = 0.05;
+/// Workgroup size of `depth.comp` in x and y
+const DEPTH_VIEW_WORKGROUP_SIZE: u32 = 8;
+
+const COLOR_SUBRESOURCE_RANGE: vk::ImageSubresourceRange = vk::ImageSubresourceRange {
+    aspect_mask: vk::ImageAspectFlags::COLOR,
+    base_mip_level: 0,
+    level_count: 1,
+    base_array_layer: 0,
+    layer_count: 1,
+};
+
+pub(crate) fn find_memorytype_index(
     memory_req: &vk::MemoryRequirements,
     memory_prop: &vk::PhysicalDeviceMemoryProperties,
     flags: vk::MemoryPropertyFlags,
@@ -27,37 +58,107 @@ pub fn find_memorytype_index(
 
 pub struct Raster<'device> {
     meshes: Vec<Rc<DeviceMesh<'device>>>,
+    /// Zero texture coordinates bound for meshes without any
+    default_uvs: Option<Buffer<'device>>,
+    /// White vertex colors bound for meshes without any
+    default_colors: Option<Buffer<'device>>,
+    /// Zero normals bound for point clouds without any
+    default_normals: Option<Buffer<'device>>,
+    default_texture: Option<Texture<'device>>,
+    samplers: Rc<SamplerCache<'device>>,
+    descriptor_set_layout: vk::DescriptorSetLayout,
+    descriptor_pool: Option<vk::DescriptorPool>,
+    /// Base color texture of each mesh and the lights
+    descriptor_sets: Vec<vk::DescriptorSet>,
+    lights: Lights,
+    lights_buffer: Option<Buffer<'device>>,
+    background: Background,
     viewports: Vec<vk::Viewport>,
     scissors: Vec<vk::Rect2D>,
     image_views: Vec<vk::ImageView>,
     framebuffers: Vec<vk::Framebuffer>,
     device: &'device ash::Device,
+    pipeline_cache: vk::PipelineCache,
     renderpass: Option<vk::RenderPass>,
     shader_pipeline: ShaderPipeline<'device>,
     pipeline: Option<vk::Pipeline>,
+    /// Depth-only pipeline of the first subpass, `None` without depth prepass
+    prepass_pipeline: Option<vk::Pipeline>,
     pipeline_layout: Option<vk::PipelineLayout>,
     resolution: vk::Rect2D,
     depth_image: vk::Image,
     depth_image_view: vk::ImageView,
     depth_image_memory: vk::DeviceMemory,
-    uniforms: Option<PushConstants>,
+    /// Multisampled color attachment, null without MSAA
+    msaa_image: vk::Image,
+    msaa_image_view: vk::ImageView,
+    msaa_image_memory: vk::DeviceMemory,
+    samples: vk::SampleCountFlags,
+    camera_buffer: Option<CameraBuffer<'device>>,
     size: vk::Extent2D,
-    zoom: f32,
-    rotation: f32,
-    translation: Point3<f32>,
-    middle_drag: bool,
+    camera: Camera,
+    /// Whether to darken the shaded image with screen-space ambient occlusion
+    ssao_enabled: bool,
+    /// Created by `set_resolution` when SSAO is enabled and the render style shades the meshes
+    ssao: Option<Ssao<'device>>,
+    /// Created by `set_resolution` for the depth render style
+    depth_view: Option<DepthView<'device>>,
+    /// Whether to draw the depth of all meshes before shading them
+    depth_prepass: bool,
+    /// Whether to draw the vertices of all meshes as points
+    points: bool,
+    /// Diameter of points in pixels
+    point_size: f32,
+    /// Part of the swapchain images drawn to, `resolution` covers it
+    region: Region,
 }
 
 impl<'device> Raster<'device> {
-    pub fn new(device: &'device ash::Device) -> anyhow::Result<Self> {
+    pub fn new(
+        device: &'device ash::Device,
+        pipeline_cache: vk::PipelineCache,
+    ) -> anyhow::Result<Self> {
+        let descriptor_set_layout = unsafe {
+            device.create_descriptor_set_layout(
+                &vk::DescriptorSetLayoutCreateInfo::default().bindings(&[
+                    vk::DescriptorSetLayoutBinding::default()
+                        .binding(0)
+                        .descriptor_count(1)
+                        .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+                        .stage_flags(vk::ShaderStageFlags::FRAGMENT),
+                    vk::DescriptorSetLayoutBinding::default()
+                        .binding(1)
+                        .descriptor_count(1)
+                        .descriptor_type(vk::DescriptorType::UNIFORM_BUFFER)
+                        .stage_flags(vk::ShaderStageFlags::FRAGMENT),
+                    vk::DescriptorSetLayoutBinding::default()
+                        .binding(2)
+                        .descriptor_count(1)
+                        .descriptor_type(vk::DescriptorType::UNIFORM_BUFFER)
+                        .stage_flags(vk::ShaderStageFlags::VERTEX),
+                ]),
+                None,
+            )
+        }?;
         Ok(Self {
-            zoom: 1.0,
             meshes: Default::default(),
+            default_uvs: None,
+            default_colors: None,
+            default_normals: None,
+            default_texture: None,
+            samplers: Rc::new(SamplerCache::new(device, 1.0)),
+            descriptor_set_layout,
+            descriptor_pool: None,
+            descriptor_sets: Vec::new(),
+            lights: Lights::default(),
+            lights_buffer: None,
+            background: Background::default(),
             viewports: Default::default(),
             scissors: Default::default(),
             image_views: Default::default(),
             framebuffers: Default::default(),
             device,
+            pipeline_cache,
             renderpass: Default::default(),
             shader_pipeline: ShaderPipeline::new(
                 device,
@@ -66,26 +167,242 @@ impl<'device> Raster<'device> {
                     &include_bytes!("../../shaders/triangle.frag.spirv")[..],
                 ],
             )?,
-            translation: Point3 {
-                x: 0.0,
-                y: 0.0,
-                z: 0.0,
-            },
+            camera: Camera::new(Point3::new(0.0, 0.0, 0.0)),
             pipeline: Default::default(),
+            prepass_pipeline: None,
             pipeline_layout: Default::default(),
             resolution: Default::default(),
             depth_image: Default::default(),
             depth_image_view: Default::default(),
             depth_image_memory: Default::default(),
-            uniforms: None,
+            msaa_image: Default::default(),
+            msaa_image_view: Default::default(),
+            msaa_image_memory: Default::default(),
+            samples: vk::SampleCountFlags::TYPE_1,
+            camera_buffer: None,
             size: vk::Extent2D {
                 width: 0,
                 height: 0,
             },
-            rotation: 0.0,
-            middle_drag: false,
+            ssao_enabled: false,
+            ssao: None,
+            depth_view: None,
+            depth_prepass: false,
+            points: false,
+            point_size: 1.0,
+            region: Region::default(),
         })
     }
+
+    /// Set the clear color. Gradients are only supported by ray tracing, the rasterizer uses
+    /// `background.color`.
+    #[must_use]
+    pub fn with_background(mut self, background: Background) -> Self {
+        self.background = background;
+        self
+    }
+
+    /// Set the number of samples per pixel. Must be supported for color and depth attachments.
+    /// Takes effect on the next `set_resolution`.
+    #[must_use]
+    pub fn with_msaa(mut self, samples: vk::SampleCountFlags) -> Self {
+        self.samples = samples;
+        self
+    }
+
+    /// Multiply screen-space ambient occlusion into the shaded image. Not supported with MSAA.
+    /// Takes effect on the next `set_resolution`.
+    #[must_use]
+    pub fn with_ssao(mut self, ssao: bool) -> Self {
+        self.ssao_enabled = ssao;
+        self
+    }
+
+    /// Draw the depth of all meshes in a first subpass so that the second one shades every pixel
+    /// only once. Takes effect on the next `set_resolution`.
+    #[must_use]
+    pub fn with_depth_prepass(mut self, depth_prepass: bool) -> Self {
+        self.depth_prepass = depth_prepass;
+        self
+    }
+
+    /// Set the format of the depth buffer, see [`crate::vulkan_app::VulkanApp::depth_format`].
+    /// Takes effect on the next `set_resolution`.
+    #[must_use]
+    pub fn with_depth_format(mut self, depth_format: vk::Format) -> Self {
+        self.shader_pipeline.set_depth_format(depth_format);
+        self
+    }
+
+    /// Share samplers with other renderers and textures
+    #[must_use]
+    pub fn with_samplers(mut self, samplers: Rc<SamplerCache<'device>>) -> Self {
+        self.samplers = samplers;
+        self
+    }
+
+    /// Draw the vertices of all meshes as unlit points instead of triangles. Done anyway when all
+    /// meshes are point clouds, which are skipped otherwise. Takes effect on the next
+    /// `set_resolution`.
+    #[must_use]
+    pub fn with_points(mut self, points: bool) -> Self {
+        self.points = points;
+        self
+    }
+
+    /// Whether the current pipeline draws points
+    fn draws_points(&self) -> bool {
+        self.points
+            || (!self.meshes.is_empty() && self.meshes.iter().all(|m| m.mesh().is_point_cloud()))
+    }
+
+    /// Save the depth buffer of the last frame as grayscale PNG. The depth of the drawn meshes is
+    /// stretched to the full range, pixels without mesh are white. Not supported with MSAA.
+    pub fn capture_depth(
+        &self,
+        cmd: vk::CommandBuffer,
+        graphics_queue: vk::Queue,
+        device_memory_properties: &vk::PhysicalDeviceMemoryProperties,
+        path: &Path,
+    ) -> anyhow::Result<()> {
+        anyhow::ensure!(
+            self.samples == vk::SampleCountFlags::TYPE_1,
+            "Capturing the depth buffer is not supported with MSAA"
+        );
+        anyhow::ensure!(
+            self.depth_image != vk::Image::null(),
+            "No depth buffer to capture"
+        );
+        let device = self.device;
+        let format = self.shader_pipeline.depth_format();
+        let num_pixels = (self.size.width * self.size.height) as usize;
+        // Texels of the depth aspect are tightly packed in buffers, X8_D24 ones take 32 bit
+        let buffer = Buffer::new(
+            device,
+            device_memory_properties,
+            &vk::BufferCreateInfo::default()
+                .size((num_pixels * size_of::<u32>()) as vk::DeviceSize)
+                .usage(vk::BufferUsageFlags::TRANSFER_DST)
+                .sharing_mode(vk::SharingMode::EXCLUSIVE),
+            Some(&vec![0u32; num_pixels]),
+        )?;
+        // Final layout of the depth attachment of the render pass
+        let layout = if self.ssao.is_some() || self.depth_view.is_some() {
+            vk::ImageLayout::DEPTH_STENCIL_READ_ONLY_OPTIMAL
+        } else {
+            vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL
+        };
+        let subresource_range = vk::ImageSubresourceRange::default()
+            .aspect_mask(vk::ImageAspectFlags::DEPTH)
+            .level_count(1)
+            .layer_count(1);
+        unsafe {
+            device.device_wait_idle()?;
+            device.begin_command_buffer(
+                cmd,
+                &vk::CommandBufferBeginInfo::default()
+                    .flags(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT),
+            )?;
+            device.cmd_pipeline_barrier(
+                cmd,
+                vk::PipelineStageFlags::LATE_FRAGMENT_TESTS,
+                vk::PipelineStageFlags::TRANSFER,
+                vk::DependencyFlags::empty(),
+                &[],
+                &[],
+                &[vk::ImageMemoryBarrier::default()
+                    .src_access_mask(vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE)
+                    .dst_access_mask(vk::AccessFlags::TRANSFER_READ)
+                    .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                    .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                    .old_layout(layout)
+                    .new_layout(vk::ImageLayout::TRANSFER_SRC_OPTIMAL)
+                    .image(self.depth_image)
+                    .subresource_range(subresource_range)],
+            );
+            device.cmd_copy_image_to_buffer(
+                cmd,
+                self.depth_image,
+                vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+                buffer.buffer(),
+                &[vk::BufferImageCopy::default()
+                    .image_subresource(vk::ImageSubresourceLayers {
+                        aspect_mask: vk::ImageAspectFlags::DEPTH,
+                        mip_level: 0,
+                        base_array_layer: 0,
+                        layer_count: 1,
+                    })
+                    .image_extent(vk::Extent3D {
+                        width: self.size.width,
+                        height: self.size.height,
+                        depth: 1,
+                    })],
+            );
+            device.cmd_pipeline_barrier(
+                cmd,
+                vk::PipelineStageFlags::TRANSFER,
+                vk::PipelineStageFlags::HOST | vk::PipelineStageFlags::EARLY_FRAGMENT_TESTS,
+                vk::DependencyFlags::empty(),
+                &[vk::MemoryBarrier::default()
+                    .src_access_mask(vk::AccessFlags::TRANSFER_WRITE)
+                    .dst_access_mask(vk::AccessFlags::HOST_READ)],
+                &[],
+                &[vk::ImageMemoryBarrier::default()
+                    .src_access_mask(vk::AccessFlags::TRANSFER_READ)
+                    .dst_access_mask(vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE)
+                    .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                    .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                    .old_layout(vk::ImageLayout::TRANSFER_SRC_OPTIMAL)
+                    .new_layout(layout)
+                    .image(self.depth_image)
+                    .subresource_range(subresource_range)],
+            );
+            device.end_command_buffer(cmd)?;
+            device
+                .queue_submit(
+                    graphics_queue,
+                    &[vk::SubmitInfo::default().command_buffers(&[cmd])],
+                    vk::Fence::null(),
+                )
+                .context("queue submit failed.")?;
+            device.queue_wait_idle(graphics_queue)?;
+        }
+
+        let depth: Vec<f32> = match format {
+            vk::Format::D16_UNORM => buffer
+                .read::<u16>(num_pixels)?
+                .into_iter()
+                .map(|d| f32::from(d) / f32::from(u16::MAX))
+                .collect(),
+            vk::Format::X8_D24_UNORM_PACK32 => buffer
+                .read::<u32>(num_pixels)?
+                .into_iter()
+                .map(|d| (d & 0xff_ffff) as f32 / 0xff_ffff as f32)
+                .collect(),
+            vk::Format::D32_SFLOAT => buffer.read::<f32>(num_pixels)?,
+            _ => anyhow::bail!("Capturing depth buffers of format {format:?} is not supported"),
+        };
+        let (min, max) = depth
+            .iter()
+            .filter(|&&d| d < 1.0)
+            .fold((1.0f32, 0.0f32), |(min, max), &d| (min.min(d), max.max(d)));
+        let range = (max - min).max(f32::EPSILON);
+        let pixels = depth
+            .iter()
+            .map(|&d| {
+                if d < 1.0 {
+                    ((d - min) / range * 254.0) as u8
+                } else {
+                    u8::MAX
+                }
+            })
+            .collect();
+        image::GrayImage::from_raw(self.size.width, self.size.height, pixels)
+            .ok_or_else(|| anyhow::anyhow!("Captured depth has unexpected size"))?
+            .save(path)?;
+        info!("Saved {format:?} depth in [{min}, {max}] to {path:?}");
+        Ok(())
+    }
 }
 
 impl std::fmt::Debug for Raster<'_> {
@@ -107,17 +424,26 @@ impl<'device> Raster<'device> {
             device.destroy_image(self.depth_image, None);
             device.destroy_image_view(self.depth_image_view, None);
             device.free_memory(self.depth_image_memory, None);
-            for img in self.image_views.iter() {
-                device.destroy_image_view(*img, None);
+            device.destroy_image(self.msaa_image, None);
+            device.destroy_image_view(self.msaa_image_view, None);
+            device.free_memory(self.msaa_image_memory, None);
+            self.msaa_image = vk::Image::null();
+            self.msaa_image_view = vk::ImageView::null();
+            self.msaa_image_memory = vk::DeviceMemory::null();
+            for img in self.image_views.drain(..) {
+                device.destroy_image_view(img, None);
             }
-            for img in self.framebuffers.iter() {
-                device.destroy_framebuffer(*img, None);
+            for img in self.framebuffers.drain(..) {
+                device.destroy_framebuffer(img, None);
             }
         }
 
         if let Some(p) = self.pipeline.take() {
             unsafe { self.device.destroy_pipeline(p, None) };
         }
+        if let Some(p) = self.prepass_pipeline.take() {
+            unsafe { self.device.destroy_pipeline(p, None) };
+        }
         if let Some(p) = self.pipeline_layout.take() {
             unsafe { self.device.destroy_pipeline_layout(p, None) };
         }
@@ -125,14 +451,87 @@ impl<'device> Raster<'device> {
             unsafe { self.device.destroy_render_pass(p, None) };
         }
     }
-    fn update_push_constants(&mut self) {
-        self.uniforms = Some(PushConstants::new(
-            self.size,
-            self.translation,
-            Vector4::new(2.0, 0.0, 0.0, 1.0),
-            self.zoom,
-            self.rotation,
-        ));
+
+    /// Bind the buffers of every mesh and draw it with the bound pipeline
+    fn cmd_draw_meshes(&self, cmd: vk::CommandBuffer) -> anyhow::Result<()> {
+        unsafe {
+            let device = self.device;
+            let points = self.draws_points();
+            for (mesh, &descriptor_set) in self.meshes.iter().zip(self.descriptor_sets.iter()) {
+                if !points && mesh.mesh().is_point_cloud() {
+                    continue;
+                }
+                device.cmd_bind_descriptor_sets(
+                    cmd,
+                    vk::PipelineBindPoint::GRAPHICS,
+                    self.pipeline_layout.unwrap(),
+                    0,
+                    &[descriptor_set],
+                    &[],
+                );
+                device.cmd_push_constants(
+                    cmd,
+                    self.pipeline_layout.unwrap(),
+                    vk::ShaderStageFlags::VERTEX,
+                    0,
+                    &[
+                        &transmute::<Matrix4<f32>, [u8; size_of::<Matrix4<f32>>()]>(
+                            mesh.transform(),
+                        )[..],
+                        &self.point_size.to_ne_bytes(),
+                    ]
+                    .concat(),
+                );
+                device.cmd_push_constants(
+                    cmd,
+                    self.pipeline_layout.unwrap(),
+                    vk::ShaderStageFlags::FRAGMENT,
+                    BASE_COLOR_PUSH_CONSTANT_OFFSET,
+                    &mesh.base_color().map(f32::to_ne_bytes).concat(),
+                );
+                device.cmd_bind_vertex_buffers(
+                    cmd,
+                    0,
+                    &[
+                        *mesh
+                            .position()
+                            .ok_or_else(|| anyhow::anyhow!("Mesh has no vertex positions"))?,
+                        mesh.normals()
+                            .copied()
+                            .or_else(|| self.default_normals.as_ref().map(Buffer::buffer))
+                            .ok_or_else(|| anyhow::anyhow!("Mesh has no vertex normals"))?,
+                        mesh.uvs()
+                            .copied()
+                            .or_else(|| self.default_uvs.as_ref().map(Buffer::buffer))
+                            .ok_or_else(|| anyhow::anyhow!("Mesh has no texture coordinates"))?,
+                        mesh.colors()
+                            .copied()
+                            .or_else(|| self.default_colors.as_ref().map(Buffer::buffer))
+                            .ok_or_else(|| anyhow::anyhow!("Mesh has no vertex colors"))?,
+                    ],
+                    &[0, 0, 0, 0],
+                );
+                match mesh.indices() {
+                    // Every vertex is drawn once as point, even if triangles share it
+                    Some(&idx_buffer) if !points => {
+                        device.cmd_bind_index_buffer(cmd, idx_buffer, 0, mesh.index_type());
+                        device.cmd_draw_indexed(cmd, mesh.num_triangles() as u32 * 3, 1, 0, 0, 0);
+                    }
+                    _ => device.cmd_draw(cmd, mesh.num_vertices() as u32, 1, 0, 0),
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn destroy_descriptor_sets(&mut self) {
+        if let Some(pool) = self.descriptor_pool.take() {
+            unsafe {
+                let _ = self.device.device_wait_idle();
+                self.device.destroy_descriptor_pool(pool, None);
+            }
+        }
+        self.descriptor_sets.clear();
     }
 }
 
@@ -144,13 +543,14 @@ impl<'device> Renderer<'device> for Raster<'device> {
         _image: vk::Image,
         _start_instant: Instant,
         swapchain_idx: usize,
+        timer: GpuTimer,
     ) -> anyhow::Result<()> {
         trace!("draw for {self:?}");
         if !self.meshes.is_empty() {
             let clear_values = [
                 vk::ClearValue {
                     color: vk::ClearColorValue {
-                        float32: [0.0, 0.0, 0.0, 0.0],
+                        float32: self.background.color.into(),
                     },
                 },
                 vk::ClearValue {
@@ -160,67 +560,67 @@ impl<'device> Renderer<'device> for Raster<'device> {
                     },
                 },
             ];
-            if let Some(pipeline) = self.pipeline {
+            if let (Some(pipeline), Some(camera_buffer)) = (self.pipeline, &self.camera_buffer) {
+                camera_buffer.cmd_update(
+                    self.device,
+                    cmd,
+                    CameraUniforms::new(self.resolution.extent, &self.camera),
+                );
+                check_swapchain_idx(swapchain_idx, self.framebuffers.len())?;
                 let render_pass_begin_info = vk::RenderPassBeginInfo::default()
                     .render_pass(
                         self.renderpass
                             .ok_or_else(|| anyhow::anyhow!("No renderpass created"))?,
                     )
-                    .framebuffer(self.framebuffers[swapchain_idx as usize])
+                    .framebuffer(self.framebuffers[swapchain_idx])
                     .render_area(self.resolution)
                     .clear_values(&clear_values);
                 trace!("{render_pass_begin_info:?}");
+                timer.cmd_begin(self.device, cmd, vk::PipelineStageFlags::TOP_OF_PIPE);
                 unsafe {
                     self.device.cmd_begin_render_pass(
                         cmd,
                         &render_pass_begin_info,
                         vk::SubpassContents::INLINE,
                     );
-                    self.device
-                        .cmd_bind_pipeline(cmd, vk::PipelineBindPoint::GRAPHICS, pipeline);
                     self.device.cmd_set_viewport(cmd, 0, &self.viewports);
                     self.device.cmd_set_scissor(cmd, 0, &self.scissors);
-
-                    self.device.cmd_push_constants(
-                        cmd,
-                        self.pipeline_layout.unwrap(),
-                        vk::ShaderStageFlags::VERTEX,
-                        0,
-                        &transmute::<PushConstants, [u8; size_of::<PushConstants>()]>(
-                            self.uniforms.unwrap(),
-                        ),
-                    );
-                    let device = self.device;
-                    for mesh in self.meshes.iter() {
-                        device.cmd_bind_vertex_buffers(
+                    if let Some(prepass_pipeline) = self.prepass_pipeline {
+                        self.device.cmd_bind_pipeline(
                             cmd,
-                            0,
-                            &[
-                                *mesh.position().ok_or_else(|| {
-                                    anyhow::anyhow!("Mesh has no vertex positions")
-                                })?,
-                                *mesh
-                                    .normals()
-                                    .ok_or_else(|| anyhow::anyhow!("Mesh has no vertex normals"))?,
-                            ],
-                            &[0, 0],
+                            vk::PipelineBindPoint::GRAPHICS,
+                            prepass_pipeline,
                         );
-                        if let Some(&idx_buffer) = mesh.indices() {
-                            device.cmd_bind_index_buffer(cmd, idx_buffer, 0, vk::IndexType::UINT32);
-                            device.cmd_draw_indexed(
-                                cmd,
-                                mesh.num_triangles() as u32 * 3,
-                                1,
-                                0,
-                                0,
-                                1,
-                            );
-                        } else {
-                            device.cmd_draw(cmd, mesh.num_vertices() as u32 * 3, 1, 0, 0);
-                        }
+                        self.cmd_draw_meshes(cmd)?;
+                        self.device
+                            .cmd_next_subpass(cmd, vk::SubpassContents::INLINE);
+                    }
+                    self.device
+                        .cmd_bind_pipeline(cmd, vk::PipelineBindPoint::GRAPHICS, pipeline);
+                    self.cmd_draw_meshes(cmd)?;
+                    self.device.cmd_end_render_pass(cmd);
+                    if let Some(ssao) = &self.ssao {
+                        ssao.cmd_apply(
+                            cmd,
+                            swapchain_idx,
+                            self.camera
+                                .projection(self.size.width as f32 / self.size.height as f32),
+                            SSAO_RELATIVE_RADIUS * self.camera.distance(),
+                        )?;
+                    }
+                    if let Some(depth_view) = &self.depth_view {
+                        depth_view.cmd_apply(
+                            cmd,
+                            swapchain_idx,
+                            self.camera.projection(
+                                self.resolution.extent.width as f32
+                                    / self.resolution.extent.height as f32,
+                            ),
+                            self.resolution,
+                        )?;
                     }
-                    device.cmd_end_render_pass(cmd);
                 }
+                timer.cmd_end(self.device, cmd, vk::PipelineStageFlags::BOTTOM_OF_PIPE);
             }
         }
         Ok(())
@@ -229,29 +629,168 @@ impl<'device> Renderer<'device> for Raster<'device> {
     fn set_meshes(
         &mut self,
         meshes: &[Rc<DeviceMesh<'device>>],
-        _cmd: vk::CommandBuffer,
-        _graphics_queue: vk::Queue,
-        _device_memory_properties: &vk::PhysicalDeviceMemoryProperties,
+        cmd: vk::CommandBuffer,
+        graphics_queue: vk::Queue,
+        device_memory_properties: &vk::PhysicalDeviceMemoryProperties,
     ) -> anyhow::Result<()> {
+        self.destroy_descriptor_sets();
         self.meshes = meshes.to_vec();
-        self.translation = meshes
+        let max_vertices_without_uvs = meshes
             .iter()
-            .flat_map(|mesh| mesh.mesh().positions().iter())
-            .fold(
-                Point3 {
-                    x: 0.0f32,
-                    y: 0.0,
-                    z: 0.0,
-                },
-                |i, &p| {
-                    i + Vector3 {
-                        x: p.x,
-                        y: p.y,
-                        z: p.z,
-                    }
-                },
-            )
-            / meshes.iter().map(|mesh| mesh.num_vertices()).sum::<usize>() as f32;
+            .filter(|m| m.uvs().is_none())
+            .map(|m| m.num_vertices())
+            .max();
+        self.default_uvs = match max_vertices_without_uvs {
+            Some(num_vertices) => Some(Buffer::new(
+                self.device,
+                device_memory_properties,
+                &vk::BufferCreateInfo::default()
+                    .size((2 * size_of::<f32>() * num_vertices) as vk::DeviceSize)
+                    .usage(vk::BufferUsageFlags::VERTEX_BUFFER)
+                    .sharing_mode(vk::SharingMode::EXCLUSIVE),
+                Some(&vec![[0.0f32; 2]; num_vertices]),
+            )?),
+            None => None,
+        };
+        let max_vertices_without_colors = meshes
+            .iter()
+            .filter(|m| m.colors().is_none())
+            .map(|m| m.num_vertices())
+            .max();
+        self.default_colors = match max_vertices_without_colors {
+            Some(num_vertices) => Some(Buffer::new(
+                self.device,
+                device_memory_properties,
+                &vk::BufferCreateInfo::default()
+                    .size((4 * size_of::<f32>() * num_vertices) as vk::DeviceSize)
+                    .usage(vk::BufferUsageFlags::VERTEX_BUFFER)
+                    .sharing_mode(vk::SharingMode::EXCLUSIVE),
+                Some(&vec![[1.0f32; 4]; num_vertices]),
+            )?),
+            None => None,
+        };
+        let max_vertices_without_normals = meshes
+            .iter()
+            .filter(|m| m.normals().is_none())
+            .map(|m| m.num_vertices())
+            .max();
+        self.default_normals = match max_vertices_without_normals {
+            Some(num_vertices) => Some(Buffer::new(
+                self.device,
+                device_memory_properties,
+                &vk::BufferCreateInfo::default()
+                    .size((3 * size_of::<f32>() * num_vertices) as vk::DeviceSize)
+                    .usage(vk::BufferUsageFlags::VERTEX_BUFFER)
+                    .sharing_mode(vk::SharingMode::EXCLUSIVE),
+                Some(&vec![[0.0f32; 3]; num_vertices]),
+            )?),
+            None => None,
+        };
+
+        if meshes.iter().any(|m| m.texture().is_none()) && self.default_texture.is_none() {
+            self.default_texture = Some(Texture::white(
+                self.device,
+                device_memory_properties,
+                cmd,
+                graphics_queue,
+                &self.samplers,
+            )?);
+        }
+        if self.lights_buffer.is_none() {
+            self.lights_buffer = Some(Buffer::new(
+                self.device,
+                device_memory_properties,
+                &vk::BufferCreateInfo::default()
+                    .size(size_of::<Lights>() as vk::DeviceSize)
+                    .usage(vk::BufferUsageFlags::UNIFORM_BUFFER)
+                    .sharing_mode(vk::SharingMode::EXCLUSIVE),
+                Some(std::slice::from_ref(&self.lights)),
+            )?);
+        }
+        if self.camera_buffer.is_none() {
+            self.camera_buffer = Some(CameraBuffer::new(
+                self.device,
+                device_memory_properties,
+                vk::PipelineStageFlags::VERTEX_SHADER,
+            )?);
+        }
+        if !meshes.is_empty() {
+            let descriptor_pool = unsafe {
+                self.device.create_descriptor_pool(
+                    &vk::DescriptorPoolCreateInfo::default()
+                        .pool_sizes(&[
+                            vk::DescriptorPoolSize {
+                                ty: vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+                                descriptor_count: meshes.len() as u32,
+                            },
+                            vk::DescriptorPoolSize {
+                                ty: vk::DescriptorType::UNIFORM_BUFFER,
+                                // Lights and camera
+                                descriptor_count: 2 * meshes.len() as u32,
+                            },
+                        ])
+                        .max_sets(meshes.len() as u32),
+                    None,
+                )
+            }?;
+            self.descriptor_pool = Some(descriptor_pool);
+            let layouts = vec![self.descriptor_set_layout; meshes.len()];
+            self.descriptor_sets = unsafe {
+                self.device.allocate_descriptor_sets(
+                    &vk::DescriptorSetAllocateInfo::default()
+                        .descriptor_pool(descriptor_pool)
+                        .set_layouts(&layouts),
+                )
+            }?;
+            let image_infos = meshes
+                .iter()
+                .map(|m| {
+                    m.texture()
+                        .map(|t| t.as_ref())
+                        .or(self.default_texture.as_ref())
+                        .map(|t| [t.descriptor_image_info()])
+                        .ok_or_else(|| anyhow::anyhow!("No texture for mesh"))
+                })
+                .collect::<anyhow::Result<Vec<_>>>()?;
+            let lights_info = [vk::DescriptorBufferInfo::default()
+                .buffer(
+                    self.lights_buffer
+                        .as_ref()
+                        .ok_or_else(|| anyhow::anyhow!("No light buffer"))?
+                        .buffer(),
+                )
+                .range(vk::WHOLE_SIZE)];
+            let camera_info = [self
+                .camera_buffer
+                .as_ref()
+                .ok_or_else(|| anyhow::anyhow!("No camera buffer"))?
+                .descriptor_buffer_info()];
+            let writes: Vec<_> = self
+                .descriptor_sets
+                .iter()
+                .zip(image_infos.iter())
+                .flat_map(|(&set, image_info)| {
+                    [
+                        vk::WriteDescriptorSet::default()
+                            .dst_set(set)
+                            .dst_binding(0)
+                            .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+                            .image_info(image_info),
+                        vk::WriteDescriptorSet::default()
+                            .dst_set(set)
+                            .dst_binding(1)
+                            .descriptor_type(vk::DescriptorType::UNIFORM_BUFFER)
+                            .buffer_info(&lights_info),
+                        vk::WriteDescriptorSet::default()
+                            .dst_set(set)
+                            .dst_binding(2)
+                            .descriptor_type(vk::DescriptorType::UNIFORM_BUFFER)
+                            .buffer_info(&camera_info),
+                    ]
+                })
+                .collect();
+            unsafe { self.device.update_descriptor_sets(&writes, &[]) };
+        }
         Ok(())
     }
 
@@ -267,17 +806,59 @@ impl<'device> Renderer<'device> for Raster<'device> {
         debug!("Set resolution: {size:?} images: {images:?}");
         self.destroy_images();
         self.size = size;
-        self.update_push_constants();
+        if is_empty_extent(size) {
+            return Ok(());
+        }
+        let points = self.draws_points();
+        // Points have no surface to occlude
+        let ssao = self.ssao_enabled && !points && render_style == RenderStyle::Normal;
+        anyhow::ensure!(
+            !ssao || self.samples == vk::SampleCountFlags::TYPE_1,
+            "SSAO is not supported with multisampling"
+        );
+        anyhow::ensure!(
+            !ssao || self.region == Region::default(),
+            "SSAO is not supported when drawing to a region"
+        );
+        if !ssao {
+            self.ssao = None;
+        }
+        // Drawn from the depth buffer after the render pass
+        let depth_view = render_style == RenderStyle::Depth;
+        if depth_view && self.samples != vk::SampleCountFlags::TYPE_1 {
+            warn!("The depth render style of the rasterizer is not supported with multisampling");
+        }
+        let depth_view = depth_view && self.samples == vk::SampleCountFlags::TYPE_1;
+        if !depth_view {
+            self.depth_view = None;
+        }
+        self.shader_pipeline.set_normal_attachment(ssao);
+        self.shader_pipeline.set_sampled_depth(depth_view);
+        self.shader_pipeline.set_depth_prepass(self.depth_prepass);
+        self.shader_pipeline.set_topology(if points {
+            vk::PrimitiveTopology::POINT_LIST
+        } else {
+            vk::PrimitiveTopology::TRIANGLE_LIST
+        });
+        self.shader_pipeline.set_specialization_constants(&[
+            specialization_constant(
+                ENCODE_SRGB_CONSTANT_ID,
+                vk::Bool32::from(needs_srgb_encoding(surface_format.format)),
+            ),
+            specialization_constant(WRITE_NORMALS_CONSTANT_ID, vk::Bool32::from(ssao)),
+            specialization_constant(POINTS_CONSTANT_ID, vk::Bool32::from(points)),
+        ]);
 
+        let area = self.region.rect(size);
         self.viewports = vec![vk::Viewport {
-            x: 0.0,
-            y: 0.0,
-            width: size.width as f32,
-            height: size.height as f32,
+            x: area.offset.x as f32,
+            y: area.offset.y as f32,
+            width: area.extent.width as f32,
+            height: area.extent.height as f32,
             min_depth: 0.0,
             max_depth: 1.0,
         }];
-        self.scissors = vec![size.into()];
+        self.scissors = vec![area];
         let vertex_attribute_desc = [
             vk::VertexInputAttributeDescription {
                 location: 0,
@@ -291,6 +872,18 @@ impl<'device> Renderer<'device> for Raster<'device> {
                 format: vk::Format::R32G32B32_SFLOAT,
                 offset: 0,
             },
+            vk::VertexInputAttributeDescription {
+                location: 2,
+                binding: 2,
+                format: vk::Format::R32G32_SFLOAT,
+                offset: 0,
+            },
+            vk::VertexInputAttributeDescription {
+                location: 3,
+                binding: 3,
+                format: vk::Format::R32G32B32A32_SFLOAT,
+                offset: 0,
+            },
         ];
         let vertex_binding_desc = [
             vk::VertexInputBindingDescription {
@@ -303,26 +896,60 @@ impl<'device> Renderer<'device> for Raster<'device> {
                 stride: std::mem::size_of::<Normal>() as u32,
                 input_rate: vk::VertexInputRate::VERTEX,
             },
+            vk::VertexInputBindingDescription {
+                binding: 2,
+                stride: std::mem::size_of::<[f32; 2]>() as u32,
+                input_rate: vk::VertexInputRate::VERTEX,
+            },
+            vk::VertexInputBindingDescription {
+                binding: 3,
+                stride: std::mem::size_of::<[f32; 4]>() as u32,
+                input_rate: vk::VertexInputRate::VERTEX,
+            },
         ];
         let (pipeline, renderpass, pipeline_layout) = self.shader_pipeline.make_graphics_pipeline(
             device,
+            self.pipeline_cache,
             &self.scissors,
             &self.viewports,
             surface_format.format,
             &vertex_attribute_desc,
             &vertex_binding_desc,
-            &[vk::PushConstantRange::default()
-                .offset(0)
-                .size(size_of::<PushConstants>().try_into()?)
-                .stage_flags(ShaderStageFlags::VERTEX)],
+            &[self.descriptor_set_layout],
+            &[
+                // Model matrix and point size
+                vk::PushConstantRange::default()
+                    .offset(0)
+                    .size((size_of::<Matrix4<f32>>() + size_of::<f32>()).try_into()?)
+                    .stage_flags(ShaderStageFlags::VERTEX),
+                vk::PushConstantRange::default()
+                    .offset(BASE_COLOR_PUSH_CONSTANT_OFFSET)
+                    .size(size_of::<[f32; 4]>().try_into()?)
+                    .stage_flags(ShaderStageFlags::FRAGMENT),
+            ],
             render_style,
+            self.samples,
         )?;
         self.renderpass = Some(renderpass);
         self.pipeline = Some(pipeline);
         self.pipeline_layout = Some(pipeline_layout);
-        let image_views: anyhow::Result<Vec<_>> = images
-            .iter()
-            .map(|&image| {
+        if self.depth_prepass {
+            self.prepass_pipeline = Some(self.shader_pipeline.make_depth_prepass_pipeline(
+                device,
+                self.pipeline_cache,
+                renderpass,
+                pipeline_layout,
+                &self.scissors,
+                &self.viewports,
+                &vertex_attribute_desc,
+                &vertex_binding_desc,
+                render_style,
+                self.samples,
+            )?);
+        }
+        self.image_views = create_per_image(
+            images,
+            |&image| {
                 let create_view_info = vk::ImageViewCreateInfo::default()
                     .view_type(vk::ImageViewType::TYPE_2D)
                     .format(surface_format.format)
@@ -345,62 +972,80 @@ impl<'device> Renderer<'device> for Raster<'device> {
                         .create_image_view(&create_view_info, None)
                         .map_err(|err| anyhow::anyhow!("Failed to create image view: {err}"))
                 }
-            })
-            .collect();
-        self.image_views = image_views?;
-
-        let depth_image_create_info = vk::ImageCreateInfo::default()
-            .image_type(vk::ImageType::TYPE_2D)
-            .format(vk::Format::D16_UNORM)
-            .extent(vk::Extent3D {
-                width: size.width,
-                height: size.height,
-                depth: 1,
-            })
-            .mip_levels(1)
-            .array_layers(1)
-            .samples(vk::SampleCountFlags::TYPE_1)
-            .tiling(vk::ImageTiling::OPTIMAL)
-            .usage(vk::ImageUsageFlags::DEPTH_STENCIL_ATTACHMENT)
-            .sharing_mode(vk::SharingMode::EXCLUSIVE);
-
-        self.depth_image = unsafe { device.create_image(&depth_image_create_info, None)? };
-
-        self.depth_image_memory = unsafe {
-            let depth_image_memory_req = device.get_image_memory_requirements(self.depth_image);
-            let depth_image_memory_index = find_memorytype_index(
-                &depth_image_memory_req,
-                device_memory_properties,
-                vk::MemoryPropertyFlags::DEVICE_LOCAL,
-            )
-            .ok_or_else(|| anyhow::anyhow!("Could not find memory index for depth buffer"))?;
-            let depth_image_allocate_info = vk::MemoryAllocateInfo::default()
-                .allocation_size(depth_image_memory_req.size)
-                .memory_type_index(depth_image_memory_index);
-
-            device.allocate_memory(&depth_image_allocate_info, None)?
-        };
-        unsafe { device.bind_image_memory(self.depth_image, self.depth_image_memory, 0)? };
-        self.depth_image_view = unsafe {
-            let depth_image_view_info = vk::ImageViewCreateInfo::default()
-                .subresource_range(
-                    vk::ImageSubresourceRange::default()
-                        .aspect_mask(vk::ImageAspectFlags::DEPTH)
-                        .level_count(1)
-                        .layer_count(1),
-                )
-                .image(self.depth_image)
-                .format(depth_image_create_info.format)
-                .view_type(vk::ImageViewType::TYPE_2D);
+            },
+            |view| unsafe { device.destroy_image_view(view, None) },
+        )?;
 
-            device.create_image_view(&depth_image_view_info, None)?
-        };
+        (
+            self.depth_image,
+            self.depth_image_memory,
+            self.depth_image_view,
+        ) = create_attachment_image(
+            device,
+            device_memory_properties,
+            size,
+            self.shader_pipeline.depth_format(),
+            self.samples,
+            // Copied by `capture_depth`
+            if ssao || depth_view {
+                vk::ImageUsageFlags::DEPTH_STENCIL_ATTACHMENT
+                    | vk::ImageUsageFlags::SAMPLED
+                    | vk::ImageUsageFlags::TRANSFER_SRC
+            } else {
+                vk::ImageUsageFlags::DEPTH_STENCIL_ATTACHMENT | vk::ImageUsageFlags::TRANSFER_SRC
+            },
+            vk::ImageAspectFlags::DEPTH,
+        )?;
+        if ssao {
+            if self.ssao.is_none() {
+                self.ssao = Some(Ssao::new(device, self.pipeline_cache)?);
+            }
+            if let Some(ssao) = &mut self.ssao {
+                ssao.set_resolution(
+                    surface_format,
+                    size,
+                    images,
+                    &self.image_views,
+                    self.depth_image_view,
+                    device_memory_properties,
+                )?;
+            }
+        }
+        if depth_view {
+            if self.depth_view.is_none() {
+                self.depth_view = Some(DepthView::new(device, self.pipeline_cache)?);
+            }
+            if let Some(depth_view) = &mut self.depth_view {
+                depth_view.set_resolution(images, &self.image_views, self.depth_image_view)?;
+            }
+        }
+        if self.samples != vk::SampleCountFlags::TYPE_1 {
+            (
+                self.msaa_image,
+                self.msaa_image_memory,
+                self.msaa_image_view,
+            ) = create_attachment_image(
+                device,
+                device_memory_properties,
+                size,
+                surface_format.format,
+                self.samples,
+                vk::ImageUsageFlags::COLOR_ATTACHMENT | vk::ImageUsageFlags::TRANSIENT_ATTACHMENT,
+                vk::ImageAspectFlags::COLOR,
+            )?;
+        }
 
-        self.framebuffers = self
-            .image_views
-            .iter()
-            .map(|&view| {
-                let framebuffer_attachments = [view, self.depth_image_view];
+        self.framebuffers = create_per_image(
+            &self.image_views,
+            |&view| {
+                // Attachment order as expected by `make_graphics_pipeline`
+                let framebuffer_attachments = if self.samples != vk::SampleCountFlags::TYPE_1 {
+                    vec![self.msaa_image_view, self.depth_image_view, view]
+                } else if let Some(ssao) = &self.ssao {
+                    vec![view, self.depth_image_view, ssao.normal_image_view]
+                } else {
+                    vec![view, self.depth_image_view]
+                };
                 let frame_buffer_create_info = vk::FramebufferCreateInfo::default()
                     .render_pass(renderpass)
                     .attachments(&framebuffer_attachments)
@@ -413,10 +1058,11 @@ impl<'device> Renderer<'device> for Raster<'device> {
                         .create_framebuffer(&frame_buffer_create_info, None)
                         .map_err(|err| anyhow::anyhow!("Failed to create framebuffer: {err}"))
                 }
-            })
-            .collect::<anyhow::Result<Vec<_>>>()?;
+            },
+            |framebuffer| unsafe { device.destroy_framebuffer(framebuffer, None) },
+        )?;
 
-        self.resolution = size.into();
+        self.resolution = area;
         Ok(())
     }
 
@@ -428,52 +1074,642 @@ impl<'device> Renderer<'device> for Raster<'device> {
         Some(&mut self.shader_pipeline)
     }
 
-    fn process_device_event(&mut self, event: &winit::event::DeviceEvent) {
-        #[allow(clippy::single_match)]
-        match event {
-            winit::event::DeviceEvent::MouseMotion { delta } => {
-                if self.middle_drag {
-                    self.translation -= Vector3 {
-                        x: self.rotation.cos() * delta.0 as f32,
-                        y: delta.1 as f32,
-                        z: self.rotation.sin() * delta.0 as f32,
-                    };
-                }
+    fn set_camera(&mut self, camera: &Camera) {
+        self.camera = *camera;
+    }
+
+    fn set_point_size(&mut self, point_size: f32) {
+        self.point_size = point_size;
+    }
+
+    fn set_region(&mut self, region: Region) {
+        self.region = region;
+    }
+
+    fn set_lights(&mut self, lights: &Lights) -> anyhow::Result<()> {
+        self.lights = *lights;
+        if let Some(buffer) = self.lights_buffer.as_ref() {
+            // Previous frames might still read the lights
+            unsafe { self.device.device_wait_idle()? };
+            buffer.write(std::slice::from_ref(lights))?;
+        }
+        Ok(())
+    }
+}
+
+/// Create a device local 2D image usable as framebuffer attachment
+pub(crate) fn create_attachment_image(
+    device: &ash::Device,
+    device_memory_properties: &vk::PhysicalDeviceMemoryProperties,
+    size: vk::Extent2D,
+    format: vk::Format,
+    samples: vk::SampleCountFlags,
+    usage: vk::ImageUsageFlags,
+    aspect_mask: vk::ImageAspectFlags,
+) -> anyhow::Result<(vk::Image, vk::DeviceMemory, vk::ImageView)> {
+    let image_create_info = vk::ImageCreateInfo::default()
+        .image_type(vk::ImageType::TYPE_2D)
+        .format(format)
+        .extent(vk::Extent3D {
+            width: size.width,
+            height: size.height,
+            depth: 1,
+        })
+        .mip_levels(1)
+        .array_layers(1)
+        .samples(samples)
+        .tiling(vk::ImageTiling::OPTIMAL)
+        .usage(usage)
+        .sharing_mode(vk::SharingMode::EXCLUSIVE);
+
+    let image = unsafe { device.create_image(&image_create_info, None)? };
+
+    let memory = unsafe {
+        let memory_req = device.get_image_memory_requirements(image);
+        let memory_index = find_memorytype_index(
+            &memory_req,
+            device_memory_properties,
+            vk::MemoryPropertyFlags::DEVICE_LOCAL,
+        )
+        .ok_or_else(|| anyhow::anyhow!("Could not find memory index for {usage:?} image"))?;
+        let allocate_info = vk::MemoryAllocateInfo::default()
+            .allocation_size(memory_req.size)
+            .memory_type_index(memory_index);
+
+        device.allocate_memory(&allocate_info, None)?
+    };
+    unsafe { device.bind_image_memory(image, memory, 0)? };
+    let view = unsafe {
+        let image_view_info = vk::ImageViewCreateInfo::default()
+            .subresource_range(
+                vk::ImageSubresourceRange::default()
+                    .aspect_mask(aspect_mask)
+                    .level_count(1)
+                    .layer_count(1),
+            )
+            .image(image)
+            .format(format)
+            .view_type(vk::ImageViewType::TYPE_2D);
+
+        device.create_image_view(&image_view_info, None)?
+    };
+    Ok((image, memory, view))
+}
+
+/// Contents of the push constants of `ssao.comp`
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+struct SsaoPushConstants {
+    proj: Matrix4<f32>,
+    radius: f32,
+}
+
+/// Screen-space ambient occlusion computed from the depth and view space normals of the
+/// rasterizer and multiplied into the shaded image with a compute pass
+struct Ssao<'device> {
+    device: &'device ash::Device,
+    pipeline_cache: vk::PipelineCache,
+    shader_pipeline: ShaderPipeline<'device>,
+    descriptor_set_layout: vk::DescriptorSetLayout,
+    sampler: vk::Sampler,
+    pipeline: Option<vk::Pipeline>,
+    pipeline_layout: Option<vk::PipelineLayout>,
+    descriptor_pool: Option<vk::DescriptorPool>,
+    /// Depth, normals and one swapchain image per set
+    descriptor_sets: Vec<vk::DescriptorSet>,
+    /// Swapchain images in the order of `descriptor_sets`
+    images: Vec<vk::Image>,
+    normal_image: vk::Image,
+    normal_image_view: vk::ImageView,
+    normal_image_memory: vk::DeviceMemory,
+    size: vk::Extent2D,
+}
+
+impl<'device> Ssao<'device> {
+    fn new(
+        device: &'device ash::Device,
+        pipeline_cache: vk::PipelineCache,
+    ) -> anyhow::Result<Self> {
+        let shader_pipeline = ShaderPipeline::new(
+            device,
+            &[&include_bytes!("../../shaders/ssao.comp.spirv")[..]],
+        )?;
+        let binding = |binding, descriptor_type| {
+            vk::DescriptorSetLayoutBinding::default()
+                .binding(binding)
+                .descriptor_count(1)
+                .descriptor_type(descriptor_type)
+                .stage_flags(vk::ShaderStageFlags::COMPUTE)
+        };
+        let descriptor_set_layout = unsafe {
+            device.create_descriptor_set_layout(
+                &vk::DescriptorSetLayoutCreateInfo::default().bindings(&[
+                    binding(0, vk::DescriptorType::COMBINED_IMAGE_SAMPLER),
+                    binding(1, vk::DescriptorType::COMBINED_IMAGE_SAMPLER),
+                    binding(2, vk::DescriptorType::STORAGE_IMAGE),
+                ]),
+                None,
+            )
+        }?;
+        let sampler = unsafe {
+            device.create_sampler(
+                &vk::SamplerCreateInfo::default()
+                    .mag_filter(vk::Filter::NEAREST)
+                    .min_filter(vk::Filter::NEAREST)
+                    .address_mode_u(vk::SamplerAddressMode::CLAMP_TO_EDGE)
+                    .address_mode_v(vk::SamplerAddressMode::CLAMP_TO_EDGE),
+                None,
+            )
+        }?;
+        Ok(Self {
+            device,
+            pipeline_cache,
+            shader_pipeline,
+            descriptor_set_layout,
+            sampler,
+            pipeline: None,
+            pipeline_layout: None,
+            descriptor_pool: None,
+            descriptor_sets: Vec::new(),
+            images: Vec::new(),
+            normal_image: Default::default(),
+            normal_image_view: Default::default(),
+            normal_image_memory: Default::default(),
+            size: vk::Extent2D {
+                width: 0,
+                height: 0,
+            },
+        })
+    }
+
+    fn destroy_images(&mut self) {
+        let device = self.device;
+        unsafe {
+            let _ = device.device_wait_idle();
+            if let Some(p) = self.pipeline.take() {
+                device.destroy_pipeline(p, None);
+            }
+            if let Some(p) = self.pipeline_layout.take() {
+                device.destroy_pipeline_layout(p, None);
+            }
+            if let Some(pool) = self.descriptor_pool.take() {
+                device.destroy_descriptor_pool(pool, None);
             }
-            _ => (),
+            self.descriptor_sets.clear();
+            self.images.clear();
+            device.destroy_image(self.normal_image, None);
+            device.destroy_image_view(self.normal_image_view, None);
+            device.free_memory(self.normal_image_memory, None);
+            self.normal_image = vk::Image::null();
+            self.normal_image_view = vk::ImageView::null();
+            self.normal_image_memory = vk::DeviceMemory::null();
         }
     }
-    fn process_window_event(&mut self, event: &winit::event::WindowEvent) {
-        let mut handled = true;
-        match event {
-            WindowEvent::MouseInput { state, button, .. } => match (button, state) {
-                //(winit::event::MouseButton::Left, winit::event::ElementState::Pressed) => todo!(),
-                //(winit::event::MouseButton::Left, winit::event::ElementState::Released) => todo!(),
-                //(winit::event::MouseButton::Right, winit::event::ElementState::Pressed) => todo!(),
-                //(winit::event::MouseButton::Right, winit::event::ElementState::Released) => todo!(),
-                (winit::event::MouseButton::Middle, winit::event::ElementState::Pressed) => {
-                    self.middle_drag = true
-                }
-                (winit::event::MouseButton::Middle, winit::event::ElementState::Released) => {
-                    self.middle_drag = false
+
+    /// Create the normal attachment and the compute pass writing into `images` through
+    /// `image_views`
+    fn set_resolution(
+        &mut self,
+        surface_format: vk::SurfaceFormatKHR,
+        size: vk::Extent2D,
+        images: &[vk::Image],
+        image_views: &[vk::ImageView],
+        depth_image_view: vk::ImageView,
+        device_memory_properties: &vk::PhysicalDeviceMemoryProperties,
+    ) -> anyhow::Result<()> {
+        self.destroy_images();
+        let device = self.device;
+        self.size = size;
+        self.images = images.to_vec();
+        (
+            self.normal_image,
+            self.normal_image_memory,
+            self.normal_image_view,
+        ) = create_attachment_image(
+            device,
+            device_memory_properties,
+            size,
+            NORMAL_ATTACHMENT_FORMAT,
+            vk::SampleCountFlags::TYPE_1,
+            vk::ImageUsageFlags::COLOR_ATTACHMENT | vk::ImageUsageFlags::SAMPLED,
+            vk::ImageAspectFlags::COLOR,
+        )?;
+
+        self.shader_pipeline
+            .set_specialization_constants(&[specialization_constant(
+                ENCODE_SRGB_CONSTANT_ID,
+                vk::Bool32::from(needs_srgb_encoding(surface_format.format)),
+            )]);
+        let (pipeline, pipeline_layout) = self.shader_pipeline.make_compute_pipeline(
+            device,
+            self.pipeline_cache,
+            &[self.descriptor_set_layout],
+            &[vk::PushConstantRange::default()
+                .offset(0)
+                .size(size_of::<SsaoPushConstants>() as u32)
+                .stage_flags(vk::ShaderStageFlags::COMPUTE)],
+            RenderStyle::Normal,
+        )?;
+        self.pipeline = Some(pipeline);
+        self.pipeline_layout = Some(pipeline_layout);
+
+        let num_sets = image_views.len() as u32;
+        let descriptor_pool = unsafe {
+            device.create_descriptor_pool(
+                &vk::DescriptorPoolCreateInfo::default()
+                    .pool_sizes(&[
+                        vk::DescriptorPoolSize {
+                            ty: vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+                            descriptor_count: 2 * num_sets,
+                        },
+                        vk::DescriptorPoolSize {
+                            ty: vk::DescriptorType::STORAGE_IMAGE,
+                            descriptor_count: num_sets,
+                        },
+                    ])
+                    .max_sets(num_sets),
+                None,
+            )
+        }?;
+        self.descriptor_pool = Some(descriptor_pool);
+        self.descriptor_sets = unsafe {
+            device.allocate_descriptor_sets(
+                &vk::DescriptorSetAllocateInfo::default()
+                    .descriptor_pool(descriptor_pool)
+                    .set_layouts(&vec![self.descriptor_set_layout; image_views.len()]),
+            )
+        }?;
+        let depth_info = [vk::DescriptorImageInfo::default()
+            .sampler(self.sampler)
+            .image_view(depth_image_view)
+            .image_layout(vk::ImageLayout::DEPTH_STENCIL_READ_ONLY_OPTIMAL)];
+        let normal_info = [vk::DescriptorImageInfo::default()
+            .sampler(self.sampler)
+            .image_view(self.normal_image_view)
+            .image_layout(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL)];
+        for (&set, &view) in self.descriptor_sets.iter().zip(image_views) {
+            let image_info = [vk::DescriptorImageInfo::default()
+                .image_layout(vk::ImageLayout::GENERAL)
+                .image_view(view)];
+            unsafe {
+                device.update_descriptor_sets(
+                    &[
+                        vk::WriteDescriptorSet::default()
+                            .dst_set(set)
+                            .dst_binding(0)
+                            .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+                            .image_info(&depth_info),
+                        vk::WriteDescriptorSet::default()
+                            .dst_set(set)
+                            .dst_binding(1)
+                            .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+                            .image_info(&normal_info),
+                        vk::WriteDescriptorSet::default()
+                            .dst_set(set)
+                            .dst_binding(2)
+                            .descriptor_type(vk::DescriptorType::STORAGE_IMAGE)
+                            .image_info(&image_info),
+                    ],
+                    &[],
+                )
+            };
+        }
+        Ok(())
+    }
+
+    /// Darken the `swapchain_idx`-th image after the render pass. The render pass leaves it in
+    /// `PRESENT_SRC_KHR` and made the attachments visible to compute shaders.
+    fn cmd_apply(
+        &self,
+        cmd: vk::CommandBuffer,
+        swapchain_idx: usize,
+        proj: Matrix4<f32>,
+        radius: f32,
+    ) -> anyhow::Result<()> {
+        let (pipeline, pipeline_layout) = match (self.pipeline, self.pipeline_layout) {
+            (Some(pipeline), Some(pipeline_layout)) => (pipeline, pipeline_layout),
+            _ => return Ok(()),
+        };
+        check_swapchain_idx(swapchain_idx, self.descriptor_sets.len())?;
+        let push_constants = unsafe {
+            transmute::<SsaoPushConstants, [u8; size_of::<SsaoPushConstants>()]>(
+                SsaoPushConstants { proj, radius },
+            )
+        };
+        cmd_dispatch_on_image(
+            self.device,
+            cmd,
+            self.images[swapchain_idx],
+            pipeline,
+            pipeline_layout,
+            self.descriptor_sets[swapchain_idx],
+            &push_constants,
+            [
+                div_up(self.size.width, SSAO_WORKGROUP_SIZE),
+                div_up(self.size.height, SSAO_WORKGROUP_SIZE),
+            ],
+        );
+        Ok(())
+    }
+}
+
+impl Drop for Ssao<'_> {
+    fn drop(&mut self) {
+        self.destroy_images();
+        unsafe {
+            self.device
+                .destroy_descriptor_set_layout(self.descriptor_set_layout, None);
+            self.device.destroy_sampler(self.sampler, None);
+        }
+    }
+}
+
+/// Record `pipeline` writing to the swapchain image `image` after a render pass, which leaves it
+/// in `PRESENT_SRC_KHR` and made its attachments visible to compute shaders
+#[allow(clippy::too_many_arguments)]
+fn cmd_dispatch_on_image(
+    device: &ash::Device,
+    cmd: vk::CommandBuffer,
+    image: vk::Image,
+    pipeline: vk::Pipeline,
+    pipeline_layout: vk::PipelineLayout,
+    descriptor_set: vk::DescriptorSet,
+    push_constants: &[u8],
+    [groups_x, groups_y]: [u32; 2],
+) {
+    unsafe {
+        device.cmd_pipeline_barrier(
+            cmd,
+            vk::PipelineStageFlags::COMPUTE_SHADER,
+            vk::PipelineStageFlags::COMPUTE_SHADER,
+            vk::DependencyFlags::default(),
+            &[],
+            &[],
+            &[vk::ImageMemoryBarrier::default()
+                .dst_access_mask(vk::AccessFlags::SHADER_READ | vk::AccessFlags::SHADER_WRITE)
+                .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                .old_layout(vk::ImageLayout::PRESENT_SRC_KHR)
+                .new_layout(vk::ImageLayout::GENERAL)
+                .image(image)
+                .subresource_range(COLOR_SUBRESOURCE_RANGE)],
+        );
+        device.cmd_bind_pipeline(cmd, vk::PipelineBindPoint::COMPUTE, pipeline);
+        device.cmd_bind_descriptor_sets(
+            cmd,
+            vk::PipelineBindPoint::COMPUTE,
+            pipeline_layout,
+            0,
+            &[descriptor_set],
+            &[],
+        );
+        device.cmd_push_constants(
+            cmd,
+            pipeline_layout,
+            vk::ShaderStageFlags::COMPUTE,
+            0,
+            push_constants,
+        );
+        device.cmd_dispatch(cmd, groups_x, groups_y, 1);
+        device.cmd_pipeline_barrier(
+            cmd,
+            vk::PipelineStageFlags::COMPUTE_SHADER,
+            vk::PipelineStageFlags::BOTTOM_OF_PIPE,
+            vk::DependencyFlags::default(),
+            &[],
+            &[],
+            &[vk::ImageMemoryBarrier::default()
+                .src_access_mask(vk::AccessFlags::SHADER_WRITE)
+                .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                .old_layout(vk::ImageLayout::GENERAL)
+                .new_layout(vk::ImageLayout::PRESENT_SRC_KHR)
+                .image(image)
+                .subresource_range(COLOR_SUBRESOURCE_RANGE)],
+        );
+    }
+}
+
+/// Contents of the push constants of `depth.comp`
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+struct DepthPushConstants {
+    proj: Matrix4<f32>,
+    offset: [i32; 2],
+    size: [u32; 2],
+}
+
+/// Depth buffer of the rasterizer, linearized between the clip planes and written over the
+/// shaded image with a compute pass
+struct DepthView<'device> {
+    device: &'device ash::Device,
+    pipeline: vk::Pipeline,
+    pipeline_layout: vk::PipelineLayout,
+    descriptor_set_layout: vk::DescriptorSetLayout,
+    sampler: vk::Sampler,
+    descriptor_pool: Option<vk::DescriptorPool>,
+    /// Depth and one swapchain image per set
+    descriptor_sets: Vec<vk::DescriptorSet>,
+    /// Swapchain images in the order of `descriptor_sets`
+    images: Vec<vk::Image>,
+}
+
+impl<'device> DepthView<'device> {
+    fn new(
+        device: &'device ash::Device,
+        pipeline_cache: vk::PipelineCache,
+    ) -> anyhow::Result<Self> {
+        let shader_pipeline = ShaderPipeline::new(
+            device,
+            &[&include_bytes!("../../shaders/depth.comp.spirv")[..]],
+        )?;
+        let binding = |binding, descriptor_type| {
+            vk::DescriptorSetLayoutBinding::default()
+                .binding(binding)
+                .descriptor_count(1)
+                .descriptor_type(descriptor_type)
+                .stage_flags(vk::ShaderStageFlags::COMPUTE)
+        };
+        let descriptor_set_layout = unsafe {
+            device.create_descriptor_set_layout(
+                &vk::DescriptorSetLayoutCreateInfo::default().bindings(&[
+                    binding(0, vk::DescriptorType::COMBINED_IMAGE_SAMPLER),
+                    binding(1, vk::DescriptorType::STORAGE_IMAGE),
+                ]),
+                None,
+            )
+        }?;
+        let pipeline = shader_pipeline.make_compute_pipeline(
+            device,
+            pipeline_cache,
+            &[descriptor_set_layout],
+            &[vk::PushConstantRange::default()
+                .offset(0)
+                .size(size_of::<DepthPushConstants>() as u32)
+                .stage_flags(vk::ShaderStageFlags::COMPUTE)],
+            RenderStyle::Normal,
+        );
+        let (pipeline, pipeline_layout) = match pipeline {
+            Ok(pipeline) => pipeline,
+            Err(err) => {
+                unsafe { device.destroy_descriptor_set_layout(descriptor_set_layout, None) };
+                return Err(err);
+            }
+        };
+        let sampler = unsafe {
+            device.create_sampler(
+                &vk::SamplerCreateInfo::default()
+                    .mag_filter(vk::Filter::NEAREST)
+                    .min_filter(vk::Filter::NEAREST)
+                    .address_mode_u(vk::SamplerAddressMode::CLAMP_TO_EDGE)
+                    .address_mode_v(vk::SamplerAddressMode::CLAMP_TO_EDGE),
+                None,
+            )
+        };
+        let sampler = match sampler {
+            Ok(sampler) => sampler,
+            Err(err) => {
+                unsafe {
+                    device.destroy_pipeline(pipeline, None);
+                    device.destroy_pipeline_layout(pipeline_layout, None);
+                    device.destroy_descriptor_set_layout(descriptor_set_layout, None);
                 }
-                _ => (),
-            },
-            WindowEvent::MouseWheel { delta, .. } => match delta {
-                winit::event::MouseScrollDelta::LineDelta(_h, v) => self.zoom += 0.1 * v,
-                winit::event::MouseScrollDelta::PixelDelta(_) => (),
-            },
-            WindowEvent::KeyboardInput { input, .. } => match input.virtual_keycode {
-                Some(winit::event::VirtualKeyCode::Left) => self.rotation += 5.0,
-                Some(winit::event::VirtualKeyCode::Right) => self.rotation -= 5.0,
-                Some(winit::event::VirtualKeyCode::Down) => self.zoom += 0.1,
-                Some(winit::event::VirtualKeyCode::Up) => self.zoom -= 0.1,
-                _ => handled = false,
-            },
-            _ => handled = false,
+                return Err(err.into());
+            }
+        };
+        Ok(Self {
+            device,
+            pipeline,
+            pipeline_layout,
+            descriptor_set_layout,
+            sampler,
+            descriptor_pool: None,
+            descriptor_sets: Vec::new(),
+            images: Vec::new(),
+        })
+    }
+
+    fn destroy_descriptor_sets(&mut self) {
+        if let Some(pool) = self.descriptor_pool.take() {
+            unsafe {
+                let _ = self.device.device_wait_idle();
+                self.device.destroy_descriptor_pool(pool, None);
+            }
+        }
+        self.descriptor_sets.clear();
+        self.images.clear();
+    }
+
+    /// Read the depth through `depth_image_view` and write to `images` through `image_views`
+    fn set_resolution(
+        &mut self,
+        images: &[vk::Image],
+        image_views: &[vk::ImageView],
+        depth_image_view: vk::ImageView,
+    ) -> anyhow::Result<()> {
+        self.destroy_descriptor_sets();
+        let device = self.device;
+        let num_sets = image_views.len() as u32;
+        let descriptor_pool = unsafe {
+            device.create_descriptor_pool(
+                &vk::DescriptorPoolCreateInfo::default()
+                    .pool_sizes(&[
+                        vk::DescriptorPoolSize {
+                            ty: vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+                            descriptor_count: num_sets,
+                        },
+                        vk::DescriptorPoolSize {
+                            ty: vk::DescriptorType::STORAGE_IMAGE,
+                            descriptor_count: num_sets,
+                        },
+                    ])
+                    .max_sets(num_sets),
+                None,
+            )
+        }?;
+        self.descriptor_pool = Some(descriptor_pool);
+        self.descriptor_sets = unsafe {
+            device.allocate_descriptor_sets(
+                &vk::DescriptorSetAllocateInfo::default()
+                    .descriptor_pool(descriptor_pool)
+                    .set_layouts(&vec![self.descriptor_set_layout; image_views.len()]),
+            )
+        }?;
+        self.images = images.to_vec();
+        let depth_info = [vk::DescriptorImageInfo::default()
+            .sampler(self.sampler)
+            .image_view(depth_image_view)
+            .image_layout(vk::ImageLayout::DEPTH_STENCIL_READ_ONLY_OPTIMAL)];
+        for (&set, &view) in self.descriptor_sets.iter().zip(image_views) {
+            let image_info = [vk::DescriptorImageInfo::default()
+                .image_layout(vk::ImageLayout::GENERAL)
+                .image_view(view)];
+            unsafe {
+                device.update_descriptor_sets(
+                    &[
+                        vk::WriteDescriptorSet::default()
+                            .dst_set(set)
+                            .dst_binding(0)
+                            .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+                            .image_info(&depth_info),
+                        vk::WriteDescriptorSet::default()
+                            .dst_set(set)
+                            .dst_binding(1)
+                            .descriptor_type(vk::DescriptorType::STORAGE_IMAGE)
+                            .image_info(&image_info),
+                    ],
+                    &[],
+                )
+            };
         }
-        if handled {
-            self.update_push_constants();
+        Ok(())
+    }
+
+    /// Overwrite `region` of the `swapchain_idx`-th image with the depth after the render pass
+    fn cmd_apply(
+        &self,
+        cmd: vk::CommandBuffer,
+        swapchain_idx: usize,
+        proj: Matrix4<f32>,
+        region: vk::Rect2D,
+    ) -> anyhow::Result<()> {
+        check_swapchain_idx(swapchain_idx, self.descriptor_sets.len())?;
+        let push_constants = unsafe {
+            transmute::<DepthPushConstants, [u8; size_of::<DepthPushConstants>()]>(
+                DepthPushConstants {
+                    proj,
+                    offset: [region.offset.x, region.offset.y],
+                    size: [region.extent.width, region.extent.height],
+                },
+            )
+        };
+        cmd_dispatch_on_image(
+            self.device,
+            cmd,
+            self.images[swapchain_idx],
+            self.pipeline,
+            self.pipeline_layout,
+            self.descriptor_sets[swapchain_idx],
+            &push_constants,
+            [
+                div_up(region.extent.width, DEPTH_VIEW_WORKGROUP_SIZE),
+                div_up(region.extent.height, DEPTH_VIEW_WORKGROUP_SIZE),
+            ],
+        );
+        Ok(())
+    }
+}
+
+impl Drop for DepthView<'_> {
+    fn drop(&mut self) {
+        self.destroy_descriptor_sets();
+        unsafe {
+            self.device.destroy_pipeline(self.pipeline, None);
+            self.device
+                .destroy_pipeline_layout(self.pipeline_layout, None);
+            self.device
+                .destroy_descriptor_set_layout(self.descriptor_set_layout, None);
+            self.device.destroy_sampler(self.sampler, None);
         }
     }
 }
@@ -481,5 +1717,93 @@ impl<'device> Renderer<'device> for Raster<'device> {
 impl Drop for Raster<'_> {
     fn drop(&mut self) {
         self.destroy_images();
+        self.destroy_descriptor_sets();
+        unsafe {
+            self.device
+                .destroy_descriptor_set_layout(self.descriptor_set_layout, None)
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        mesh::{Mesh, ReadOptions},
+        vulkan_app::{AppOptions, TracingMode, VulkanApp},
+    };
+
+    #[test]
+    #[ignore = "needs a Vulkan device and VK_LAYER_KHRONOS_validation"]
+    fn index_less_triangle_draws_without_validation_errors() -> anyhow::Result<()> {
+        let mut vulkan_app = VulkanApp::new_headless(
+            vk::Extent2D {
+                width: 64,
+                height: 64,
+            },
+            AppOptions {
+                with_raytracing: false,
+                with_cuda: false,
+                tracing_mode: TracingMode::NoTracing,
+                validation: true,
+                gpu_timestamps: false,
+                frames_in_flight: 1,
+                supersample: 1,
+                present_mode: vk::PresentModeKHR::FIFO,
+                device: None,
+                surface_formats: Vec::new(),
+                sampler_anisotropy: false,
+            },
+        )?;
+        anyhow::ensure!(
+            vulkan_app.validation_errors().is_some(),
+            "Validation layer is not available"
+        );
+        // Renderers borrow the device while `vulkan_app` is borrowed mutably for drawing
+        let device: &ash::Device = Box::leak(Box::new(vulkan_app.device().clone()));
+
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/triangle.obj");
+        let mesh = Rc::new(Mesh::from_file(&path, ReadOptions::OnlyTriangles, false)?);
+        let mesh = Rc::new(DeviceMesh::new(
+            device,
+            vulkan_app.device_memory_properties(),
+            &mesh,
+            vulkan_app.raytracing_support(),
+        )?);
+        assert!(mesh.indices().is_none());
+
+        let mut raster = Raster::new(device, vulkan_app.pipeline_cache())?
+            .with_depth_format(vulkan_app.depth_format());
+        let cmd = vulkan_app.allocate_command_buffers(1)?[0];
+        raster.set_meshes(
+            &[mesh],
+            cmd,
+            vulkan_app.graphics_queue(),
+            vulkan_app.device_memory_properties(),
+        )?;
+        vulkan_app.free_command_buffers(&[cmd]);
+        raster.set_camera(&Camera::new(Point3::new(0.0, 0.0, 0.0)));
+        raster.set_resolution(
+            vulkan_app.surface_format(),
+            vulkan_app.extent(),
+            vulkan_app.images(),
+            vulkan_app.device_memory_properties(),
+            RenderStyle::Normal,
+        )?;
+        vulkan_app.draw(|device, cmd, image, instant, swapchain_idx, timers| {
+            raster.draw(
+                device,
+                cmd,
+                image,
+                instant,
+                swapchain_idx,
+                timers.renderer(0),
+            )
+        })?;
+        unsafe { device.device_wait_idle()? };
+        drop(raster);
+
+        assert_eq!(vulkan_app.validation_errors(), Some(0));
+        Ok(())
     }
 }
@@ -1,70 +1,226 @@
 use crate::{
-    acceleration_structure::{BottomLevelAccelerationStructure, TopLevelAccelerationStructure},
-    device_mesh::Buffer,
+    acceleration_structure::{
+        BottomLevelAccelerationStructure, HitGeometry, ProceduralGeometry, ScratchBuffer,
+        TopLevelAccelerationStructure,
+    },
+    device_mesh::{find_memorytype_index, Buffer},
+    primitive::Primitive,
 };
 use std::{
+    cell::Cell,
     io::{Cursor, Write},
     mem::size_of,
     rc::Rc,
     time::Instant,
 };
 
-use ash::vk::{self, ShaderStageFlags};
-use cgmath::{Point3, Vector3, Vector4};
-use log::{debug, trace};
-use winit::event::WindowEvent;
+use ash::vk;
+use cgmath::Point3;
+use log::{debug, trace, warn};
 
-use crate::{device_mesh::DeviceMesh, shader::ShaderPipeline, uniforms::PushConstants};
+use crate::{
+    device_mesh::DeviceMesh,
+    gpu_timer::GpuTimer,
+    sampler::SamplerCache,
+    shader::{
+        specialization_constant, ShaderPipeline, ENCODE_SRGB_CONSTANT_ID, SHADOWS_CONSTANT_ID,
+        TONEMAP_CONSTANT_ID,
+    },
+    texture::Texture,
+    uniforms::{Background, Camera, CameraBuffer, CameraUniforms, Lights, PushConstants},
+};
 
-use super::{RenderStyle, Renderer};
+use super::{
+    check_swapchain_idx, create_per_image, is_empty_extent, needs_srgb_encoding, Region,
+    RenderStyle, Renderer,
+};
 
 pub struct RayTrace<'device> {
     image_views: Vec<vk::ImageView>,
     device: &'device ash::Device,
+    pipeline_cache: vk::PipelineCache,
     shader_pipeline: ShaderPipeline<'device>,
     pipeline: Option<vk::Pipeline>,
     pipeline_layout: Option<vk::PipelineLayout>,
     resolution: vk::Rect2D,
     uniforms: Option<PushConstants>,
     size: vk::Extent2D,
-    zoom: f32,
-    rotation: f32,
-    translation: Point3<f32>,
-    middle_drag: bool,
+    camera: Camera,
     toplevel_as: Option<TopLevelAccelerationStructure<'device>>,
+    /// Reused by all acceleration structure builds
+    scratch_buffer: ScratchBuffer<'device>,
+    compact_acceleration_structures: bool,
+    /// Winding of the triangles that camera rays do not cull
+    front_face: vk::FrontFace,
+    /// Implicit surfaces traced in addition to the meshes
+    primitives: Vec<Primitive>,
     raytracing_tracing_ext: ash::extensions::khr::RayTracingPipeline,
     acceleration_structure_ext: ash::extensions::khr::AccelerationStructure,
     rt_pipeline_properties: vk::PhysicalDeviceRayTracingPipelinePropertiesKHR<'device>,
     descriptor_set: Option<vk::DescriptorSet>,
+    /// Set 1 holding the storage image, one per swapchain image so that frames in flight keep
+    /// their descriptors. Empty if the image is pushed with `push_descriptor_ext` instead.
+    image_descriptor_sets: Vec<vk::DescriptorSet>,
     descriptor_pool: Option<vk::DescriptorPool>,
+    push_descriptor_ext: Option<ash::extensions::khr::PushDescriptor>,
     sbt: Option<Buffer<'device>>,
+    max_recursion_depth: u32,
+    accumulation: Option<AccumulationImage<'device>>,
+    /// Samples accumulated since the last camera, mesh or resolution change
+    sample_index: Cell<u32>,
+    max_samples: Option<u32>,
+    lights: Lights,
+    lights_buffer: Option<Buffer<'device>>,
+    camera_buffer: Option<CameraBuffer<'device>>,
+    background: Background,
+    /// Equirectangular environment sampled by rays that miss, a dummy texture if
+    /// `use_environment_map` is false
+    environment_map: Option<Texture<'device>>,
+    use_environment_map: bool,
+    /// White texture bound for meshes without a base color texture
+    default_texture: Option<Texture<'device>>,
+    samplers: Rc<SamplerCache<'device>>,
+    tonemap: Tonemap,
+    /// Part of the swapchain images traced, `resolution` covers it
+    region: Region,
+}
+
+/// Operator mapping the linear radiance of the accumulation image to the displayable range.
+/// Discriminants match the `tonemap` specialization constant of `raygen.glsl`.
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Default)]
+pub enum Tonemap {
+    /// Clamp to `[0, 1]`, highlights clip
+    #[default]
+    Clamp,
+    Reinhard,
+    /// Curve fit of the ACES filmic tonemapper by Krzysztof Narkowicz
+    Aces,
+}
+
+/// Miss shaders in the order of their SBT records: background and shadow miss
+const NUM_MISS_SHADERS: u32 = 2;
+
+/// Float image the traced samples of consecutive frames are summed in
+struct AccumulationImage<'device> {
+    device: &'device ash::Device,
+    image: vk::Image,
+    memory: vk::DeviceMemory,
+    view: vk::ImageView,
+}
+
+impl<'device> AccumulationImage<'device> {
+    const FORMAT: vk::Format = vk::Format::R32G32B32A32_SFLOAT;
+
+    fn new(
+        device: &'device ash::Device,
+        mem_properties: &vk::PhysicalDeviceMemoryProperties,
+        size: vk::Extent2D,
+    ) -> anyhow::Result<Self> {
+        unsafe {
+            let image = device.create_image(
+                &vk::ImageCreateInfo::default()
+                    .image_type(vk::ImageType::TYPE_2D)
+                    .format(Self::FORMAT)
+                    .extent(size.into())
+                    .mip_levels(1)
+                    .array_layers(1)
+                    .samples(vk::SampleCountFlags::TYPE_1)
+                    .tiling(vk::ImageTiling::OPTIMAL)
+                    .usage(vk::ImageUsageFlags::STORAGE)
+                    .sharing_mode(vk::SharingMode::EXCLUSIVE)
+                    .initial_layout(vk::ImageLayout::UNDEFINED),
+                None,
+            )?;
+            let req = device.get_image_memory_requirements(image);
+            let index =
+                find_memorytype_index(&req, mem_properties, vk::MemoryPropertyFlags::DEVICE_LOCAL)
+                    .ok_or_else(|| {
+                        anyhow::anyhow!("Failed to get memory index for accumulation image")
+                    })?;
+            let memory = device.allocate_memory(
+                &vk::MemoryAllocateInfo::default()
+                    .allocation_size(req.size)
+                    .memory_type_index(index),
+                None,
+            )?;
+            device.bind_image_memory(image, memory, 0)?;
+            let view = device.create_image_view(
+                &vk::ImageViewCreateInfo::default()
+                    .view_type(vk::ImageViewType::TYPE_2D)
+                    .format(Self::FORMAT)
+                    .subresource_range(COLOR_SUBRESOURCE_RANGE)
+                    .image(image),
+                None,
+            )?;
+            Ok(Self {
+                device,
+                image,
+                memory,
+                view,
+            })
+        }
+    }
+}
+
+impl Drop for AccumulationImage<'_> {
+    fn drop(&mut self) {
+        unsafe {
+            self.device.destroy_image_view(self.view, None);
+            self.device.destroy_image(self.image, None);
+            self.device.free_memory(self.memory, None);
+        }
+    }
 }
 
-static NUM_ATTRIBUTES: usize = 2;
+const COLOR_SUBRESOURCE_RANGE: vk::ImageSubresourceRange = vk::ImageSubresourceRange {
+    aspect_mask: vk::ImageAspectFlags::COLOR,
+    base_mip_level: 0,
+    level_count: 1,
+    base_array_layer: 0,
+    layer_count: 1,
+};
+
+/// Row-major 3x4 instance transform of geometry that is not moved
+const IDENTITY_TRANSFORM: [f32; 12] = [
+    1.0, 0.0, 0.0, 0.0, //
+    0.0, 1.0, 0.0, 0.0, //
+    0.0, 0.0, 1.0, 0.0,
+];
+
+/// 8 byte fields in each hit record, see [`hit_record_stride`]
+static NUM_ATTRIBUTES: usize = 10;
+
+/// Offset of the base color behind the shader handle of a hit record. The four attribute
+/// addresses come first so that the `vec4` is 16 byte aligned in the std430 `SBT` block.
+const BASE_COLOR_OFFSET: u64 = 4 * size_of::<vk::DeviceAddress>() as u64;
+
+const PUSH_CONSTANT_STAGES: vk::ShaderStageFlags = vk::ShaderStageFlags::from_raw(
+    vk::ShaderStageFlags::RAYGEN_KHR.as_raw() | vk::ShaderStageFlags::MISS_KHR.as_raw(),
+);
 
 impl<'device> RayTrace<'device> {
     pub fn new(
         device: &'device ash::Device,
         instance: &ash::Instance,
         rt_pipeline_properties: vk::PhysicalDeviceRayTracingPipelinePropertiesKHR<'device>,
+        pipeline_cache: vk::PipelineCache,
     ) -> anyhow::Result<Self> {
         Ok(Self {
-            zoom: 1.0,
             image_views: Default::default(),
             device,
+            pipeline_cache,
             shader_pipeline: ShaderPipeline::new(
                 device,
                 &[
                     &include_bytes!("../../shaders/raygen.glsl.spirv")[..],
                     &include_bytes!("../../shaders/miss.glsl.spirv")[..],
                     &include_bytes!("../../shaders/closest_hit.glsl.spirv")[..],
+                    &include_bytes!("../../shaders/anyhit.glsl.spirv")[..],
+                    &include_bytes!("../../shaders/sphere_intersection.glsl.spirv")[..],
+                    &include_bytes!("../../shaders/shadow_miss.glsl.spirv")[..],
                 ],
             )?,
-            translation: Point3 {
-                x: 0.0,
-                y: 0.0,
-                z: 0.0,
-            },
+            camera: Camera::new(Point3::new(0.0, 0.0, 0.0)),
             pipeline: Default::default(),
             pipeline_layout: Default::default(),
             resolution: Default::default(),
@@ -73,9 +229,11 @@ impl<'device> RayTrace<'device> {
                 width: 0,
                 height: 0,
             },
-            rotation: 0.0,
-            middle_drag: false,
             toplevel_as: Default::default(),
+            scratch_buffer: Default::default(),
+            compact_acceleration_structures: false,
+            front_face: vk::FrontFace::COUNTER_CLOCKWISE,
+            primitives: Vec::new(),
             acceleration_structure_ext: ash::extensions::khr::AccelerationStructure::new(
                 instance, device,
             ),
@@ -83,23 +241,163 @@ impl<'device> RayTrace<'device> {
             rt_pipeline_properties,
             sbt: None,
             descriptor_set: None,
+            image_descriptor_sets: Vec::new(),
             descriptor_pool: None,
+            push_descriptor_ext: None,
+            max_recursion_depth: 1,
+            accumulation: None,
+            sample_index: Cell::new(0),
+            max_samples: None,
+            lights: Lights::default(),
+            lights_buffer: None,
+            camera_buffer: None,
+            background: Background::default(),
+            environment_map: None,
+            use_environment_map: false,
+            default_texture: None,
+            samplers: Rc::new(SamplerCache::new(device, 1.0)),
+            tonemap: Tonemap::default(),
+            region: Region::default(),
         })
     }
 
-    fn num_instances(&self) -> u32 {
+    /// Stop tracing new samples once `max_samples` frames have been accumulated for the current
+    /// view. Accumulates indefinitely for `None`.
+    #[must_use]
+    pub fn with_max_samples(mut self, max_samples: Option<u32>) -> Self {
+        self.max_samples = max_samples.map(|m| m.max(1));
+        self
+    }
+
+    /// Compact bottom level acceleration structures after building them, which saves memory
+    /// but takes longer to load meshes
+    #[must_use]
+    pub fn with_compaction(mut self, compact: bool) -> Self {
+        self.compact_acceleration_structures = compact;
+        self
+    }
+
+    /// Set the winding of front faces. Takes effect on the next `set_meshes`.
+    #[must_use]
+    pub fn with_front_face(mut self, front_face: vk::FrontFace) -> Self {
+        self.front_face = front_face;
+        self
+    }
+
+    /// Trace `primitives` next to the meshes. Takes effect on the next `set_meshes`.
+    #[must_use]
+    pub fn with_primitives(mut self, primitives: Vec<Primitive>) -> Self {
+        self.primitives = primitives;
+        self
+    }
+
+    /// Set the color of rays missing all meshes
+    #[must_use]
+    pub fn with_background(mut self, background: Background) -> Self {
+        self.background = background;
+        self
+    }
+
+    /// Share samplers with other renderers and textures
+    #[must_use]
+    pub fn with_samplers(mut self, samplers: Rc<SamplerCache<'device>>) -> Self {
+        self.samplers = samplers;
+        self
+    }
+
+    /// Let rays that miss all meshes sample an equirectangular environment map instead of the
+    /// background color
+    #[must_use]
+    pub fn with_environment_map(mut self, environment_map: Option<Texture<'device>>) -> Self {
+        self.use_environment_map = environment_map.is_some();
+        self.environment_map = environment_map;
+        self
+    }
+
+    /// Set the tonemapping operator applied before writing to the swapchain. Takes effect on the
+    /// next `set_resolution`.
+    #[must_use]
+    pub fn with_tonemap(mut self, tonemap: Tonemap) -> Self {
+        self.tonemap = tonemap;
+        self
+    }
+
+    /// Push the storage image descriptor in each frame instead of rewriting a descriptor set.
+    /// Requires `VK_KHR_push_descriptor` to be enabled if `supported`. Takes effect on the next
+    /// `set_resolution`.
+    #[must_use]
+    pub fn with_push_descriptors(mut self, instance: &ash::Instance, supported: bool) -> Self {
+        self.push_descriptor_ext =
+            supported.then(|| ash::extensions::khr::PushDescriptor::new(instance, self.device));
+        self
+    }
+
+    /// Discard the accumulated samples, e.g. because the view changed
+    fn reset_accumulation(&self) {
+        self.sample_index.set(0);
+    }
+
+    /// Set the maximum ray recursion depth of the pipeline (clamped to the device limit). From a
+    /// depth of 2 on, hits are shadowed by tracing rays towards the lights. Takes effect on the
+    /// next `set_resolution`.
+    #[must_use]
+    pub fn with_recursion_depth(mut self, max_recursion_depth: u32) -> Self {
+        let device_limit = self.rt_pipeline_properties.max_ray_recursion_depth;
+        if max_recursion_depth > device_limit {
+            warn!("Requested ray recursion depth {max_recursion_depth} exceeds device limit {device_limit}");
+        }
+        self.max_recursion_depth = max_recursion_depth.clamp(1, device_limit.max(1));
+        self
+    }
+
+    /// Number of hit records, one per geometry of each bottom level acceleration structure
+    fn num_geometries(&self) -> u32 {
         self.toplevel_as
             .as_ref()
-            .map(|a| a.bottomlevel_as().len() as u32)
+            .map(|a| a.hit_geometries().len() as u32)
             .unwrap_or(0)
     }
 
+    /// White default texture followed by the distinct base color textures of the meshes, bound
+    /// as an array for the alpha test of the any-hit shader
+    fn textures(&self) -> Vec<&Texture<'device>> {
+        let mut textures: Vec<&Texture<'device>> = self.default_texture.iter().collect();
+        for geometry in self
+            .toplevel_as
+            .iter()
+            .flat_map(|toplevel_as| toplevel_as.hit_geometries())
+        {
+            if let HitGeometry::Triangles(mesh) = geometry {
+                if let Some(texture) = mesh.texture() {
+                    if !textures.iter().any(|&t| std::ptr::eq(t, &**texture)) {
+                        textures.push(texture);
+                    }
+                }
+            }
+        }
+        textures
+    }
+
+    /// Raygen, miss, hit and callable regions of the SBT for `cmd_trace_rays`, `None` before
+    /// `set_resolution` created the SBT
+    fn sbt_regions(&self) -> Option<[vk::StridedDeviceAddressRegionKHR; 4]> {
+        Some(sbt_layout(
+            &self.rt_pipeline_properties,
+            self.num_geometries(),
+            self.sbt.as_ref()?.device_address(),
+        ))
+    }
+
     fn destroy_descriptor_sets(&mut self) {
         unsafe {
             if let Some(pool) = self.descriptor_pool.take() {
-                self.descriptor_set
+                let sets: Vec<_> = self
+                    .descriptor_set
                     .take()
-                    .map(|l| self.device.free_descriptor_sets(pool, &[l]));
+                    .into_iter()
+                    .chain(self.image_descriptor_sets.drain(..))
+                    .collect();
+                let _ = self.device.free_descriptor_sets(pool, &sets);
                 self.device.destroy_descriptor_pool(pool, None);
             }
         }
@@ -133,13 +431,11 @@ impl<'device> RayTrace<'device> {
     }
 
     fn update_push_constants(&mut self) {
-        self.uniforms = Some(PushConstants::new(
-            self.size,
-            self.translation,
-            Vector4::new(2.0, 0.0, 0.0, 1.0),
-            self.zoom,
-            self.rotation,
-        ));
+        self.uniforms = Some(
+            PushConstants::default()
+                .with_background(&self.background)
+                .with_environment_map(self.use_environment_map),
+        );
     }
 }
 
@@ -148,106 +444,165 @@ impl<'device> Renderer<'device> for RayTrace<'device> {
         &self,
         device: &ash::Device,
         cmd: vk::CommandBuffer,
-        _image: vk::Image,
+        image: vk::Image,
         _start_instant: Instant,
         swapchain_idx: usize,
+        timer: GpuTimer,
     ) -> anyhow::Result<()> {
         trace!("draw for {self:?}");
-        if self.toplevel_as.is_some() {
-            let accel_structs = [self
-                .toplevel_as
+        if is_empty_extent(self.resolution.extent) {
+            return Ok(());
+        }
+        if let (Some(_), Some(camera_buffer)) = (&self.toplevel_as, &self.camera_buffer) {
+            camera_buffer.cmd_update(
+                device,
+                cmd,
+                CameraUniforms::new(self.resolution.extent, &self.camera),
+            );
+            let sample_index = self.sample_index.get();
+            let max_samples = self.max_samples.unwrap_or(0);
+            if max_samples == 0 || sample_index < max_samples {
+                self.sample_index.set(sample_index + 1);
+            }
+            let accumulation = self
+                .accumulation
                 .as_ref()
-                .ok_or_else(|| anyhow::anyhow!("No toplevel acceleration structure"))?
-                .structure()];
-            let mut accel_info = vk::WriteDescriptorSetAccelerationStructureKHR::default()
-                .acceleration_structures(&accel_structs);
-
-            let mut accel_write = vk::WriteDescriptorSet::default()
-                .dst_set(self.descriptor_set.unwrap())
-                .dst_binding(0)
-                .dst_array_element(0)
-                .descriptor_type(vk::DescriptorType::ACCELERATION_STRUCTURE_KHR)
-                .push_next(&mut accel_info);
-
-            // This is only set by the builder for images, buffers, or views; need to set explicitly after
-            accel_write.descriptor_count = 1;
-
-            let image_info = [vk::DescriptorImageInfo::default()
-                .image_layout(vk::ImageLayout::GENERAL)
-                .image_view(self.image_views[swapchain_idx])];
-
-            // TODO: Probably the image should be a PushConstant
-            let image_write = vk::WriteDescriptorSet::default()
-                .dst_set(self.descriptor_set.unwrap())
-                .dst_binding(1)
-                .dst_array_element(0)
-                .descriptor_type(vk::DescriptorType::STORAGE_IMAGE)
-                .image_info(&image_info);
-
+                .ok_or_else(|| anyhow::anyhow!("No accumulation image"))?;
+            // Previous frames on the queue read and write the same accumulation image. The first
+            // sample discards whatever was accumulated before.
+            let (old_layout, src_access_mask) = if sample_index == 0 {
+                (vk::ImageLayout::UNDEFINED, vk::AccessFlags::empty())
+            } else {
+                (vk::ImageLayout::GENERAL, vk::AccessFlags::SHADER_WRITE)
+            };
             unsafe {
-                trace!("update_descriptor_sets");
-                device.update_descriptor_sets(&[accel_write, image_write], &[]);
+                device.cmd_pipeline_barrier(
+                    cmd,
+                    vk::PipelineStageFlags::RAY_TRACING_SHADER_KHR,
+                    vk::PipelineStageFlags::RAY_TRACING_SHADER_KHR,
+                    vk::DependencyFlags::empty(),
+                    &[],
+                    &[],
+                    &[vk::ImageMemoryBarrier::default()
+                        .src_access_mask(src_access_mask)
+                        .dst_access_mask(
+                            vk::AccessFlags::SHADER_READ | vk::AccessFlags::SHADER_WRITE,
+                        )
+                        .old_layout(old_layout)
+                        .new_layout(vk::ImageLayout::GENERAL)
+                        .image(accumulation.image)
+                        .subresource_range(COLOR_SUBRESOURCE_RANGE)],
+                );
+                // Swapchain images arrive in `PRESENT_SRC_KHR` (or `UNDEFINED` when fresh) once
+                // the acquire semaphore waited at `COLOR_ATTACHMENT_OUTPUT` or renderers composed
+                // before this one finished. If every pixel is traced, the old contents can be
+                // discarded, otherwise the rest of the image has to be kept.
+                let old_layout = if self.region == Region::default() {
+                    vk::ImageLayout::UNDEFINED
+                } else {
+                    vk::ImageLayout::PRESENT_SRC_KHR
+                };
+                device.cmd_pipeline_barrier(
+                    cmd,
+                    vk::PipelineStageFlags::ALL_COMMANDS,
+                    vk::PipelineStageFlags::RAY_TRACING_SHADER_KHR,
+                    vk::DependencyFlags::empty(),
+                    &[],
+                    &[],
+                    &[vk::ImageMemoryBarrier::default()
+                        .src_access_mask(vk::AccessFlags::MEMORY_WRITE)
+                        .dst_access_mask(vk::AccessFlags::SHADER_WRITE)
+                        .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                        .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                        .old_layout(old_layout)
+                        .new_layout(vk::ImageLayout::GENERAL)
+                        .image(image)
+                        .subresource_range(COLOR_SUBRESOURCE_RANGE)],
+                );
             }
 
             {
-                let sbt_address = self.sbt.as_ref().unwrap().device_address();
-
-                let aligned_size = aligned_size(
-                    self.rt_pipeline_properties.shader_group_handle_size,
-                    self.rt_pipeline_properties.shader_group_base_alignment,
-                ) as u64;
-                let sbt_raygen_region = vk::StridedDeviceAddressRegionKHR::default()
-                    .device_address(sbt_address)
-                    .size(self.rt_pipeline_properties.shader_group_handle_size.into())
-                    .stride(aligned_size);
-
-                let sbt_miss_region = vk::StridedDeviceAddressRegionKHR::default()
-                    .device_address(sbt_address + aligned_size)
-                    .size(aligned_size)
-                    .stride(self.rt_pipeline_properties.shader_group_handle_size.into());
-
-                let sbt_hit_region = vk::StridedDeviceAddressRegionKHR::default()
-                    .device_address(sbt_address + 2 * aligned_size)
-                    .size(aligned_size * self.num_instances() as u64)
-                    .stride(self.rt_pipeline_properties.shader_group_handle_size.into());
-
-                let sbt_call_region = vk::StridedDeviceAddressRegionKHR::default();
+                let [sbt_raygen_region, sbt_miss_region, sbt_hit_region, sbt_call_region] = self
+                    .sbt_regions()
+                    .ok_or_else(|| anyhow::anyhow!("No shader binding table created"))?;
 
+                check_swapchain_idx(swapchain_idx, self.image_views.len())?;
                 unsafe {
                     device.cmd_bind_pipeline(
                         cmd,
                         vk::PipelineBindPoint::RAY_TRACING_KHR,
                         self.pipeline.unwrap(),
                     );
+                    let descriptor_sets: Vec<_> = self
+                        .descriptor_set
+                        .into_iter()
+                        .chain(self.image_descriptor_sets.get(swapchain_idx).copied())
+                        .collect();
                     device.cmd_bind_descriptor_sets(
                         cmd,
                         vk::PipelineBindPoint::RAY_TRACING_KHR,
                         self.pipeline_layout.unwrap(),
                         0,
-                        &[self.descriptor_set.unwrap()],
+                        &descriptor_sets,
                         &[],
                     );
+                    if let Some(push_descriptor_ext) = self.push_descriptor_ext.as_ref() {
+                        let image_info = [vk::DescriptorImageInfo::default()
+                            .image_layout(vk::ImageLayout::GENERAL)
+                            .image_view(self.image_views[swapchain_idx])];
+                        push_descriptor_ext.cmd_push_descriptor_set(
+                            cmd,
+                            vk::PipelineBindPoint::RAY_TRACING_KHR,
+                            self.pipeline_layout.unwrap(),
+                            1,
+                            &[vk::WriteDescriptorSet::default()
+                                .dst_binding(0)
+                                .descriptor_type(vk::DescriptorType::STORAGE_IMAGE)
+                                .image_info(&image_info)],
+                        );
+                    }
 
                     self.device.cmd_push_constants(
                         cmd,
                         self.pipeline_layout.unwrap(),
-                        vk::ShaderStageFlags::RAYGEN_KHR,
+                        PUSH_CONSTANT_STAGES,
                         0,
                         &std::mem::transmute::<PushConstants, [u8; size_of::<PushConstants>()]>(
-                            self.uniforms.unwrap(),
+                            self.uniforms
+                                .unwrap()
+                                .with_samples(sample_index, max_samples)
+                                .with_offset(self.resolution.offset),
                         ),
                     );
                     trace!("cmd_trace_rays");
+                    timer.cmd_begin(device, cmd, vk::PipelineStageFlags::TOP_OF_PIPE);
                     self.raytracing_tracing_ext.cmd_trace_rays(
                         cmd,
                         &sbt_raygen_region,
                         &sbt_miss_region,
                         &sbt_hit_region,
                         &sbt_call_region,
-                        self.size.width,
-                        self.size.height,
+                        self.resolution.extent.width,
+                        self.resolution.extent.height,
                         1,
                     );
+                    timer.cmd_end(device, cmd, vk::PipelineStageFlags::RAY_TRACING_SHADER_KHR);
+                    device.cmd_pipeline_barrier(
+                        cmd,
+                        vk::PipelineStageFlags::RAY_TRACING_SHADER_KHR,
+                        vk::PipelineStageFlags::BOTTOM_OF_PIPE,
+                        vk::DependencyFlags::empty(),
+                        &[],
+                        &[],
+                        &[vk::ImageMemoryBarrier::default()
+                            .src_access_mask(vk::AccessFlags::SHADER_WRITE)
+                            .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                            .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                            .old_layout(vk::ImageLayout::GENERAL)
+                            .new_layout(vk::ImageLayout::PRESENT_SRC_KHR)
+                            .image(image)
+                            .subresource_range(COLOR_SUBRESOURCE_RANGE)],
+                    );
                 }
             }
         }
@@ -261,55 +616,121 @@ impl<'device> Renderer<'device> for RayTrace<'device> {
         graphics_queue: vk::Queue,
         device_memory_properties: &vk::PhysicalDeviceMemoryProperties,
     ) -> anyhow::Result<()> {
-        self.translation = meshes
+        if self.environment_map.is_none() {
+            self.environment_map = Some(Texture::white(
+                self.device,
+                device_memory_properties,
+                cmd,
+                graphics_queue,
+                &self.samplers,
+            )?);
+        }
+        if self.default_texture.is_none() {
+            self.default_texture = Some(Texture::white(
+                self.device,
+                device_memory_properties,
+                cmd,
+                graphics_queue,
+                &self.samplers,
+            )?);
+        }
+        // Bottom level structures of meshes that were already traced are kept, so that meshes
+        // arriving one file at a time only build their own
+        let mut previous_bottomlevel_as = Vec::new();
+        if let Some(toplevel_as) = self.toplevel_as.take() {
+            // Previous frames might still trace against the old top level acceleration structure
+            unsafe { self.device.device_wait_idle()? };
+            previous_bottomlevel_as = toplevel_as
+                .bottomlevel_as()
+                .iter()
+                .map(|(bottomlevel_as, _)| Rc::clone(bottomlevel_as))
+                .collect();
+        }
+        // Points have no surface to intersect rays with, only the rasterizer draws them
+        let meshes: Vec<_> = meshes
             .iter()
-            .flat_map(|mesh| mesh.mesh().positions().iter())
-            .fold(
-                Point3 {
-                    x: 0.0f32,
-                    y: 0.0,
-                    z: 0.0,
-                },
-                |i, &p| {
-                    i + Vector3 {
-                        x: p.x,
-                        y: p.y,
-                        z: p.z,
-                    }
-                },
-            )
-            / meshes.iter().map(|mesh| mesh.num_vertices()).sum::<usize>() as f32;
-        let bottomlevel_as = meshes
+            .filter(|m| !m.mesh().is_point_cloud())
+            .cloned()
+            .collect();
+        // Consecutive meshes of the same group (e.g. from the same file) share one
+        // multi-geometry bottom level acceleration structure and thus its transform
+        let mut groups: Vec<&[Rc<DeviceMesh<'device>>]> = Vec::new();
+        let mut rest = &meshes[..];
+        while let Some(first) = rest.first() {
+            let len = match first.group() {
+                Some(group) => rest.iter().take_while(|m| m.group() == Some(group)).count(),
+                None => 1,
+            };
+            let (group, tail) = rest.split_at(len);
+            groups.push(group);
+            rest = tail;
+        }
+        let kept: Vec<_> = groups
             .iter()
-            .enumerate()
-            .flat_map(|(i, m)| {
-                Some((
-                    BottomLevelAccelerationStructure::build_bottomlevel(
-                        cmd,
-                        self.device,
-                        Rc::clone(m),
-                        device_memory_properties,
-                        &self.acceleration_structure_ext,
-                        graphics_queue,
-                    )
-                    .ok()?,
-                    [
-                        1.0,
-                        0.0,
-                        0.0,
-                        100.0 * i as f32,
-                        0.0,
-                        1.0 + i as f32,
-                        0.0,
-                        0.0,
-                        0.0,
-                        0.0,
-                        1.0,
-                        0.0,
-                    ],
-                ))
+            .map(|group| {
+                previous_bottomlevel_as
+                    .iter()
+                    .find(|bottomlevel_as| bottomlevel_as.is_built_from(group))
+                    .cloned()
             })
             .collect();
+        let new_groups: Vec<_> = groups
+            .iter()
+            .zip(&kept)
+            .filter(|(_, kept)| kept.is_none())
+            .map(|(&group, _)| group)
+            .collect();
+        debug!(
+            "Keeping {} bottom level acceleration structures",
+            groups.len() - new_groups.len()
+        );
+        let mut built = BottomLevelAccelerationStructure::build_bottomlevels(
+            cmd,
+            self.device,
+            &new_groups,
+            device_memory_properties,
+            &self.acceleration_structure_ext,
+            graphics_queue,
+            &mut self.scratch_buffer,
+            self.compact_acceleration_structures,
+        )?
+        .into_iter()
+        .map(Rc::new);
+        let mut bottomlevel_as = kept
+            .into_iter()
+            .zip(groups.iter())
+            .map(|(kept, group)| {
+                let bottomlevel_as = kept.or_else(|| built.next()).ok_or_else(|| {
+                    anyhow::anyhow!("Missing bottom level acceleration structure")
+                })?;
+                Ok((bottomlevel_as, group[0].instance_transform(), None))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        if !self.primitives.is_empty() {
+            let procedural = match previous_bottomlevel_as.iter().find(|b| b.is_procedural()) {
+                Some(procedural) => Rc::clone(procedural),
+                None => Rc::new(BottomLevelAccelerationStructure::build_procedural(
+                    cmd,
+                    self.device,
+                    ProceduralGeometry::new(
+                        self.device,
+                        device_memory_properties,
+                        &self.primitives,
+                    )?,
+                    device_memory_properties,
+                    &self.acceleration_structure_ext,
+                    graphics_queue,
+                    &mut self.scratch_buffer,
+                )?),
+            };
+            bottomlevel_as.push((procedural, IDENTITY_TRANSFORM, None));
+        }
+        if bottomlevel_as.is_empty() {
+            // A top level acceleration structure without instances would have an empty instance
+            // buffer, there is nothing to trace until meshes arrive
+            self.reset_accumulation();
+            return Ok(());
+        }
         self.toplevel_as = Some(TopLevelAccelerationStructure::build_toplevel(
             cmd,
             self.device,
@@ -317,8 +738,48 @@ impl<'device> Renderer<'device> for RayTrace<'device> {
             device_memory_properties,
             self.acceleration_structure_ext.clone(),
             graphics_queue,
-            NUM_ATTRIBUTES as u32,
+            true,
+            // Culling is left to the ray flags of the raygen shader
+            match self.front_face {
+                vk::FrontFace::CLOCKWISE => vk::GeometryInstanceFlagsKHR::empty(),
+                _ => vk::GeometryInstanceFlagsKHR::TRIANGLE_FRONT_COUNTERCLOCKWISE,
+            },
+            &mut self.scratch_buffer,
         )?);
+        self.reset_accumulation();
+        Ok(())
+    }
+
+    fn update_transforms(
+        &mut self,
+        cmd: vk::CommandBuffer,
+        graphics_queue: vk::Queue,
+    ) -> anyhow::Result<()> {
+        if let Some(toplevel_as) = self.toplevel_as.as_mut() {
+            let transforms: Vec<_> = toplevel_as
+                .bottomlevel_as()
+                .iter()
+                .map(|(bottomlevel_as, transform)| {
+                    // Procedural geometry isn't transformed by the scene file
+                    let (transform, group) = match bottomlevel_as.meshes().first() {
+                        Some(mesh) => (mesh.instance_transform(), mesh.group()),
+                        None => return *transform,
+                    };
+                    if bottomlevel_as
+                        .meshes()
+                        .iter()
+                        .any(|m| m.instance_transform() != transform)
+                    {
+                        warn!("Meshes of group {group:?} got different transforms, using the first one");
+                    }
+                    transform
+                })
+                .collect();
+            // Refitting happens in place while previous frames might still trace against it
+            unsafe { self.device.device_wait_idle()? };
+            toplevel_as.update_instances(cmd, graphics_queue, &transforms)?;
+        }
+        self.reset_accumulation();
         Ok(())
     }
 
@@ -328,14 +789,59 @@ impl<'device> Renderer<'device> for RayTrace<'device> {
         size: vk::Extent2D,
         images: &[vk::Image],
         device_memory_properties: &vk::PhysicalDeviceMemoryProperties,
-        _render_style: RenderStyle,
+        render_style: RenderStyle,
     ) -> anyhow::Result<()> {
         let device = self.device;
         debug!("Set resolution: {size:?} images: {images:?}");
         self.destroy_images()?;
         self.destroy_descriptor_sets();
-        self.update_push_constants();
         self.size = size;
+        self.resolution = self.region.rect(size);
+        if is_empty_extent(self.resolution.extent) {
+            return Ok(());
+        }
+        self.update_push_constants();
+        let encode_srgb = needs_srgb_encoding(surface_format.format);
+        debug!(
+            "Tonemap: {:?}, sRGB encoding in shader: {encode_srgb}",
+            self.tonemap
+        );
+        self.shader_pipeline.set_specialization_constants(&[
+            specialization_constant(TONEMAP_CONSTANT_ID, self.tonemap as u32),
+            specialization_constant(ENCODE_SRGB_CONSTANT_ID, vk::Bool32::from(encode_srgb)),
+            specialization_constant(
+                SHADOWS_CONSTANT_ID,
+                vk::Bool32::from(self.max_recursion_depth >= 2),
+            ),
+        ]);
+        self.accumulation = Some(AccumulationImage::new(
+            device,
+            device_memory_properties,
+            self.resolution.extent,
+        )?);
+        self.reset_accumulation();
+        if self.lights_buffer.is_none() {
+            self.lights_buffer = Some(Buffer::new(
+                device,
+                device_memory_properties,
+                &vk::BufferCreateInfo::default()
+                    .size(size_of::<Lights>() as vk::DeviceSize)
+                    .usage(vk::BufferUsageFlags::UNIFORM_BUFFER)
+                    .sharing_mode(vk::SharingMode::EXCLUSIVE),
+                Some(std::slice::from_ref(&self.lights)),
+            )?);
+        }
+        if self.camera_buffer.is_none() {
+            self.camera_buffer = Some(CameraBuffer::new(
+                device,
+                device_memory_properties,
+                vk::PipelineStageFlags::RAY_TRACING_SHADER_KHR,
+            )?);
+        }
+        if self.toplevel_as.is_none() {
+            // The pipeline, SBT and descriptors are created once there is something to trace
+            return Ok(());
+        }
 
         let mut shader_groups = vec![
             // raygen
@@ -346,31 +852,47 @@ impl<'device> Renderer<'device> for RayTrace<'device> {
                 .any_hit_shader(vk::SHADER_UNUSED_KHR)
                 .intersection_shader(vk::SHADER_UNUSED_KHR),
         ];
-        shader_groups.push(
-            // miss
-            vk::RayTracingShaderGroupCreateInfoKHR::default()
-                .ty(vk::RayTracingShaderGroupTypeKHR::GENERAL)
-                .general_shader(1)
-                .closest_hit_shader(vk::SHADER_UNUSED_KHR)
-                .any_hit_shader(vk::SHADER_UNUSED_KHR)
-                .intersection_shader(vk::SHADER_UNUSED_KHR),
-        );
-        for _ in 0..self.num_instances() {
+        // Miss shaders: background (1) and shadow (5)
+        for miss_shader in [1, 5] {
             shader_groups.push(
-                // closest
                 vk::RayTracingShaderGroupCreateInfoKHR::default()
+                    .ty(vk::RayTracingShaderGroupTypeKHR::GENERAL)
+                    .general_shader(miss_shader)
+                    .closest_hit_shader(vk::SHADER_UNUSED_KHR)
+                    .any_hit_shader(vk::SHADER_UNUSED_KHR)
+                    .intersection_shader(vk::SHADER_UNUSED_KHR),
+            );
+        }
+        for geometry in self
+            .toplevel_as
+            .iter()
+            .flat_map(|toplevel_as| toplevel_as.hit_geometries())
+        {
+            shader_groups.push(match geometry {
+                HitGeometry::Triangles(_) => vk::RayTracingShaderGroupCreateInfoKHR::default()
                     .ty(vk::RayTracingShaderGroupTypeKHR::TRIANGLES_HIT_GROUP)
                     .general_shader(vk::SHADER_UNUSED_KHR)
                     .closest_hit_shader(2)
-                    .any_hit_shader(vk::SHADER_UNUSED_KHR)
+                    .any_hit_shader(3)
                     .intersection_shader(vk::SHADER_UNUSED_KHR),
-            );
+                HitGeometry::Procedural(_) => vk::RayTracingShaderGroupCreateInfoKHR::default()
+                    .ty(vk::RayTracingShaderGroupTypeKHR::PROCEDURAL_HIT_GROUP)
+                    .general_shader(vk::SHADER_UNUSED_KHR)
+                    .closest_hit_shader(2)
+                    .any_hit_shader(vk::SHADER_UNUSED_KHR)
+                    .intersection_shader(4),
+            });
         }
 
+        let num_textures = self.textures().len() as u32;
         let descriptor_set_layout = unsafe {
             let binding_flags_inner = [
                 vk::DescriptorBindingFlagsEXT::empty(),
                 vk::DescriptorBindingFlagsEXT::empty(),
+                vk::DescriptorBindingFlagsEXT::empty(),
+                vk::DescriptorBindingFlagsEXT::empty(),
+                vk::DescriptorBindingFlagsEXT::empty(),
+                vk::DescriptorBindingFlagsEXT::empty(),
             ];
 
             let mut binding_flags = vk::DescriptorSetLayoutBindingFlagsCreateInfoEXT::default()
@@ -386,28 +908,67 @@ impl<'device> Renderer<'device> for RayTrace<'device> {
                                     | vk::ShaderStageFlags::CLOSEST_HIT_KHR,
                             )
                             .binding(0),
+                        vk::DescriptorSetLayoutBinding::default()
+                            .descriptor_count(1)
+                            .descriptor_type(vk::DescriptorType::UNIFORM_BUFFER)
+                            .stage_flags(
+                                vk::ShaderStageFlags::RAYGEN_KHR
+                                    | vk::ShaderStageFlags::CLOSEST_HIT_KHR,
+                            )
+                            .binding(1),
                         vk::DescriptorSetLayoutBinding::default()
                             .descriptor_count(1)
                             .descriptor_type(vk::DescriptorType::STORAGE_IMAGE)
                             .stage_flags(vk::ShaderStageFlags::RAYGEN_KHR)
-                            .binding(1),
+                            .binding(2),
+                        vk::DescriptorSetLayoutBinding::default()
+                            .descriptor_count(1)
+                            .descriptor_type(vk::DescriptorType::UNIFORM_BUFFER)
+                            .stage_flags(vk::ShaderStageFlags::CLOSEST_HIT_KHR)
+                            .binding(3),
+                        vk::DescriptorSetLayoutBinding::default()
+                            .descriptor_count(1)
+                            .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+                            .stage_flags(vk::ShaderStageFlags::MISS_KHR)
+                            .binding(4),
+                        vk::DescriptorSetLayoutBinding::default()
+                            .descriptor_count(num_textures)
+                            .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+                            .stage_flags(vk::ShaderStageFlags::ANY_HIT_KHR)
+                            .binding(5),
                     ])
                     .push_next(&mut binding_flags),
                 None,
             )
         }?;
-        let max_recursion_depth = 1;
-
+        let image_set_layout = unsafe {
+            device.create_descriptor_set_layout(
+                &vk::DescriptorSetLayoutCreateInfo::default()
+                    .flags(if self.push_descriptor_ext.is_some() {
+                        vk::DescriptorSetLayoutCreateFlags::PUSH_DESCRIPTOR_KHR
+                    } else {
+                        vk::DescriptorSetLayoutCreateFlags::empty()
+                    })
+                    .bindings(&[vk::DescriptorSetLayoutBinding::default()
+                        .descriptor_count(1)
+                        .descriptor_type(vk::DescriptorType::STORAGE_IMAGE)
+                        .stage_flags(vk::ShaderStageFlags::RAYGEN_KHR)
+                        .binding(0)]),
+                None,
+            )
+        }?;
         let (pipeline, pipeline_layout) = self.shader_pipeline.make_rtx_pipeline(
             device,
+            self.pipeline_cache,
             &shader_groups,
             &self.raytracing_tracing_ext,
-            descriptor_set_layout,
-            max_recursion_depth,
+            &[descriptor_set_layout, image_set_layout],
+            self.max_recursion_depth,
             &[vk::PushConstantRange::default()
                 .offset(0)
                 .size(size_of::<PushConstants>().try_into()?)
-                .stage_flags(ShaderStageFlags::RAYGEN_KHR | ShaderStageFlags::CLOSEST_HIT_KHR)],
+                .stage_flags(PUSH_CONSTANT_STAGES)],
+            render_style,
         )?;
 
         let sbt = {
@@ -419,81 +980,101 @@ impl<'device> Renderer<'device> for RayTrace<'device> {
 
             let missdata = unsafe {
                 self.raytracing_tracing_ext
-                    .get_ray_tracing_shader_group_handles(pipeline, 1, 1, handle_size as usize)
+                    .get_ray_tracing_shader_group_handles(
+                        pipeline,
+                        1,
+                        NUM_MISS_SHADERS,
+                        (NUM_MISS_SHADERS * handle_size) as usize,
+                    )
             }?;
 
             let chit_data = unsafe {
                 self.raytracing_tracing_ext
                     .get_ray_tracing_shader_group_handles(
                         pipeline,
-                        2,
-                        self.num_instances(),
-                        handle_size as usize * self.num_instances() as usize,
+                        1 + NUM_MISS_SHADERS,
+                        self.num_geometries(),
+                        handle_size as usize * self.num_geometries() as usize,
                     )
             }?;
 
-            let table_size = aligned_size(
-                raygen_data.len() as u32,
-                self.rt_pipeline_properties.shader_group_base_alignment,
-            ) + self.num_instances()
-                * aligned_size(
-                    self.rt_pipeline_properties.shader_group_handle_size as u32
-                        + 2 * NUM_ATTRIBUTES as u32,
-                    self.rt_pipeline_properties.shader_group_base_alignment,
-                )
-                + aligned_size(
-                    missdata.len() as u32,
-                    self.rt_pipeline_properties.shader_group_base_alignment,
-                );
+            let (miss_offset, hit_offset) = sbt_region_offsets(&self.rt_pipeline_properties);
+            let miss_stride = miss_record_stride(&self.rt_pipeline_properties);
+            let hit_stride = hit_record_stride(&self.rt_pipeline_properties);
+            let table_size = hit_offset + self.num_geometries() * hit_stride;
             let mut table_data = vec![0u8; table_size as usize];
             let mut cur = Cursor::new(&mut table_data);
-            let mut written = 0;
-            written += cur.write(&raygen_data)?;
-            written = aligned_size(
-                written as u32,
-                self.rt_pipeline_properties.shader_group_base_alignment,
-            ) as usize;
-            cur.set_position(written as u64);
-
-            written += cur.write(&missdata)?;
-            written = aligned_size(
-                written as u32,
-                self.rt_pipeline_properties.shader_group_base_alignment,
-            ) as usize;
-            cur.set_position(written as u64);
-
-            for (i, mesh) in self
+            cur.write_all(&raygen_data)?;
+            for (i, handle) in missdata.chunks_exact(handle_size as usize).enumerate() {
+                cur.set_position((miss_offset + i as u32 * miss_stride).into());
+                cur.write_all(handle)?;
+            }
+
+            let hit_region_start = hit_offset as usize;
+            let textures = self.textures();
+            for (i, geometry) in self
                 .toplevel_as
                 .as_ref()
                 .unwrap()
-                .meshes()
+                .hit_geometries()
                 .iter()
                 .enumerate()
             {
-                written += cur.write(
+                cur.set_position((hit_region_start + i * hit_stride as usize) as u64);
+                cur.write_all(
                     &chit_data[i * self.rt_pipeline_properties.shader_group_handle_size as usize
                         ..((i + 1)
                             * self.rt_pipeline_properties.shader_group_handle_size as usize)],
                 )?;
-                written += cur.write(
+                let attributes_start = cur.position();
+                let mesh = match geometry {
+                    HitGeometry::Triangles(mesh) => mesh,
+                    HitGeometry::Procedural(procedural) => {
+                        cur.write_all(&procedural.primitives_device_address().to_le_bytes())?;
+                        cur.set_position(attributes_start + BASE_COLOR_OFFSET);
+                        for c in [1.0f32; 4] {
+                            cur.write_all(&c.to_le_bytes())?;
+                        }
+                        continue;
+                    }
+                };
+                cur.write_all(&mesh.indices_device_address().unwrap_or(0).to_le_bytes())?;
+                cur.write_all(
                     &mesh
-                        .indices_device_address()
-                        .ok_or_else(|| anyhow::anyhow!("No indices found on mesh"))?
+                        .normals_device_address()
+                        .ok_or_else(|| anyhow::anyhow!("No normals found on mesh"))?
                         .to_le_bytes(),
                 )?;
-                written += cur.write(
+                cur.write_all(
                     &mesh
-                        .normals_device_address()
-                        .ok_or_else(|| anyhow::anyhow!("No normals found on mesh"))?
+                        .positions_device_address()
+                        .ok_or_else(|| anyhow::anyhow!("No positions found on mesh"))?
                         .to_le_bytes(),
                 )?;
-                written = aligned_size(
-                    written as u32,
-                    self.rt_pipeline_properties.shader_group_base_alignment,
-                ) as usize;
-                cur.set_position(written as u64);
+                // Null when the mesh has no texture coordinates to derive tangents from
+                cur.write_all(&mesh.tangents_device_address().unwrap_or(0).to_le_bytes())?;
+                debug_assert_eq!(cur.position(), attributes_start + BASE_COLOR_OFFSET);
+                for c in mesh.base_color() {
+                    cur.write_all(&c.to_le_bytes())?;
+                }
+                let index_size: u64 = match mesh.index_type() {
+                    vk::IndexType::NONE_KHR => 0,
+                    vk::IndexType::UINT16 => 2,
+                    _ => 4,
+                };
+                cur.write_all(&index_size.to_le_bytes())?;
+                cur.write_all(&mesh.colors_device_address().unwrap_or(0).to_le_bytes())?;
+                cur.write_all(&mesh.uvs_device_address().unwrap_or(0).to_le_bytes())?;
+                let texture_index = mesh
+                    .texture()
+                    .and_then(|texture| textures.iter().position(|&t| std::ptr::eq(t, &**texture)))
+                    .unwrap_or(0) as u64;
+                cur.write_all(&texture_index.to_le_bytes())?;
             }
-            assert_eq!(written, table_size as usize);
+            assert_eq!(
+                hit_region_start + (self.num_geometries() * hit_stride) as usize,
+                table_size as usize
+            );
 
             Buffer::new(
                 device,
@@ -506,11 +1087,14 @@ impl<'device> Renderer<'device> for RayTrace<'device> {
         };
 
         self.sbt = Some(sbt);
+        if let Some(regions) = self.sbt_regions() {
+            check_sbt_regions(&regions, &self.rt_pipeline_properties)?;
+        }
         self.pipeline = Some(pipeline);
         self.pipeline_layout = Some(pipeline_layout);
-        self.image_views = images
-            .iter()
-            .map(|&image| {
+        self.image_views = create_per_image(
+            images,
+            |&image| {
                 let create_view_info = vk::ImageViewCreateInfo::default()
                     .view_type(vk::ImageViewType::TYPE_2D)
                     .format(surface_format.format)
@@ -528,10 +1112,19 @@ impl<'device> Renderer<'device> for RayTrace<'device> {
                         layer_count: 1,
                     })
                     .image(image);
-                unsafe { device.create_image_view(&create_view_info, None).unwrap() }
-            })
-            .collect();
+                unsafe {
+                    device
+                        .create_image_view(&create_view_info, None)
+                        .map_err(|err| anyhow::anyhow!("Failed to create image view: {err}"))
+                }
+            },
+            |view| unsafe { device.destroy_image_view(view, None) },
+        )?;
 
+        let num_image_descriptor_sets = match self.push_descriptor_ext {
+            Some(_) => 0,
+            None => self.image_views.len() as u32,
+        };
         let descriptor_sizes = [
             vk::DescriptorPoolSize {
                 ty: vk::DescriptorType::ACCELERATION_STRUCTURE_KHR,
@@ -539,13 +1132,24 @@ impl<'device> Renderer<'device> for RayTrace<'device> {
             },
             vk::DescriptorPoolSize {
                 ty: vk::DescriptorType::STORAGE_IMAGE,
-                descriptor_count: 1,
+                // Accumulation image and the swapchain images unless they are pushed
+                descriptor_count: 1 + num_image_descriptor_sets,
+            },
+            vk::DescriptorPoolSize {
+                ty: vk::DescriptorType::UNIFORM_BUFFER,
+                // Lights and camera
+                descriptor_count: 2,
+            },
+            vk::DescriptorPoolSize {
+                ty: vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+                // Environment map and the textures
+                descriptor_count: 1 + num_textures,
             },
         ];
 
         let descriptor_pool_info = vk::DescriptorPoolCreateInfo::default()
             .pool_sizes(&descriptor_sizes)
-            .max_sets(1);
+            .max_sets(1 + num_image_descriptor_sets);
 
         let descriptor_pool =
             unsafe { device.create_descriptor_pool(&descriptor_pool_info, None) }?;
@@ -562,16 +1166,134 @@ impl<'device> Renderer<'device> for RayTrace<'device> {
             )
         }?;
 
+        let image_descriptor_sets = if num_image_descriptor_sets > 0 {
+            let image_descriptor_sets = unsafe {
+                device.allocate_descriptor_sets(
+                    &vk::DescriptorSetAllocateInfo::default()
+                        .descriptor_pool(descriptor_pool)
+                        .set_layouts(&vec![image_set_layout; self.image_views.len()]),
+                )
+            }?;
+            for (&set, &view) in image_descriptor_sets.iter().zip(self.image_views.iter()) {
+                let image_info = [vk::DescriptorImageInfo::default()
+                    .image_layout(vk::ImageLayout::GENERAL)
+                    .image_view(view)];
+                unsafe {
+                    device.update_descriptor_sets(
+                        &[vk::WriteDescriptorSet::default()
+                            .dst_set(set)
+                            .dst_binding(0)
+                            .descriptor_type(vk::DescriptorType::STORAGE_IMAGE)
+                            .image_info(&image_info)],
+                        &[],
+                    )
+                };
+            }
+            image_descriptor_sets
+        } else {
+            Vec::new()
+        };
+
         unsafe {
             self.device
-                .destroy_descriptor_set_layout(descriptor_set_layout, None)
+                .destroy_descriptor_set_layout(descriptor_set_layout, None);
+            self.device
+                .destroy_descriptor_set_layout(image_set_layout, None);
         };
 
         let descriptor_set = descriptor_sets[0];
+        if let Some(toplevel_as) = self.toplevel_as.as_ref() {
+            let accel_structs = [toplevel_as.structure()];
+            let mut accel_info = vk::WriteDescriptorSetAccelerationStructureKHR::default()
+                .acceleration_structures(&accel_structs);
+            let mut accel_write = vk::WriteDescriptorSet::default()
+                .dst_set(descriptor_set)
+                .dst_binding(0)
+                .dst_array_element(0)
+                .descriptor_type(vk::DescriptorType::ACCELERATION_STRUCTURE_KHR)
+                .push_next(&mut accel_info);
+            // This is only set by the builder for images, buffers, or views; need to set explicitly after
+            accel_write.descriptor_count = 1;
+            unsafe { device.update_descriptor_sets(&[accel_write], &[]) };
+        }
+        if let Some(lights_buffer) = self.lights_buffer.as_ref() {
+            let lights_info = [vk::DescriptorBufferInfo::default()
+                .buffer(lights_buffer.buffer())
+                .range(vk::WHOLE_SIZE)];
+            unsafe {
+                device.update_descriptor_sets(
+                    &[vk::WriteDescriptorSet::default()
+                        .dst_set(descriptor_set)
+                        .dst_binding(3)
+                        .descriptor_type(vk::DescriptorType::UNIFORM_BUFFER)
+                        .buffer_info(&lights_info)],
+                    &[],
+                )
+            };
+        }
+        if let Some(camera_buffer) = self.camera_buffer.as_ref() {
+            let camera_info = [camera_buffer.descriptor_buffer_info()];
+            unsafe {
+                device.update_descriptor_sets(
+                    &[vk::WriteDescriptorSet::default()
+                        .dst_set(descriptor_set)
+                        .dst_binding(1)
+                        .descriptor_type(vk::DescriptorType::UNIFORM_BUFFER)
+                        .buffer_info(&camera_info)],
+                    &[],
+                )
+            };
+        }
+        if let Some(environment_map) = self.environment_map.as_ref() {
+            let environment_info = [environment_map.descriptor_image_info()];
+            unsafe {
+                device.update_descriptor_sets(
+                    &[vk::WriteDescriptorSet::default()
+                        .dst_set(descriptor_set)
+                        .dst_binding(4)
+                        .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+                        .image_info(&environment_info)],
+                    &[],
+                )
+            };
+        }
+        let texture_infos: Vec<_> = self
+            .textures()
+            .iter()
+            .map(|texture| texture.descriptor_image_info())
+            .collect();
+        if !texture_infos.is_empty() {
+            unsafe {
+                device.update_descriptor_sets(
+                    &[vk::WriteDescriptorSet::default()
+                        .dst_set(descriptor_set)
+                        .dst_binding(5)
+                        .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+                        .image_info(&texture_infos)],
+                    &[],
+                )
+            };
+        }
+        if let Some(accumulation) = self.accumulation.as_ref() {
+            let accumulation_info = [vk::DescriptorImageInfo::default()
+                .image_layout(vk::ImageLayout::GENERAL)
+                .image_view(accumulation.view)];
+            unsafe {
+                device.update_descriptor_sets(
+                    &[vk::WriteDescriptorSet::default()
+                        .dst_set(descriptor_set)
+                        .dst_binding(2)
+                        .dst_array_element(0)
+                        .descriptor_type(vk::DescriptorType::STORAGE_IMAGE)
+                        .image_info(&accumulation_info)],
+                    &[],
+                )
+            };
+        }
         self.descriptor_set = Some(descriptor_set);
+        self.image_descriptor_sets = image_descriptor_sets;
         self.descriptor_pool = Some(descriptor_pool);
 
-        self.resolution = size.into();
         Ok(())
     }
 
@@ -583,54 +1305,25 @@ impl<'device> Renderer<'device> for RayTrace<'device> {
         Some(&mut self.shader_pipeline)
     }
 
-    fn process_device_event(&mut self, event: &winit::event::DeviceEvent) {
-        #[allow(clippy::single_match)]
-        match event {
-            winit::event::DeviceEvent::MouseMotion { delta } => {
-                if self.middle_drag {
-                    self.translation -= Vector3 {
-                        x: self.rotation.cos() * delta.0 as f32,
-                        y: delta.1 as f32,
-                        z: self.rotation.sin() * delta.0 as f32,
-                    };
-                }
-            }
-            _ => (),
-        }
+    fn set_camera(&mut self, camera: &Camera) {
+        self.camera = *camera;
+        self.update_push_constants();
+        self.reset_accumulation();
     }
 
-    fn process_window_event(&mut self, event: &winit::event::WindowEvent) {
-        let mut handled = true;
-        match event {
-            WindowEvent::MouseInput { state, button, .. } => match (button, state) {
-                //(winit::event::MouseButton::Left, winit::event::ElementState::Pressed) => todo!(),
-                //(winit::event::MouseButton::Left, winit::event::ElementState::Released) => todo!(),
-                //(winit::event::MouseButton::Right, winit::event::ElementState::Pressed) => todo!(),
-                //(winit::event::MouseButton::Right, winit::event::ElementState::Released) => todo!(),
-                (winit::event::MouseButton::Middle, winit::event::ElementState::Pressed) => {
-                    self.middle_drag = true
-                }
-                (winit::event::MouseButton::Middle, winit::event::ElementState::Released) => {
-                    self.middle_drag = false
-                }
-                _ => (),
-            },
-            WindowEvent::MouseWheel { delta, .. } => match delta {
-                winit::event::MouseScrollDelta::LineDelta(_h, v) => self.zoom += 0.1 * v,
-                winit::event::MouseScrollDelta::PixelDelta(_) => (),
-            },
-            WindowEvent::KeyboardInput { input, .. } => match input.virtual_keycode {
-                Some(winit::event::VirtualKeyCode::Left) => self.rotation += 5.0,
-                Some(winit::event::VirtualKeyCode::Right) => self.rotation -= 5.0,
-                Some(winit::event::VirtualKeyCode::Down) => self.zoom += 0.1,
-                Some(winit::event::VirtualKeyCode::Up) => self.zoom -= 0.1,
-                _ => handled = false,
-            },
-            _ => handled = false,
-        }
-        if handled {
-            self.update_push_constants();
+    fn set_region(&mut self, region: Region) {
+        self.region = region;
+    }
+
+    fn set_lights(&mut self, lights: &Lights) -> anyhow::Result<()> {
+        self.lights = *lights;
+        if let Some(buffer) = self.lights_buffer.as_ref() {
+            // Previous frames might still read the lights
+            unsafe { self.device.device_wait_idle()? };
+            buffer.write(std::slice::from_ref(lights))?;
         }
+        self.reset_accumulation();
+        Ok(())
     }
 }
 
@@ -641,6 +1334,208 @@ impl Drop for RayTrace<'_> {
     }
 }
 
+/// Check the SBT regions passed to `cmd_trace_rays` against the valid usage rules of
+/// `vkCmdTraceRaysKHR`: regions start at multiples of `shaderGroupBaseAlignment`, strides are
+/// multiples of `shaderGroupHandleAlignment` (at most `maxShaderGroupStride`) with room for a
+/// handle, sizes are multiples of the stride and the raygen size equals its stride
+fn check_sbt_regions(
+    [raygen, miss, hit, callable]: &[vk::StridedDeviceAddressRegionKHR; 4],
+    properties: &vk::PhysicalDeviceRayTracingPipelinePropertiesKHR,
+) -> anyhow::Result<()> {
+    anyhow::ensure!(
+        raygen.size == raygen.stride,
+        "SBT raygen region size {} differs from its stride {}",
+        raygen.size,
+        raygen.stride
+    );
+    for (name, region) in [
+        ("raygen", raygen),
+        ("miss", miss),
+        ("hit", hit),
+        ("callable", callable),
+    ] {
+        if region.size == 0 {
+            continue;
+        }
+        anyhow::ensure!(
+            region.device_address % u64::from(properties.shader_group_base_alignment) == 0,
+            "SBT {name} region at {:#x} is not aligned to {}",
+            region.device_address,
+            properties.shader_group_base_alignment
+        );
+        anyhow::ensure!(
+            region.stride % u64::from(properties.shader_group_handle_alignment) == 0
+                && region.stride >= u64::from(properties.shader_group_handle_size)
+                && region.stride <= u64::from(properties.max_shader_group_stride),
+            "SBT {name} region has invalid stride {} (handle size {}, alignment {}, max {})",
+            region.stride,
+            properties.shader_group_handle_size,
+            properties.shader_group_handle_alignment,
+            properties.max_shader_group_stride
+        );
+        anyhow::ensure!(
+            region.size % region.stride == 0,
+            "SBT {name} region size {} is not a multiple of its stride {}",
+            region.size,
+            region.stride
+        );
+    }
+    Ok(())
+}
+
+/// Shader handle followed by the device addresses of the mesh attributes, matching the
+/// `SBT` block of the closest-hit shader: indices (0 for triangle soups), normals, positions
+/// and tangents (0 if the mesh has no texture coordinates), followed by the linear RGBA base
+/// color, the size of an index in bytes padded to 8 bytes, the address of the vertex
+/// colors (0 if the mesh has none), the address of the texture coordinates (0 if the mesh
+/// has none) and the index of the base color texture in [`RayTrace::textures`] padded to 8
+/// bytes. Records of procedural geometry hold the address of the primitive parameters for the
+/// intersection shader and a white base color.
+fn hit_record_stride(properties: &vk::PhysicalDeviceRayTracingPipelinePropertiesKHR) -> u32 {
+    aligned_size(
+        properties.shader_group_handle_size
+            + (NUM_ATTRIBUTES * size_of::<vk::DeviceAddress>()) as u32,
+        properties.shader_group_handle_alignment,
+    )
+}
+
+/// Miss records only hold the shader handle
+fn miss_record_stride(properties: &vk::PhysicalDeviceRayTracingPipelinePropertiesKHR) -> u32 {
+    aligned_size(
+        properties.shader_group_handle_size,
+        properties.shader_group_handle_alignment,
+    )
+}
+
+/// Offsets of the miss and hit regions in the SBT, which follow the raygen record. Regions
+/// start at multiples of `shader_group_base_alignment`.
+fn sbt_region_offsets(
+    properties: &vk::PhysicalDeviceRayTracingPipelinePropertiesKHR,
+) -> (u32, u32) {
+    let base_alignment = properties.shader_group_base_alignment;
+    let miss_offset = aligned_size(properties.shader_group_handle_size, base_alignment);
+    let hit_offset = miss_offset
+        + aligned_size(
+            NUM_MISS_SHADERS * miss_record_stride(properties),
+            base_alignment,
+        );
+    (miss_offset, hit_offset)
+}
+
+/// Raygen, miss, hit and callable regions of an SBT at `sbt_address` with `num_geometries` hit
+/// records, as written by `RayTrace::set_resolution`
+fn sbt_layout(
+    properties: &vk::PhysicalDeviceRayTracingPipelinePropertiesKHR,
+    num_geometries: u32,
+    sbt_address: vk::DeviceAddress,
+) -> [vk::StridedDeviceAddressRegionKHR; 4] {
+    let (miss_offset, hit_offset) = sbt_region_offsets(properties);
+    let miss_stride = miss_record_stride(properties);
+    let hit_stride = hit_record_stride(properties);
+    [
+        // The raygen region holds a single record, its size has to equal its stride
+        vk::StridedDeviceAddressRegionKHR::default()
+            .device_address(sbt_address)
+            .size(miss_stride.into())
+            .stride(miss_stride.into()),
+        vk::StridedDeviceAddressRegionKHR::default()
+            .device_address(sbt_address + u64::from(miss_offset))
+            .size((NUM_MISS_SHADERS * miss_stride).into())
+            .stride(miss_stride.into()),
+        // Without hit groups the region has to be empty instead of pointing past the table
+        if num_geometries == 0 {
+            vk::StridedDeviceAddressRegionKHR::default()
+        } else {
+            vk::StridedDeviceAddressRegionKHR::default()
+                .device_address(sbt_address + u64::from(hit_offset))
+                .size(u64::from(num_geometries * hit_stride))
+                .stride(hit_stride.into())
+        },
+        vk::StridedDeviceAddressRegionKHR::default(),
+    ]
+}
+
 fn aligned_size(value: u32, alignment: u32) -> u32 {
     (value + alignment - 1) & !(alignment - 1)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn properties() -> vk::PhysicalDeviceRayTracingPipelinePropertiesKHR<'static> {
+        vk::PhysicalDeviceRayTracingPipelinePropertiesKHR {
+            shader_group_handle_size: 32,
+            shader_group_handle_alignment: 32,
+            shader_group_base_alignment: 64,
+            max_shader_group_stride: 4096,
+            ..Default::default()
+        }
+    }
+
+    fn regions(num_geometries: u32) -> [vk::StridedDeviceAddressRegionKHR; 4] {
+        sbt_layout(&properties(), num_geometries, 0x1000)
+    }
+
+    #[test]
+    fn sbt_layout_matches_the_written_table() {
+        let layout =
+            |num_geometries| regions(num_geometries).map(|r| (r.device_address, r.stride, r.size));
+        // 32 byte miss records, hit records of a 32 byte handle and 80 bytes of attributes
+        // rounded up to 128 bytes, regions start at multiples of 64 bytes
+        assert_eq!(
+            layout(3),
+            [
+                (0x1000, 32, 32),
+                (0x1040, 32, 64),
+                (0x1080, 128, 384),
+                (0, 0, 0)
+            ]
+        );
+        assert_eq!(layout(0)[2], (0, 0, 0));
+    }
+
+    #[test]
+    fn sbt_layout_passes_the_region_checks() {
+        for num_geometries in [0, 1, 3] {
+            let regions = regions(num_geometries);
+            check_sbt_regions(&regions, &properties()).unwrap();
+            for region in regions.iter().filter(|r| r.size != 0) {
+                assert_eq!(region.size % region.stride, 0);
+                assert_eq!(
+                    region.stride % u64::from(properties().shader_group_handle_alignment),
+                    0
+                );
+            }
+            assert_eq!(regions[0].size, regions[0].stride);
+        }
+    }
+
+    #[test]
+    fn invalid_sbt_regions_are_rejected() {
+        let properties = properties();
+        let mut raygen_size = regions(1);
+        raygen_size[0].size *= 2;
+        assert!(check_sbt_regions(&raygen_size, &properties).is_err());
+
+        let mut miss_size = regions(1);
+        miss_size[1].size += 8;
+        assert!(check_sbt_regions(&miss_size, &properties).is_err());
+
+        let mut hit_stride = regions(1);
+        hit_stride[2].stride += 8;
+        hit_stride[2].size = hit_stride[2].stride;
+        assert!(check_sbt_regions(&hit_stride, &properties).is_err());
+
+        let mut hit_alignment = regions(1);
+        hit_alignment[2].device_address += 32;
+        assert!(check_sbt_regions(&hit_alignment, &properties).is_err());
+    }
+
+    #[test]
+    fn sizes_are_aligned_up() {
+        assert_eq!(aligned_size(32, 32), 32);
+        assert_eq!(aligned_size(33, 32), 64);
+        assert_eq!(aligned_size(112, 64), 128);
+    }
+}
@@ -0,0 +1,113 @@
+//
+// sampler.rs
+// Copyright (C) 2022 Stephan Seitz <stephan.seitz@fau.de>
+// Distributed under terms of the GPLv3 license.
+//
+
+use std::{cell::RefCell, collections::HashMap};
+
+use ash::vk;
+use log::debug;
+
+/// Filtering and addressing of a sampler, the key of [`SamplerCache`]
+#[derive(Debug, Copy, Clone, Hash, Eq, PartialEq)]
+pub struct SamplerOptions {
+    mag_filter: vk::Filter,
+    min_filter: vk::Filter,
+    mipmap_mode: vk::SamplerMipmapMode,
+    address_mode: vk::SamplerAddressMode,
+    /// 1 disables anisotropic filtering
+    max_anisotropy: u32,
+}
+
+impl SamplerOptions {
+    /// Nearest-neighbor filtering with repeating texture coordinates
+    pub fn nearest() -> Self {
+        Self {
+            mag_filter: vk::Filter::NEAREST,
+            min_filter: vk::Filter::NEAREST,
+            mipmap_mode: vk::SamplerMipmapMode::NEAREST,
+            address_mode: vk::SamplerAddressMode::REPEAT,
+            max_anisotropy: 1,
+        }
+    }
+
+    /// Linear filtering with repeating texture coordinates
+    pub fn linear() -> Self {
+        Self {
+            mag_filter: vk::Filter::LINEAR,
+            min_filter: vk::Filter::LINEAR,
+            mipmap_mode: vk::SamplerMipmapMode::LINEAR,
+            ..Self::nearest()
+        }
+    }
+
+    /// Set the address mode of all texture coordinates
+    #[must_use]
+    pub fn with_address_mode(mut self, address_mode: vk::SamplerAddressMode) -> Self {
+        self.address_mode = address_mode;
+        self
+    }
+
+    /// Set the maximum number of anisotropic samples, clamped to the device limit by
+    /// [`SamplerCache::get`]. 1 disables anisotropic filtering.
+    #[must_use]
+    pub fn with_max_anisotropy(mut self, max_anisotropy: u32) -> Self {
+        self.max_anisotropy = max_anisotropy.max(1);
+        self
+    }
+}
+
+/// Creates each kind of sampler once and destroys them when dropped, so it has to outlive the
+/// users of its samplers
+pub struct SamplerCache<'device> {
+    device: &'device ash::Device,
+    /// `maxSamplerAnisotropy` of the device, 1 without the `samplerAnisotropy` feature
+    max_supported_anisotropy: f32,
+    samplers: RefCell<HashMap<SamplerOptions, vk::Sampler>>,
+}
+
+impl Drop for SamplerCache<'_> {
+    fn drop(&mut self) {
+        for (_, sampler) in self.samplers.get_mut().drain() {
+            unsafe { self.device.destroy_sampler(sampler, None) };
+        }
+    }
+}
+
+impl<'device> SamplerCache<'device> {
+    /// `max_supported_anisotropy` is usually [`crate::vulkan_app::VulkanApp::max_sampler_anisotropy`]
+    pub fn new(device: &'device ash::Device, max_supported_anisotropy: f32) -> Self {
+        Self {
+            device,
+            max_supported_anisotropy,
+            samplers: Default::default(),
+        }
+    }
+
+    /// Sampler for `options`, created on first use
+    pub fn get(&self, options: SamplerOptions) -> anyhow::Result<vk::Sampler> {
+        if let Some(&sampler) = self.samplers.borrow().get(&options) {
+            return Ok(sampler);
+        }
+        let max_anisotropy = (options.max_anisotropy as f32).min(self.max_supported_anisotropy);
+        debug!("Creating sampler {options:?} with max anisotropy {max_anisotropy}");
+        let sampler = unsafe {
+            self.device.create_sampler(
+                &vk::SamplerCreateInfo::default()
+                    .mag_filter(options.mag_filter)
+                    .min_filter(options.min_filter)
+                    .mipmap_mode(options.mipmap_mode)
+                    .address_mode_u(options.address_mode)
+                    .address_mode_v(options.address_mode)
+                    .address_mode_w(options.address_mode)
+                    .anisotropy_enable(max_anisotropy > 1.0)
+                    .max_anisotropy(max_anisotropy)
+                    .max_lod(vk::LOD_CLAMP_NONE),
+                None,
+            )
+        }?;
+        self.samplers.borrow_mut().insert(options, sampler);
+        Ok(sampler)
+    }
+}
@@ -0,0 +1,190 @@
+//
+// scene.rs
+// Copyright (C) 2022 Stephan Seitz <stephan.seitz@fau.de>
+// Distributed under terms of the GPLv3 license.
+//
+
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+use cgmath::{Deg, Matrix4, SquareMatrix};
+use serde::Deserialize;
+
+use crate::{
+    primitive::Primitive,
+    uniforms::{Light, Lights},
+};
+
+/// Placement and material of a mesh. The transform is applied as scale, then rotation, then
+/// translation.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct SceneMesh {
+    pub translation: [f32; 3],
+    /// Euler angles in degrees, applied around X, then Y, then Z
+    pub rotation: [f32; 3],
+    pub scale: [f32; 3],
+    /// sRGB base color texture sampled with the mesh's texture coordinates
+    pub texture: Option<PathBuf>,
+    /// Linear RGBA base color, multiplied with the texture
+    pub color: Option<[f32; 4]>,
+}
+
+impl Default for SceneMesh {
+    fn default() -> Self {
+        Self {
+            translation: [0.0; 3],
+            rotation: [0.0; 3],
+            scale: [1.0; 3],
+            texture: None,
+            color: None,
+        }
+    }
+}
+
+impl SceneMesh {
+    pub fn matrix(&self) -> Matrix4<f32> {
+        let [tx, ty, tz] = self.translation;
+        let [rx, ry, rz] = self.rotation;
+        let [sx, sy, sz] = self.scale;
+        Matrix4::from_translation([tx, ty, tz].into())
+            * Matrix4::from_angle_z(Deg(rz))
+            * Matrix4::from_angle_y(Deg(ry))
+            * Matrix4::from_angle_x(Deg(rx))
+            * Matrix4::from_nonuniform_scale(sx, sy, sz)
+    }
+}
+
+/// Light source, e.g. `{ "type": "point", "position": [0.0, 2.0, 0.0], "intensity": 4.0 }`
+#[derive(Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum SceneLight {
+    Directional {
+        /// Direction towards the light
+        direction: [f32; 3],
+        #[serde(default = "white")]
+        color: [f32; 3],
+        #[serde(default = "one")]
+        intensity: f32,
+    },
+    Point {
+        position: [f32; 3],
+        #[serde(default = "white")]
+        color: [f32; 3],
+        #[serde(default = "one")]
+        intensity: f32,
+    },
+}
+
+fn white() -> [f32; 3] {
+    [1.0; 3]
+}
+
+fn one() -> f32 {
+    1.0
+}
+
+impl From<&SceneLight> for Light {
+    fn from(light: &SceneLight) -> Self {
+        match *light {
+            SceneLight::Directional {
+                direction,
+                color,
+                intensity,
+            } => Light::directional(direction.into(), color.into(), intensity),
+            SceneLight::Point {
+                position,
+                color,
+                intensity,
+            } => Light::point(position.into(), color.into(), intensity),
+        }
+    }
+}
+
+/// Scene file assigning transforms and textures to mesh files, e.g.
+///
+/// ```json
+/// { "meshes": { "bunny.ply": { "translation": [1.0, 0.0, 0.0], "texture": "bunny.png" } } }
+/// ```
+///
+/// Relative mesh and texture paths are resolved against the directory of the scene file.
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Scene {
+    #[serde(default)]
+    meshes: HashMap<PathBuf, SceneMesh>,
+    /// A single key light is used if no lights are given
+    #[serde(default)]
+    lights: Vec<SceneLight>,
+    /// Implicit surfaces, only rendered by the raytracer
+    #[serde(default)]
+    primitives: Vec<Primitive>,
+}
+
+impl Scene {
+    pub fn from_file(path: &Path) -> anyhow::Result<Self> {
+        let file = std::fs::File::open(path)
+            .with_context(|| format!("Failed to open scene file {}", path.display()))?;
+        let scene: Scene = serde_json::from_reader(std::io::BufReader::new(file))
+            .with_context(|| format!("Failed to parse scene file {}", path.display()))?;
+        let base = path.parent().unwrap_or_else(|| Path::new(""));
+        let Scene {
+            meshes,
+            lights,
+            primitives,
+        } = scene;
+        let meshes = meshes
+            .into_iter()
+            .map(|(mesh_path, mut mesh)| {
+                mesh.texture = mesh.texture.map(|texture| base.join(texture));
+                (normalize_path(&base.join(mesh_path)), mesh)
+            })
+            .collect();
+        Ok(Self {
+            meshes,
+            lights,
+            primitives,
+        })
+    }
+
+    /// Model matrix for `mesh_path`, identity if the scene doesn't mention it
+    pub fn transform(&self, mesh_path: &Path) -> Matrix4<f32> {
+        self.meshes
+            .get(&normalize_path(mesh_path))
+            .map(SceneMesh::matrix)
+            .unwrap_or_else(Matrix4::identity)
+    }
+
+    pub fn lights(&self) -> Lights {
+        if self.lights.is_empty() {
+            Lights::default()
+        } else {
+            Lights::new(&self.lights.iter().map(Light::from).collect::<Vec<_>>())
+        }
+    }
+
+    pub fn primitives(&self) -> &[Primitive] {
+        &self.primitives
+    }
+
+    /// Base color texture for `mesh_path`
+    pub fn texture(&self, mesh_path: &Path) -> Option<&Path> {
+        self.meshes
+            .get(&normalize_path(mesh_path))
+            .and_then(|mesh| mesh.texture.as_deref())
+    }
+
+    /// Base color for `mesh_path` if the scene assigns one
+    pub fn color(&self, mesh_path: &Path) -> Option<[f32; 4]> {
+        self.meshes
+            .get(&normalize_path(mesh_path))
+            .and_then(|mesh| mesh.color)
+    }
+}
+
+fn normalize_path(path: &Path) -> PathBuf {
+    path.canonicalize().unwrap_or_else(|_| path.to_owned())
+}
@@ -1,7 +1,7 @@
 use std::ffi::CStr;
-use std::intrinsics::transmute;
 use std::io::Cursor;
-use std::process::Command;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
 
 use ash::vk::{VertexInputAttributeDescription, VertexInputBindingDescription};
 use ash::{util::read_spv, vk};
@@ -9,15 +9,62 @@ use log::{debug, info};
 
 use crate::renderers::RenderStyle;
 
+// Specialization constant IDs of all shaders. Keep them unique, pipelines pass the same IDs to
+// every stage.
+
+/// Specialization constants of all shaders selecting the normal and depth visualizations
+const VISUALIZE_NORMALS_CONSTANT_ID: u32 = 0;
+const VISUALIZE_DEPTH_CONSTANT_ID: u32 = 1;
+/// Only read by the closest-hit shader, the rasterizer draws wireframes with `PolygonMode::LINE`
+const VISUALIZE_WIREFRAME_CONSTANT_ID: u32 = 2;
+/// Shade with the geometric normal of each triangle instead of interpolated vertex normals
+const FLAT_SHADING_CONSTANT_ID: u32 = 3;
+/// Shade back faces instead of culling them
+const DOUBLE_SIDED_CONSTANT_ID: u32 = 4;
+/// Tone mapping operator of `raygen.glsl`
+pub(crate) const TONEMAP_CONSTANT_ID: u32 = 5;
+/// Tells shaders to sRGB encode their output themselves
+pub(crate) const ENCODE_SRGB_CONSTANT_ID: u32 = 6;
+/// Enables the normal output of `triangle.frag` for SSAO
+pub(crate) const WRITE_NORMALS_CONSTANT_ID: u32 = 7;
+/// Makes `triangle.frag` draw unlit points
+pub(crate) const POINTS_CONSTANT_ID: u32 = 8;
+/// Enables shadow rays towards the lights in `closest_hit.glsl`
+pub(crate) const SHADOWS_CONSTANT_ID: u32 = 9;
+/// Workgroup size of `simple.comp` in x and y
+pub(crate) const WORKGROUP_SIZE_X_CONSTANT_ID: u32 = 10;
+pub(crate) const WORKGROUP_SIZE_Y_CONSTANT_ID: u32 = 11;
+
+/// Format of the view space normals written by graphics pipelines with a normal attachment
+pub const NORMAL_ATTACHMENT_FORMAT: vk::Format = vk::Format::R16G16B16A16_SFLOAT;
+
 pub struct Shader {
     module: vk::ShaderModule,
     info: spirv_reflect::ShaderModule,
+    stage: vk::ShaderStageFlags,
 }
 
 pub struct ShaderPipeline<'device> {
     shaders: Vec<Shader>,
     device: &'device ash::Device,
     hot_reload_sources: Vec<String>,
+    source_modification_times: Vec<Option<SystemTime>>,
+    flat_shading: bool,
+    double_sided: bool,
+    front_face: vk::FrontFace,
+    topology: vk::PrimitiveTopology,
+    line_width: f32,
+    specialization_constants: Vec<(u32, Vec<u8>)>,
+    /// Whether graphics pipelines draw on top of the image instead of clearing it
+    overlay: bool,
+    /// Whether graphics pipelines write normals to a second color attachment for a compute pass
+    normal_attachment: bool,
+    /// Whether a compute pass samples the depth attachment after render passes of graphics
+    /// pipelines, implied by `normal_attachment`
+    sampled_depth: bool,
+    /// Whether render passes of graphics pipelines start with a depth-only subpass
+    depth_prepass: bool,
+    depth_format: vk::Format,
 }
 
 impl Drop for ShaderPipeline<'_> {
@@ -43,6 +90,7 @@ impl<'device> ShaderPipeline<'device> {
                 info.enumerate_push_constant_blocks(None)
             );
 
+            let stage = shader_stage(&info)?;
             shaders.push(Shader {
                 module: unsafe {
                     device.create_shader_module(
@@ -52,30 +100,54 @@ impl<'device> ShaderPipeline<'device> {
                     )?
                 },
                 info,
+                stage,
                 //alt_info,
             });
         }
-        let hot_reload_sources = shaders.iter().map(|s| s.info.get_source_file()).collect();
+        let hot_reload_sources: Vec<_> = shaders.iter().map(|s| s.info.get_source_file()).collect();
         info!("hot_reload_sources: {hot_reload_sources:?}");
+        let source_modification_times = hot_reload_sources
+            .iter()
+            .map(|source| modification_time(source))
+            .collect();
         Ok(Self {
             shaders,
             device,
             hot_reload_sources,
+            source_modification_times,
+            flat_shading: false,
+            double_sided: false,
+            front_face: vk::FrontFace::COUNTER_CLOCKWISE,
+            topology: vk::PrimitiveTopology::TRIANGLE_LIST,
+            line_width: 1.0,
+            specialization_constants: Vec::new(),
+            overlay: false,
+            normal_attachment: false,
+            sampled_depth: false,
+            depth_prepass: false,
+            depth_format: vk::Format::D16_UNORM,
         })
     }
 
     pub fn make_graphics_pipeline(
         &self,
         device: &ash::Device,
+        pipeline_cache: vk::PipelineCache,
         scissors: &[vk::Rect2D],
         viewports: &[vk::Viewport],
         format: vk::Format,
         vertex_input_attribute_descriptions: &[VertexInputAttributeDescription],
         vertex_input_binding_descriptions: &[VertexInputBindingDescription],
+        descriptor_set_layouts: &[vk::DescriptorSetLayout],
         push_constant_ranges: &[vk::PushConstantRange], // TODO: do this via reflection
         render_style: RenderStyle,
+        samples: vk::SampleCountFlags,
     ) -> anyhow::Result<(vk::Pipeline, vk::RenderPass, vk::PipelineLayout)> {
         let shader_entry_name = unsafe { CStr::from_bytes_with_nul_unchecked(b"main\0") };
+        let (map_entries, specialization_data) = self.specialization(render_style);
+        let specialization_info = vk::SpecializationInfo::default()
+            .map_entries(&map_entries)
+            .data(&specialization_data);
         let shader_stage_create_infos = self
             .shaders
             .iter()
@@ -83,7 +155,8 @@ impl<'device> ShaderPipeline<'device> {
                 vk::PipelineShaderStageCreateInfo::default()
                     .name(shader_entry_name)
                     .module(shader.module)
-                    .stage(unsafe { transmute(shader.info.get_shader_stage()) })
+                    .stage(shader.stage)
+                    .specialization_info(&specialization_info)
             })
             .collect::<Vec<_>>();
 
@@ -91,21 +164,12 @@ impl<'device> ShaderPipeline<'device> {
             .vertex_attribute_descriptions(vertex_input_attribute_descriptions)
             .vertex_binding_descriptions(vertex_input_binding_descriptions);
         let vertex_input_assembly_state_info = vk::PipelineInputAssemblyStateCreateInfo {
-            topology: vk::PrimitiveTopology::TRIANGLE_LIST,
-            ..Default::default()
-        };
-        let rasterization_info = vk::PipelineRasterizationStateCreateInfo {
-            front_face: vk::FrontFace::COUNTER_CLOCKWISE,
-            line_width: 1.0,
-            polygon_mode: match render_style {
-                RenderStyle::Normal => vk::PolygonMode::FILL,
-                RenderStyle::Wireframe => vk::PolygonMode::LINE,
-            },
-            cull_mode: vk::CullModeFlags::BACK,
+            topology: self.topology,
             ..Default::default()
         };
+        let rasterization_info = self.rasterization_state(render_style);
         let multisample_state_info = vk::PipelineMultisampleStateCreateInfo {
-            rasterization_samples: vk::SampleCountFlags::TYPE_1,
+            rasterization_samples: samples,
             ..Default::default()
         };
         let noop_stencil_state = vk::StencilOpState {
@@ -115,16 +179,21 @@ impl<'device> ShaderPipeline<'device> {
             compare_op: vk::CompareOp::ALWAYS,
             ..Default::default()
         };
+        // After a depth prepass, only the fragments that ended up visible are shaded
         let depth_state_info = vk::PipelineDepthStencilStateCreateInfo {
             depth_test_enable: 1,
-            depth_write_enable: 1,
-            depth_compare_op: vk::CompareOp::LESS_OR_EQUAL,
+            depth_write_enable: vk::Bool32::from(!self.depth_prepass),
+            depth_compare_op: if self.depth_prepass {
+                vk::CompareOp::EQUAL
+            } else {
+                vk::CompareOp::LESS_OR_EQUAL
+            },
             front: noop_stencil_state,
             back: noop_stencil_state,
             max_depth_bounds: 1.0,
             ..Default::default()
         };
-        let color_blend_attachment_states = [vk::PipelineColorBlendAttachmentState {
+        let color_blend_attachment_state = vk::PipelineColorBlendAttachmentState {
             blend_enable: 0,
             src_color_blend_factor: vk::BlendFactor::SRC_COLOR,
             dst_color_blend_factor: vk::BlendFactor::ONE_MINUS_DST_COLOR,
@@ -133,7 +202,12 @@ impl<'device> ShaderPipeline<'device> {
             dst_alpha_blend_factor: vk::BlendFactor::ZERO,
             alpha_blend_op: vk::BlendOp::ADD,
             color_write_mask: vk::ColorComponentFlags::RGBA,
-        }];
+        };
+        let color_blend_attachment_states = if self.normal_attachment {
+            vec![color_blend_attachment_state; 2]
+        } else {
+            vec![color_blend_attachment_state]
+        };
         let color_blend_state = vk::PipelineColorBlendStateCreateInfo::default()
             .logic_op(vk::LogicOp::CLEAR)
             .attachments(&color_blend_attachment_states);
@@ -142,7 +216,7 @@ impl<'device> ShaderPipeline<'device> {
             .scissors(scissors)
             .viewports(viewports);
         let dynamic_state = [vk::DynamicState::VIEWPORT, vk::DynamicState::SCISSOR];
-        let color_attachment_refs = [vk::AttachmentReference {
+        let mut color_attachment_refs = vec![vk::AttachmentReference {
             attachment: 0,
             layout: vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
         }];
@@ -150,44 +224,172 @@ impl<'device> ShaderPipeline<'device> {
             attachment: 1,
             layout: vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL,
         };
+        // With multisampling, attachment 0 is a multisampled color image resolved into the
+        // swapchain image at attachment 2
+        let multisampled = samples != vk::SampleCountFlags::TYPE_1;
+        anyhow::ensure!(
+            !(multisampled && self.normal_attachment),
+            "Normal attachments are only supported without multisampling"
+        );
+        let sampled_depth = self.normal_attachment || self.sampled_depth;
+        anyhow::ensure!(
+            !(multisampled && sampled_depth),
+            "Sampling the depth attachment is only supported without multisampling"
+        );
+        // Normals follow the depth attachment
+        if self.normal_attachment {
+            color_attachment_refs.push(vk::AttachmentReference {
+                attachment: 2,
+                layout: vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
+            });
+        }
+        let resolve_attachment_refs = [vk::AttachmentReference {
+            attachment: 2,
+            layout: vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
+        }];
 
-        let subpass = vk::SubpassDescription::default()
+        let mut subpass = vk::SubpassDescription::default()
             .color_attachments(&color_attachment_refs)
             .depth_stencil_attachment(&depth_attachment_ref)
             .pipeline_bind_point(vk::PipelineBindPoint::GRAPHICS);
+        if multisampled {
+            subpass = subpass.resolve_attachments(&resolve_attachment_refs);
+        }
+        let prepass = vk::SubpassDescription::default()
+            .depth_stencil_attachment(&depth_attachment_ref)
+            .pipeline_bind_point(vk::PipelineBindPoint::GRAPHICS);
+        let (subpasses, color_subpass) = if self.depth_prepass {
+            (vec![prepass, subpass], 1)
+        } else {
+            (vec![subpass], 0)
+        };
 
-        let renderpass_attachments = [
+        let swapchain_attachment = vk::AttachmentDescription {
+            format,
+            samples: vk::SampleCountFlags::TYPE_1,
+            load_op: if self.overlay {
+                vk::AttachmentLoadOp::LOAD
+            } else {
+                vk::AttachmentLoadOp::CLEAR
+            },
+            store_op: vk::AttachmentStoreOp::STORE,
+            initial_layout: vk::ImageLayout::PRESENT_SRC_KHR,
+            final_layout: vk::ImageLayout::PRESENT_SRC_KHR,
+            ..Default::default()
+        };
+        let depth_attachment = if sampled_depth {
+            // Sampled by the compute pass
             vk::AttachmentDescription {
-                format,
-                samples: vk::SampleCountFlags::TYPE_1,
+                format: self.depth_format,
+                samples,
                 load_op: vk::AttachmentLoadOp::CLEAR,
                 store_op: vk::AttachmentStoreOp::STORE,
-                initial_layout: vk::ImageLayout::PRESENT_SRC_KHR,
-                final_layout: vk::ImageLayout::PRESENT_SRC_KHR,
+                initial_layout: vk::ImageLayout::UNDEFINED,
+                final_layout: vk::ImageLayout::DEPTH_STENCIL_READ_ONLY_OPTIMAL,
                 ..Default::default()
-            },
+            }
+        } else {
             vk::AttachmentDescription {
-                format: vk::Format::D16_UNORM,
-                samples: vk::SampleCountFlags::TYPE_1,
+                format: self.depth_format,
+                samples,
                 load_op: vk::AttachmentLoadOp::CLEAR,
+                // Kept for depth captures
+                store_op: vk::AttachmentStoreOp::STORE,
                 initial_layout: vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL,
                 final_layout: vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL,
                 ..Default::default()
-            },
-        ];
+            }
+        };
+        let renderpass_attachments = if multisampled {
+            vec![
+                vk::AttachmentDescription {
+                    format,
+                    samples,
+                    load_op: vk::AttachmentLoadOp::CLEAR,
+                    store_op: vk::AttachmentStoreOp::DONT_CARE,
+                    initial_layout: vk::ImageLayout::UNDEFINED,
+                    final_layout: vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
+                    ..Default::default()
+                },
+                depth_attachment,
+                vk::AttachmentDescription {
+                    load_op: vk::AttachmentLoadOp::DONT_CARE,
+                    ..swapchain_attachment
+                },
+            ]
+        } else if self.normal_attachment {
+            vec![
+                swapchain_attachment,
+                depth_attachment,
+                vk::AttachmentDescription {
+                    format: NORMAL_ATTACHMENT_FORMAT,
+                    samples,
+                    load_op: vk::AttachmentLoadOp::CLEAR,
+                    store_op: vk::AttachmentStoreOp::STORE,
+                    initial_layout: vk::ImageLayout::UNDEFINED,
+                    final_layout: vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+                    ..Default::default()
+                },
+            ]
+        } else {
+            vec![swapchain_attachment, depth_attachment]
+        };
 
-        let dependencies = [vk::SubpassDependency {
+        let mut dependencies = vec![vk::SubpassDependency {
             src_subpass: vk::SUBPASS_EXTERNAL,
-            src_stage_mask: vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT,
+            dst_subpass: color_subpass,
+            // Renderers composed before this one might have written the image by any means
+            src_stage_mask: vk::PipelineStageFlags::ALL_COMMANDS,
+            src_access_mask: vk::AccessFlags::MEMORY_WRITE,
             dst_access_mask: vk::AccessFlags::COLOR_ATTACHMENT_READ
                 | vk::AccessFlags::COLOR_ATTACHMENT_WRITE,
             dst_stage_mask: vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT,
             ..Default::default()
         }];
+        if sampled_depth {
+            // Depth and normals of the previous frame might still be read by its compute pass
+            dependencies[0].dst_stage_mask |= vk::PipelineStageFlags::EARLY_FRAGMENT_TESTS;
+            dependencies[0].dst_access_mask |= vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE;
+            // The compute pass reads all attachments and writes the color
+            dependencies.push(vk::SubpassDependency {
+                src_subpass: color_subpass,
+                dst_subpass: vk::SUBPASS_EXTERNAL,
+                src_stage_mask: vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT
+                    | vk::PipelineStageFlags::LATE_FRAGMENT_TESTS,
+                src_access_mask: vk::AccessFlags::COLOR_ATTACHMENT_WRITE
+                    | vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE,
+                dst_stage_mask: vk::PipelineStageFlags::COMPUTE_SHADER,
+                dst_access_mask: vk::AccessFlags::SHADER_READ | vk::AccessFlags::SHADER_WRITE,
+                ..Default::default()
+            });
+        }
+        if self.depth_prepass {
+            dependencies.extend([
+                // The depth image might still be read by the compute pass of the previous frame
+                vk::SubpassDependency {
+                    src_subpass: vk::SUBPASS_EXTERNAL,
+                    dst_subpass: 0,
+                    src_stage_mask: vk::PipelineStageFlags::ALL_COMMANDS,
+                    dst_stage_mask: vk::PipelineStageFlags::EARLY_FRAGMENT_TESTS
+                        | vk::PipelineStageFlags::LATE_FRAGMENT_TESTS,
+                    dst_access_mask: vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE,
+                    ..Default::default()
+                },
+                vk::SubpassDependency {
+                    src_subpass: 0,
+                    dst_subpass: 1,
+                    src_stage_mask: vk::PipelineStageFlags::LATE_FRAGMENT_TESTS,
+                    src_access_mask: vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE,
+                    dst_stage_mask: vk::PipelineStageFlags::EARLY_FRAGMENT_TESTS,
+                    dst_access_mask: vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_READ,
+                    dependency_flags: vk::DependencyFlags::BY_REGION,
+                },
+            ]);
+        }
 
         let renderpass_create_info = vk::RenderPassCreateInfo::default()
             .attachments(&renderpass_attachments)
-            .subpasses(std::slice::from_ref(&subpass))
+            .subpasses(&subpasses)
             .dependencies(&dependencies);
 
         let renderpass = unsafe { device.create_render_pass(&renderpass_create_info, None)? };
@@ -195,14 +397,15 @@ impl<'device> ShaderPipeline<'device> {
         let dynamic_state_info =
             vk::PipelineDynamicStateCreateInfo::default().dynamic_states(&dynamic_state);
 
-        let layout_create_info =
-            vk::PipelineLayoutCreateInfo::default().push_constant_ranges(push_constant_ranges);
+        let layout_create_info = vk::PipelineLayoutCreateInfo::default()
+            .set_layouts(descriptor_set_layouts)
+            .push_constant_ranges(push_constant_ranges);
 
         let pipeline_layout = unsafe { device.create_pipeline_layout(&layout_create_info, None)? };
         Ok((
             unsafe {
                 device.create_graphics_pipelines(
-                    vk::PipelineCache::null(), // TODO:: create cache
+                    pipeline_cache,
                     &[vk::GraphicsPipelineCreateInfo::default()
                         .stages(&shader_stage_create_infos)
                         .vertex_input_state(&vertex_input_state_info)
@@ -214,7 +417,8 @@ impl<'device> ShaderPipeline<'device> {
                         .color_blend_state(&color_blend_state)
                         .dynamic_state(&dynamic_state_info)
                         .layout(pipeline_layout)
-                        .render_pass(renderpass)],
+                        .render_pass(renderpass)
+                        .subpass(color_subpass)],
                     None,
                 )
             }
@@ -224,25 +428,257 @@ impl<'device> ShaderPipeline<'device> {
         ))
     }
 
-    pub fn shaders_source_files(&self) -> &Vec<String> {
-        &self.hot_reload_sources
+    /// Depth-only pipeline for the first subpass of a `renderpass` created by
+    /// [`ShaderPipeline::make_graphics_pipeline`] with a depth prepass. Only the vertex stage
+    /// runs, with the same vertex input and layout as the shading pipeline.
+    #[allow(clippy::too_many_arguments)]
+    pub fn make_depth_prepass_pipeline(
+        &self,
+        device: &ash::Device,
+        pipeline_cache: vk::PipelineCache,
+        renderpass: vk::RenderPass,
+        pipeline_layout: vk::PipelineLayout,
+        scissors: &[vk::Rect2D],
+        viewports: &[vk::Viewport],
+        vertex_input_attribute_descriptions: &[VertexInputAttributeDescription],
+        vertex_input_binding_descriptions: &[VertexInputBindingDescription],
+        render_style: RenderStyle,
+        samples: vk::SampleCountFlags,
+    ) -> anyhow::Result<vk::Pipeline> {
+        anyhow::ensure!(
+            self.depth_prepass,
+            "Render passes without depth prepass have no depth-only subpass"
+        );
+        let shader_entry_name = unsafe { CStr::from_bytes_with_nul_unchecked(b"main\0") };
+        let (map_entries, specialization_data) = self.specialization(render_style);
+        let specialization_info = vk::SpecializationInfo::default()
+            .map_entries(&map_entries)
+            .data(&specialization_data);
+        let shader_stage_create_infos = self
+            .shaders
+            .iter()
+            .filter(|shader| shader.stage == vk::ShaderStageFlags::VERTEX)
+            .map(|shader| {
+                vk::PipelineShaderStageCreateInfo::default()
+                    .name(shader_entry_name)
+                    .module(shader.module)
+                    .stage(shader.stage)
+                    .specialization_info(&specialization_info)
+            })
+            .collect::<Vec<_>>();
+
+        let vertex_input_state_info = vk::PipelineVertexInputStateCreateInfo::default()
+            .vertex_attribute_descriptions(vertex_input_attribute_descriptions)
+            .vertex_binding_descriptions(vertex_input_binding_descriptions);
+        let vertex_input_assembly_state_info = vk::PipelineInputAssemblyStateCreateInfo {
+            topology: self.topology,
+            ..Default::default()
+        };
+        let rasterization_info = self.rasterization_state(render_style);
+        let multisample_state_info = vk::PipelineMultisampleStateCreateInfo {
+            rasterization_samples: samples,
+            ..Default::default()
+        };
+        let depth_state_info = vk::PipelineDepthStencilStateCreateInfo {
+            depth_test_enable: 1,
+            depth_write_enable: 1,
+            depth_compare_op: vk::CompareOp::LESS_OR_EQUAL,
+            max_depth_bounds: 1.0,
+            ..Default::default()
+        };
+        let color_blend_state = vk::PipelineColorBlendStateCreateInfo::default();
+        let viewport_state_info = vk::PipelineViewportStateCreateInfo::default()
+            .scissors(scissors)
+            .viewports(viewports);
+        let dynamic_state = [vk::DynamicState::VIEWPORT, vk::DynamicState::SCISSOR];
+        let dynamic_state_info =
+            vk::PipelineDynamicStateCreateInfo::default().dynamic_states(&dynamic_state);
+
+        Ok(unsafe {
+            device.create_graphics_pipelines(
+                pipeline_cache,
+                &[vk::GraphicsPipelineCreateInfo::default()
+                    .stages(&shader_stage_create_infos)
+                    .vertex_input_state(&vertex_input_state_info)
+                    .input_assembly_state(&vertex_input_assembly_state_info)
+                    .viewport_state(&viewport_state_info)
+                    .rasterization_state(&rasterization_info)
+                    .multisample_state(&multisample_state_info)
+                    .depth_stencil_state(&depth_state_info)
+                    .color_blend_state(&color_blend_state)
+                    .dynamic_state(&dynamic_state_info)
+                    .layout(pipeline_layout)
+                    .render_pass(renderpass)
+                    .subpass(0)],
+                None,
+            )
+        }
+        .map_err(|(_pipes, err)| err)?[0])
+    }
+
+    fn rasterization_state(
+        &self,
+        render_style: RenderStyle,
+    ) -> vk::PipelineRasterizationStateCreateInfo<'static> {
+        vk::PipelineRasterizationStateCreateInfo {
+            front_face: self.front_face,
+            line_width: self.line_width,
+            polygon_mode: match render_style {
+                RenderStyle::Normal | RenderStyle::Normals | RenderStyle::Depth => {
+                    vk::PolygonMode::FILL
+                }
+                RenderStyle::Wireframe => vk::PolygonMode::LINE,
+            },
+            cull_mode: if self.double_sided {
+                vk::CullModeFlags::NONE
+            } else {
+                vk::CullModeFlags::BACK
+            },
+            ..Default::default()
+        }
+    }
+
+    /// Use flat shading in pipelines created from now on
+    pub fn set_flat_shading(&mut self, flat_shading: bool) {
+        self.flat_shading = flat_shading;
+    }
+
+    /// Render back faces instead of culling them in pipelines created from now on
+    pub fn set_double_sided(&mut self, double_sided: bool) {
+        self.double_sided = double_sided;
+    }
+
+    /// Winding of front faces in pipelines created from now on
+    pub fn set_front_face(&mut self, front_face: vk::FrontFace) {
+        self.front_face = front_face;
+    }
+
+    /// Additional `(constant ID, value)` specialization constants of all stages in pipelines
+    /// created from now on, e.g. from [`specialization_constant`]. IDs up to
+    /// `DOUBLE_SIDED_CONSTANT_ID` are set by the pipeline itself.
+    pub fn set_specialization_constants(&mut self, constants: &[(u32, Vec<u8>)]) {
+        self.specialization_constants = constants.to_vec();
+    }
+
+    /// Map entries and data of the built-in specialization constants followed by the additional
+    /// ones
+    fn specialization(
+        &self,
+        render_style: RenderStyle,
+    ) -> (Vec<vk::SpecializationMapEntry>, Vec<u8>) {
+        let built_in = [
+            (
+                VISUALIZE_NORMALS_CONSTANT_ID,
+                render_style == RenderStyle::Normals,
+            ),
+            (
+                VISUALIZE_DEPTH_CONSTANT_ID,
+                render_style == RenderStyle::Depth,
+            ),
+            (
+                VISUALIZE_WIREFRAME_CONSTANT_ID,
+                render_style == RenderStyle::Wireframe,
+            ),
+            (FLAT_SHADING_CONSTANT_ID, self.flat_shading),
+            (DOUBLE_SIDED_CONSTANT_ID, self.double_sided),
+        ]
+        .map(|(constant_id, value)| specialization_constant(constant_id, vk::Bool32::from(value)));
+
+        let mut map_entries = Vec::new();
+        let mut data = Vec::new();
+        for (constant_id, bytes) in built_in.iter().chain(&self.specialization_constants) {
+            map_entries.push(vk::SpecializationMapEntry {
+                constant_id: *constant_id,
+                offset: data.len() as u32,
+                size: bytes.len(),
+            });
+            data.extend_from_slice(bytes);
+        }
+        (map_entries, data)
+    }
+
+    /// Width of lines and wireframe edges in pipelines created from now on. Widths other than 1.0
+    /// require the `wideLines` feature.
+    pub fn set_line_width(&mut self, line_width: f32) {
+        self.line_width = line_width;
+    }
+
+    /// Primitive topology of graphics pipelines created from now on
+    pub fn set_topology(&mut self, topology: vk::PrimitiveTopology) {
+        self.topology = topology;
+    }
+
+    /// Keep the image contents in graphics pipelines created from now on to draw on top of
+    /// another renderer. Only supported without multisampling.
+    pub fn set_overlay(&mut self, overlay: bool) {
+        self.overlay = overlay;
     }
 
+    /// Write view space normals in `NORMAL_ATTACHMENT_FORMAT` to a second color attachment
+    /// following the depth attachment in graphics pipelines created from now on. Depth and
+    /// normals end up readable by compute shaders. Only supported without multisampling.
+    pub fn set_normal_attachment(&mut self, normal_attachment: bool) {
+        self.normal_attachment = normal_attachment;
+    }
+
+    /// Make the depth attachment readable by compute shaders after render passes of graphics
+    /// pipelines created from now on. Only supported without multisampling.
+    pub fn set_sampled_depth(&mut self, sampled_depth: bool) {
+        self.sampled_depth = sampled_depth;
+    }
+
+    /// Start render passes with a depth-only subpass and shade only the fragments with equal
+    /// depth in the second subpass in pipelines created from now on. The first subpass needs a
+    /// pipeline from [`ShaderPipeline::make_depth_prepass_pipeline`].
+    pub fn set_depth_prepass(&mut self, depth_prepass: bool) {
+        self.depth_prepass = depth_prepass;
+    }
+
+    /// Format of the depth attachment of render passes created from now on, `D16_UNORM` by
+    /// default
+    pub fn set_depth_format(&mut self, depth_format: vk::Format) {
+        self.depth_format = depth_format;
+    }
+
+    /// Format the depth images of framebuffers for the created render passes must have
+    pub fn depth_format(&self) -> vk::Format {
+        self.depth_format
+    }
+
+    /// Source files of the shaders as recorded in their debug info. Shaders compiled without
+    /// debug info (e.g. stripped SPIR-V) have none.
+    pub fn shaders_source_files(&self) -> Vec<PathBuf> {
+        self.hot_reload_sources
+            .iter()
+            .filter(|source| !source.is_empty())
+            .map(PathBuf::from)
+            .collect()
+    }
+
+    /// Recompiles all shader sources that changed since they were last loaded. On compilation
+    /// errors, no shader module is replaced.
     pub fn reload_sources(&mut self) -> anyhow::Result<()> {
-        for (shader, source) in self.shaders.iter_mut().zip(self.hot_reload_sources.iter()) {
-            info!("Trying to reload {source}");
-            let output = Command::new("glslc")
-                .args([source, "--target-env=vulkan1.3", "-g", "-O", "-o", "-"])
-                .output()?;
-            let bytes = output.stdout;
-            if !output.stderr.is_empty() {
-                return Err(anyhow::anyhow!(
-                    "Failure during shader compilation!: {}",
-                    String::from_utf8(output.stderr)
-                        .unwrap_or_else(|_err| "<unknown error>".into())
-                ));
+        let compiler = shaderc::Compiler::new()
+            .ok_or_else(|| anyhow::anyhow!("Failed to initialize shaderc"))?;
+
+        let mut reloaded = Vec::new();
+        for (i, source) in self.hot_reload_sources.iter().enumerate() {
+            let modified = modification_time(source);
+            if modified.is_none() || modified == self.source_modification_times[i] {
+                continue;
             }
+            info!("Trying to reload {source}");
+            let bytes = compile_shader(&compiler, source, self.shaders[i].stage)?;
+            let info = spirv_reflect::ShaderModule::load_u8_data(&bytes)
+                .map_err(|err| anyhow::anyhow!("{err}"))?;
+            anyhow::ensure!(
+                shader_stage(&info)? == self.shaders[i].stage,
+                "Reloaded {source} has a different shader stage"
+            );
+            reloaded.push((i, bytes, info, modified));
+        }
 
+        for (i, bytes, info, modified) in reloaded {
             let new_module = unsafe {
                 self.device.create_shader_module(
                     &vk::ShaderModuleCreateInfo::default()
@@ -250,8 +686,11 @@ impl<'device> ShaderPipeline<'device> {
                     None,
                 )?
             };
+            let shader = &mut self.shaders[i];
             unsafe { self.device.destroy_shader_module(shader.module, None) };
             shader.module = new_module;
+            shader.info = info;
+            self.source_modification_times[i] = modified;
         }
         Ok(())
     }
@@ -259,20 +698,25 @@ impl<'device> ShaderPipeline<'device> {
     pub fn make_rtx_pipeline(
         &self,
         device: &'device ash::Device,
+        pipeline_cache: vk::PipelineCache,
         shader_groups: &[vk::RayTracingShaderGroupCreateInfoKHR],
         raytracing_ext: &ash::extensions::khr::RayTracingPipeline,
-        descriptor_set_layout: vk::DescriptorSetLayout,
+        descriptor_set_layouts: &[vk::DescriptorSetLayout],
         max_pipeline_ray_recursion_depth: u32,
         push_constant_ranges: &[vk::PushConstantRange], // TODO: do this via reflection
+        render_style: RenderStyle,
     ) -> anyhow::Result<(vk::Pipeline, vk::PipelineLayout)> {
-        let layouts = vec![descriptor_set_layout];
         let layout_create_info = vk::PipelineLayoutCreateInfo::default()
-            .set_layouts(&layouts)
+            .set_layouts(descriptor_set_layouts)
             .push_constant_ranges(push_constant_ranges);
 
         let pipeline_layout =
             unsafe { device.create_pipeline_layout(&layout_create_info, None) }.unwrap();
 
+        let (map_entries, specialization_data) = self.specialization(render_style);
+        let specialization_info = vk::SpecializationInfo::default()
+            .map_entries(&map_entries)
+            .data(&specialization_data);
         let shader_stage_create_infos = self
             .shaders
             .iter()
@@ -280,13 +724,14 @@ impl<'device> ShaderPipeline<'device> {
                 vk::PipelineShaderStageCreateInfo::default()
                     .name(unsafe { CStr::from_bytes_with_nul_unchecked(b"main\0") })
                     .module(shader.module)
-                    .stage(unsafe { transmute(shader.info.get_shader_stage()) })
+                    .stage(shader.stage)
+                    .specialization_info(&specialization_info)
             })
             .collect::<Vec<_>>();
         let pipeline = unsafe {
             raytracing_ext.create_ray_tracing_pipelines(
                 vk::DeferredOperationKHR::null(),
-                vk::PipelineCache::null(),
+                pipeline_cache,
                 &[vk::RayTracingPipelineCreateInfoKHR::default()
                     .stages(&shader_stage_create_infos)
                     .groups(shader_groups)
@@ -298,4 +743,252 @@ impl<'device> ShaderPipeline<'device> {
 
         Ok((pipeline, pipeline_layout))
     }
+
+    /// Compute pipeline of the first shader
+    pub fn make_compute_pipeline(
+        &self,
+        device: &'device ash::Device,
+        pipeline_cache: vk::PipelineCache,
+        descriptor_set_layouts: &[vk::DescriptorSetLayout],
+        push_constant_ranges: &[vk::PushConstantRange],
+        render_style: RenderStyle,
+    ) -> anyhow::Result<(vk::Pipeline, vk::PipelineLayout)> {
+        let shader = self
+            .shaders
+            .first()
+            .filter(|shader| shader.stage == vk::ShaderStageFlags::COMPUTE)
+            .ok_or_else(|| anyhow::anyhow!("No compute shader loaded"))?;
+        let layout_create_info = vk::PipelineLayoutCreateInfo::default()
+            .set_layouts(descriptor_set_layouts)
+            .push_constant_ranges(push_constant_ranges);
+        let pipeline_layout = unsafe { device.create_pipeline_layout(&layout_create_info, None) }?;
+
+        let (map_entries, specialization_data) = self.specialization(render_style);
+        let specialization_info = vk::SpecializationInfo::default()
+            .map_entries(&map_entries)
+            .data(&specialization_data);
+        let pipeline = unsafe {
+            device.create_compute_pipelines(
+                pipeline_cache,
+                &[vk::ComputePipelineCreateInfo::default()
+                    .stage(
+                        vk::PipelineShaderStageCreateInfo::default()
+                            .name(CStr::from_bytes_with_nul_unchecked(b"main\0"))
+                            .module(shader.module)
+                            .stage(vk::ShaderStageFlags::COMPUTE)
+                            .specialization_info(&specialization_info),
+                    )
+                    .layout(pipeline_layout)],
+                None,
+            )
+        }
+        .map_err(|(_, err)| err)?[0];
+
+        Ok((pipeline, pipeline_layout))
+    }
+}
+
+mod sealed {
+    pub trait Sealed {}
+}
+
+/// Scalar types of specialization constants. `bool` constants have to be passed as
+/// [`vk::Bool32`].
+pub trait SpecializationConstant: sealed::Sealed + Copy {
+    /// Bytes of the value as read by the shader
+    fn to_ne_bytes(self) -> [u8; 4];
+}
+
+macro_rules! impl_specialization_constant {
+    ($($t:ty),*) => {
+        $(
+            impl sealed::Sealed for $t {}
+            impl SpecializationConstant for $t {
+                fn to_ne_bytes(self) -> [u8; 4] {
+                    <$t>::to_ne_bytes(self)
+                }
+            }
+        )*
+    };
+}
+
+// `vk::Bool32` is a `u32`
+impl_specialization_constant!(u32, i32, f32);
+
+/// Specialization constant `constant_id` with the bytes of `value`
+pub fn specialization_constant<T: SpecializationConstant>(
+    constant_id: u32,
+    value: T,
+) -> (u32, Vec<u8>) {
+    (constant_id, value.to_ne_bytes().to_vec())
+}
+
+/// Shader stage reflected from SPIR-V, for the stages this crate builds pipelines with
+fn shader_stage(info: &spirv_reflect::ShaderModule) -> anyhow::Result<vk::ShaderStageFlags> {
+    let stage = vk::ShaderStageFlags::from_raw(info.get_shader_stage().bits());
+    match stage {
+        vk::ShaderStageFlags::VERTEX
+        | vk::ShaderStageFlags::FRAGMENT
+        | vk::ShaderStageFlags::GEOMETRY
+        | vk::ShaderStageFlags::COMPUTE
+        | vk::ShaderStageFlags::RAYGEN_KHR
+        | vk::ShaderStageFlags::MISS_KHR
+        | vk::ShaderStageFlags::CLOSEST_HIT_KHR
+        | vk::ShaderStageFlags::ANY_HIT_KHR
+        | vk::ShaderStageFlags::INTERSECTION_KHR
+        | vk::ShaderStageFlags::CALLABLE_KHR => Ok(stage),
+        _ => Err(anyhow::anyhow!(
+            "Unsupported shader stage {stage:?} of {}",
+            info.get_source_file()
+        )),
+    }
+}
+
+fn modification_time(path: impl AsRef<Path>) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+fn compile_shader(
+    compiler: &shaderc::Compiler,
+    source_file: &str,
+    stage: vk::ShaderStageFlags,
+) -> anyhow::Result<Vec<u8>> {
+    let kind = match stage {
+        vk::ShaderStageFlags::VERTEX => shaderc::ShaderKind::Vertex,
+        vk::ShaderStageFlags::FRAGMENT => shaderc::ShaderKind::Fragment,
+        vk::ShaderStageFlags::COMPUTE => shaderc::ShaderKind::Compute,
+        vk::ShaderStageFlags::GEOMETRY => shaderc::ShaderKind::Geometry,
+        vk::ShaderStageFlags::RAYGEN_KHR => shaderc::ShaderKind::RayGeneration,
+        vk::ShaderStageFlags::MISS_KHR => shaderc::ShaderKind::Miss,
+        vk::ShaderStageFlags::CLOSEST_HIT_KHR => shaderc::ShaderKind::ClosestHit,
+        vk::ShaderStageFlags::ANY_HIT_KHR => shaderc::ShaderKind::AnyHit,
+        vk::ShaderStageFlags::INTERSECTION_KHR => shaderc::ShaderKind::Intersection,
+        vk::ShaderStageFlags::CALLABLE_KHR => shaderc::ShaderKind::Callable,
+        _ => shaderc::ShaderKind::InferFromSource,
+    };
+    let mut options = shaderc::CompileOptions::new()
+        .ok_or_else(|| anyhow::anyhow!("Failed to create shaderc options"))?;
+    options.set_target_env(
+        shaderc::TargetEnv::Vulkan,
+        shaderc::EnvVersion::Vulkan1_3 as u32,
+    );
+    options.set_generate_debug_info();
+    options.set_optimization_level(shaderc::OptimizationLevel::Performance);
+    if source_file.ends_with(".hlsl") {
+        options.set_source_language(shaderc::SourceLanguage::HLSL);
+    }
+
+    let source = std::fs::read_to_string(source_file)?;
+    let artifact = compiler
+        .compile_into_spirv(&source, kind, source_file, "main", Some(&options))
+        .map_err(|err| anyhow::anyhow!("Failure during shader compilation!: {err}"))?;
+    if artifact.get_num_warnings() > 0 {
+        log::warn!("{}", artifact.get_warning_messages());
+    }
+    Ok(artifact.as_binary_u8().to_vec())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn specialization_constant_bytes() {
+        assert_eq!(
+            specialization_constant(TONEMAP_CONSTANT_ID, 2u32),
+            (TONEMAP_CONSTANT_ID, 2u32.to_ne_bytes().to_vec())
+        );
+        assert_eq!(
+            specialization_constant(0, vk::TRUE),
+            (0, 1u32.to_ne_bytes().to_vec())
+        );
+        assert_eq!(specialization_constant(0, -1i32).1, [0xff; 4]);
+        assert_eq!(
+            specialization_constant(0, 1.5f32).1,
+            1.5f32.to_ne_bytes().to_vec()
+        );
+    }
+
+    /// Stage of a bundled shader implied by its file name
+    fn stage_from_file_name(name: &str) -> Option<vk::ShaderStageFlags> {
+        let stem = name.strip_suffix(".spirv")?;
+        Some(match Path::new(stem).extension()?.to_str()? {
+            "vert" => vk::ShaderStageFlags::VERTEX,
+            "frag" => vk::ShaderStageFlags::FRAGMENT,
+            "comp" => vk::ShaderStageFlags::COMPUTE,
+            // Ray tracing stages are set with `#pragma shader_stage`
+            "glsl" => match Path::new(stem).file_stem()?.to_str()? {
+                "raygen" => vk::ShaderStageFlags::RAYGEN_KHR,
+                "miss" | "shadow_miss" => vk::ShaderStageFlags::MISS_KHR,
+                "closest_hit" => vk::ShaderStageFlags::CLOSEST_HIT_KHR,
+                "anyhit" => vk::ShaderStageFlags::ANY_HIT_KHR,
+                "sphere_intersection" => vk::ShaderStageFlags::INTERSECTION_KHR,
+                _ => return None,
+            },
+            _ => return None,
+        })
+    }
+
+    #[test]
+    fn bundled_shaders_have_the_stage_of_their_file_name() {
+        macro_rules! bundled {
+            ($($name:literal),*) => {
+                [$(($name, &include_bytes!(concat!("../shaders/", $name))[..])),*]
+            };
+        }
+        for (name, bytes) in bundled!(
+            "triangle.vert.spirv",
+            "triangle.frag.spirv",
+            "lines.vert.spirv",
+            "lines.frag.spirv",
+            "simple.comp.spirv",
+            "ssao.comp.spirv",
+            "depth.comp.spirv",
+            "raygen.glsl.spirv",
+            "miss.glsl.spirv",
+            "shadow_miss.glsl.spirv",
+            "closest_hit.glsl.spirv",
+            "anyhit.glsl.spirv",
+            "sphere_intersection.glsl.spirv"
+        ) {
+            let info = spirv_reflect::ShaderModule::load_u8_data(bytes).unwrap();
+            assert_eq!(
+                shader_stage(&info).unwrap(),
+                stage_from_file_name(name).unwrap(),
+                "{name}"
+            );
+        }
+    }
+
+    #[test]
+    fn unsupported_shader_stages_are_errors() {
+        // Empty tessellation control shader with 3 output vertices
+        let words: &[&[u32]] = &[
+            // Header with the SPIR-V magic number, version 1.0 and an ID bound of 5
+            &[0x0723_0203, 0x0001_0000, 0, 5, 0],
+            // OpCapability Tessellation
+            &[0x0002_0011, 3],
+            // OpMemoryModel Logical GLSL450
+            &[0x0003_000e, 0, 1],
+            // OpEntryPoint TessellationControl %1 "main"
+            &[0x0005_000f, 1, 1, u32::from_le_bytes(*b"main"), 0],
+            // OpExecutionMode %1 OutputVertices 3
+            &[0x0004_0010, 1, 26, 3],
+            // %2 = OpTypeVoid, %3 = OpTypeFunction %2
+            &[0x0002_0013, 2],
+            &[0x0003_0021, 3, 2],
+            // %1 = OpFunction %2 None %3, %4 = OpLabel, OpReturn, OpFunctionEnd
+            &[0x0005_0036, 2, 1, 0, 3],
+            &[0x0002_00f8, 4],
+            &[0x0001_00fd],
+            &[0x0001_0038],
+        ];
+        let bytes: Vec<u8> = words
+            .concat()
+            .iter()
+            .flat_map(|w| w.to_le_bytes())
+            .collect();
+        let info = spirv_reflect::ShaderModule::load_u8_data(&bytes).unwrap();
+        assert!(shader_stage(&info).is_err());
+    }
 }
@@ -0,0 +1,268 @@
+//
+// texture.rs
+// Copyright (C) 2022 Stephan Seitz <stephan.seitz@fau.de>
+// Distributed under terms of the GPLv3 license.
+//
+
+use std::{path::Path, rc::Rc};
+
+use anyhow::Context;
+use ash::vk;
+use log::info;
+
+use crate::device_mesh::{find_memorytype_index, Buffer};
+use crate::sampler::{SamplerCache, SamplerOptions};
+
+/// Sampled 2D image with view and sampler
+pub struct Texture<'device> {
+    device: &'device ash::Device,
+    image: vk::Image,
+    memory: vk::DeviceMemory,
+    view: vk::ImageView,
+    sampler: vk::Sampler,
+    /// Owns `sampler`
+    _samplers: Rc<SamplerCache<'device>>,
+}
+
+impl Drop for Texture<'_> {
+    fn drop(&mut self) {
+        unsafe {
+            self.device.destroy_image_view(self.view, None);
+            self.device.destroy_image(self.image, None);
+            self.device.free_memory(self.memory, None);
+        }
+    }
+}
+
+impl<'device> Texture<'device> {
+    /// Load an image file. Color textures are usually sRGB encoded, data textures like normal
+    /// maps should pass `srgb = false`.
+    #[allow(clippy::too_many_arguments)]
+    pub fn from_file(
+        device: &'device ash::Device,
+        mem_properties: &vk::PhysicalDeviceMemoryProperties,
+        cmd: vk::CommandBuffer,
+        graphics_queue: vk::Queue,
+        samplers: &Rc<SamplerCache<'device>>,
+        sampler: SamplerOptions,
+        path: &Path,
+        srgb: bool,
+    ) -> anyhow::Result<Self> {
+        info!("Reading texture {path:?}");
+        let image = image::open(path)
+            .with_context(|| format!("Failed to read texture {}", path.display()))?
+            .to_rgba8();
+        Self::from_pixels(
+            device,
+            mem_properties,
+            cmd,
+            graphics_queue,
+            samplers,
+            sampler,
+            vk::Extent2D {
+                width: image.width(),
+                height: image.height(),
+            },
+            image.as_raw(),
+            if srgb {
+                vk::Format::R8G8B8A8_SRGB
+            } else {
+                vk::Format::R8G8B8A8_UNORM
+            },
+        )
+    }
+
+    /// Load a high dynamic range image (e.g. a Radiance `.hdr` file) as linear float texture
+    pub fn from_hdr_file(
+        device: &'device ash::Device,
+        mem_properties: &vk::PhysicalDeviceMemoryProperties,
+        cmd: vk::CommandBuffer,
+        graphics_queue: vk::Queue,
+        samplers: &Rc<SamplerCache<'device>>,
+        sampler: SamplerOptions,
+        path: &Path,
+    ) -> anyhow::Result<Self> {
+        info!("Reading HDR texture {path:?}");
+        let image = image::open(path)
+            .with_context(|| format!("Failed to read texture {}", path.display()))?
+            .to_rgba32f();
+        Self::from_pixels(
+            device,
+            mem_properties,
+            cmd,
+            graphics_queue,
+            samplers,
+            sampler,
+            vk::Extent2D {
+                width: image.width(),
+                height: image.height(),
+            },
+            image.as_raw(),
+            vk::Format::R32G32B32A32_SFLOAT,
+        )
+    }
+
+    /// 1x1 white texture for meshes without material
+    pub fn white(
+        device: &'device ash::Device,
+        mem_properties: &vk::PhysicalDeviceMemoryProperties,
+        cmd: vk::CommandBuffer,
+        graphics_queue: vk::Queue,
+        samplers: &Rc<SamplerCache<'device>>,
+    ) -> anyhow::Result<Self> {
+        Self::from_pixels(
+            device,
+            mem_properties,
+            cmd,
+            graphics_queue,
+            samplers,
+            SamplerOptions::nearest(),
+            vk::Extent2D {
+                width: 1,
+                height: 1,
+            },
+            &[255; 4],
+            vk::Format::R8G8B8A8_UNORM,
+        )
+    }
+
+    /// Upload tightly packed `pixels` of `format`
+    #[allow(clippy::too_many_arguments)]
+    fn from_pixels<T: Copy>(
+        device: &'device ash::Device,
+        mem_properties: &vk::PhysicalDeviceMemoryProperties,
+        cmd: vk::CommandBuffer,
+        graphics_queue: vk::Queue,
+        samplers: &Rc<SamplerCache<'device>>,
+        sampler: SamplerOptions,
+        extent: vk::Extent2D,
+        pixels: &[T],
+        format: vk::Format,
+    ) -> anyhow::Result<Self> {
+        let staging_buffer = Buffer::new(
+            device,
+            mem_properties,
+            &vk::BufferCreateInfo::default()
+                .size(std::mem::size_of_val(pixels) as vk::DeviceSize)
+                .usage(vk::BufferUsageFlags::TRANSFER_SRC)
+                .sharing_mode(vk::SharingMode::EXCLUSIVE),
+            Some(pixels),
+        )?;
+        let subresource_range = vk::ImageSubresourceRange {
+            aspect_mask: vk::ImageAspectFlags::COLOR,
+            base_mip_level: 0,
+            level_count: 1,
+            base_array_layer: 0,
+            layer_count: 1,
+        };
+        unsafe {
+            let image = device.create_image(
+                &vk::ImageCreateInfo::default()
+                    .image_type(vk::ImageType::TYPE_2D)
+                    .format(format)
+                    .extent(extent.into())
+                    .mip_levels(1)
+                    .array_layers(1)
+                    .samples(vk::SampleCountFlags::TYPE_1)
+                    .tiling(vk::ImageTiling::OPTIMAL)
+                    .usage(vk::ImageUsageFlags::TRANSFER_DST | vk::ImageUsageFlags::SAMPLED)
+                    .sharing_mode(vk::SharingMode::EXCLUSIVE)
+                    .initial_layout(vk::ImageLayout::UNDEFINED),
+                None,
+            )?;
+            let req = device.get_image_memory_requirements(image);
+            let index =
+                find_memorytype_index(&req, mem_properties, vk::MemoryPropertyFlags::DEVICE_LOCAL)
+                    .ok_or_else(|| anyhow::anyhow!("Failed to get memory index for texture"))?;
+            let memory = device.allocate_memory(
+                &vk::MemoryAllocateInfo::default()
+                    .allocation_size(req.size)
+                    .memory_type_index(index),
+                None,
+            )?;
+            device.bind_image_memory(image, memory, 0)?;
+
+            device.begin_command_buffer(
+                cmd,
+                &vk::CommandBufferBeginInfo::default()
+                    .flags(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT),
+            )?;
+            device.cmd_pipeline_barrier(
+                cmd,
+                vk::PipelineStageFlags::TOP_OF_PIPE,
+                vk::PipelineStageFlags::TRANSFER,
+                vk::DependencyFlags::empty(),
+                &[],
+                &[],
+                &[vk::ImageMemoryBarrier::default()
+                    .dst_access_mask(vk::AccessFlags::TRANSFER_WRITE)
+                    .old_layout(vk::ImageLayout::UNDEFINED)
+                    .new_layout(vk::ImageLayout::TRANSFER_DST_OPTIMAL)
+                    .image(image)
+                    .subresource_range(subresource_range)],
+            );
+            device.cmd_copy_buffer_to_image(
+                cmd,
+                staging_buffer.buffer(),
+                image,
+                vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                &[vk::BufferImageCopy::default()
+                    .image_subresource(vk::ImageSubresourceLayers {
+                        aspect_mask: vk::ImageAspectFlags::COLOR,
+                        mip_level: 0,
+                        base_array_layer: 0,
+                        layer_count: 1,
+                    })
+                    .image_extent(extent.into())],
+            );
+            device.cmd_pipeline_barrier(
+                cmd,
+                vk::PipelineStageFlags::TRANSFER,
+                vk::PipelineStageFlags::FRAGMENT_SHADER,
+                vk::DependencyFlags::empty(),
+                &[],
+                &[],
+                &[vk::ImageMemoryBarrier::default()
+                    .src_access_mask(vk::AccessFlags::TRANSFER_WRITE)
+                    .dst_access_mask(vk::AccessFlags::SHADER_READ)
+                    .old_layout(vk::ImageLayout::TRANSFER_DST_OPTIMAL)
+                    .new_layout(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL)
+                    .image(image)
+                    .subresource_range(subresource_range)],
+            );
+            device.end_command_buffer(cmd)?;
+            device
+                .queue_submit(
+                    graphics_queue,
+                    &[vk::SubmitInfo::default().command_buffers(&[cmd])],
+                    vk::Fence::null(),
+                )
+                .context("queue submit failed.")?;
+            device.queue_wait_idle(graphics_queue)?;
+
+            let view = device.create_image_view(
+                &vk::ImageViewCreateInfo::default()
+                    .view_type(vk::ImageViewType::TYPE_2D)
+                    .format(format)
+                    .subresource_range(subresource_range)
+                    .image(image),
+                None,
+            )?;
+            Ok(Self {
+                device,
+                image,
+                memory,
+                view,
+                sampler: samplers.get(sampler)?,
+                _samplers: Rc::clone(samplers),
+            })
+        }
+    }
+
+    pub fn descriptor_image_info(&self) -> vk::DescriptorImageInfo {
+        vk::DescriptorImageInfo::default()
+            .image_layout(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL)
+            .image_view(self.view)
+            .sampler(self.sampler)
+    }
+}
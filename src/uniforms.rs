@@ -1,42 +1,473 @@
+use std::mem::{size_of, transmute};
+
 use ash::vk;
-use cgmath::{Deg, Matrix4, Point3, Vector3, Vector4};
+use cgmath::{
+    Deg, EuclideanSpace, InnerSpace, Matrix4, Point3, Quaternion, Rad, Rotation, Rotation3,
+    Vector3, Vector4, Zero,
+};
+use log::warn;
+use winit::{
+    dpi::{PhysicalPosition, PhysicalSize},
+    event::{DeviceEvent, ElementState, MouseButton, MouseScrollDelta, WindowEvent},
+};
+
+use crate::device_mesh::Buffer;
+
+/// Maximum number of lights in [`Lights`], must match the shaders
+pub const MAX_LIGHTS: usize = 8;
 
 #[repr(C)]
-#[derive(Clone, Copy)]
-pub struct PushConstants {
-    light_position: Vector4<f32>,
+#[derive(Clone, Copy, Debug)]
+pub struct Light {
+    /// Direction towards the light for `w = 0`, position of a point light for `w = 1`
+    position: Vector4<f32>,
+    /// Color in `rgb`, intensity in `a`
+    color: Vector4<f32>,
+}
+
+impl Light {
+    pub fn directional(direction: Vector3<f32>, color: Vector3<f32>, intensity: f32) -> Self {
+        Self {
+            position: direction.normalize().extend(0.0),
+            color: color.extend(intensity),
+        }
+    }
+
+    /// Point light with quadratic falloff
+    pub fn point(position: Point3<f32>, color: Vector3<f32>, intensity: f32) -> Self {
+        Self {
+            position: position.to_homogeneous(),
+            color: color.extend(intensity),
+        }
+    }
+}
+
+/// Contents of the light uniform buffer (std140 layout)
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct Lights {
+    count: u32,
+    _padding: [u32; 3],
+    lights: [Light; MAX_LIGHTS],
+}
+
+impl Lights {
+    /// Takes the first `MAX_LIGHTS` lights
+    pub fn new(lights: &[Light]) -> Self {
+        if lights.len() > MAX_LIGHTS {
+            warn!("Only {MAX_LIGHTS} of {} lights are supported", lights.len());
+        }
+        let mut result = Self {
+            count: 0,
+            _padding: [0; 3],
+            lights: [Light::directional(Vector3::unit_z(), Vector3::zero(), 0.0); MAX_LIGHTS],
+        };
+        for (dst, src) in result.lights.iter_mut().zip(lights) {
+            *dst = *src;
+            result.count += 1;
+        }
+        result
+    }
+}
+
+impl Default for Lights {
+    /// A white key light from the upper right front
+    fn default() -> Self {
+        Self::new(&[Light::directional(
+            Vector3::new(1.0, 1.0, 1.0),
+            Vector3::new(1.0, 1.0, 1.0),
+            1.0,
+        )])
+    }
+}
+
+/// Clear color of the rasterizer and color of rays that miss all meshes
+#[derive(Clone, Copy, Debug)]
+pub struct Background {
+    pub color: Vector4<f32>,
+    /// Ray tracing blends from `color` for downward rays to this for upward rays
+    pub gradient_top: Option<Vector4<f32>>,
+}
+
+impl Default for Background {
+    fn default() -> Self {
+        Self {
+            color: Vector4::zero(),
+            gradient_top: None,
+        }
+    }
+}
+
+/// Contents of the camera uniform buffer (std140 layout)
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct CameraUniforms {
     view: Matrix4<f32>,
-    model: Matrix4<f32>,
     proj: Matrix4<f32>,
 }
 
-impl PushConstants {
+impl CameraUniforms {
+    /// Matrices of `camera` rendering to an image of size `extent`
+    pub fn new(extent: vk::Extent2D, camera: &Camera) -> Self {
+        Self {
+            view: camera.view(),
+            proj: camera.projection(extent.width as f32 / extent.height as f32),
+        }
+    }
+}
+
+/// Device local uniform buffer with [`CameraUniforms`], updated in the command buffer of each
+/// frame so that frames in flight keep their own camera
+pub struct CameraBuffer<'device> {
+    buffer: Buffer<'device>,
+    /// Shader stages that read the uniforms
+    stages: vk::PipelineStageFlags,
+}
+
+impl<'device> CameraBuffer<'device> {
     pub fn new(
-        extent: vk::Extent2D,
-        translation: Point3<f32>,
-        light_position: Vector4<f32>,
-        zoom: f32,
-        rotation: f32,
-    ) -> Self {
+        device: &'device ash::Device,
+        device_memory_properties: &vk::PhysicalDeviceMemoryProperties,
+        stages: vk::PipelineStageFlags,
+    ) -> anyhow::Result<Self> {
+        let buffer = Buffer::new(
+            device,
+            device_memory_properties,
+            &vk::BufferCreateInfo::default()
+                .size(size_of::<CameraUniforms>() as vk::DeviceSize)
+                .usage(vk::BufferUsageFlags::UNIFORM_BUFFER | vk::BufferUsageFlags::TRANSFER_DST)
+                .sharing_mode(vk::SharingMode::EXCLUSIVE),
+            None::<&[CameraUniforms]>,
+        )?;
+        Ok(Self { buffer, stages })
+    }
+
+    pub fn descriptor_buffer_info(&self) -> vk::DescriptorBufferInfo {
+        vk::DescriptorBufferInfo::default()
+            .buffer(self.buffer.buffer())
+            .range(vk::WHOLE_SIZE)
+    }
+
+    /// Record an update of the uniforms, which has to happen outside of render passes
+    pub fn cmd_update(
+        &self,
+        device: &ash::Device,
+        cmd: vk::CommandBuffer,
+        uniforms: CameraUniforms,
+    ) {
+        unsafe {
+            // Previous frames might still read the old uniforms
+            device.cmd_pipeline_barrier(
+                cmd,
+                self.stages,
+                vk::PipelineStageFlags::TRANSFER,
+                vk::DependencyFlags::empty(),
+                &[],
+                &[],
+                &[],
+            );
+            device.cmd_update_buffer(
+                cmd,
+                self.buffer.buffer(),
+                0,
+                &transmute::<CameraUniforms, [u8; size_of::<CameraUniforms>()]>(uniforms),
+            );
+            device.cmd_pipeline_barrier(
+                cmd,
+                vk::PipelineStageFlags::TRANSFER,
+                self.stages,
+                vk::DependencyFlags::empty(),
+                &[vk::MemoryBarrier::default()
+                    .src_access_mask(vk::AccessFlags::TRANSFER_WRITE)
+                    .dst_access_mask(vk::AccessFlags::UNIFORM_READ)],
+                &[],
+                &[],
+            );
+        }
+    }
+}
+
+/// Small per-frame data of the ray tracer, the camera is in a [`CameraBuffer`]
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct PushConstants {
+    background: Vector4<f32>,
+    background_top: Vector4<f32>,
+    /// Number of samples already accumulated by the ray tracer
+    sample_index: u32,
+    /// Ray tracing stops accumulating after this many samples (0 for never)
+    max_samples: u32,
+    /// Whether rays that miss sample the environment map instead of the background color
+    use_environment_map: u32,
+    /// Pixel of the swapchain image traced by the first ray
+    offset_x: u32,
+    offset_y: u32,
+}
+
+impl Default for PushConstants {
+    fn default() -> Self {
         Self {
-            light_position,
-            model: Matrix4::from_axis_angle(Vector3::new(0.0, 1.0, 0.0), Deg(rotation)),
-            view: Matrix4::look_at_rh(
-                zoom * Point3::new(0.0, 1.0, 5.0),
-                translation,
-                Vector3::new(0.0, 1.0, 0.0),
-            ),
-            proj: Matrix4::perspective(
-                Deg(60.0),
-                extent.width as f32 / extent.height as f32,
-                0.01,
-                100.0,
-            ),
+            background: Vector4::zero(),
+            background_top: Vector4::zero(),
+            sample_index: 0,
+            max_samples: 0,
+            use_environment_map: 0,
+            offset_x: 0,
+            offset_y: 0,
         }
     }
 }
 
-// Add perspective method
+impl PushConstants {
+    #[must_use]
+    pub fn with_background(mut self, background: &Background) -> Self {
+        self.background = background.color;
+        self.background_top = background.gradient_top.unwrap_or(background.color);
+        self
+    }
+
+    #[must_use]
+    pub fn with_environment_map(mut self, use_environment_map: bool) -> Self {
+        self.use_environment_map = use_environment_map.into();
+        self
+    }
+
+    #[must_use]
+    pub fn with_samples(mut self, sample_index: u32, max_samples: u32) -> Self {
+        self.sample_index = sample_index;
+        self.max_samples = max_samples;
+        self
+    }
+
+    #[must_use]
+    pub fn with_offset(mut self, offset: vk::Offset2D) -> Self {
+        self.offset_x = offset.x.max(0) as u32;
+        self.offset_y = offset.y.max(0) as u32;
+        self
+    }
+}
+
+/// How a [`Camera`] maps view space to clip space
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Projection {
+    Perspective,
+    /// Parallel projection covering the height the perspective projection sees at the target, so
+    /// that framing, dollying and zooming work alike
+    Orthographic,
+}
+
+/// Arcball camera orbiting around `target`.
+///
+/// Left-drag rotates, middle-drag pans and the mouse wheel dollies (or zooms by changing the field
+/// of view, toggled with `Z`). The arrow keys rotate around the vertical axis and dolly.
+#[derive(Debug, Clone, Copy)]
+pub struct Camera {
+    orientation: Quaternion<f32>,
+    target: Point3<f32>,
+    distance: f32,
+    fov: Deg<f32>,
+    near: f32,
+    far: f32,
+    projection: Projection,
+    left_drag: bool,
+    middle_drag: bool,
+    cursor: Option<PhysicalPosition<f64>>,
+    /// Whether the mouse wheel changes the field of view instead of the distance
+    scroll_zooms_fov: bool,
+}
+
+impl Camera {
+    pub fn new(target: Point3<f32>) -> Self {
+        Self {
+            // Look slightly from above like the previous fixed eye at (0, 1, 5)
+            orientation: Quaternion::from_angle_x(Rad(1.0f32.atan2(5.0))),
+            target,
+            distance: 5.0,
+            fov: Deg(60.0),
+            near: 0.01,
+            far: 100.0,
+            projection: Projection::Perspective,
+            left_drag: false,
+            middle_drag: false,
+            cursor: None,
+            scroll_zooms_fov: false,
+        }
+    }
+
+    /// Set vertical field of view and clip planes.
+    #[must_use]
+    pub fn with_projection(mut self, fov: Deg<f32>, near: f32, far: f32) -> Self {
+        self.fov = fov;
+        self.near = near;
+        self.far = far;
+        self
+    }
+
+    /// Switch between perspective and orthographic projection. Orthographic near planes may be
+    /// negative to include geometry behind the eye.
+    #[must_use]
+    pub fn with_projection_type(mut self, projection: Projection) -> Self {
+        self.projection = projection;
+        self
+    }
+
+    pub fn projection_type(&self) -> Projection {
+        self.projection
+    }
+
+    /// Orbit `center` at a distance where a sphere of `radius` fills the vertical field of view
+    pub fn frame(&mut self, center: Point3<f32>, radius: f32) {
+        self.target = center;
+        self.distance = radius / (Rad::from(self.fov).0 / 2.0).sin();
+    }
+
+    /// Restore the view (orientation, target, distance and projection) of `initial`, keeping the
+    /// mouse interaction state
+    pub fn reset_view(&mut self, initial: &Camera) {
+        self.orientation = initial.orientation;
+        self.target = initial.target;
+        self.distance = initial.distance;
+        self.fov = initial.fov;
+        self.near = initial.near;
+        self.far = initial.far;
+    }
+
+    /// Let the mouse wheel zoom by changing the field of view instead of dollying
+    #[must_use]
+    pub fn with_scroll_zooms_fov(mut self, scroll_zooms_fov: bool) -> Self {
+        self.scroll_zooms_fov = scroll_zooms_fov;
+        self
+    }
+
+    pub fn distance(&self) -> f32 {
+        self.distance
+    }
+
+    pub fn projection(&self, aspect: f32) -> Matrix4<f32> {
+        match self.projection {
+            Projection::Perspective => Matrix4::perspective(self.fov, aspect, self.near, self.far),
+            Projection::Orthographic => {
+                let half_height = self.distance * (Rad::from(self.fov).0 / 2.0).tan();
+                Matrix4::orthographic(half_height * aspect, half_height, self.near, self.far)
+            }
+        }
+    }
+
+    pub fn view(&self) -> Matrix4<f32> {
+        Matrix4::from_translation(Vector3::new(0.0, 0.0, -self.distance))
+            * Matrix4::from(self.orientation)
+            * Matrix4::from_translation(Point3::new(0.0, 0.0, 0.0) - self.target)
+    }
+
+    fn dolly(&mut self, amount: f32) {
+        self.distance = (self.distance * (1.0 - 0.1 * amount)).clamp(1e-3, 1e6);
+    }
+
+    fn zoom(&mut self, amount: f32) {
+        self.fov = Deg((self.fov.0 * (1.0 - 0.1 * amount)).clamp(1.0, 170.0));
+    }
+
+    /// Maps a cursor position onto the unit arcball sphere (in view space).
+    fn arcball_vector(position: PhysicalPosition<f64>, size: PhysicalSize<u32>) -> Vector3<f32> {
+        let radius = 0.5 * size.width.min(size.height).max(1) as f32;
+        let x = (position.x as f32 - 0.5 * size.width as f32) / radius;
+        let y = -(position.y as f32 - 0.5 * size.height as f32) / radius;
+        let length2 = x * x + y * y;
+        if length2 <= 1.0 {
+            Vector3::new(x, y, (1.0 - length2).sqrt())
+        } else {
+            Vector3::new(x, y, 0.0).normalize()
+        }
+    }
+
+    /// Returns whether the camera changed.
+    pub fn process_window_event(&mut self, event: &WindowEvent, size: PhysicalSize<u32>) -> bool {
+        match event {
+            WindowEvent::MouseInput { state, button, .. } => {
+                let pressed = *state == ElementState::Pressed;
+                match button {
+                    MouseButton::Left => self.left_drag = pressed,
+                    MouseButton::Middle => self.middle_drag = pressed,
+                    _ => (),
+                }
+                false
+            }
+            WindowEvent::CursorMoved { position, .. } => {
+                let last = self.cursor.replace(*position);
+                match last {
+                    Some(last) if self.left_drag => {
+                        let from = Self::arcball_vector(last, size);
+                        let to = Self::arcball_vector(*position, size);
+                        self.orientation =
+                            (Quaternion::between_vectors(from, to) * self.orientation).normalize();
+                        true
+                    }
+                    _ => false,
+                }
+            }
+            WindowEvent::CursorLeft { .. } => {
+                self.cursor = None;
+                false
+            }
+            WindowEvent::MouseWheel { delta, .. } => match delta {
+                MouseScrollDelta::LineDelta(_h, v) => {
+                    if self.scroll_zooms_fov {
+                        self.zoom(*v);
+                    } else {
+                        self.dolly(*v);
+                    }
+                    true
+                }
+                MouseScrollDelta::PixelDelta(_) => false,
+            },
+            WindowEvent::KeyboardInput { input, .. } if input.state == ElementState::Pressed => {
+                match input.virtual_keycode {
+                    Some(winit::event::VirtualKeyCode::Left) => {
+                        self.orientation = self.orientation * Quaternion::from_angle_y(Deg(-5.0));
+                        true
+                    }
+                    Some(winit::event::VirtualKeyCode::Right) => {
+                        self.orientation = self.orientation * Quaternion::from_angle_y(Deg(5.0));
+                        true
+                    }
+                    Some(winit::event::VirtualKeyCode::Down) => {
+                        self.dolly(-1.0);
+                        true
+                    }
+                    Some(winit::event::VirtualKeyCode::Up) => {
+                        self.dolly(1.0);
+                        true
+                    }
+                    Some(winit::event::VirtualKeyCode::Z) => {
+                        self.scroll_zooms_fov = !self.scroll_zooms_fov;
+                        false
+                    }
+                    _ => false,
+                }
+            }
+            _ => false,
+        }
+    }
+
+    /// Returns whether the camera changed.
+    pub fn process_device_event(&mut self, event: &DeviceEvent, size: PhysicalSize<u32>) -> bool {
+        match event {
+            DeviceEvent::MouseMotion { delta } if self.middle_drag => {
+                // Pan in the view plane so that the mesh follows the cursor
+                let scale = 2.0 * self.distance / size.height.max(1) as f32;
+                let inverse = self.orientation.invert();
+                let right = inverse.rotate_vector(Vector3::unit_x());
+                let up = inverse.rotate_vector(Vector3::unit_y());
+                self.target -= right * (delta.0 as f32 * scale);
+                self.target += up * (delta.1 as f32 * scale);
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+// Add perspective and orthographic methods
 trait Matrix4Ext {
     fn perspective<A: Into<cgmath::Rad<f32>>>(
         fovy: A,
@@ -44,6 +475,8 @@ trait Matrix4Ext {
         near: f32,
         far: f32,
     ) -> Matrix4<f32>;
+
+    fn orthographic(half_width: f32, half_height: f32, near: f32, far: f32) -> Matrix4<f32>;
 }
 impl Matrix4Ext for Matrix4<f32> {
     fn perspective<A: Into<cgmath::Rad<f32>>>(
@@ -75,4 +508,26 @@ impl Matrix4Ext for Matrix4<f32> {
             0.0,
         )
     }
+
+    /// Maps `near` to depth 0 and `far` to depth 1 and flips y like [`Matrix4Ext::perspective`]
+    fn orthographic(half_width: f32, half_height: f32, near: f32, far: f32) -> Matrix4<f32> {
+        Matrix4::<f32>::new(
+            1.0 / half_width,
+            0.0,
+            0.0,
+            0.0,
+            0.0,
+            -1.0 / half_height,
+            0.0,
+            0.0,
+            0.0,
+            0.0,
+            1.0 / (near - far),
+            0.0,
+            0.0,
+            0.0,
+            near / (near - far),
+            1.0,
+        )
+    }
 }
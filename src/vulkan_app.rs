@@ -1,29 +1,86 @@
-use std::collections::HashSet;
+use std::collections::{HashSet, VecDeque};
 use std::ffi::CString;
-use std::time::Instant;
-use std::{ffi::CStr, os::raw::c_char};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use std::{
+    ffi::{c_void, CStr},
+    os::raw::c_char,
+};
 
 use anyhow::Context;
 use ash::{
-    extensions::khr,
+    extensions::{ext, khr},
     prelude::VkResult,
     vk::{self, SurfaceFormatKHR},
 };
 use ash_swapchain::Swapchain;
-use log::{debug, error, info};
+use log::{debug, error, info, trace, warn};
 use tracing::{span, Level};
 use tracy_client::frame_mark;
 use winit::{dpi::PhysicalSize, window::Window};
 
+use crate::device_mesh::find_memorytype_index;
+use crate::gpu_timer::{FrameTimers, MAX_TIMED_RENDERERS};
+
 #[derive(thiserror::Error, Debug)]
 pub enum VulkanError {
     #[error("Found no device with surface support")]
     NoDeviceForSurfaceFound,
+    #[error("No device matches {selector:?}, available devices: {available}")]
+    NoMatchingDevice {
+        selector: DeviceSelector,
+        available: String,
+    },
+    #[error("No frame has been captured since the capture was requested")]
+    NoFrameCaptured,
+    #[error("Frame capture does not support surface format {0:?}")]
+    UnsupportedCaptureFormat(vk::Format),
 }
 
 struct Frame {
     cmd: vk::CommandBuffer,
     complete: vk::Semaphore,
+    /// Signaled when the last submission of `cmd` finished executing
+    fence: vk::Fence,
+}
+
+impl Frame {
+    /// Wait until `cmd` can be recorded again
+    unsafe fn wait(&self, device: &ash::Device) -> VkResult<()> {
+        device.wait_for_fences(&[self.fence], true, !0)
+    }
+
+    /// Submit `cmd`, the fence is only reset here so that failed recordings don't leave it
+    /// unsignaled
+    unsafe fn submit(
+        &self,
+        device: &ash::Device,
+        queue: vk::Queue,
+        submit_info: vk::SubmitInfo,
+    ) -> VkResult<()> {
+        device.reset_fences(&[self.fence])?;
+        device.queue_submit(
+            queue,
+            &[submit_info.command_buffers(&[self.cmd])],
+            self.fence,
+        )
+    }
+}
+
+/// Where frames end up: a window's swapchain or a single offscreen image for headless rendering
+enum Target {
+    Window {
+        surface: vk::SurfaceKHR,
+        swapchain: Swapchain,
+        present_mode: vk::PresentModeKHR,
+    },
+    Offscreen {
+        image: vk::Image,
+        memory: vk::DeviceMemory,
+        format: SurfaceFormatKHR,
+        extent: vk::Extent2D,
+    },
 }
 
 struct Functions {
@@ -37,45 +94,627 @@ pub enum TracingMode {
     Basic,
 }
 
+/// Physical device chosen on the command line
+#[derive(Clone, Debug)]
+pub enum DeviceSelector {
+    /// Index into the devices supporting graphics (and the surface)
+    Index(usize),
+    /// Case-insensitive substring of the device name
+    Name(String),
+}
+
+impl std::str::FromStr for DeviceSelector {
+    type Err = std::convert::Infallible;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s.parse() {
+            Ok(index) => DeviceSelector::Index(index),
+            Err(_) => DeviceSelector::Name(s.to_owned()),
+        })
+    }
+}
+
+/// Settings for creating a [`VulkanApp`]
+#[derive(Clone, Debug)]
+pub struct AppOptions {
+    pub with_raytracing: bool,
+    /// Enable the NVX extensions needed to launch CUDA kernels if the device supports them
+    pub with_cuda: bool,
+    pub tracing_mode: TracingMode,
+    /// Enable `VK_LAYER_KHRONOS_validation` with synchronization validation and forward its
+    /// messages to `log`
+    pub validation: bool,
+    /// Measure the GPU time of each frame and of the renderers drawing into it with timestamp
+    /// queries, see [`VulkanApp::take_gpu_times`]
+    pub gpu_timestamps: bool,
+    /// Number of frames the CPU may record ahead of the GPU, at least 1
+    pub frames_in_flight: usize,
+    /// Render at this multiple of the window (or headless) resolution and downsample to it,
+    /// 1 disables supersampling
+    pub supersample: u32,
+    /// Requested present mode, FIFO is used if the surface doesn't support it
+    pub present_mode: vk::PresentModeKHR,
+    /// Device overriding the automatic choice of the first NVIDIA device
+    pub device: Option<DeviceSelector>,
+    /// Swapchain formats in order of preference. The first one the surface supports is used,
+    /// otherwise whatever the surface offers.
+    pub surface_formats: Vec<vk::Format>,
+    /// Enable the `samplerAnisotropy` feature if the device supports it
+    pub sampler_anisotropy: bool,
+}
+
+/// Pick the first format of `preference` in the sRGB color space that the surface supports and
+/// that can be used as `usage`, falling back to the first usable format of the surface
+unsafe fn choose_surface_format(
+    instance: &ash::Instance,
+    surface_fn: &khr::Surface,
+    physical_device: vk::PhysicalDevice,
+    surface: vk::SurfaceKHR,
+    preference: &[vk::Format],
+    usage: vk::ImageUsageFlags,
+) -> VkResult<Option<SurfaceFormatKHR>> {
+    let supported = surface_fn
+        .get_physical_device_surface_formats(physical_device, surface)?
+        .into_iter()
+        .filter(|surface_format| {
+            surface_format.color_space == vk::ColorSpaceKHR::SRGB_NONLINEAR
+                && instance
+                    .get_physical_device_image_format_properties(
+                        physical_device,
+                        surface_format.format,
+                        vk::ImageType::TYPE_2D,
+                        vk::ImageTiling::OPTIMAL,
+                        usage,
+                        vk::ImageCreateFlags::empty(),
+                    )
+                    .is_ok()
+        })
+        .collect::<Vec<_>>();
+    debug!("Usable surface formats: {supported:?}");
+    Ok(preference
+        .iter()
+        .find_map(|&format| supported.iter().find(|s| s.format == format))
+        .or_else(|| supported.first())
+        .copied())
+}
+
+/// Device extensions required by the raytracing renderer
+fn raytracing_extensions() -> [&'static CStr; 3] {
+    [
+        ash::extensions::khr::RayTracingPipeline::name(),
+        ash::extensions::khr::AccelerationStructure::name(),
+        ash::extensions::khr::DeferredHostOperations::name(),
+    ]
+}
+
+/// Features of the selected physical device
+#[derive(Clone, Debug)]
+pub struct Capabilities {
+    pub device_name: String,
+    pub raytracing: bool,
+    pub cuda: bool,
+    /// Whether `VK_KHR_push_descriptor` is enabled
+    pub push_descriptor: bool,
+    /// Raytracing extensions the device doesn't support
+    pub missing_raytracing_extensions: Vec<CString>,
+    /// Whether the graphics queue supports timestamp queries
+    pub timestamps: bool,
+    /// Whether the `wideLines` feature is enabled
+    pub wide_lines: bool,
+    /// Whether the `largePoints` feature is enabled
+    pub large_points: bool,
+    /// Whether the `samplerAnisotropy` feature is enabled
+    pub sampler_anisotropy: bool,
+}
+
+impl std::fmt::Display for Capabilities {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: ", self.device_name)?;
+        if self.raytracing {
+            write!(f, "raytracing supported")?;
+        } else if self.missing_raytracing_extensions.is_empty() {
+            write!(f, "raytracing disabled")?;
+        } else {
+            write!(f, "raytracing unavailable, missing")?;
+            for ext in self.missing_raytracing_extensions.iter() {
+                write!(f, " {}", ext.to_string_lossy())?;
+            }
+        }
+        write!(
+            f,
+            ", CUDA {}",
+            if self.cuda {
+                "supported"
+            } else {
+                "unavailable"
+            }
+        )?;
+        write!(
+            f,
+            ", timestamps {}",
+            if self.timestamps {
+                "supported"
+            } else {
+                "unavailable"
+            }
+        )
+    }
+}
+
+/// Queries of one frame: begin and end of the draw callback followed by the pairs of
+/// [`FrameTimers`]
+const QUERIES_PER_FRAME: usize = 2 + 2 * MAX_TIMED_RENDERERS;
+
+/// GPU times collected by [`VulkanApp::take_gpu_times`]
+#[derive(Clone, Debug, Default)]
+pub struct GpuTimes {
+    /// Time of each frame's draw callback
+    pub frames: Vec<Duration>,
+    /// Times of the `idx`-th renderer of the frames in which it wrote its timestamps
+    pub renderers: [Vec<Duration>; MAX_TIMED_RENDERERS],
+}
+
+/// Timestamp queries of each frame in flight
+struct TimestampQueries {
+    pool: vk::QueryPool,
+    /// Nanoseconds per timestamp tick
+    period: f32,
+    /// Bits of the timestamps that are valid
+    valid_mask: u64,
+    /// Frames whose queries were submitted but not read back yet
+    pending: Vec<bool>,
+    gpu_times: GpuTimes,
+}
+
+impl TimestampQueries {
+    unsafe fn new(
+        device: &ash::Device,
+        num_frames: usize,
+        period: f32,
+        valid_bits: u32,
+    ) -> VkResult<Self> {
+        let pool = device.create_query_pool(
+            &vk::QueryPoolCreateInfo::default()
+                .query_type(vk::QueryType::TIMESTAMP)
+                .query_count((QUERIES_PER_FRAME * num_frames) as u32),
+            None,
+        )?;
+        Ok(Self {
+            pool,
+            period,
+            valid_mask: if valid_bits >= 64 {
+                !0
+            } else {
+                (1 << valid_bits) - 1
+            },
+            pending: vec![false; num_frames],
+            gpu_times: GpuTimes::default(),
+        })
+    }
+
+    fn first_query(frame: usize) -> u32 {
+        (QUERIES_PER_FRAME * frame) as u32
+    }
+
+    /// Reset the queries of `frame` and write its begin timestamp
+    unsafe fn cmd_begin(
+        &self,
+        device: &ash::Device,
+        cmd: vk::CommandBuffer,
+        frame: usize,
+    ) -> FrameTimers {
+        let first_query = Self::first_query(frame);
+        device.cmd_reset_query_pool(cmd, self.pool, first_query, QUERIES_PER_FRAME as u32);
+        device.cmd_write_timestamp(
+            cmd,
+            vk::PipelineStageFlags::TOP_OF_PIPE,
+            self.pool,
+            first_query,
+        );
+        FrameTimers::new(self.pool, first_query + 2)
+    }
+
+    unsafe fn cmd_end(&mut self, device: &ash::Device, cmd: vk::CommandBuffer, frame: usize) {
+        device.cmd_write_timestamp(
+            cmd,
+            vk::PipelineStageFlags::BOTTOM_OF_PIPE,
+            self.pool,
+            Self::first_query(frame) + 1,
+        );
+        self.pending[frame] = true;
+    }
+
+    /// Time between two `[timestamp, availability]` query results, `None` unless both were
+    /// written
+    fn elapsed(&self, begin: [u64; 2], end: [u64; 2]) -> Option<Duration> {
+        if begin[1] == 0 || end[1] == 0 {
+            return None;
+        }
+        let ticks = end[0].wrapping_sub(begin[0]) & self.valid_mask;
+        Some(Duration::from_nanos(
+            (ticks as f64 * self.period as f64) as u64,
+        ))
+    }
+
+    /// Collect the GPU times of the last submission of `frame`, which has to be complete
+    unsafe fn read(&mut self, device: &ash::Device, frame: usize) -> VkResult<()> {
+        if !std::mem::take(&mut self.pending[frame]) {
+            return Ok(());
+        }
+        // Renderers that didn't write their timestamps leave them unavailable
+        let mut results = [[0u64; 2]; QUERIES_PER_FRAME];
+        match device.get_query_pool_results(
+            self.pool,
+            Self::first_query(frame),
+            &mut results,
+            vk::QueryResultFlags::TYPE_64 | vk::QueryResultFlags::WITH_AVAILABILITY,
+        ) {
+            Ok(()) | Err(vk::Result::NOT_READY) => (),
+            Err(err) => return Err(err),
+        }
+        if let Some(elapsed) = self.elapsed(results[0], results[1]) {
+            self.gpu_times.frames.push(elapsed);
+        }
+        for idx in 0..MAX_TIMED_RENDERERS {
+            if let Some(elapsed) = self.elapsed(results[2 + 2 * idx], results[3 + 2 * idx]) {
+                self.gpu_times.renderers[idx].push(elapsed);
+            }
+        }
+        Ok(())
+    }
+}
+
+const COLOR_SUBRESOURCE_RANGE: vk::ImageSubresourceRange = vk::ImageSubresourceRange {
+    aspect_mask: vk::ImageAspectFlags::COLOR,
+    base_mip_level: 0,
+    level_count: 1,
+    base_array_layer: 0,
+    layer_count: 1,
+};
+
+/// Images at a multiple of the target resolution that renderers draw into. They are downsampled
+/// into the target images at the end of each frame.
+struct Supersampling {
+    factor: u32,
+    usage: vk::ImageUsageFlags,
+    /// One per target image, empty until the target images are known
+    images: Vec<vk::Image>,
+    memories: Vec<vk::DeviceMemory>,
+    extent: vk::Extent2D,
+}
+
+impl Supersampling {
+    fn new(factor: u32, usage: vk::ImageUsageFlags) -> Self {
+        Self {
+            factor,
+            usage,
+            images: Vec::new(),
+            memories: Vec::new(),
+            extent: vk::Extent2D::default(),
+        }
+    }
+
+    fn scaled(&self, extent: vk::Extent2D) -> vk::Extent2D {
+        vk::Extent2D {
+            width: extent.width * self.factor,
+            height: extent.height * self.factor,
+        }
+    }
+
+    unsafe fn destroy(&mut self, device: &ash::Device) {
+        for image in self.images.drain(..) {
+            device.destroy_image(image, None);
+        }
+        for memory in self.memories.drain(..) {
+            device.free_memory(memory, None);
+        }
+    }
+
+    /// Recreate the images unless there already is one of the scaled size per target image
+    #[allow(clippy::too_many_arguments)]
+    unsafe fn update(
+        &mut self,
+        device: &ash::Device,
+        device_memory_properties: &vk::PhysicalDeviceMemoryProperties,
+        max_image_dimension: u32,
+        command_pool: vk::CommandPool,
+        queue: vk::Queue,
+        format: vk::Format,
+        num_images: usize,
+        target_extent: vk::Extent2D,
+    ) -> anyhow::Result<()> {
+        let extent = self.scaled(target_extent);
+        if self.images.len() == num_images && self.extent == extent {
+            return Ok(());
+        }
+        anyhow::ensure!(
+            extent.width.max(extent.height) <= max_image_dimension,
+            "Supersampled resolution {}x{} exceeds the device limit of {max_image_dimension}",
+            extent.width,
+            extent.height
+        );
+        device.device_wait_idle()?;
+        self.destroy(device);
+        self.extent = extent;
+        let mut allocated = 0;
+        for _ in 0..num_images {
+            let (image, memory) = create_offscreen_image(
+                device,
+                device_memory_properties,
+                command_pool,
+                queue,
+                format,
+                extent,
+                self.usage,
+            )?;
+            allocated += device.get_image_memory_requirements(image).size;
+            self.images.push(image);
+            self.memories.push(memory);
+        }
+        info!(
+            "Supersampling {}x at {}x{}, {:.1} MiB for {num_images} images",
+            self.factor,
+            extent.width,
+            extent.height,
+            allocated as f64 / (1024.0 * 1024.0)
+        );
+        Ok(())
+    }
+
+    /// Downsample the `idx`-th image into `target`, which can be in any layout and ends up in
+    /// `PRESENT_SRC_KHR`
+    unsafe fn cmd_downsample(
+        &self,
+        device: &ash::Device,
+        cmd: vk::CommandBuffer,
+        idx: usize,
+        target: vk::Image,
+        target_extent: vk::Extent2D,
+    ) {
+        let image = self.images[idx];
+        device.cmd_pipeline_barrier(
+            cmd,
+            vk::PipelineStageFlags::ALL_COMMANDS,
+            vk::PipelineStageFlags::TRANSFER,
+            vk::DependencyFlags::default(),
+            &[],
+            &[],
+            &[
+                vk::ImageMemoryBarrier::default()
+                    .src_access_mask(vk::AccessFlags::MEMORY_WRITE)
+                    .dst_access_mask(vk::AccessFlags::TRANSFER_READ)
+                    .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                    .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                    .old_layout(vk::ImageLayout::PRESENT_SRC_KHR)
+                    .new_layout(vk::ImageLayout::TRANSFER_SRC_OPTIMAL)
+                    .image(image)
+                    .subresource_range(COLOR_SUBRESOURCE_RANGE),
+                vk::ImageMemoryBarrier::default()
+                    .dst_access_mask(vk::AccessFlags::TRANSFER_WRITE)
+                    .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                    .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                    .old_layout(vk::ImageLayout::UNDEFINED)
+                    .new_layout(vk::ImageLayout::TRANSFER_DST_OPTIMAL)
+                    .image(target)
+                    .subresource_range(COLOR_SUBRESOURCE_RANGE),
+            ],
+        );
+        let layers = vk::ImageSubresourceLayers {
+            aspect_mask: vk::ImageAspectFlags::COLOR,
+            mip_level: 0,
+            base_array_layer: 0,
+            layer_count: 1,
+        };
+        let corner = |extent: vk::Extent2D| vk::Offset3D {
+            x: extent.width as i32,
+            y: extent.height as i32,
+            z: 1,
+        };
+        device.cmd_blit_image(
+            cmd,
+            image,
+            vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+            target,
+            vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+            &[vk::ImageBlit {
+                src_subresource: layers,
+                src_offsets: [vk::Offset3D::default(), corner(self.extent)],
+                dst_subresource: layers,
+                dst_offsets: [vk::Offset3D::default(), corner(target_extent)],
+            }],
+            vk::Filter::LINEAR,
+        );
+        device.cmd_pipeline_barrier(
+            cmd,
+            vk::PipelineStageFlags::TRANSFER,
+            vk::PipelineStageFlags::BOTTOM_OF_PIPE,
+            vk::DependencyFlags::default(),
+            &[],
+            &[],
+            &[
+                vk::ImageMemoryBarrier::default()
+                    .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                    .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                    .old_layout(vk::ImageLayout::TRANSFER_SRC_OPTIMAL)
+                    .new_layout(vk::ImageLayout::PRESENT_SRC_KHR)
+                    .image(image)
+                    .subresource_range(COLOR_SUBRESOURCE_RANGE),
+                vk::ImageMemoryBarrier::default()
+                    .src_access_mask(vk::AccessFlags::TRANSFER_WRITE)
+                    .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                    .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                    .old_layout(vk::ImageLayout::TRANSFER_DST_OPTIMAL)
+                    .new_layout(vk::ImageLayout::PRESENT_SRC_KHR)
+                    .image(target)
+                    .subresource_range(COLOR_SUBRESOURCE_RANGE),
+            ],
+        );
+    }
+}
+
+/// Whether acquiring or presenting failed because the swapchain no longer matches the surface,
+/// e.g. while the window is resized. ash reports `SUBOPTIMAL_KHR` as success, `ash_swapchain`
+/// checks that flag itself and rebuilds the swapchain on the next acquire.
+fn is_out_of_date(err: vk::Result) -> bool {
+    err == vk::Result::ERROR_OUT_OF_DATE_KHR
+}
+
+/// Number of frames [`VulkanApp::frame_timings`] averages over
+const FRAME_TIME_HISTORY: usize = 500;
+
+/// Frame times of the last presented frames
+#[derive(Copy, Clone, Debug)]
+pub struct FrameTimings {
+    pub average: Duration,
+    /// Average of the slowest 1% of frames
+    pub one_percent_low: Duration,
+}
+
 pub struct VulkanApp {
     instance: ash::Instance,
-    surface: vk::SurfaceKHR,
     _entry: ash::Entry,
     graphics_queue: vk::Queue,
     start_instant: Instant,
     device: ash::Device,
-    swapchain: Swapchain,
+    target: Target,
     frames: Vec<Frame>,
     functions: Functions,
     command_pool: vk::CommandPool,
     device_memory_properties: vk::PhysicalDeviceMemoryProperties,
     physical_device: vk::PhysicalDevice,
     tracing_mode: TracingMode,
-    cuda_support: bool,
-    raytracing_support: bool,
+    capabilities: Capabilities,
+    /// Set by `request_capture`, the next drawn frame is copied to `captured_frame`
+    capture_requested: bool,
+    captured_frame: Option<CaptureImage>,
+    pipeline_cache: vk::PipelineCache,
+    debug_messenger: Option<(ext::DebugUtils, vk::DebugUtilsMessengerEXT)>,
+    /// Errors reported to `debug_messenger`, boxed as the messenger keeps a pointer to it
+    validation_errors: Option<Box<AtomicUsize>>,
+    frame_times: VecDeque<Duration>,
+    last_frame_instant: Option<Instant>,
+    timestamp_queries: Option<TimestampQueries>,
+    /// Set when acquiring or presenting failed because the surface changed
+    swapchain_out_of_date: bool,
+    supersampling: Option<Supersampling>,
+    max_image_dimension: u32,
 }
 
 impl VulkanApp {
-    pub fn new(
-        window: &Window,
-        with_raytracing: bool,
-        tracing_mode: TracingMode,
+    pub fn new(window: &Window, options: AppOptions) -> anyhow::Result<Self> {
+        let size = window.inner_size();
+        Self::create(
+            Some(window),
+            vk::Extent2D {
+                width: size.width,
+                height: size.height,
+            },
+            options,
+        )
+    }
+
+    /// Create an app without window that renders into a single offscreen image of size `extent`.
+    pub fn new_headless(extent: vk::Extent2D, options: AppOptions) -> anyhow::Result<Self> {
+        Self::create(None, extent, options)
+    }
+
+    fn create(
+        window: Option<&Window>,
+        extent: vk::Extent2D,
+        options: AppOptions,
     ) -> anyhow::Result<Self> {
+        let AppOptions {
+            with_raytracing,
+            with_cuda,
+            tracing_mode,
+            validation,
+            gpu_timestamps,
+            frames_in_flight,
+            supersample,
+            present_mode,
+            device: device_selector,
+            surface_formats,
+            sampler_anisotropy,
+        } = options;
         unsafe {
-            let surface_extensions = ash_window::enumerate_required_extensions(window)?;
-            let instance_extensions = surface_extensions.to_vec();
+            let entry = ash::Entry::load()?;
+            let mut instance_extensions = match window {
+                Some(window) => ash_window::enumerate_required_extensions(window)?.to_vec(),
+                None => Vec::new(),
+            };
+            let validation_layer =
+                CStr::from_bytes_with_nul_unchecked(b"VK_LAYER_KHRONOS_validation\0");
+            let validation = validation
+                && {
+                    let has_layer = entry
+                        .enumerate_instance_layer_properties()?
+                        .iter()
+                        .any(|layer| CStr::from_ptr(layer.layer_name.as_ptr()) == validation_layer);
+                    let has_debug_utils = entry
+                        .enumerate_instance_extension_properties(None)?
+                        .iter()
+                        .any(|ext| {
+                            CStr::from_ptr(ext.extension_name.as_ptr()) == ext::DebugUtils::name()
+                        });
+                    if !has_layer || !has_debug_utils {
+                        warn!("Validation layers requested but {validation_layer:?} or {:?} is not available", ext::DebugUtils::name());
+                    }
+                    has_layer && has_debug_utils
+                };
+            let mut layers = Vec::new();
+            if validation {
+                layers.push(validation_layer.as_ptr());
+                instance_extensions.push(ext::DebugUtils::name().as_ptr());
+                // Provided by the validation layer
+                instance_extensions.push(vk::ExtValidationFeaturesFn::name().as_ptr());
+            }
+            info!("Validation layers: {validation}");
             let app_desc = vk::ApplicationInfo::default()
                 .api_version(vk::make_api_version(0, 1, 3, 204))
                 .application_name(std::ffi::CStr::from_bytes_with_nul_unchecked(
                     b"ash-rtx-renderer\0",
                 ));
-            let instance_desc = vk::InstanceCreateInfo::default()
+            // Reports hazards like descriptors or images written while frames in flight use them
+            let enabled_validation_features =
+                [vk::ValidationFeatureEnableEXT::SYNCHRONIZATION_VALIDATION];
+            let mut validation_features = vk::ValidationFeaturesEXT::default()
+                .enabled_validation_features(&enabled_validation_features);
+            let mut instance_desc = vk::InstanceCreateInfo::default()
                 .application_info(&app_desc)
+                .enabled_layer_names(&layers)
                 .enabled_extension_names(&instance_extensions);
+            if validation {
+                instance_desc = instance_desc.push_next(&mut validation_features);
+            }
 
-            let entry = ash::Entry::load()?;
             let instance = entry.create_instance(&instance_desc, None)?;
-            let surface = ash_window::create_surface(&entry, &instance, window, None)?;
+            let validation_errors = validation.then(|| Box::new(AtomicUsize::new(0)));
+            let debug_messenger = if let Some(validation_errors) = &validation_errors {
+                let debug_utils = ext::DebugUtils::new(&entry, &instance);
+                let messenger = debug_utils.create_debug_utils_messenger(
+                    &vk::DebugUtilsMessengerCreateInfoEXT::default()
+                        .message_severity(
+                            vk::DebugUtilsMessageSeverityFlagsEXT::ERROR
+                                | vk::DebugUtilsMessageSeverityFlagsEXT::WARNING
+                                | vk::DebugUtilsMessageSeverityFlagsEXT::INFO
+                                | vk::DebugUtilsMessageSeverityFlagsEXT::VERBOSE,
+                        )
+                        .message_type(
+                            vk::DebugUtilsMessageTypeFlagsEXT::GENERAL
+                                | vk::DebugUtilsMessageTypeFlagsEXT::VALIDATION
+                                | vk::DebugUtilsMessageTypeFlagsEXT::PERFORMANCE,
+                        )
+                        .pfn_user_callback(Some(vulkan_debug_callback))
+                        .user_data(&**validation_errors as *const AtomicUsize as *mut c_void),
+                    None,
+                )?;
+                Some((debug_utils, messenger))
+            } else {
+                None
+            };
+            let surface = window
+                .map(|window| ash_window::create_surface(&entry, &instance, window, None))
+                .transpose()?;
             let surface_fn = khr::Surface::new(&entry, &instance);
 
             let mut supported_devices: Vec<_> = instance
@@ -94,8 +733,12 @@ impl VulkanApp {
                             info.queue_flags.contains(vk::QueueFlags::GRAPHICS)
                         })?;
                     let family = family as u32;
-                    let supported =
-                        surface_fn.get_physical_device_surface_support(dev, family, surface);
+                    let supported = match surface {
+                        Some(surface) => {
+                            surface_fn.get_physical_device_surface_support(dev, family, surface)
+                        }
+                        None => Ok(true),
+                    };
                     match supported {
                         Ok(false) => {
                             info!(
@@ -138,13 +781,37 @@ impl VulkanApp {
                             None
                         }
                     });
-            let (physical_device, queue_family_index, props) = first_nvidia_device
-                .or_else(|| supported_devices.pop())
-                .ok_or(VulkanError::NoDeviceForSurfaceFound)?;
-            info!(
-                "Selected {:?}",
-                ::std::ffi::CStr::from_ptr(props.properties.device_name.as_ptr() as *const c_char)
-            );
+            let (physical_device, queue_family_index, props) = match device_selector {
+                Some(selector) => {
+                    let selected = match &selector {
+                        DeviceSelector::Index(index) => supported_devices.get(*index),
+                        DeviceSelector::Name(name) => {
+                            let name = name.to_lowercase();
+                            supported_devices.iter().find(|(_, _, props)| {
+                                device_name(&props.properties)
+                                    .to_lowercase()
+                                    .contains(&name)
+                            })
+                        }
+                    };
+                    *selected.ok_or_else(|| VulkanError::NoMatchingDevice {
+                        available: supported_devices
+                            .iter()
+                            .enumerate()
+                            .map(|(index, (_, _, props))| {
+                                format!("{index}: {}", device_name(&props.properties))
+                            })
+                            .collect::<Vec<_>>()
+                            .join(", "),
+                        selector,
+                    })?
+                }
+                None => first_nvidia_device
+                    .or_else(|| supported_devices.pop())
+                    .ok_or(VulkanError::NoDeviceForSurfaceFound)?,
+            };
+            let device_name = device_name(&props.properties);
+            info!("Selected {device_name:?}");
 
             let mut extensions = HashSet::new();
             for ext in instance.enumerate_device_extension_properties(physical_device)? {
@@ -152,9 +819,12 @@ impl VulkanApp {
                 debug!("Device supports: {ext:?}");
             }
             let mut features11 = vk::PhysicalDeviceVulkan11Features::default();
+            // The any-hit shader indexes the texture array with the texture of each hit
             let mut features12 = vk::PhysicalDeviceVulkan12Features::default()
                 .buffer_device_address(true)
-                .vulkan_memory_model(true);
+                .vulkan_memory_model(true)
+                .runtime_descriptor_array(true)
+                .shader_sampled_image_array_non_uniform_indexing(true);
 
             let mut as_feature = vk::PhysicalDeviceAccelerationStructureFeaturesKHR::default()
                 .acceleration_structure(true);
@@ -165,26 +835,51 @@ impl VulkanApp {
 
             let mut enabled_extension_names = vec![khr::Swapchain::name().as_ptr()];
 
+            let missing_raytracing_extensions: Vec<_> = raytracing_extensions()
+                .into_iter()
+                .filter(|ext| !extensions.contains(*ext))
+                .map(CStr::to_owned)
+                .collect();
             let raytracing_support = with_raytracing
+                && add_if_supported(
+                    &extensions,
+                    &raytracing_extensions(),
+                    &mut enabled_extension_names,
+                );
+            let cuda_support = with_cuda
                 && add_if_supported(
                     &extensions,
                     &[
-                        ash::extensions::khr::RayTracingPipeline::name(),
-                        ash::extensions::khr::AccelerationStructure::name(),
-                        ash::extensions::khr::DeferredHostOperations::name(),
+                        vk::NvxBinaryImportFn::name(),
+                        vk::NvxImageViewHandleFn::name(),
                     ],
                     &mut enabled_extension_names,
                 );
-            info!("Raytracing support: {raytracing_support}");
-            let cuda_support = add_if_supported(
+            let push_descriptor_support = add_if_supported(
                 &extensions,
-                &[
-                    vk::NvxBinaryImportFn::name(),
-                    vk::NvxImageViewHandleFn::name(),
-                ],
+                &[khr::PushDescriptor::name()],
                 &mut enabled_extension_names,
             );
-            info!("CUDA support: {cuda_support}");
+            let timestamp_valid_bits = instance
+                .get_physical_device_queue_family_properties(physical_device)
+                [queue_family_index as usize]
+                .timestamp_valid_bits;
+            let features = instance.get_physical_device_features(physical_device);
+            let capabilities = Capabilities {
+                device_name,
+                raytracing: raytracing_support,
+                cuda: cuda_support,
+                push_descriptor: push_descriptor_support,
+                missing_raytracing_extensions,
+                timestamps: timestamp_valid_bits > 0,
+                wide_lines: features.wide_lines == vk::TRUE,
+                large_points: features.large_points == vk::TRUE,
+                sampler_anisotropy: sampler_anisotropy && features.sampler_anisotropy == vk::TRUE,
+            };
+            let enabled_features = vk::PhysicalDeviceFeatures::default()
+                .wide_lines(capabilities.wide_lines)
+                .large_points(capabilities.large_points)
+                .sampler_anisotropy(capabilities.sampler_anisotropy);
 
             let queue_create_info = [vk::DeviceQueueCreateInfo::default()
                 .queue_family_index(queue_family_index)
@@ -197,10 +892,12 @@ impl VulkanApp {
                     .push_next(&mut features12)
                     .push_next(&mut as_feature)
                     .push_next(&mut raytracing_pipeline)
+                    .enabled_features(&enabled_features)
                     .queue_create_infos(&queue_create_info)
             } else {
                 vk::DeviceCreateInfo::default()
                     .enabled_extension_names(&enabled_extension_names)
+                    .enabled_features(&enabled_features)
                     .queue_create_infos(&queue_create_info)
             };
             let mut vulkan11_features = vk::PhysicalDeviceVulkan11Features::default();
@@ -212,25 +909,81 @@ impl VulkanApp {
             let device = instance.create_device(physical_device, &device_create_info, None)?;
             let swapchain_fn = khr::Swapchain::new(&instance, &device);
             let graphics_queue = device.get_device_queue(queue_family_index, 0);
-            let mut swapchain_options = ash_swapchain::Options::default();
-            swapchain_options
-                .frames_in_flight(3)
-                .usage(vk::ImageUsageFlags::COLOR_ATTACHMENT | vk::ImageUsageFlags::STORAGE);
-            let size = window.inner_size();
-            let swapchain = Swapchain::new(
-                &ash_swapchain::Functions {
-                    device: &device,
-                    swapchain: &swapchain_fn,
-                    surface: &surface_fn,
-                },
-                swapchain_options,
-                surface,
-                physical_device,
-                vk::Extent2D {
-                    width: size.width,
-                    height: size.height,
-                },
-            );
+            let render_usage = vk::ImageUsageFlags::COLOR_ATTACHMENT
+                | vk::ImageUsageFlags::STORAGE
+                | vk::ImageUsageFlags::TRANSFER_SRC;
+            // Supersampled frames are blitted into the target images
+            let image_usage = if supersample > 1 {
+                render_usage | vk::ImageUsageFlags::TRANSFER_DST
+            } else {
+                render_usage
+            };
+            let swapchain = surface
+                .map(|surface| -> VkResult<_> {
+                    let present_mode = if surface_fn
+                        .get_physical_device_surface_present_modes(physical_device, surface)?
+                        .contains(&present_mode)
+                    {
+                        present_mode
+                    } else {
+                        warn!("Present mode {present_mode:?} not supported, falling back to FIFO");
+                        vk::PresentModeKHR::FIFO
+                    };
+                    info!("Present mode: {present_mode:?}");
+                    let mut swapchain_options = ash_swapchain::Options::default();
+                    swapchain_options
+                        .frames_in_flight(frames_in_flight)
+                        .usage(image_usage)
+                        .present_mode_preference(vec![present_mode]);
+                    match choose_surface_format(
+                        &instance,
+                        &surface_fn,
+                        physical_device,
+                        surface,
+                        &surface_formats,
+                        image_usage,
+                    )? {
+                        Some(surface_format) => {
+                            if !surface_formats.contains(&surface_format.format) {
+                                warn!(
+                                    "None of the surface formats {surface_formats:?} is supported"
+                                );
+                            }
+                            info!(
+                                "Surface format: {:?} ({:?})",
+                                surface_format.format, surface_format.color_space
+                            );
+                            swapchain_options.format_preference(vec![surface_format]);
+                        }
+                        None => warn!("The surface supports no format usable as {image_usage:?}"),
+                    }
+                    let swapchain = Swapchain::new(
+                        &ash_swapchain::Functions {
+                            device: &device,
+                            swapchain: &swapchain_fn,
+                            surface: &surface_fn,
+                        },
+                        swapchain_options,
+                        surface,
+                        physical_device,
+                        extent,
+                    );
+                    Ok((swapchain, present_mode))
+                })
+                .transpose()?;
+            let frames_in_flight = match &swapchain {
+                Some((swapchain, _)) => {
+                    if swapchain.frames_in_flight() != frames_in_flight {
+                        warn!(
+                            "Requested {frames_in_flight} frames in flight, the swapchain uses {}",
+                            swapchain.frames_in_flight()
+                        );
+                    }
+                    swapchain.frames_in_flight()
+                }
+                None => 1,
+            };
+            info!("Frames in flight: {frames_in_flight}");
 
             let command_pool = device
                 .create_command_pool(
@@ -248,7 +1001,7 @@ impl VulkanApp {
                     &vk::CommandBufferAllocateInfo::default()
                         .command_pool(command_pool)
                         .level(vk::CommandBufferLevel::PRIMARY)
-                        .command_buffer_count(swapchain.frames_in_flight() as u32),
+                        .command_buffer_count(frames_in_flight as u32),
                 )
                 .unwrap();
             let frames = cmds
@@ -258,6 +1011,12 @@ impl VulkanApp {
                     complete: device
                         .create_semaphore(&vk::SemaphoreCreateInfo::default(), None)
                         .unwrap(),
+                    fence: device
+                        .create_fence(
+                            &vk::FenceCreateInfo::default().flags(vk::FenceCreateFlags::SIGNALED),
+                            None,
+                        )
+                        .unwrap(),
                 })
                 .collect();
 
@@ -266,6 +1025,76 @@ impl VulkanApp {
             let device_memory_properties =
                 instance.get_physical_device_memory_properties(physical_device);
 
+            let pipeline_cache = create_pipeline_cache(&device, &props.properties)?;
+
+            let timestamp_queries = match (gpu_timestamps, capabilities.timestamps) {
+                (true, true) => Some(TimestampQueries::new(
+                    &device,
+                    frames_in_flight,
+                    props.properties.limits.timestamp_period,
+                    timestamp_valid_bits,
+                )?),
+                (true, false) => {
+                    warn!("The graphics queue doesn't support timestamp queries");
+                    None
+                }
+                (false, _) => None,
+            };
+
+            let target = match (surface, swapchain) {
+                (Some(surface), Some((swapchain, present_mode))) => Target::Window {
+                    surface,
+                    swapchain,
+                    present_mode,
+                },
+                _ => {
+                    let format = SurfaceFormatKHR::default()
+                        .format(vk::Format::R8G8B8A8_UNORM)
+                        .color_space(vk::ColorSpaceKHR::SRGB_NONLINEAR);
+                    let (image, memory) = create_offscreen_image(
+                        &device,
+                        &device_memory_properties,
+                        command_pool,
+                        graphics_queue,
+                        format.format,
+                        extent,
+                        image_usage,
+                    )?;
+                    Target::Offscreen {
+                        image,
+                        memory,
+                        format,
+                        extent,
+                    }
+                }
+            };
+
+            let max_image_dimension = props.properties.limits.max_image_dimension2_d;
+            let supersampling = if supersample > 1 {
+                let mut supersampling = Supersampling::new(supersample, render_usage);
+                let (format, num_images, target_extent) = match &target {
+                    Target::Window { swapchain, .. } => (
+                        swapchain.format().format,
+                        swapchain.images().len(),
+                        swapchain.extent(),
+                    ),
+                    Target::Offscreen { format, extent, .. } => (format.format, 1, *extent),
+                };
+                supersampling.update(
+                    &device,
+                    &device_memory_properties,
+                    max_image_dimension,
+                    command_pool,
+                    graphics_queue,
+                    format,
+                    num_images,
+                    target_extent,
+                )?;
+                Some(supersampling)
+            } else {
+                None
+            };
+
             let mut rt_pipeline_properties =
                 vk::PhysicalDeviceRayTracingPipelinePropertiesKHR::default();
 
@@ -282,8 +1111,7 @@ impl VulkanApp {
             Ok(Self {
                 _entry: entry,
                 instance,
-                surface,
-                swapchain,
+                target,
                 frames,
                 start_instant: Instant::now(),
                 graphics_queue,
@@ -296,19 +1124,66 @@ impl VulkanApp {
                 },
                 device_memory_properties,
                 tracing_mode,
-                cuda_support,
-                raytracing_support,
+                capabilities,
+                capture_requested: false,
+                captured_frame: None,
+                pipeline_cache,
+                debug_messenger,
+                validation_errors,
+                frame_times: VecDeque::with_capacity(FRAME_TIME_HISTORY),
+                last_frame_instant: None,
+                timestamp_queries,
+                swapchain_out_of_date: false,
+                supersampling,
+                max_image_dimension,
             })
         }
     }
 
+    /// Resize the swapchain on its next recreation. Zero sizes of minimized windows are ignored,
+    /// callers should not draw until the window has a size again.
     pub fn resize(&mut self, size: PhysicalSize<u32>) {
-        self.swapchain.update(vk::Extent2D {
-            width: size.width,
-            height: size.height,
-        });
+        if size.width == 0 || size.height == 0 {
+            return;
+        }
+        if let Target::Window { swapchain, .. } = &mut self.target {
+            swapchain.update(vk::Extent2D {
+                width: size.width,
+                height: size.height,
+            });
+        }
+    }
+
+    /// Number of errors the validation layer reported so far, `None` if it is not enabled
+    pub fn validation_errors(&self) -> Option<usize> {
+        self.validation_errors
+            .as_ref()
+            .map(|errors| errors.load(Ordering::Relaxed))
+    }
+
+    pub fn is_headless(&self) -> bool {
+        matches!(self.target, Target::Offscreen { .. })
     }
 
+    /// Extent of the images returned by [`VulkanApp::images`].
+    pub fn extent(&self) -> vk::Extent2D {
+        match &self.supersampling {
+            Some(supersampling) => supersampling.extent,
+            None => self.target_extent(),
+        }
+    }
+
+    /// Extent of the swapchain or offscreen image, smaller than [`VulkanApp::extent`] when
+    /// supersampling
+    fn target_extent(&self) -> vk::Extent2D {
+        match &self.target {
+            Target::Window { swapchain, .. } => swapchain.extent(),
+            Target::Offscreen { extent, .. } => *extent,
+        }
+    }
+
+    /// Record and submit a frame. `draw_fn` receives timers for the renderers it draws with,
+    /// which are no-ops unless GPU timestamps were requested.
     pub fn draw(
         &mut self,
         draw_fn: impl Fn(
@@ -317,71 +1192,295 @@ impl VulkanApp {
             vk::Image,
             Instant,
             usize,
+            FrameTimers,
         ) -> anyhow::Result<()>,
     ) -> anyhow::Result<()> {
         if self.tracing_mode == TracingMode::Basic {
             frame_mark();
         }
+        let now = Instant::now();
+        if let Some(last) = self.last_frame_instant.replace(now) {
+            if self.frame_times.len() == FRAME_TIME_HISTORY {
+                self.frame_times.pop_front();
+            }
+            self.frame_times.push_back(now - last);
+        }
         let span = span!(Level::INFO, "draw");
         let _ = span.enter();
         let device = &self.device;
+        let swapchain = match &mut self.target {
+            Target::Window { swapchain, .. } => swapchain,
+            Target::Offscreen {
+                image,
+                extent,
+                format,
+                ..
+            } => {
+                let cmd = self.frames[0].cmd;
+                unsafe {
+                    device.begin_command_buffer(
+                        cmd,
+                        &vk::CommandBufferBeginInfo::default()
+                            .flags(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT),
+                    )?;
+                    let timers = self
+                        .timestamp_queries
+                        .as_ref()
+                        .map(|queries| queries.cmd_begin(device, cmd, 0))
+                        .unwrap_or_default();
+                    let render_image = match &self.supersampling {
+                        Some(supersampling) => supersampling.images[0],
+                        None => *image,
+                    };
+                    draw_fn(
+                        &self.device,
+                        cmd,
+                        render_image,
+                        self.start_instant,
+                        0,
+                        timers,
+                    )?;
+                    if let Some(supersampling) = &self.supersampling {
+                        supersampling.cmd_downsample(device, cmd, 0, *image, *extent);
+                    }
+                    if std::mem::take(&mut self.capture_requested) {
+                        self.captured_frame = Some(CaptureImage::cmd_copy(
+                            device,
+                            &self.device_memory_properties,
+                            cmd,
+                            *image,
+                            format.format,
+                            *extent,
+                        )?);
+                    }
+                    if let Some(queries) = &mut self.timestamp_queries {
+                        queries.cmd_end(device, cmd, 0);
+                    }
+                    device.end_command_buffer(cmd)?;
+                    self.frames[0].submit(
+                        device,
+                        self.graphics_queue,
+                        vk::SubmitInfo::default(),
+                    )?;
+                    self.frames[0].wait(device)?;
+                    if let Some(queries) = &mut self.timestamp_queries {
+                        queries.read(device, 0)?;
+                    }
+                }
+                return Ok(());
+            }
+        };
         unsafe {
-            let acq = self
-                .swapchain
-                .acquire(
-                    &ash_swapchain::Functions {
-                        device: &self.device,
-                        swapchain: &self.functions.swapchain,
-                        surface: &self.functions.surface,
-                    },
-                    !0,
-                )
-                .context("Failed to acquire swapchain image")?;
+            let acq = match swapchain.acquire(
+                &ash_swapchain::Functions {
+                    device: &self.device,
+                    swapchain: &self.functions.swapchain,
+                    surface: &self.functions.surface,
+                },
+                !0,
+            ) {
+                Ok(acq) => acq,
+                Err(err) if is_out_of_date(err) => {
+                    debug!("Swapchain out of date on acquire ({err}), skipping frame");
+                    self.swapchain_out_of_date = true;
+                    return Ok(());
+                }
+                Err(err) => return Err(err).context("Failed to acquire swapchain image"),
+            };
 
             let span = span!(Level::INFO, "drawing");
             let _ = span.enter();
-            let cmd = self.frames[acq.frame_index].cmd;
-            let swapchain_image = self.swapchain.images()[acq.image_index];
+            let frame = &self.frames[acq.frame_index];
+            let cmd = frame.cmd;
+            let swapchain_image = swapchain.images()[acq.image_index];
+            if let Some(supersampling) = &mut self.supersampling {
+                // Acquiring may have recreated the swapchain
+                supersampling.update(
+                    device,
+                    &self.device_memory_properties,
+                    self.max_image_dimension,
+                    self.command_pool,
+                    self.graphics_queue,
+                    swapchain.format().format,
+                    swapchain.images().len(),
+                    swapchain.extent(),
+                )?;
+            }
+            frame.wait(device)?;
+            if let Some(queries) = &mut self.timestamp_queries {
+                queries.read(device, acq.frame_index)?;
+            }
             device.begin_command_buffer(
                 cmd,
                 &vk::CommandBufferBeginInfo::default()
                     .flags(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT),
             )?;
+            let timers = self
+                .timestamp_queries
+                .as_ref()
+                .map(|queries| queries.cmd_begin(device, cmd, acq.frame_index))
+                .unwrap_or_default();
 
+            let render_image = match &self.supersampling {
+                Some(supersampling) => supersampling.images[acq.image_index],
+                None => swapchain_image,
+            };
             draw_fn(
                 &self.device,
                 cmd,
-                swapchain_image,
+                render_image,
                 self.start_instant,
-                acq.frame_index,
+                // Renderers keep their per-image resources in the order of `images()`
+                acq.image_index,
+                timers,
             )?;
+            if let Some(supersampling) = &self.supersampling {
+                supersampling.cmd_downsample(
+                    device,
+                    cmd,
+                    acq.image_index,
+                    swapchain_image,
+                    swapchain.extent(),
+                );
+            }
+            // Copied while the image still belongs to the app, before it is presented
+            if std::mem::take(&mut self.capture_requested) {
+                self.captured_frame = Some(CaptureImage::cmd_copy(
+                    device,
+                    &self.device_memory_properties,
+                    cmd,
+                    swapchain_image,
+                    swapchain.format().format,
+                    swapchain.extent(),
+                )?);
+            }
+            if let Some(queries) = &mut self.timestamp_queries {
+                queries.cmd_end(device, cmd, acq.frame_index);
+            }
 
             device.end_command_buffer(cmd)?;
-            device.queue_submit(
+            frame.submit(
+                device,
                 self.graphics_queue,
-                &[vk::SubmitInfo::default()
+                vk::SubmitInfo::default()
                     .wait_semaphores(&[acq.ready])
-                    .wait_dst_stage_mask(&[vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT])
-                    .signal_semaphores(&[self.frames[acq.frame_index].complete])
-                    .command_buffers(&[cmd])],
-                acq.complete,
+                    .wait_dst_stage_mask(&[if self.supersampling.is_some() {
+                        vk::PipelineStageFlags::TRANSFER
+                    } else {
+                        vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT
+                    }])
+                    .signal_semaphores(&[frame.complete]),
             )?;
-            self.swapchain.queue_present(
+            // The swapchain paces acquisition with its own fence, signaled once the frame's work
+            // completed
+            device.queue_submit(self.graphics_queue, &[], acq.complete)?;
+            match swapchain.queue_present(
                 &ash_swapchain::Functions {
                     device: &self.device,
                     swapchain: &self.functions.swapchain,
                     surface: &self.functions.surface,
                 },
                 self.graphics_queue,
-                self.frames[acq.frame_index].complete,
+                frame.complete,
                 acq.image_index,
-            )?;
+            ) {
+                Ok(()) => (),
+                Err(err) if is_out_of_date(err) => {
+                    debug!("Swapchain out of date on present ({err})");
+                    self.swapchain_out_of_date = true;
+                    return Ok(());
+                }
+                Err(err) => return Err(err.into()),
+            }
         }
         Ok(())
     }
 
+    /// Whether a frame found the swapchain out of date since the last call. Pass the window's
+    /// current size to [`VulkanApp::resize`] then, the swapchain is recreated on the next draw,
+    /// after which renderers need to use the new images.
+    pub fn take_swapchain_out_of_date(&mut self) -> bool {
+        std::mem::take(&mut self.swapchain_out_of_date)
+    }
+
+    /// When the last frame started drawing, `None` before the first frame
+    pub fn last_frame_instant(&self) -> Option<Instant> {
+        self.last_frame_instant
+    }
+
+    /// Timings of the last frames, `None` before the second frame
+    pub fn frame_timings(&self) -> Option<FrameTimings> {
+        if self.frame_times.is_empty() {
+            return None;
+        }
+        let mut sorted: Vec<_> = self.frame_times.iter().copied().collect();
+        sorted.sort_unstable_by(|a, b| b.cmp(a));
+        let slowest = &sorted[..(sorted.len() / 100).max(1)];
+        Some(FrameTimings {
+            average: sorted.iter().sum::<Duration>() / sorted.len() as u32,
+            one_percent_low: slowest.iter().sum::<Duration>() / slowest.len() as u32,
+        })
+    }
+
+    /// Wait for all submitted frames and return the GPU times of the frames drawn since the last
+    /// call, `None` if timestamps weren't requested or aren't supported
+    pub fn take_gpu_times(&mut self) -> anyhow::Result<Option<GpuTimes>> {
+        let queries = match &mut self.timestamp_queries {
+            Some(queries) => queries,
+            None => return Ok(None),
+        };
+        unsafe {
+            self.device.device_wait_idle()?;
+            for frame in 0..queries.pending.len() {
+                queries.read(&self.device, frame)?;
+            }
+        }
+        Ok(Some(std::mem::take(&mut queries.gpu_times)))
+    }
+
+    /// Copy the next drawn frame to the host before it is presented, see
+    /// [`VulkanApp::save_captured_frame`]
+    pub fn request_capture(&mut self) -> anyhow::Result<()> {
+        let format = self.surface_format().format;
+        capture_swaps_red_blue(format)?;
+        self.capture_requested = true;
+        Ok(())
+    }
+
+    /// Whether a frame was copied since [`VulkanApp::request_capture`]
+    pub fn has_captured_frame(&self) -> bool {
+        self.captured_frame.is_some()
+    }
+
+    /// Wait for the frame copied after [`VulkanApp::request_capture`] and write it to `path`
+    /// (format is deduced from the file extension).
+    pub fn save_captured_frame(&mut self, path: &Path) -> anyhow::Result<()> {
+        let captured_frame = self
+            .captured_frame
+            .take()
+            .ok_or(VulkanError::NoFrameCaptured)?;
+        unsafe {
+            self.device.queue_wait_idle(self.graphics_queue)?;
+            captured_frame.save(path)?;
+        }
+        info!("Saved frame to {path:?}");
+        Ok(())
+    }
+
+    /// Images renderers draw into, one per swapchain image
     pub fn images(&self) -> &[vk::Image] {
-        self.swapchain.images()
+        match &self.supersampling {
+            Some(supersampling) => &supersampling.images,
+            None => self.target_images(),
+        }
+    }
+
+    /// Swapchain images or the offscreen image
+    fn target_images(&self) -> &[vk::Image] {
+        match &self.target {
+            Target::Window { swapchain, .. } => swapchain.images(),
+            Target::Offscreen { image, .. } => std::slice::from_ref(image),
+        }
     }
 
     /// Get a reference to the vulkan app's device.
@@ -393,10 +1492,21 @@ impl VulkanApp {
     /// Get the vulkan app's surface format.
     #[must_use]
     pub fn surface_format(&self) -> SurfaceFormatKHR {
-        self.swapchain.format()
+        match &self.target {
+            Target::Window { swapchain, .. } => swapchain.format(),
+            Target::Offscreen { format, .. } => *format,
+        }
     }
 
-    pub(crate) fn device_memory_properties(&self) -> &vk::PhysicalDeviceMemoryProperties {
+    /// Present mode of the swapchain, `None` when rendering headless
+    pub fn present_mode(&self) -> Option<vk::PresentModeKHR> {
+        match &self.target {
+            Target::Window { present_mode, .. } => Some(*present_mode),
+            Target::Offscreen { .. } => None,
+        }
+    }
+
+    pub fn device_memory_properties(&self) -> &vk::PhysicalDeviceMemoryProperties {
         &self.device_memory_properties
     }
 
@@ -443,16 +1553,311 @@ impl VulkanApp {
         rt_pipeline_properties
     }
 
+    /// First of `D32_SFLOAT`, `X8_D24_UNORM_PACK32` and `D16_UNORM` that can be used as sampled
+    /// and copied depth attachment. Higher precision avoids z-fighting in large scenes.
+    pub fn depth_format(&self) -> vk::Format {
+        let features = vk::FormatFeatureFlags::DEPTH_STENCIL_ATTACHMENT
+            | vk::FormatFeatureFlags::SAMPLED_IMAGE
+            | vk::FormatFeatureFlags::TRANSFER_SRC;
+        [vk::Format::D32_SFLOAT, vk::Format::X8_D24_UNORM_PACK32]
+            .into_iter()
+            .find(|&format| {
+                unsafe {
+                    self.instance
+                        .get_physical_device_format_properties(self.physical_device, format)
+                }
+                .optimal_tiling_features
+                .contains(features)
+            })
+            // Supported as depth attachment by all devices
+            .unwrap_or(vk::Format::D16_UNORM)
+    }
+
+    /// Sample counts usable for both color and depth framebuffer attachments
+    pub fn supported_sample_counts(&self) -> vk::SampleCountFlags {
+        let limits = unsafe {
+            self.instance
+                .get_physical_device_properties(self.physical_device)
+                .limits
+        };
+        limits.framebuffer_color_sample_counts & limits.framebuffer_depth_sample_counts
+    }
+
+    /// Range of supported line widths, only 1.0 without the `wideLines` feature
+    pub fn line_width_range(&self) -> [f32; 2] {
+        if !self.capabilities.wide_lines {
+            return [1.0, 1.0];
+        }
+        unsafe {
+            self.instance
+                .get_physical_device_properties(self.physical_device)
+                .limits
+                .line_width_range
+        }
+    }
+
+    /// Range of supported point sizes, only 1.0 without the `largePoints` feature
+    pub fn point_size_range(&self) -> [f32; 2] {
+        if !self.capabilities.large_points {
+            return [1.0, 1.0];
+        }
+        unsafe {
+            self.instance
+                .get_physical_device_properties(self.physical_device)
+                .limits
+                .point_size_range
+        }
+    }
+
+    /// Upper bound of the anisotropy of samplers, 1.0 without the `samplerAnisotropy` feature
+    pub fn max_sampler_anisotropy(&self) -> f32 {
+        if !self.capabilities.sampler_anisotropy {
+            return 1.0;
+        }
+        unsafe {
+            self.instance
+                .get_physical_device_properties(self.physical_device)
+                .limits
+                .max_sampler_anisotropy
+        }
+    }
+
+    /// Size limit of the push constants of a pipeline layout, at least 128 bytes
+    pub fn max_push_constants_size(&self) -> u32 {
+        unsafe {
+            self.instance
+                .get_physical_device_properties(self.physical_device)
+                .limits
+                .max_push_constants_size
+        }
+    }
+
+    pub fn pipeline_cache(&self) -> vk::PipelineCache {
+        self.pipeline_cache
+    }
+
     pub fn physical_device(&self) -> vk::PhysicalDevice {
         self.physical_device
     }
 
+    pub fn capabilities(&self) -> &Capabilities {
+        &self.capabilities
+    }
+
     pub fn cuda_support(&self) -> bool {
-        self.cuda_support
+        self.capabilities.cuda
     }
 
     pub fn raytracing_support(&self) -> bool {
-        self.raytracing_support
+        self.capabilities.raytracing
+    }
+
+    pub fn push_descriptor_support(&self) -> bool {
+        self.capabilities.push_descriptor
+    }
+}
+
+/// Whether captured pixels of `format` are in BGRA order, fails for formats that can't be saved
+fn capture_swaps_red_blue(format: vk::Format) -> Result<bool, VulkanError> {
+    match format {
+        vk::Format::B8G8R8A8_UNORM | vk::Format::B8G8R8A8_SRGB => Ok(true),
+        vk::Format::R8G8B8A8_UNORM | vk::Format::R8G8B8A8_SRGB => Ok(false),
+        _ => Err(VulkanError::UnsupportedCaptureFormat(format)),
+    }
+}
+
+/// Linear, host visible copy of a drawn frame, destroyed when dropped
+struct CaptureImage {
+    device: ash::Device,
+    image: vk::Image,
+    memory: vk::DeviceMemory,
+    format: vk::Format,
+    extent: vk::Extent2D,
+}
+
+impl CaptureImage {
+    const SUBRESOURCE_RANGE: vk::ImageSubresourceRange = vk::ImageSubresourceRange {
+        aspect_mask: vk::ImageAspectFlags::COLOR,
+        base_mip_level: 0,
+        level_count: 1,
+        base_array_layer: 0,
+        layer_count: 1,
+    };
+
+    /// Create the copy and record copying `image`, which is in `PRESENT_SRC_KHR` and stays in
+    /// that layout, into `cmd`. It holds the frame once `cmd` finished executing.
+    unsafe fn cmd_copy(
+        device: &ash::Device,
+        device_memory_properties: &vk::PhysicalDeviceMemoryProperties,
+        cmd: vk::CommandBuffer,
+        image: vk::Image,
+        format: vk::Format,
+        extent: vk::Extent2D,
+    ) -> anyhow::Result<Self> {
+        let mut capture = Self {
+            device: device.clone(),
+            image: device.create_image(
+                &vk::ImageCreateInfo::default()
+                    .image_type(vk::ImageType::TYPE_2D)
+                    .format(format)
+                    .extent(vk::Extent3D {
+                        width: extent.width,
+                        height: extent.height,
+                        depth: 1,
+                    })
+                    .mip_levels(1)
+                    .array_layers(1)
+                    .samples(vk::SampleCountFlags::TYPE_1)
+                    .tiling(vk::ImageTiling::LINEAR)
+                    .usage(vk::ImageUsageFlags::TRANSFER_DST)
+                    .sharing_mode(vk::SharingMode::EXCLUSIVE),
+                None,
+            )?,
+            memory: vk::DeviceMemory::null(),
+            format,
+            extent,
+        };
+        // From here on dropping `capture` frees whatever was created when an error is returned
+        let req = device.get_image_memory_requirements(capture.image);
+        let memory_index = find_memorytype_index(
+            &req,
+            device_memory_properties,
+            vk::MemoryPropertyFlags::HOST_VISIBLE | vk::MemoryPropertyFlags::HOST_COHERENT,
+        )
+        .ok_or_else(|| anyhow::anyhow!("Could not find memory index for frame capture"))?;
+        capture.memory = device.allocate_memory(
+            &vk::MemoryAllocateInfo::default()
+                .allocation_size(req.size)
+                .memory_type_index(memory_index),
+            None,
+        )?;
+        device.bind_image_memory(capture.image, capture.memory, 0)?;
+
+        let subresource_layers = vk::ImageSubresourceLayers {
+            aspect_mask: vk::ImageAspectFlags::COLOR,
+            mip_level: 0,
+            base_array_layer: 0,
+            layer_count: 1,
+        };
+        device.cmd_pipeline_barrier(
+            cmd,
+            vk::PipelineStageFlags::ALL_COMMANDS,
+            vk::PipelineStageFlags::TRANSFER,
+            vk::DependencyFlags::default(),
+            &[],
+            &[],
+            &[
+                vk::ImageMemoryBarrier::default()
+                    .src_access_mask(vk::AccessFlags::MEMORY_WRITE)
+                    .dst_access_mask(vk::AccessFlags::TRANSFER_READ)
+                    .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                    .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                    .old_layout(vk::ImageLayout::PRESENT_SRC_KHR)
+                    .new_layout(vk::ImageLayout::TRANSFER_SRC_OPTIMAL)
+                    .image(image)
+                    .subresource_range(Self::SUBRESOURCE_RANGE),
+                vk::ImageMemoryBarrier::default()
+                    .dst_access_mask(vk::AccessFlags::TRANSFER_WRITE)
+                    .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                    .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                    .old_layout(vk::ImageLayout::UNDEFINED)
+                    .new_layout(vk::ImageLayout::TRANSFER_DST_OPTIMAL)
+                    .image(capture.image)
+                    .subresource_range(Self::SUBRESOURCE_RANGE),
+            ],
+        );
+        device.cmd_copy_image(
+            cmd,
+            image,
+            vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+            capture.image,
+            vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+            &[vk::ImageCopy {
+                src_subresource: subresource_layers,
+                src_offset: vk::Offset3D::default(),
+                dst_subresource: subresource_layers,
+                dst_offset: vk::Offset3D::default(),
+                extent: vk::Extent3D {
+                    width: extent.width,
+                    height: extent.height,
+                    depth: 1,
+                },
+            }],
+        );
+        device.cmd_pipeline_barrier(
+            cmd,
+            vk::PipelineStageFlags::TRANSFER,
+            vk::PipelineStageFlags::HOST | vk::PipelineStageFlags::BOTTOM_OF_PIPE,
+            vk::DependencyFlags::default(),
+            &[],
+            &[],
+            &[
+                vk::ImageMemoryBarrier::default()
+                    .src_access_mask(vk::AccessFlags::TRANSFER_READ)
+                    .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                    .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                    .old_layout(vk::ImageLayout::TRANSFER_SRC_OPTIMAL)
+                    .new_layout(vk::ImageLayout::PRESENT_SRC_KHR)
+                    .image(image)
+                    .subresource_range(Self::SUBRESOURCE_RANGE),
+                vk::ImageMemoryBarrier::default()
+                    .src_access_mask(vk::AccessFlags::TRANSFER_WRITE)
+                    .dst_access_mask(vk::AccessFlags::HOST_READ)
+                    .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                    .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                    .old_layout(vk::ImageLayout::TRANSFER_DST_OPTIMAL)
+                    .new_layout(vk::ImageLayout::GENERAL)
+                    .image(capture.image)
+                    .subresource_range(Self::SUBRESOURCE_RANGE),
+            ],
+        );
+        Ok(capture)
+    }
+
+    /// Write the copied frame to `path` once the command buffer recording the copy finished
+    unsafe fn save(&self, path: &Path) -> anyhow::Result<()> {
+        let swap_red_blue = capture_swaps_red_blue(self.format)?;
+        let layout = self.device.get_image_subresource_layout(
+            self.image,
+            vk::ImageSubresource {
+                aspect_mask: vk::ImageAspectFlags::COLOR,
+                mip_level: 0,
+                array_layer: 0,
+            },
+        );
+        let ptr =
+            self.device
+                .map_memory(self.memory, 0, vk::WHOLE_SIZE, vk::MemoryMapFlags::empty())?
+                as *const u8;
+        let row_bytes = 4 * self.extent.width as usize;
+        let mut pixels = Vec::with_capacity(row_bytes * self.extent.height as usize);
+        for y in 0..self.extent.height as usize {
+            let row = std::slice::from_raw_parts(
+                ptr.add(layout.offset as usize + y * layout.row_pitch as usize),
+                row_bytes,
+            );
+            pixels.extend_from_slice(row);
+        }
+        self.device.unmap_memory(self.memory);
+
+        if swap_red_blue {
+            for pixel in pixels.chunks_exact_mut(4) {
+                pixel.swap(0, 2);
+            }
+        }
+        image::RgbaImage::from_raw(self.extent.width, self.extent.height, pixels)
+            .ok_or_else(|| anyhow::anyhow!("Captured frame has unexpected size"))?
+            .save(path)?;
+        Ok(())
+    }
+}
+
+impl Drop for CaptureImage {
+    fn drop(&mut self) {
+        unsafe {
+            self.device.destroy_image(self.image, None);
+            self.device.free_memory(self.memory, None);
+        }
     }
 }
 
@@ -460,22 +1865,238 @@ impl Drop for VulkanApp {
     fn drop(&mut self) {
         unsafe {
             let _ = self.device.device_wait_idle();
+            self.captured_frame = None;
+            if let Err(err) = save_pipeline_cache(&self.device, self.pipeline_cache) {
+                warn!("Failed to save pipeline cache: {err}");
+            }
+            self.device
+                .destroy_pipeline_cache(self.pipeline_cache, None);
+            if let Some(queries) = &self.timestamp_queries {
+                self.device.destroy_query_pool(queries.pool, None);
+            }
+            if let Some(supersampling) = &mut self.supersampling {
+                supersampling.destroy(&self.device);
+            }
             for frame in &self.frames {
                 self.device.destroy_semaphore(frame.complete, None);
+                self.device.destroy_fence(frame.fence, None);
             }
             self.device.destroy_command_pool(self.command_pool, None);
-            self.swapchain.destroy(&ash_swapchain::Functions {
-                device: &self.device,
-                swapchain: &self.functions.swapchain,
-                surface: &self.functions.surface,
-            });
-            self.functions.surface.destroy_surface(self.surface, None);
+            match &mut self.target {
+                Target::Window {
+                    surface, swapchain, ..
+                } => {
+                    swapchain.destroy(&ash_swapchain::Functions {
+                        device: &self.device,
+                        swapchain: &self.functions.swapchain,
+                        surface: &self.functions.surface,
+                    });
+                    self.functions.surface.destroy_surface(*surface, None);
+                }
+                Target::Offscreen { image, memory, .. } => {
+                    self.device.destroy_image(*image, None);
+                    self.device.free_memory(*memory, None);
+                }
+            }
             self.device.destroy_device(None);
+            if let Some((debug_utils, messenger)) = self.debug_messenger.take() {
+                debug_utils.destroy_debug_utils_messenger(messenger, None);
+            }
             self.instance.destroy_instance(None);
         }
     }
 }
 
+unsafe extern "system" fn vulkan_debug_callback(
+    message_severity: vk::DebugUtilsMessageSeverityFlagsEXT,
+    message_type: vk::DebugUtilsMessageTypeFlagsEXT,
+    callback_data: *const vk::DebugUtilsMessengerCallbackDataEXT,
+    user_data: *mut c_void,
+) -> vk::Bool32 {
+    if callback_data.is_null() || (*callback_data).p_message.is_null() {
+        return vk::FALSE;
+    }
+    let message = CStr::from_ptr((*callback_data).p_message).to_string_lossy();
+    if message_severity.contains(vk::DebugUtilsMessageSeverityFlagsEXT::ERROR) {
+        // The error count of the `VulkanApp` that created the messenger
+        if let Some(errors) = (user_data as *const AtomicUsize).as_ref() {
+            errors.fetch_add(1, Ordering::Relaxed);
+        }
+        error!("[{message_type:?}] {message}");
+    } else if message_severity.contains(vk::DebugUtilsMessageSeverityFlagsEXT::WARNING) {
+        warn!("[{message_type:?}] {message}");
+    } else if message_severity.contains(vk::DebugUtilsMessageSeverityFlagsEXT::INFO) {
+        debug!("[{message_type:?}] {message}");
+    } else {
+        trace!("[{message_type:?}] {message}");
+    }
+    vk::FALSE
+}
+
+fn pipeline_cache_path() -> PathBuf {
+    let cache_dir = std::env::var_os("XDG_CACHE_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".cache")))
+        .or_else(|| std::env::var_os("LOCALAPPDATA").map(PathBuf::from))
+        .unwrap_or_default();
+    cache_dir
+        .join("ash-rtx-renderer")
+        .join("pipeline_cache.bin")
+}
+
+/// Whether `data` has a pipeline cache header (version one) matching `props`
+fn is_compatible_pipeline_cache(data: &[u8], props: &vk::PhysicalDeviceProperties) -> bool {
+    const HEADER_SIZE: usize = 16 + vk::UUID_SIZE;
+    let read_u32 = |offset: usize| u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap());
+    data.len() >= HEADER_SIZE
+        && read_u32(0) as usize >= HEADER_SIZE
+        && read_u32(4) == vk::PipelineCacheHeaderVersion::ONE.as_raw() as u32
+        && read_u32(8) == props.vendor_id
+        && read_u32(12) == props.device_id
+        && data[16..HEADER_SIZE] == props.pipeline_cache_uuid
+}
+
+/// Creates a pipeline cache seeded from disk. Starts empty when there is no usable cache file.
+unsafe fn create_pipeline_cache(
+    device: &ash::Device,
+    props: &vk::PhysicalDeviceProperties,
+) -> anyhow::Result<vk::PipelineCache> {
+    let path = pipeline_cache_path();
+    let initial_data = match std::fs::read(&path) {
+        Ok(data) if is_compatible_pipeline_cache(&data, props) => {
+            info!("Loaded pipeline cache {path:?}");
+            data
+        }
+        Ok(_) => {
+            warn!("Ignoring incompatible or corrupt pipeline cache {path:?}");
+            Vec::new()
+        }
+        Err(err) => {
+            debug!("No pipeline cache loaded from {path:?}: {err}");
+            Vec::new()
+        }
+    };
+    match device.create_pipeline_cache(
+        &vk::PipelineCacheCreateInfo::default().initial_data(&initial_data),
+        None,
+    ) {
+        Ok(cache) => Ok(cache),
+        Err(err) if !initial_data.is_empty() => {
+            warn!("Failed to create pipeline cache from {path:?}: {err}. Starting empty");
+            Ok(device.create_pipeline_cache(&vk::PipelineCacheCreateInfo::default(), None)?)
+        }
+        Err(err) => Err(err.into()),
+    }
+}
+
+unsafe fn save_pipeline_cache(
+    device: &ash::Device,
+    pipeline_cache: vk::PipelineCache,
+) -> anyhow::Result<()> {
+    let data = device.get_pipeline_cache_data(pipeline_cache)?;
+    let path = pipeline_cache_path();
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    std::fs::write(&path, data)?;
+    debug!("Saved pipeline cache to {path:?}");
+    Ok(())
+}
+
+/// Creates an offscreen render target and transitions it to `PRESENT_SRC_KHR` so that renderers
+/// can treat it like a swapchain image.
+unsafe fn create_offscreen_image(
+    device: &ash::Device,
+    device_memory_properties: &vk::PhysicalDeviceMemoryProperties,
+    command_pool: vk::CommandPool,
+    queue: vk::Queue,
+    format: vk::Format,
+    extent: vk::Extent2D,
+    usage: vk::ImageUsageFlags,
+) -> anyhow::Result<(vk::Image, vk::DeviceMemory)> {
+    let image = device.create_image(
+        &vk::ImageCreateInfo::default()
+            .image_type(vk::ImageType::TYPE_2D)
+            .format(format)
+            .extent(vk::Extent3D {
+                width: extent.width,
+                height: extent.height,
+                depth: 1,
+            })
+            .mip_levels(1)
+            .array_layers(1)
+            .samples(vk::SampleCountFlags::TYPE_1)
+            .tiling(vk::ImageTiling::OPTIMAL)
+            .usage(usage | vk::ImageUsageFlags::TRANSFER_DST)
+            .sharing_mode(vk::SharingMode::EXCLUSIVE),
+        None,
+    )?;
+    let req = device.get_image_memory_requirements(image);
+    let memory_index = find_memorytype_index(
+        &req,
+        device_memory_properties,
+        vk::MemoryPropertyFlags::DEVICE_LOCAL,
+    )
+    .ok_or_else(|| anyhow::anyhow!("Could not find memory index for offscreen image"))?;
+    let memory = device.allocate_memory(
+        &vk::MemoryAllocateInfo::default()
+            .allocation_size(req.size)
+            .memory_type_index(memory_index),
+        None,
+    )?;
+    device.bind_image_memory(image, memory, 0)?;
+
+    let cmd = device.allocate_command_buffers(
+        &vk::CommandBufferAllocateInfo::default()
+            .command_pool(command_pool)
+            .level(vk::CommandBufferLevel::PRIMARY)
+            .command_buffer_count(1),
+    )?[0];
+    device.begin_command_buffer(
+        cmd,
+        &vk::CommandBufferBeginInfo::default().flags(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT),
+    )?;
+    device.cmd_pipeline_barrier(
+        cmd,
+        vk::PipelineStageFlags::TOP_OF_PIPE,
+        vk::PipelineStageFlags::BOTTOM_OF_PIPE,
+        vk::DependencyFlags::default(),
+        &[],
+        &[],
+        &[vk::ImageMemoryBarrier::default()
+            .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+            .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+            .old_layout(vk::ImageLayout::UNDEFINED)
+            .new_layout(vk::ImageLayout::PRESENT_SRC_KHR)
+            .image(image)
+            .subresource_range(vk::ImageSubresourceRange {
+                aspect_mask: vk::ImageAspectFlags::COLOR,
+                base_mip_level: 0,
+                level_count: 1,
+                base_array_layer: 0,
+                layer_count: 1,
+            })],
+    );
+    device.end_command_buffer(cmd)?;
+    device
+        .queue_submit(
+            queue,
+            &[vk::SubmitInfo::default().command_buffers(&[cmd])],
+            vk::Fence::null(),
+        )
+        .context("queue submit failed.")?;
+    device.queue_wait_idle(queue)?;
+    device.free_command_buffers(command_pool, &[cmd]);
+
+    Ok((image, memory))
+}
+
+fn device_name(props: &vk::PhysicalDeviceProperties) -> String {
+    unsafe { CStr::from_ptr(props.device_name.as_ptr() as *const c_char) }
+        .to_string_lossy()
+        .into_owned()
+}
+
 fn add_if_supported(
     supported: &HashSet<CString>,
     ext: &[&'static CStr],